@@ -35,9 +35,29 @@ pub struct CublasLTBatchMatmul {
     pub c: Option<Tensor>,
     pub alpha: Option<f32>,
     pub beta: Option<f32>,
+    /// `b` is stored as (batch, k, n) (NN) instead of (batch, n, k) (TN).
+    pub b_nn: bool,
 }
 
 impl CublasLTBatchMatmul {
+    /// Returns `(batch, n, ldb)` for `b`, checking that its inner dim matches `k`.
+    fn b_dims(&self, b_l: &Layout, k: usize) -> Result<(usize, usize, usize)> {
+        if self.b_nn {
+            let (b_0, b_1, n) = b_l.shape().dims3()?;
+            if b_1 != k {
+                diffusion_rs_common::bail!("NN layout expects `b` of shape (batch, k, n)");
+            }
+            Ok((b_0, n, n))
+        } else {
+            // Assume TN
+            let (b_0, n, b_2) = b_l.shape().dims3()?;
+            if b_2 != k {
+                diffusion_rs_common::bail!("This layer only supports TN layout");
+            }
+            Ok((b_0, n, k))
+        }
+    }
+
     pub fn fwd_f16(
         &self,
         a: &diffusion_rs_common::core::CudaStorage,
@@ -49,20 +69,14 @@ impl CublasLTBatchMatmul {
     ) -> Result<(diffusion_rs_common::core::CudaStorage, Shape)> {
         let dev = a.device();
 
-        // Assume TN
         let (batch_size, m, k) = a_l.shape().dims3()?;
-        let (b_0, n, b_2) = b_l.shape().dims3()?;
-
-        if b_2 != k {
-            diffusion_rs_common::bail!("This layer only supports TN layout");
-        }
+        let (b_0, n, ldb) = self.b_dims(b_l, k)?;
 
         if b_0 != batch_size {
             diffusion_rs_common::bail!("`b` must have the same batch size as `a`")
         }
 
         let lda = k;
-        let ldb = k;
         let ldc = m;
 
         let out_shape = Shape::from((batch_size, n, m));
@@ -117,7 +131,7 @@ impl CublasLTBatchMatmul {
 
         let config = MatmulConfig {
             transa: true,
-            transb: false,
+            transb: self.b_nn,
             m: m as u64,
             n: n as u64,
             k: k as u64,
@@ -144,7 +158,9 @@ impl CublasLTBatchMatmul {
         Ok((out, out_shape))
     }
 
-    pub fn fwd_bf16(
+    /// F16 `a`/`b` accumulated into an F32 `c`, returning an F32 result. The bias, if any, must
+    /// be F32 too.
+    pub fn fwd_f16_f32_out(
         &self,
         a: &diffusion_rs_common::core::CudaStorage,
         a_l: &Layout,
@@ -155,20 +171,119 @@ impl CublasLTBatchMatmul {
     ) -> Result<(diffusion_rs_common::core::CudaStorage, Shape)> {
         let dev = a.device();
 
-        // Assume TN
         let (batch_size, m, k) = a_l.shape().dims3()?;
-        let (b_0, n, b_2) = b_l.shape().dims3()?;
+        let (b_0, n, ldb) = self.b_dims(b_l, k)?;
+
+        if b_0 != batch_size {
+            diffusion_rs_common::bail!("`b` must have the same batch size as `a`")
+        }
+        if b.dtype() != DType::F16 {
+            diffusion_rs_common::bail!("`b` must be f16 like `a`, got {:?}", b.dtype())
+        }
+
+        let lda = k;
+        let ldc = m;
+
+        let out_shape = Shape::from((batch_size, n, m));
+
+        let a = a.as_cuda_slice::<f16>()?.slice(a_l.start_offset()..);
+        let b = b.as_cuda_slice::<f16>()?.slice(b_l.start_offset()..);
+
+        let bias = if let (Some(bias), Some(bias_l)) = (bias, bias_l) {
+            if bias_l.shape().dims1()? != m {
+                diffusion_rs_common::bail!("Bias does not have the correct shape");
+            }
+            if bias.dtype() != DType::F32 {
+                diffusion_rs_common::bail!(
+                    "Bias must be f32 when accumulating into an f32 `c`, got {:?}",
+                    bias.dtype()
+                );
+            }
+
+            Some(bias.as_cuda_slice::<f32>()?.slice(bias_l.start_offset()..))
+        } else {
+            None
+        };
+
+        let Some(c) = &self.c else {
+            diffusion_rs_common::bail!("f16 inputs with an f32 output require an f32 `c`")
+        };
+        let (c, c_l) = c.storage_and_layout();
+        let c = match &*c {
+            Storage::Cuda(storage) => storage.as_cuda_slice::<f32>()?,
+            _ => diffusion_rs_common::bail!("`c` must be a cuda tensor"),
+        };
+        match c_l.contiguous_offsets() {
+            Some((o1, o2)) => {
+                if o1 != 0 {
+                    diffusion_rs_common::bail!("`c` start offset must be 0");
+                }
+                if o2 != out_shape.elem_count() {
+                    diffusion_rs_common::bail!("`c` end offset must be {}", out_shape.elem_count())
+                }
+            }
+            None => diffusion_rs_common::bail!("`c` has to be contiguous"),
+        };
+
+        if c_l.shape().dims3()? != (batch_size, n, m) {
+            diffusion_rs_common::bail!("`c` does not have the correct shape");
+        }
+        let mut out = c.clone();
+        let stride_c = c_l.stride()[0];
+
+        let config = MatmulConfig {
+            transa: true,
+            transb: self.b_nn,
+            m: m as u64,
+            n: n as u64,
+            k: k as u64,
+            alpha: self.alpha.unwrap_or(1.0),
+            lda: lda as i64,
+            ldb: ldb as i64,
+            beta: self.beta.unwrap_or(0.0),
+            ldc: ldc as i64,
+            stride_a: Some(a_l.stride()[0] as i64),
+            stride_b: Some(b_l.stride()[0] as i64),
+            stride_c: Some(stride_c as i64),
+            stride_bias: None,
+            batch_size: Some(c_int::try_from(batch_size)?),
+        };
 
-        if b_2 != k {
-            diffusion_rs_common::bail!("This layer only supports TN layout");
+        unsafe {
+            self.cublaslt
+                .matmul_f32_out(config, &a, &b, &mut out, bias.as_ref(), self.act.as_ref())
+                .map_err(|e| diffusion_rs_common::core::Error::Cuda(Box::new(e)))?;
         }
 
+        let out = diffusion_rs_common::core::CudaStorage::wrap_cuda_slice(out, dev.clone());
+
+        Ok((out, out_shape))
+    }
+
+    /// Whether the f16 inputs accumulate into an f32 `c`.
+    fn f32_c(&self) -> bool {
+        self.c.as_ref().is_some_and(|c| c.dtype() == DType::F32)
+    }
+
+    pub fn fwd_bf16(
+        &self,
+        a: &diffusion_rs_common::core::CudaStorage,
+        a_l: &Layout,
+        b: &diffusion_rs_common::core::CudaStorage,
+        b_l: &Layout,
+        bias: Option<&diffusion_rs_common::core::CudaStorage>,
+        bias_l: Option<&Layout>,
+    ) -> Result<(diffusion_rs_common::core::CudaStorage, Shape)> {
+        let dev = a.device();
+
+        let (batch_size, m, k) = a_l.shape().dims3()?;
+        let (b_0, n, ldb) = self.b_dims(b_l, k)?;
+
         if b_0 != batch_size {
             diffusion_rs_common::bail!("`b` must have the same batch size as `a`")
         }
 
         let lda = k;
-        let ldb = k;
         let ldc = m;
 
         let out_shape = Shape::from((batch_size, n, m));
@@ -223,7 +338,7 @@ impl CublasLTBatchMatmul {
 
         let config = MatmulConfig {
             transa: true,
-            transb: false,
+            transb: self.b_nn,
             m: m as u64,
             n: n as u64,
             k: k as u64,
@@ -261,20 +376,14 @@ impl CublasLTBatchMatmul {
     ) -> Result<(diffusion_rs_common::core::CudaStorage, Shape)> {
         let dev = a.device();
 
-        // Assume TN
         let (batch_size, m, k) = a_l.shape().dims3()?;
-        let (b_0, n, b_2) = b_l.shape().dims3()?;
-
-        if b_2 != k {
-            diffusion_rs_common::bail!("This layer only supports TN layout");
-        }
+        let (b_0, n, ldb) = self.b_dims(b_l, k)?;
 
         if b_0 != batch_size {
             diffusion_rs_common::bail!("`b` must have the same batch size as `a`")
         }
 
         let lda = k;
-        let ldb = k;
         let ldc = m;
 
         let out_shape = Shape::from((batch_size, n, m));
@@ -329,7 +438,7 @@ impl CublasLTBatchMatmul {
 
         let config = MatmulConfig {
             transa: true,
-            transb: false,
+            transb: self.b_nn,
             m: m as u64,
             n: n as u64,
             k: k as u64,
@@ -380,6 +489,9 @@ impl diffusion_rs_common::core::CustomOp2 for CublasLTBatchMatmul {
         b_l: &Layout,
     ) -> Result<(diffusion_rs_common::core::CudaStorage, Shape)> {
         match a.dtype() {
+            diffusion_rs_common::core::DType::F16 if self.f32_c() => {
+                self.fwd_f16_f32_out(a, a_l, b, b_l, None, None)
+            }
             diffusion_rs_common::core::DType::F16 => self.fwd_f16(a, a_l, b, b_l, None, None),
             diffusion_rs_common::core::DType::BF16 => self.fwd_bf16(a, a_l, b, b_l, None, None),
             diffusion_rs_common::core::DType::F32 => self.fwd_f32(a, a_l, b, b_l, None, None),
@@ -419,6 +531,9 @@ impl diffusion_rs_common::core::CustomOp3 for CublasLTBatchMatmul {
         bias_l: &Layout,
     ) -> Result<(diffusion_rs_common::core::CudaStorage, Shape)> {
         match a.dtype() {
+            diffusion_rs_common::core::DType::F16 if self.f32_c() => {
+                self.fwd_f16_f32_out(a, a_l, b, b_l, Some(bias), Some(bias_l))
+            }
             diffusion_rs_common::core::DType::F16 => {
                 self.fwd_f16(a, a_l, b, b_l, Some(bias), Some(bias_l))
             }
@@ -442,11 +557,16 @@ impl diffusion_rs_common::core::CustomOp3 for CublasLTBatchMatmul {
 /// * `a` - Input tensor of size BxMxK
 /// * `b` - Input tensor of size BxNxK
 /// * `out` - Optional Output tensor of size BxNxK.
-///           If set and beta != 0, will be added to the end result of A*B before `act`
+///           If set and beta != 0, will be added to the end result of A*B, see
+///           `act_before_residual` for the ordering with `act`.
+///           May be F32 with F16 `a`/`b` to accumulate in F32, the result and `bias` are then F32
 /// * `alpha` - Optional scaling factor for A*B
 /// * `beta` - Optional scaling factor for C
 /// * `bias` - Optional bias tensor of size M
 /// * `act` - Optional Gelu or Relu activation. If set, will be added to the end result
+/// * `act_before_residual` - If false (the cuBLASLt epilogue order), the result is
+///           `act(alpha*A*B + bias + beta*C)`. If true, it is `act(alpha*A*B + bias) + beta*C`;
+///           cuBLASLt cannot express this, so `beta*C` is then added by a separate kernel
 /// * `cublaslt` - CublasLt handle
 ///
 /// The resulting tensor is of shape NxM
@@ -459,14 +579,109 @@ pub fn fused_batch_matmul(
     beta: Option<f32>,
     bias: Option<&Tensor>,
     act: Option<Activation>,
+    act_before_residual: bool,
+    cublaslt: CublasLt,
+) -> Result<Tensor> {
+    fused_batch_matmul_impl(
+        a,
+        b,
+        out,
+        alpha,
+        beta,
+        bias,
+        act,
+        act_before_residual,
+        cublaslt,
+        false,
+    )
+}
+
+/// Same as [`fused_batch_matmul`], but `b` is given in NN layout.
+///
+/// # Arguments
+///
+/// * `a` - Input tensor of size BxMxK
+/// * `b` - Input tensor of size BxKxN
+///
+/// All other arguments are the same as for [`fused_batch_matmul`]. This avoids
+/// materializing a transposed copy of weights stored as (batch, k, n).
+///
+/// The resulting tensor is of shape NxM
+#[allow(clippy::too_many_arguments)]
+pub fn fused_batch_matmul_nn(
+    a: &Tensor,
+    b: &Tensor,
+    out: Option<&Tensor>,
+    alpha: Option<f32>,
+    beta: Option<f32>,
+    bias: Option<&Tensor>,
+    act: Option<Activation>,
+    act_before_residual: bool,
+    cublaslt: CublasLt,
+) -> Result<Tensor> {
+    fused_batch_matmul_impl(
+        a,
+        b,
+        out,
+        alpha,
+        beta,
+        bias,
+        act,
+        act_before_residual,
+        cublaslt,
+        true,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn fused_batch_matmul_impl(
+    a: &Tensor,
+    b: &Tensor,
+    out: Option<&Tensor>,
+    alpha: Option<f32>,
+    beta: Option<f32>,
+    bias: Option<&Tensor>,
+    act: Option<Activation>,
+    act_before_residual: bool,
     cublaslt: CublasLt,
+    b_nn: bool,
 ) -> Result<Tensor> {
+    let has_act = matches!(act, Some(Activation::Relu | Activation::Gelu));
+    let beta_c = match (out, beta) {
+        (Some(c), Some(beta)) if beta != 0.0 => Some((c, beta)),
+        _ => None,
+    };
+    // The epilogue always runs after the `beta * c` accumulation, so the activated product is
+    // computed without `c` and the residual is added afterwards. An f32 `c` for f16 inputs is
+    // replaced by zeros rather than dropped so the product still accumulates in f32.
+    if let (true, true, Some((c, beta))) = (act_before_residual, has_act, beta_c) {
+        let zeros = if c.dtype() != a.dtype() {
+            Some(c.zeros_like()?)
+        } else {
+            None
+        };
+        let ys = fused_batch_matmul_impl(
+            a,
+            b,
+            zeros.as_ref(),
+            alpha,
+            None,
+            bias,
+            act,
+            false,
+            cublaslt,
+            b_nn,
+        )?;
+        return ys + (c * beta as f64)?;
+    }
+
     let op = CublasLTBatchMatmul {
         act,
         cublaslt: cublaslt.0,
         c: out.cloned(),
         alpha,
         beta,
+        b_nn,
     };
 
     if let Some(bias) = bias {
@@ -475,3 +690,178 @@ pub fn fused_batch_matmul(
         a.apply_op2(b, op)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn max_abs_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+        (a.to_dtype(DType::F32)? - b.to_dtype(DType::F32)?)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()
+    }
+
+    #[test]
+    fn fused_batch_matmul_nn_matches_tn() -> Result<()> {
+        let device = Device::new_cuda(0)?;
+        let cublaslt = CublasLt::new(&device)?;
+        let a = Tensor::randn(0f32, 1., (2, 8, 16), &device)?;
+        let b_nn = Tensor::randn(0f32, 1., (2, 16, 12), &device)?;
+        let b_tn = b_nn.transpose(1, 2)?.contiguous()?;
+        let bias = Tensor::randn(0f32, 1., 8, &device)?;
+
+        let tn = fused_batch_matmul(
+            &a,
+            &b_tn,
+            None,
+            None,
+            None,
+            Some(&bias),
+            Some(Activation::Relu),
+            false,
+            cublaslt.clone(),
+        )?;
+        let nn = fused_batch_matmul_nn(
+            &a,
+            &b_nn,
+            None,
+            None,
+            None,
+            Some(&bias),
+            Some(Activation::Relu),
+            false,
+            cublaslt,
+        )?;
+        assert_eq!(nn.dims(), &[2, 12, 8]);
+        assert!(max_abs_diff(&nn, &tn)? < 1e-4);
+        Ok(())
+    }
+
+    #[test]
+    fn identity_activation_matches_none() -> Result<()> {
+        let device = Device::new_cuda(0)?;
+        let cublaslt = CublasLt::new(&device)?;
+        let a = Tensor::randn(0f32, 1., (2, 8, 16), &device)?.to_dtype(DType::F16)?;
+        let b = Tensor::randn(0f32, 1., (2, 12, 16), &device)?.to_dtype(DType::F16)?;
+        let bias = Tensor::randn(0f32, 1., 8, &device)?.to_dtype(DType::F16)?;
+
+        let none = fused_batch_matmul(
+            &a,
+            &b,
+            None,
+            None,
+            None,
+            Some(&bias),
+            None,
+            false,
+            cublaslt.clone(),
+        )?;
+        let identity = fused_batch_matmul(
+            &a,
+            &b,
+            None,
+            None,
+            None,
+            Some(&bias),
+            Some(Activation::Identity),
+            false,
+            cublaslt,
+        )?;
+        assert_eq!(
+            none.flatten_all()?.to_vec1::<f16>()?,
+            identity.flatten_all()?.to_vec1::<f16>()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn f16_inputs_accumulate_into_f32_c() -> Result<()> {
+        let device = Device::new_cuda(0)?;
+        let cublaslt = CublasLt::new(&device)?;
+        let a = Tensor::randn(0f32, 1., (2, 32, 1024), &device)?.to_dtype(DType::F16)?;
+        let b = Tensor::randn(0f32, 1., (2, 24, 1024), &device)?.to_dtype(DType::F16)?;
+        let c = Tensor::zeros((2, 24, 32), DType::F32, &device)?;
+        // F32 reference on the same F16-rounded inputs.
+        let reference = b
+            .to_dtype(DType::F32)?
+            .matmul(&a.to_dtype(DType::F32)?.t()?)?;
+
+        let f32_out = fused_batch_matmul(
+            &a,
+            &b,
+            Some(&c),
+            None,
+            None,
+            None,
+            None,
+            false,
+            cublaslt.clone(),
+        )?;
+        assert_eq!(f32_out.dtype(), DType::F32);
+        let f16_out = fused_batch_matmul(&a, &b, None, None, None, None, None, false, cublaslt)?;
+        assert_eq!(f16_out.dtype(), DType::F16);
+
+        let f32_error = max_abs_diff(&f32_out, &reference)?;
+        let f16_error = max_abs_diff(&f16_out, &reference)?;
+        assert!(f32_error < 1e-3, "{f32_error}");
+        assert!(f32_error < f16_error, "{f32_error} {f16_error}");
+        Ok(())
+    }
+
+    #[test]
+    fn act_residual_orderings() -> Result<()> {
+        let device = Device::new_cuda(0)?;
+        let cublaslt = CublasLt::new(&device)?;
+        // F16-representable inputs so the F32 reference also holds for the F16 inputs.
+        let a = Tensor::randn(0f32, 1., (2, 8, 16), &device)?
+            .to_dtype(DType::F16)?
+            .to_dtype(DType::F32)?;
+        let b = Tensor::randn(0f32, 1., (2, 12, 16), &device)?
+            .to_dtype(DType::F16)?
+            .to_dtype(DType::F32)?;
+        let c = Tensor::randn(0f32, 1., (2, 12, 8), &device)?;
+        let bias = Tensor::randn(0f32, 1., 8, &device)?;
+        let beta = 0.5;
+        let product = b.matmul(&a.t()?)?.broadcast_add(&bias)?;
+
+        for act_before_residual in [false, true] {
+            let expected = if act_before_residual {
+                (product.relu()? + (&c * beta as f64)?)?
+            } else {
+                (&product + (&c * beta as f64)?)?.relu()?
+            };
+            let ys = fused_batch_matmul(
+                &a,
+                &b,
+                Some(&c),
+                None,
+                Some(beta),
+                Some(&bias),
+                Some(Activation::Relu),
+                act_before_residual,
+                cublaslt.clone(),
+            )?;
+            let error = max_abs_diff(&ys, &expected)?;
+            assert!(error < 1e-4, "{act_before_residual} {error}");
+
+            // F16 inputs accumulating into an F32 `c`.
+            let ys = fused_batch_matmul(
+                &a.to_dtype(DType::F16)?,
+                &b.to_dtype(DType::F16)?,
+                Some(&c),
+                None,
+                Some(beta),
+                Some(&bias),
+                Some(Activation::Relu),
+                act_before_residual,
+                cublaslt.clone(),
+            )?;
+            assert_eq!(ys.dtype(), DType::F32);
+            let error = max_abs_diff(&ys, &expected)?;
+            assert!(error < 1e-3, "{act_before_residual} {error}");
+        }
+        Ok(())
+    }
+}
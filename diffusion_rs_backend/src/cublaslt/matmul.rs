@@ -87,6 +87,8 @@ impl Workspace {
 pub enum Activation {
     Relu,
     Gelu,
+    /// No activation: uses the same epilogue as `None`.
+    Identity,
 }
 
 /// MatrixLayout helper type
@@ -210,6 +212,8 @@ impl MatmulDesc {
                     // Act + bias
                     Activation::Relu => sys::cublasLtEpilogue_t::CUBLASLT_EPILOGUE_RELU_BIAS,
                     Activation::Gelu => sys::cublasLtEpilogue_t::CUBLASLT_EPILOGUE_GELU_BIAS,
+                    // Only bias
+                    Activation::Identity => sys::cublasLtEpilogue_t::CUBLASLT_EPILOGUE_BIAS,
                 })
                 // Only bias
                 .unwrap_or(sys::cublasLtEpilogue_t::CUBLASLT_EPILOGUE_BIAS);
@@ -241,6 +245,8 @@ impl MatmulDesc {
             match act {
                 Activation::Relu => sys::cublasLtEpilogue_t::CUBLASLT_EPILOGUE_RELU,
                 Activation::Gelu => sys::cublasLtEpilogue_t::CUBLASLT_EPILOGUE_GELU,
+                // No epilogue
+                Activation::Identity => sys::cublasLtEpilogue_t::CUBLASLT_EPILOGUE_DEFAULT,
             }
         } else {
             // No epilogue
@@ -507,6 +513,44 @@ pub trait Matmul<T>: MatmulShared {
         c: &mut O,
         bias: Option<&I>,
         act: Option<&Activation>,
+    ) -> Result<(), CublasError> {
+        self.matmul_with_c_type(cfg, a, b, c, bias, act, Self::matrix_type())
+    }
+
+    /// Matrix matrix multiplication of `T` inputs accumulated into an f32 `c`, e.g. f16 inputs
+    /// with an f32 residual. The bias, if any, is f32 as well.
+    ///
+    /// # Safety
+    /// This is unsafe because improper arguments may lead to invalid
+    /// memory accesses.
+    unsafe fn matmul_f32_out<I: DevicePtr<T>, O: DevicePtrMut<f32>, B: DevicePtr<f32>>(
+        &self,
+        cfg: MatmulConfig,
+        a: &I,
+        b: &I,
+        c: &mut O,
+        bias: Option<&B>,
+        act: Option<&Activation>,
+    ) -> Result<(), CublasError> {
+        self.matmul_with_c_type(cfg, a, b, c, bias, act, sys::cudaDataType_t::CUDA_R_32F)
+    }
+
+    /// Matrix matrix multiplication where `c` (and the output, written in place) has the
+    /// element type `C` described by `c_type`.
+    ///
+    /// # Safety
+    /// This is unsafe because improper arguments may lead to invalid
+    /// memory accesses, or `c_type` not matching `C`.
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn matmul_with_c_type<C, I: DevicePtr<T>, O: DevicePtrMut<C>, B: DevicePtr<C>>(
+        &self,
+        cfg: MatmulConfig,
+        a: &I,
+        b: &I,
+        c: &mut O,
+        bias: Option<&B>,
+        act: Option<&Activation>,
+        c_type: sys::cudaDataType,
     ) -> Result<(), CublasError> {
         let (a_rows, a_cols) = if cfg.transa {
             (cfg.k, cfg.m)
@@ -530,7 +574,7 @@ pub trait Matmul<T>: MatmulShared {
             b_layout.set_batch(batch_size, stride_b)?;
         }
 
-        let c_layout = MatrixLayout::new(Self::matrix_type(), cfg.m, cfg.n, cfg.ldc)?;
+        let c_layout = MatrixLayout::new(c_type, cfg.m, cfg.n, cfg.ldc)?;
         if let (Some(batch_size), Some(stride_c)) = (cfg.batch_size, cfg.stride_c) {
             c_layout.set_batch(batch_size, stride_c)?;
         }
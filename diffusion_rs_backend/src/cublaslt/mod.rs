@@ -13,7 +13,7 @@ mod api;
 mod matmul;
 
 #[cfg(feature = "cuda")]
-pub use api::{fused_batch_matmul, CublasLt};
+pub use api::{fused_batch_matmul, fused_batch_matmul_nn, CublasLt};
 
 pub enum F8MatmulOutType {
     F8,
@@ -70,11 +70,17 @@ impl CublasLtWrapper {
     /// * `a` - Input tensor of size BxMxK
     /// * `b` - Input tensor of size BxNxK
     /// * `out` - Optional Output tensor of size BxNxK.
-    ///           If set and beta != 0, will be added to the end result of A*B before `act`
+    ///           If set and beta != 0, will be added to the end result of A*B, see
+    ///           `act_before_residual` for the ordering with `act`.
+    ///           May be F32 with F16 `a`/`b` to accumulate in F32
     /// * `alpha` - Optional scaling factor for A*B
     /// * `beta` - Optional scaling factor for C
     /// * `bias` - Optional bias tensor of size M
-    /// * `act` - Optional Gelu or Relu activation. If set, will be added to the end result
+    /// * `act` - Optional Gelu or Relu activation. If set, will be added to the end result.
+    ///           `Identity` is the same as `None`.
+    /// * `act_before_residual` - If false, `act` is applied after the `beta * out` accumulation
+    ///           (the cuBLASLt epilogue order). If true, `beta * out` is added to the activated
+    ///           product in a separate step.
     ///
     /// The resulting tensor is of shape NxM
     #[allow(clippy::too_many_arguments)]
@@ -87,12 +93,14 @@ impl CublasLtWrapper {
         beta: Option<f32>,
         bias: Option<&Tensor>,
         act: Option<CandleActivation>,
+        act_before_residual: bool,
     ) -> Result<Tensor> {
         #[cfg(feature = "cuda")]
         {
             let inner_act = act.map(|a| match a {
                 CandleActivation::Relu => matmul::Activation::Relu,
                 CandleActivation::Gelu => matmul::Activation::Gelu,
+                CandleActivation::Identity => matmul::Activation::Identity,
                 _ => unreachable!("Unsupported activation in cublaslt matmul"),
             });
             let mut result = fused_batch_matmul(
@@ -103,6 +111,51 @@ impl CublasLtWrapper {
                 beta,
                 bias,
                 inner_act,
+                act_before_residual,
+                self.cublaslt.clone(),
+            )?;
+
+            if Some(CandleActivation::Swiglu) == act {
+                result = diffusion_rs_common::nn::ops::swiglu(&result)?;
+            }
+            Ok(result)
+        }
+        #[cfg(not(feature = "cuda"))]
+        {
+            diffusion_rs_common::bail!("`cuda` feature is not enabled")
+        }
+    }
+
+    /// Same as [`CublasLtWrapper::batch_matmul`], but `b` is of size BxKxN (NN layout).
+    #[allow(clippy::too_many_arguments)]
+    pub fn batch_matmul_nn(
+        &self,
+        a: &Tensor,
+        b: &Tensor,
+        out: Option<&Tensor>,
+        alpha: Option<f32>,
+        beta: Option<f32>,
+        bias: Option<&Tensor>,
+        act: Option<CandleActivation>,
+        act_before_residual: bool,
+    ) -> Result<Tensor> {
+        #[cfg(feature = "cuda")]
+        {
+            let inner_act = act.map(|a| match a {
+                CandleActivation::Relu => matmul::Activation::Relu,
+                CandleActivation::Gelu => matmul::Activation::Gelu,
+                CandleActivation::Identity => matmul::Activation::Identity,
+                _ => unreachable!("Unsupported activation in cublaslt matmul"),
+            });
+            let mut result = fused_batch_matmul_nn(
+                a,
+                b,
+                out,
+                alpha,
+                beta,
+                bias,
+                inner_act,
+                act_before_residual,
                 self.cublaslt.clone(),
             )?;
 
@@ -61,6 +61,7 @@ impl QuantMethod for UnquantLinear {
                                 Some(1.0),
                                 None,
                                 None,
+                                false,
                             )?
                             .t()
                     } else {
@@ -0,0 +1,13 @@
+mod benchmarks;
+
+fn main() -> diffusion_rs_common::core::Result<()> {
+    let filter = std::env::args().skip(1).find(|arg| !arg.starts_with('-'));
+    let device = benchmarks::device()?;
+    println!("running benchmarks on {device:?}");
+    for (name, run) in benchmarks::ALL {
+        if filter.as_ref().is_none_or(|f| name.contains(f.as_str())) {
+            run(&device)?;
+        }
+    }
+    Ok(())
+}
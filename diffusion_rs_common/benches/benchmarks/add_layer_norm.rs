@@ -0,0 +1,27 @@
+use crate::benchmarks::{bench, report_speedup};
+use diffusion_rs_common::core::{DType, Device, Result, Tensor};
+use diffusion_rs_common::nn::ops;
+
+/// The mixed precision pre-norm prologue: a BF16 block output added to an F32 residual stream,
+/// normed and cast back to BF16. The composed version materializes the F32 upcast of `x`, the
+/// F32 normed tensor and the BF16 cast of the norm as separate tensors.
+pub(crate) fn run(device: &Device) -> Result<()> {
+    let (tokens, hidden) = (4096, 3072);
+    let x = Tensor::randn(0f32, 1., (tokens, hidden), device)?.to_dtype(DType::BF16)?;
+    let residual = Tensor::randn(0f32, 1., (tokens, hidden), device)?;
+    let alpha = Tensor::randn(0f32, 1., hidden, device)?;
+    let beta = Tensor::randn(0f32, 1., hidden, device)?;
+    // Reads x and residual, writes the new residual and the normed output.
+    let bytes = tokens * hidden * (2 + 4 + 4 + 2);
+
+    let composed = bench("add_layer_norm_cast/composed", device, bytes, || {
+        let new_residual = (x.to_dtype(DType::F32)? + &residual)?;
+        let normed = ops::layer_norm(&new_residual, &alpha, &beta, 1e-5)?.to_dtype(DType::BF16)?;
+        Ok((normed, new_residual))
+    })?;
+    let fused = bench("add_layer_norm_cast/fused", device, bytes, || {
+        ops::add_layer_norm_cast(&x, &residual, &alpha, &beta, 1e-5, DType::BF16)
+    })?;
+    report_speedup("add_layer_norm_cast speedup", composed, fused);
+    Ok(())
+}
@@ -0,0 +1,24 @@
+use crate::benchmarks::{bench, report_speedup};
+use diffusion_rs_common::core::{DType, Device, Result, Tensor};
+use diffusion_rs_common::nn::ops;
+
+/// Masked and scaled softmax over attention scores of a typical shape, the composed version
+/// materializes the masked scores before the softmax.
+pub(crate) fn run(device: &Device) -> Result<()> {
+    let (batch, heads, seq) = (8, 32, 512);
+    let dtype = DType::F16;
+    let xs = Tensor::randn(0f32, 1., (batch, heads, seq, seq), device)?.to_dtype(dtype)?;
+    let mask = Tensor::randn(0f32, 1., (seq, seq), device)?.to_dtype(dtype)?;
+    let scale = 0.125;
+    // Reads the scores and writes the probabilities, the mask is negligible.
+    let bytes = 2 * xs.elem_count() * dtype.size_in_bytes();
+
+    let composed = bench("attn_softmax/composed", device, bytes, || {
+        ops::softmax_last_dim(&(xs.broadcast_add(&mask)? * scale as f64)?)
+    })?;
+    let fused = bench("attn_softmax/fused", device, bytes, || {
+        ops::attn_softmax_last_dim(&xs, &mask, scale)
+    })?;
+    report_speedup("attn_softmax speedup", composed, fused);
+    Ok(())
+}
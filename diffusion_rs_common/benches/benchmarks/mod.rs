@@ -0,0 +1,69 @@
+//! Micro benchmarks comparing the fused ops against the composed tensor ops they replace.
+//!
+//! Run with `cargo bench -p diffusion_rs_common --features cuda` (or `metal`), an optional
+//! argument only runs the benchmarks whose name contains it. Without a GPU feature the cpu is
+//! used.
+use diffusion_rs_common::core::{Device, Result};
+use std::time::Instant;
+
+pub(crate) mod add_layer_norm;
+pub(crate) mod attn_softmax;
+pub(crate) mod norm;
+pub(crate) mod sigmoid;
+pub(crate) mod silu_mul;
+pub(crate) mod softmax_dim;
+pub(crate) mod swiglu;
+
+type BenchFn = fn(&Device) -> Result<()>;
+
+pub(crate) const ALL: &[(&str, BenchFn)] = &[
+    ("norm", norm::run),
+    ("add_layer_norm_cast", add_layer_norm::run),
+    ("silu_mul", silu_mul::run),
+    ("sigmoid", sigmoid::run),
+    ("attn_softmax", attn_softmax::run),
+    ("softmax_dim", softmax_dim::run),
+    ("swiglu", swiglu::run),
+];
+
+pub(crate) fn device() -> Result<Device> {
+    if cfg!(feature = "metal") {
+        Device::new_metal(0)
+    } else {
+        Device::cuda_if_available(0)
+    }
+}
+
+const WARMUP_ITERS: usize = 5;
+const ITERS: usize = 100;
+
+/// Times `f` over `ITERS` iterations after a warmup and prints the mean time per iteration
+/// along with the effective bandwidth for `bytes` moved per iteration.
+pub(crate) fn bench<T>(
+    name: &str,
+    device: &Device,
+    bytes: usize,
+    mut f: impl FnMut() -> Result<T>,
+) -> Result<f64> {
+    for _ in 0..WARMUP_ITERS {
+        f()?;
+    }
+    device.synchronize()?;
+    let start = Instant::now();
+    for _ in 0..ITERS {
+        f()?;
+    }
+    device.synchronize()?;
+    let secs = start.elapsed().as_secs_f64() / ITERS as f64;
+    println!(
+        "{name:<48} {:>10.2} us {:>8.2} GB/s",
+        secs * 1e6,
+        bytes as f64 / secs / 1e9
+    );
+    Ok(secs)
+}
+
+/// Prints the speedup of `fused` over `baseline`, both being times returned by [`bench`].
+pub(crate) fn report_speedup(name: &str, baseline: f64, fused: f64) {
+    println!("{name:<48} {:>10.2}x", baseline / fused);
+}
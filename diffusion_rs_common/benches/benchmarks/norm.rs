@@ -0,0 +1,31 @@
+use crate::benchmarks::{bench, report_speedup};
+use diffusion_rs_common::core::{DType, Device, Result, Tensor};
+use diffusion_rs_common::nn::ops;
+
+/// RMS and layer norm at hidden sizes below, around and above the old fixed block sizes of the
+/// CUDA kernels (32 threads under 1024 columns, 1024 threads otherwise).
+pub(crate) fn run(device: &Device) -> Result<()> {
+    for hidden in [256, 512, 2048] {
+        let xs = Tensor::randn(0f32, 1., (4096, hidden), device)?.to_dtype(DType::BF16)?;
+        let alpha = Tensor::randn(0f32, 1., hidden, device)?.to_dtype(DType::BF16)?;
+        let beta = Tensor::randn(0f32, 1., hidden, device)?.to_dtype(DType::BF16)?;
+        let bytes = 2 * xs.elem_count() * DType::BF16.size_in_bytes();
+
+        let slow = bench(&format!("rms_norm_slow/{hidden}"), device, bytes, || {
+            ops::rms_norm_slow(&xs, &alpha, 1e-5)
+        })?;
+        let fused = bench(&format!("rms_norm/{hidden}"), device, bytes, || {
+            ops::rms_norm(&xs, &alpha, 1e-5)
+        })?;
+        report_speedup(&format!("rms_norm/{hidden} speedup"), slow, fused);
+
+        let slow = bench(&format!("layer_norm_slow/{hidden}"), device, bytes, || {
+            ops::layer_norm_slow(&xs, &alpha, &beta, 1e-5)
+        })?;
+        let fused = bench(&format!("layer_norm/{hidden}"), device, bytes, || {
+            ops::layer_norm(&xs, &alpha, &beta, 1e-5)
+        })?;
+        report_speedup(&format!("layer_norm/{hidden} speedup"), slow, fused);
+    }
+    Ok(())
+}
@@ -0,0 +1,32 @@
+use crate::benchmarks::{bench, report_speedup};
+use diffusion_rs_common::core::{DType, Device, Result, Tensor};
+use diffusion_rs_common::nn::ops;
+
+/// F32 sigmoid through the Metal `contiguous_tiled` kernel against the plain contiguous one.
+/// The tiled kernel handles two elements per thread and is only used for even element counts, so
+/// dropping a single element selects the plain kernel on an otherwise identical input.
+pub(crate) fn run(device: &Device) -> Result<()> {
+    if !device.is_metal() {
+        println!("sigmoid: the tiled kernel is Metal only, skipping");
+        return Ok(());
+    }
+    let n = 1 << 24;
+    let even = Tensor::randn(0f32, 1., n, device)?;
+    let odd = even.narrow(0, 0, n - 1)?.contiguous()?;
+    let bytes = 2 * n * DType::F32.size_in_bytes();
+
+    let plain = bench("sigmoid/f32 contiguous", device, bytes, || {
+        ops::sigmoid(&odd)
+    })?;
+    let tiled = bench("sigmoid/f32 contiguous_tiled", device, bytes, || {
+        ops::sigmoid(&even)
+    })?;
+    report_speedup("sigmoid/f32 tiled speedup", plain, tiled);
+
+    let diff = (ops::sigmoid(&even)?.narrow(0, 0, n - 1)? - ops::sigmoid(&odd)?)?
+        .abs()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    println!("sigmoid/f32 tiled max abs diff {diff:e}");
+    Ok(())
+}
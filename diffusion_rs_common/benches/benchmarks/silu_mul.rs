@@ -0,0 +1,20 @@
+use crate::benchmarks::{bench, report_speedup};
+use diffusion_rs_common::core::{DType, Device, Result, Tensor};
+use diffusion_rs_common::nn::ops;
+
+/// `silu(gate) * up` on pre-split FFN projections, the composed version allocates the
+/// `silu(gate)` intermediate.
+pub(crate) fn run(device: &Device) -> Result<()> {
+    let (tokens, hidden) = (4096, 8192);
+    let gate = Tensor::randn(0f32, 1., (tokens, hidden), device)?.to_dtype(DType::BF16)?;
+    let up = Tensor::randn(0f32, 1., (tokens, hidden), device)?.to_dtype(DType::BF16)?;
+    // Reads gate and up, writes the product.
+    let bytes = 3 * gate.elem_count() * DType::BF16.size_in_bytes();
+
+    let composed = bench("silu_mul/composed", device, bytes, || gate.silu()? * &up)?;
+    let fused = bench("silu_mul/fused", device, bytes, || {
+        ops::silu_mul(&gate, &up)
+    })?;
+    report_speedup("silu_mul speedup", composed, fused);
+    Ok(())
+}
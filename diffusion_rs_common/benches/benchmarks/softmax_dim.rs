@@ -0,0 +1,24 @@
+use crate::benchmarks::{bench, report_speedup};
+use diffusion_rs_common::core::{DType, Device, Result, Tensor};
+use diffusion_rs_common::nn::ops;
+
+/// Softmax over the query dim of attention scores, the composed version runs the max, sub, exp,
+/// sum and div kernels separately.
+pub(crate) fn run(device: &Device) -> Result<()> {
+    let (batch, heads, seq) = (8, 32, 512);
+    let dtype = DType::F32;
+    let xs = Tensor::randn(0f32, 1., (batch, heads, seq, seq), device)?;
+    let dim = 2;
+    // Reads the scores and writes the probabilities.
+    let bytes = 2 * xs.elem_count() * dtype.size_in_bytes();
+
+    let composed = bench("softmax_dim/composed", device, bytes, || {
+        let num = xs.broadcast_sub(&xs.max_keepdim(dim)?)?.exp()?;
+        num.broadcast_div(&num.sum_keepdim(dim)?)
+    })?;
+    let fused = bench("softmax_dim/fused", device, bytes, || {
+        ops::softmax(&xs, dim)
+    })?;
+    report_speedup("softmax_dim speedup", composed, fused);
+    Ok(())
+}
@@ -0,0 +1,22 @@
+use crate::benchmarks::{bench, report_speedup};
+use diffusion_rs_common::core::{DType, Device, Result, Tensor};
+use diffusion_rs_common::nn::ops;
+
+/// SwiGLU on the doubled-width up projection of a 4096-hidden FFN with an 11008 intermediate
+/// size, the composed version allocates `silu(gate)` on top of the output.
+pub(crate) fn run(device: &Device) -> Result<()> {
+    let (tokens, intermediate) = (2048, 11008);
+    let dtype = DType::BF16;
+    let xs = Tensor::randn(0f32, 1., (tokens, 2 * intermediate), device)?.to_dtype(dtype)?;
+    // Reads both halves, writes the product.
+    let bytes = 3 * tokens * intermediate * dtype.size_in_bytes();
+
+    let composed = bench("swiglu/composed", device, bytes, || {
+        let gate = xs.narrow(1, 0, intermediate)?;
+        let up = xs.narrow(1, intermediate, intermediate)?;
+        gate.silu()? * up
+    })?;
+    let fused = bench("swiglu/fused", device, bytes, || ops::swiglu(&xs))?;
+    report_speedup("swiglu speedup", composed, fused);
+    Ok(())
+}
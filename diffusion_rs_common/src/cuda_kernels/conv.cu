@@ -497,6 +497,113 @@ __device__ void max_pool2d(
   dst[dst_i] = d;
 }
 
+// Pooling over a (b_size, c, h_in, w_in) input where `padding` virtual rows/cols surround each
+// plane. Padded positions never contribute to the max, the average divides either by the full
+// window (`count_include_pad`) or by the number of in-bounds elements.
+template <typename T, typename A>
+__device__ void avg_pool2d_padded(
+    const size_t h_k,
+    const size_t w_k,
+    const size_t h_stride,
+    const size_t w_stride,
+    const size_t h_pad,
+    const size_t w_pad,
+    const int count_include_pad,
+    const size_t *info,
+    const T *src,
+    T *dst
+) {
+  const size_t dst_i = blockIdx.x * blockDim.x + threadIdx.x;
+  const size_t *src_dims = info;
+  const size_t *src_s = info + 4;
+
+  const size_t c = src_dims[1];
+  const size_t h_in = src_dims[2];
+  const size_t w_in = src_dims[3];
+
+  const size_t h_out = (h_in + 2 * h_pad - h_k) / h_stride + 1;
+  const size_t w_out = (w_in + 2 * w_pad - w_k) / w_stride + 1;
+  if (dst_i >= src_dims[0] * c * h_out * w_out) {
+    return;
+  }
+
+  const size_t b_idx = dst_i / (h_out * w_out * c);
+  const size_t c_idx = (dst_i / (h_out * w_out)) % c;
+  const size_t dst_h = (dst_i / w_out) % h_out;
+  const size_t dst_w = dst_i % w_out;
+
+  // Window bounds in padded coordinates, clamped to the padded and then to the real input.
+  const size_t h_start = dst_h * h_stride;
+  const size_t w_start = dst_w * w_stride;
+  const size_t h_end = min(h_start + h_k, h_in + 2 * h_pad);
+  const size_t w_end = min(w_start + w_k, w_in + 2 * w_pad);
+  const size_t h_lo = max(h_start, h_pad) - h_pad;
+  const size_t w_lo = max(w_start, w_pad) - w_pad;
+  const size_t h_hi = min(h_end, h_in + h_pad) - h_pad;
+  const size_t w_hi = min(w_end, w_in + w_pad) - w_pad;
+
+  const size_t src_idx0 = b_idx * src_s[0] + c_idx * src_s[1];
+  A d = 0;
+  for (size_t src_h = h_lo; src_h < h_hi; ++src_h) {
+    for (size_t src_w = w_lo; src_w < w_hi; ++src_w) {
+      d += static_cast<A>(src[src_idx0 + src_h * src_s[2] + src_w * src_s[3]]);
+    }
+  }
+  const size_t count = count_include_pad
+    ? (h_end - h_start) * (w_end - w_start)
+    : (h_hi - h_lo) * (w_hi - w_lo);
+  dst[dst_i] = static_cast<T>(d / static_cast<A>(count));
+}
+
+template <typename T>
+__device__ void max_pool2d_padded(
+    const size_t h_k,
+    const size_t w_k,
+    const size_t h_stride,
+    const size_t w_stride,
+    const size_t h_pad,
+    const size_t w_pad,
+    const size_t *info,
+    const T *src,
+    T *dst
+) {
+  const size_t dst_i = blockIdx.x * blockDim.x + threadIdx.x;
+  const size_t *src_dims = info;
+  const size_t *src_s = info + 4;
+
+  const size_t c = src_dims[1];
+  const size_t h_in = src_dims[2];
+  const size_t w_in = src_dims[3];
+
+  const size_t h_out = (h_in + 2 * h_pad - h_k) / h_stride + 1;
+  const size_t w_out = (w_in + 2 * w_pad - w_k) / w_stride + 1;
+  if (dst_i >= src_dims[0] * c * h_out * w_out) {
+    return;
+  }
+
+  const size_t b_idx = dst_i / (h_out * w_out * c);
+  const size_t c_idx = (dst_i / (h_out * w_out)) % c;
+  const size_t dst_h = (dst_i / w_out) % h_out;
+  const size_t dst_w = dst_i % w_out;
+
+  const size_t h_start = dst_h * h_stride;
+  const size_t w_start = dst_w * w_stride;
+  const size_t h_lo = max(h_start, h_pad) - h_pad;
+  const size_t w_lo = max(w_start, w_pad) - w_pad;
+  const size_t h_hi = min(h_start + h_k, h_in + h_pad) - h_pad;
+  const size_t w_hi = min(w_start + w_k, w_in + w_pad) - w_pad;
+
+  // With a padding of at most half the kernel, every window holds at least one input element.
+  const size_t src_idx0 = b_idx * src_s[0] + c_idx * src_s[1];
+  T d = src[src_idx0 + h_lo * src_s[2] + w_lo * src_s[3]];
+  for (size_t src_h = h_lo; src_h < h_hi; ++src_h) {
+    for (size_t src_w = w_lo; src_w < w_hi; ++src_w) {
+      d = maxg(d, src[src_idx0 + src_h * src_s[2] + src_w * src_s[3]]);
+    }
+  }
+  dst[dst_i] = d;
+}
+
 template <typename T>
 __device__ void upsample_nearest2d(
     const size_t w_out,
@@ -678,6 +785,37 @@ extern "C" __global__ void FN_NAME(  \
   max_pool2d<TYPENAME>(src_numel, w_k, h_k, w_stride, h_stride, info, src, dst); \
 } \
 
+#define AVG_POOL2D_PADDED_OP(TYPENAME, TYPEACC, FN_NAME) \
+extern "C" __global__ void FN_NAME(  \
+    const size_t h_k, \
+    const size_t w_k, \
+    const size_t h_stride, \
+    const size_t w_stride, \
+    const size_t h_pad, \
+    const size_t w_pad, \
+    const int count_include_pad, \
+    const size_t *info, \
+    const TYPENAME *src, \
+    TYPENAME *dst \
+) {  \
+  avg_pool2d_padded<TYPENAME, TYPEACC>(h_k, w_k, h_stride, w_stride, h_pad, w_pad, count_include_pad, info, src, dst); \
+} \
+
+#define MAX_POOL2D_PADDED_OP(TYPENAME, FN_NAME) \
+extern "C" __global__ void FN_NAME(  \
+    const size_t h_k, \
+    const size_t w_k, \
+    const size_t h_stride, \
+    const size_t w_stride, \
+    const size_t h_pad, \
+    const size_t w_pad, \
+    const size_t *info, \
+    const TYPENAME *src, \
+    TYPENAME *dst \
+) {  \
+  max_pool2d_padded<TYPENAME>(h_k, w_k, h_stride, w_stride, h_pad, w_pad, info, src, dst); \
+} \
+
 #define UPSAMPLE_NEAREST2D_OP(TYPENAME, FN_NAME) \
 extern "C" __global__ void FN_NAME(  \
     const size_t w_out, \
@@ -700,6 +838,8 @@ CONVT1D_OP(__nv_bfloat16, float, conv_transpose1d_bf16)
 CONVT2D_OP(__nv_bfloat16, float, conv_transpose2d_bf16)
 AVG_POOL2D_OP(__nv_bfloat16, float, avg_pool2d_bf16)
 MAX_POOL2D_OP(__nv_bfloat16, max_pool2d_bf16)
+AVG_POOL2D_PADDED_OP(__nv_bfloat16, float, avg_pool2d_padded_bf16)
+MAX_POOL2D_PADDED_OP(__nv_bfloat16, max_pool2d_padded_bf16)
 UPSAMPLE_NEAREST2D_OP(__nv_bfloat16, upsample_nearest2d_bf16)
 IM2COL_OP(__nv_bfloat16, im2col_bf16)
 IM2COL1D_OP(__nv_bfloat16, im2col1d_bf16)
@@ -725,6 +865,8 @@ CONVT1D_OP(__half, float, conv_transpose1d_f16)
 CONVT2D_OP(__half, float, conv_transpose2d_f16)
 AVG_POOL2D_OP(__half, float, avg_pool2d_f16)
 MAX_POOL2D_OP(__half, max_pool2d_f16)
+AVG_POOL2D_PADDED_OP(__half, float, avg_pool2d_padded_f16)
+MAX_POOL2D_PADDED_OP(__half, max_pool2d_padded_f16)
 UPSAMPLE_NEAREST2D_OP(__half, upsample_nearest2d_f16)
 IM2COL_OP(__half, im2col_f16)
 IM2COL1D_OP(__half, im2col1d_f16)
@@ -761,6 +903,12 @@ MAX_POOL2D_OP(double, max_pool2d_f64)
 MAX_POOL2D_OP(uint8_t, max_pool2d_u8)
 MAX_POOL2D_OP(uint32_t, max_pool2d_u32)
 
+AVG_POOL2D_PADDED_OP(float, float, avg_pool2d_padded_f32)
+AVG_POOL2D_PADDED_OP(double, double, avg_pool2d_padded_f64)
+
+MAX_POOL2D_PADDED_OP(float, max_pool2d_padded_f32)
+MAX_POOL2D_PADDED_OP(double, max_pool2d_padded_f64)
+
 UPSAMPLE_NEAREST2D_OP(float, upsample_nearest2d_f32)
 UPSAMPLE_NEAREST2D_OP(double, upsample_nearest2d_f64)
 UPSAMPLE_NEAREST2D_OP(uint8_t, upsample_nearest2d_u8)
@@ -16,6 +16,23 @@ extern "C" __global__ void fill_i64(int64_t *buf, int64_t value, const size_t nu
 extern "C" __global__ void fill_f32(float *buf, float value, const size_t numel) { fill_with(buf, value, numel); }
 extern "C" __global__ void fill_f64(double *buf, double value, const size_t numel) { fill_with(buf, value, numel); }
 
+// Scaled keep-mask of `seeded_dropout`: element `i` is kept when the `i`-th output of a
+// splitmix64 generator seeded with `seed`, reduced to a 24 bits uniform in [0, 1), is at least
+// `drop_p`. This matches the cpu implementation bit for bit.
+template<typename T>
+__device__ void dropout_mask(T *buf, const uint64_t seed, const float drop_p, const float scale, const size_t numel) {
+    for (size_t i = blockIdx.x * blockDim.x + threadIdx.x; i < numel; i += blockDim.x * gridDim.x) {
+        uint64_t z = seed + (i + 1) * 0x9E3779B97F4A7C15ull;
+        z = (z ^ (z >> 30)) * 0xBF58476D1CE4E5B9ull;
+        z = (z ^ (z >> 27)) * 0x94D049BB133111EBull;
+        z = z ^ (z >> 31);
+        const float u = static_cast<float>(z >> 40) * (1.0f / 16777216.0f);
+        buf[i] = static_cast<T>(u >= drop_p ? scale : 0.0f);
+    }
+}
+extern "C" __global__ void dropout_mask_f32(float *buf, const uint64_t seed, const float drop_p, const float scale, const size_t numel) { dropout_mask(buf, seed, drop_p, scale, numel); }
+extern "C" __global__ void dropout_mask_f64(double *buf, const uint64_t seed, const float drop_p, const float scale, const size_t numel) { dropout_mask(buf, seed, drop_p, scale, numel); }
+
 template<typename T>
 __device__ void copy2d(const T *src, T *dst, uint32_t d1, uint32_t d2, uint32_t src_s, uint32_t dst_s) {
   uint32_t idx = blockIdx.x * blockDim.x + threadIdx.x;
@@ -44,6 +61,7 @@ COPY2D_OP(int64_t, copy2d_i64)
 #if __CUDA_ARCH__ >= 530
 extern "C" __global__ void fill_f16(__half *buf, __half value, const size_t numel) { fill_with(buf, value, numel); }
 COPY2D_OP(__half, copy2d_f16)
+extern "C" __global__ void dropout_mask_f16(__half *buf, const uint64_t seed, const float drop_p, const float scale, const size_t numel) { dropout_mask(buf, seed, drop_p, scale, numel); }
 #endif
 
 #if __CUDA_ARCH__ >= 800
@@ -52,6 +70,7 @@ COPY2D_OP(__half, copy2d_f16)
 
 extern "C" __global__ void fill_bf16(__nv_bfloat16 *buf, __nv_bfloat16 value, const size_t numel) { fill_with(buf, value, numel); }
 COPY2D_OP(__nv_bfloat16, copy2d_bf16)
+extern "C" __global__ void dropout_mask_bf16(__nv_bfloat16 *buf, const uint64_t seed, const float drop_p, const float scale, const size_t numel) { dropout_mask(buf, seed, drop_p, scale, numel); }
 
 extern "C" __global__ void fill_f8_e4m3(__nv_fp8_e4m3 *buf, __nv_fp8_e4m3 value, const size_t numel) { fill_with(buf, value, numel); }
 COPY2D_OP(__nv_fp8_e4m3, copy2d_f8_e4m3)
@@ -92,7 +92,8 @@ __device__ void layernorm(const T * x, T * dst, const T * alpha, const T * beta,
             s_sum[warp_id] = mean_var;
         }
         __syncthreads();
-        mean_var = s_sum[lane_id];
+        // Only the first block_size / WARP_SIZE entries have been written.
+        mean_var = lane_id < block_size / WARP_SIZE ? s_sum[lane_id] : make_float2(0.f, 0.f);
         mean_var = warp_reduce_sum(mean_var);
     }
 
@@ -130,10 +131,95 @@ __device__ void layernorm(const T * x, T * dst, const T * alpha, const T * beta,
     }
 }
 
+// Sum over the whole block, the result is returned to every thread. `s_sum` may be reused by
+// consecutive calls.
+static __device__ __forceinline__ float block_reduce_sum(float x, float * s_sum, const int block_size) {
+    x = warp_reduce_sum(x);
+    if (block_size > WARP_SIZE) {
+        int warp_id = threadIdx.x / WARP_SIZE;
+        int lane_id = threadIdx.x % WARP_SIZE;
+        // Wait for the reads of a previous reduction before overwriting `s_sum`.
+        __syncthreads();
+        if (lane_id == 0) {
+            s_sum[warp_id] = x;
+        }
+        __syncthreads();
+        x = lane_id < block_size / WARP_SIZE ? s_sum[lane_id] : 0.f;
+        x = warp_reduce_sum(x);
+    }
+    return x;
+}
+
+// GroupNorm over a contiguous (batch, channels, spatial...) input, each block normalizes the
+// `group_size = channels / num_groups * spatial` elements of one (batch, group) pair. The
+// variance is computed in a second pass over the centered values as `E[x²] - E[x]²` loses too
+// much precision in f32 on large activations.
+template <typename T>
+__device__ void groupnorm(const T * x, T * dst, const T * weight, const T * bias, const int group_size, const int spatial, const int num_groups, const int block_size, const float eps) {
+    const int row = blockIdx.x;
+    const int tid = threadIdx.x;
+    const T * x_row = x + (size_t)row * group_size;
+    T * dst_row = dst + (size_t)row * group_size;
+    const int channel_offset = (row % num_groups) * (group_size / spatial);
+    __shared__ float s_sum[32];
+
+    float sum = 0.f;
+    for (int col = tid; col < group_size; col += block_size) {
+        sum += static_cast<float>(x_row[col]);
+    }
+    const float mean = block_reduce_sum(sum, s_sum, block_size) / group_size;
+
+    float sum_sq = 0.f;
+    for (int col = tid; col < group_size; col += block_size) {
+        const float d = static_cast<float>(x_row[col]) - mean;
+        sum_sq += d * d;
+    }
+    const float var = block_reduce_sum(sum_sq, s_sum, block_size) / group_size;
+    const float inv_std = rsqrtf(var + eps);
+
+    for (int col = tid; col < group_size; col += block_size) {
+        const int c = channel_offset + col / spatial;
+        const float y = (static_cast<float>(x_row[col]) - mean) * inv_std;
+        dst_row[col] = static_cast<T>(y * static_cast<float>(weight[c]) + static_cast<float>(bias[c]));
+    }
+}
+
+// LayerNorm over the channel dim of a contiguous (batch, channels, spatial) input, each thread
+// normalizes the `channels` elements of one (batch, spatial) location. Neighbouring threads read
+// neighbouring locations so the loads over the channels stay coalesced.
+template <typename T>
+__device__ void layernorm2d(const T * x, T * dst, const T * alpha, const T * beta, const int channels, const int spatial, const int n_locations, const float eps) {
+    const int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i >= n_locations) {
+        return;
+    }
+    const size_t offset = (size_t)(i / spatial) * channels * spatial + i % spatial;
+    const T * x_col = x + offset;
+    T * dst_col = dst + offset;
+
+    float mean = 0.f;
+    for (int c = 0; c < channels; ++c) {
+        mean += static_cast<float>(x_col[(size_t)c * spatial]);
+    }
+    mean /= channels;
+    float var = 0.f;
+    for (int c = 0; c < channels; ++c) {
+        const float d = static_cast<float>(x_col[(size_t)c * spatial]) - mean;
+        var += d * d;
+    }
+    const float inv_std = rsqrtf(var / channels + eps);
+
+    for (int c = 0; c < channels; ++c) {
+        const float y = (static_cast<float>(x_col[(size_t)c * spatial]) - mean) * inv_std;
+        dst_col[(size_t)c * spatial] = static_cast<T>(y * static_cast<float>(alpha[c]) + static_cast<float>(beta[c]));
+    }
+}
+
 // RmsNorm implementation adapted from ggml, accumulation is made using f32.
 // https://github.com/ggerganov/llama.cpp/blob/d59bd97065cd7ded6c4ecab54b1d5e0b1b11e318/ggml-cuda.cu#L523
-template <typename T>
-__device__ void rmsnorm(const T * x, T * dst, const T * alpha, const int ncols, const int block_size, const float eps) {
+// The output type O can differ from the input type T, the accumulation is in f32 either way.
+template <typename T, typename O = T>
+__device__ void rmsnorm(const T * x, O * dst, const T * alpha, const int ncols, const int block_size, const float eps) {
     const int row = blockIdx.x*blockDim.y + threadIdx.y;
     const int tid = threadIdx.x;
 
@@ -154,7 +240,8 @@ __device__ void rmsnorm(const T * x, T * dst, const T * alpha, const int ncols,
             s_sum[warp_id] = tmp;
         }
         __syncthreads();
-        tmp = s_sum[lane_id];
+        // Only the first block_size / WARP_SIZE entries have been written.
+        tmp = lane_id < block_size / WARP_SIZE ? s_sum[lane_id] : 0.f;
         tmp = warp_reduce_sum(tmp);
     }
 
@@ -163,13 +250,13 @@ __device__ void rmsnorm(const T * x, T * dst, const T * alpha, const int ncols,
 
     if (alpha == nullptr) {
       for (int col = tid; col < ncols; col += block_size) {
-          dst[row*ncols + col] = static_cast<T>(scale * static_cast<float>(x[row*ncols + col]));
+          dst[row*ncols + col] = static_cast<O>(scale * static_cast<float>(x[row*ncols + col]));
       }
     }
     else {
       for (int col = tid; col < ncols; col += block_size) {
           float a = static_cast<float>(alpha[col]);
-          dst[row*ncols + col] = static_cast<T>(scale * static_cast<float>(x[row*ncols + col]) * a);
+          dst[row*ncols + col] = static_cast<O>(scale * static_cast<float>(x[row*ncols + col]) * a);
       }
     }
 }
@@ -194,6 +281,10 @@ __device__ void softmax(const T * x, T * dst, const int ncols) {
     for (int mask = 16; mask > 0; mask >>= 1) {
         max_val = maxg(max_val, __shfl_xor_sync(0xffffffff, max_val, mask, 32));
     }
+    // Fully masked rows only hold -inf, shift them by 0 so that they sum to 0 rather than NaN.
+    if (static_cast<float>(max_val) == -INFINITY) {
+        max_val = static_cast<T>(0.f);
+    }
 
     ACC tmp = 0.;
 
@@ -210,7 +301,8 @@ __device__ void softmax(const T * x, T * dst, const int ncols) {
         tmp += __shfl_xor_sync(0xffffffff, tmp, mask, 32);
     }
 
-    const ACC inv_tmp = 1. / tmp;
+    // Fully masked rows output zeros.
+    const ACC inv_tmp = tmp == static_cast<ACC>(0.) ? static_cast<ACC>(0.) : static_cast<ACC>(1.) / tmp;
 
     for (int col = tid; col < ncols; col += block_size) {
         const int i = row*ncols + col;
@@ -218,6 +310,191 @@ __device__ void softmax(const T * x, T * dst, const int ncols) {
     }
 }
 
+// `x - max - log(sum(exp(x - max)))` over each row, laid out as `softmax`. Rows are only
+// written in the last pass so `x` and `dst` may alias.
+template <typename T, typename ACC>
+__device__ void log_softmax(const T * x, T * dst, const int ncols) {
+    const int row = blockDim.x*blockIdx.x + threadIdx.x;
+    const int block_size = blockDim.y;
+    const int tid = threadIdx.y;
+
+    ACC max_val = -INFINITY;
+    for (int col = tid; col < ncols; col += block_size) {
+        max_val = maxg(max_val, static_cast<ACC>(x[row*ncols + col]));
+    }
+#pragma unroll
+    for (int mask = 16; mask > 0; mask >>= 1) {
+        max_val = maxg(max_val, __shfl_xor_sync(0xffffffff, max_val, mask, 32));
+    }
+
+    ACC tmp = 0.;
+    if (max_val != static_cast<ACC>(-INFINITY)) {
+        for (int col = tid; col < ncols; col += block_size) {
+            tmp += expg(static_cast<ACC>(x[row*ncols + col]) - max_val);
+        }
+    }
+#pragma unroll
+    for (int mask = 16; mask > 0; mask >>= 1) {
+        tmp += __shfl_xor_sync(0xffffffff, tmp, mask, 32);
+    }
+
+    // Fully masked rows output -inf, i.e. the log of the zeros output by `softmax`.
+    const ACC lse = max_val + logg(tmp);
+    for (int col = tid; col < ncols; col += block_size) {
+        const int i = row*ncols + col;
+        dst[i] = tmp == static_cast<ACC>(0.)
+            ? static_cast<T>(-INFINITY)
+            : static_cast<T>(static_cast<ACC>(x[i]) - lse);
+    }
+}
+
+// Softmax over a dim that is not the last one of a contiguous `(n_outer, dim_size, inner)` input.
+// Each thread handles one `(outer, inner)` column so that neighbouring threads read neighbouring
+// elements, the column is read once for the max, once for the sum and once for the output.
+template <typename T, typename ACC>
+__device__ void softmax_strided(const T * x, T * dst, const int n_outer, const int dim_size, const int inner) {
+    const int idx = blockDim.x*blockIdx.x + threadIdx.x;
+    if (idx >= n_outer * inner) {
+        return;
+    }
+    const int base = (idx / inner) * dim_size * inner + idx % inner;
+
+    ACC max_val = -INFINITY;
+    for (int j = 0; j < dim_size; ++j) {
+        max_val = maxg(max_val, static_cast<ACC>(x[base + j*inner]));
+    }
+    // Fully masked columns output zeros, as in `softmax`.
+    if (max_val == static_cast<ACC>(-INFINITY)) {
+        max_val = static_cast<ACC>(0.);
+    }
+
+    ACC tmp = 0.;
+    for (int j = 0; j < dim_size; ++j) {
+        tmp += expg(static_cast<ACC>(x[base + j*inner]) - max_val);
+    }
+    const ACC inv_tmp = tmp == static_cast<ACC>(0.) ? static_cast<ACC>(0.) : static_cast<ACC>(1.) / tmp;
+
+    for (int j = 0; j < dim_size; ++j) {
+        const int i = base + j*inner;
+        dst[i] = static_cast<T>(expg(static_cast<ACC>(x[i]) - max_val) * inv_tmp);
+    }
+}
+
+__device__ __forceinline__ float softcap_logit(float v, const float scale, const float softcap) {
+    v *= scale;
+    return softcap > 0.f ? softcap * tanhf(v / softcap) : v;
+}
+
+// Softmax of `softcap * tanh(x * scale / softcap)`, the capped logits are recomputed rather than
+// stored between the max and the exp passes. A `softcap` of 0 disables the cap.
+template <typename T, typename ACC>
+__device__ void softcap_softmax(const T * x, T * dst, const int ncols, const float scale, const float softcap) {
+    const int row = blockDim.x*blockIdx.x + threadIdx.x;
+    const int block_size = blockDim.y;
+    const int tid = threadIdx.y;
+
+    float max_val = -INFINITY;
+
+    for (int col = tid; col < ncols; col += block_size) {
+        const int i = row*ncols + col;
+        max_val = fmaxf(max_val, softcap_logit(static_cast<float>(x[i]), scale, softcap));
+    }
+
+    // find the max value in the block
+#pragma unroll
+    for (int mask = 16; mask > 0; mask >>= 1) {
+        max_val = fmaxf(max_val, __shfl_xor_sync(0xffffffff, max_val, mask, 32));
+    }
+    // Fully masked rows only hold -inf, shift them by 0 so that they sum to 0 rather than NaN.
+    if (max_val == -INFINITY) {
+        max_val = 0.f;
+    }
+
+    ACC tmp = 0.;
+
+    for (int col = tid; col < ncols; col += block_size) {
+        const int i = row*ncols + col;
+        const float val = expf(softcap_logit(static_cast<float>(x[i]), scale, softcap) - max_val);
+        tmp += static_cast<ACC>(val);
+        dst[i] = static_cast<T>(val);
+    }
+
+    // sum up partial sums
+#pragma unroll
+    for (int mask = 16; mask > 0; mask >>= 1) {
+        tmp += __shfl_xor_sync(0xffffffff, tmp, mask, 32);
+    }
+
+    // Fully masked rows output zeros.
+    const ACC inv_tmp = tmp == static_cast<ACC>(0.) ? static_cast<ACC>(0.) : static_cast<ACC>(1.) / tmp;
+
+    for (int col = tid; col < ncols; col += block_size) {
+        const int i = row*ncols + col;
+        dst[i] = static_cast<T>(static_cast<ACC>(dst[i]) * inv_tmp);
+    }
+}
+
+// Merges two partial online softmax states, `x` holds the running max and `y` the sum of
+// `exp(v - max)`. States that have not seen any finite value keep a zero sum.
+static __device__ __forceinline__ float2 online_softmax_combine(float2 a, float2 b) {
+    const float m = fmaxf(a.x, b.x);
+    if (m == -INFINITY) {
+        return make_float2(m, 0.f);
+    }
+    return make_float2(m, a.y * expf(a.x - m) + b.y * expf(b.x - m));
+}
+
+static __device__ __forceinline__ float2 warp_reduce_online_softmax(float2 a) {
+#pragma unroll
+    for (int mask = 16; mask > 0; mask >>= 1) {
+        float2 b;
+        b.x = __shfl_xor_sync(0xffffffff, a.x, mask, 32);
+        b.y = __shfl_xor_sync(0xffffffff, a.y, mask, 32);
+        a = online_softmax_combine(a, b);
+    }
+    return a;
+}
+
+// Softmax of `(x + mask) * scale` over the last dim, the `(mask_rows, ncols)` mask is shared by
+// every batch and head. The max and the sum are computed in a single online pass, accumulation is
+// made using f32. `x` and `dst` may alias.
+template <typename T>
+__device__ void attn_softmax(const T * x, const T * mask, T * dst, const int ncols, const int mask_rows, const int block_size, const float scale) {
+    const int row = blockIdx.x;
+    const int tid = threadIdx.x;
+    const T * x_row = x + (size_t)row*ncols;
+    const T * mask_row = mask + (size_t)(row % mask_rows)*ncols;
+    T * dst_row = dst + (size_t)row*ncols;
+
+    float2 state = make_float2(-INFINITY, 0.f);
+    for (int col = tid; col < ncols; col += block_size) {
+        const float v = (static_cast<float>(x_row[col]) + static_cast<float>(mask_row[col])) * scale;
+        state = online_softmax_combine(state, make_float2(v, 1.f));
+    }
+
+    state = warp_reduce_online_softmax(state);
+    if (block_size > WARP_SIZE) {
+        __shared__ float2 s_state[32];
+        int warp_id = threadIdx.x / WARP_SIZE;
+        int lane_id = threadIdx.x % WARP_SIZE;
+        if (lane_id == 0) {
+            s_state[warp_id] = state;
+        }
+        __syncthreads();
+        // Only the first block_size / WARP_SIZE entries have been written.
+        state = lane_id < block_size / WARP_SIZE ? s_state[lane_id] : make_float2(-INFINITY, 0.f);
+        state = warp_reduce_online_softmax(state);
+    }
+
+    // Fully masked rows have a -inf max and a zero sum, they output zeros rather than NaN.
+    const float max_val = state.x == -INFINITY ? 0.f : state.x;
+    const float inv_sum = state.y == 0.f ? 0.f : 1.f / state.y;
+    for (int col = tid; col < ncols; col += block_size) {
+        const float v = (static_cast<float>(x_row[col]) + static_cast<float>(mask_row[col])) * scale;
+        dst_row[col] = static_cast<T>(expf(v - max_val) * inv_sum);
+    }
+}
+
 template <typename T>
 __device__ void ropei(const T * src, const T * cos, const T * sin, T * dst, const uint32_t bh, const uint32_t td) {
     const int idx = blockIdx.x * blockDim.x + threadIdx.x;
@@ -525,6 +802,35 @@ fast_argmax(const size_t src_numel, const size_t el_to_sum_per_block,
     softmax<TYPENAME, ACC_TYPENAME>(src, dst, n_cols);                         \
   }                                                                            \
 
+#define LOG_SOFTMAX_OP(TYPENAME, ACC_TYPENAME, FN_NAME) \
+  extern "C" __global__ void FN_NAME(                                          \
+      const TYPENAME *src, TYPENAME *dst,                                      \
+      const int n_cols) {                                                      \
+    log_softmax<TYPENAME, ACC_TYPENAME>(src, dst, n_cols);                     \
+  }                                                                            \
+
+#define SOFTMAX_STRIDED_OP(TYPENAME, ACC_TYPENAME, FN_NAME) \
+  extern "C" __global__ void FN_NAME(                                          \
+      const TYPENAME *src, TYPENAME *dst,                                      \
+      const int n_outer, const int dim_size, const int inner) {                \
+    softmax_strided<TYPENAME, ACC_TYPENAME>(src, dst, n_outer, dim_size, inner); \
+  }                                                                            \
+
+#define SOFTCAP_SOFTMAX_OP(TYPENAME, FN_NAME) \
+  extern "C" __global__ void FN_NAME(                                          \
+      const TYPENAME *src, TYPENAME *dst,                                      \
+      const int n_cols, const float scale, const float softcap) {              \
+    softcap_softmax<TYPENAME, float>(src, dst, n_cols, scale, softcap);        \
+  }                                                                            \
+
+#define ATTN_SOFTMAX_OP(TYPENAME, FN_NAME) \
+  extern "C" __global__ void FN_NAME(                                          \
+      const TYPENAME *src, const TYPENAME *mask, TYPENAME *dst,                \
+      const int n_cols, const int mask_rows, const int block_size,             \
+      const float scale) {                                                     \
+    attn_softmax<TYPENAME>(src, mask, dst, n_cols, mask_rows, block_size, scale); \
+  }                                                                            \
+
 #define RMSNORM_OP(TYPENAME, FN_NAME) \
   extern "C" __global__ void FN_NAME(                                          \
       const TYPENAME *src, TYPENAME *dst, const TYPENAME *alpha,               \
@@ -532,6 +838,13 @@ fast_argmax(const size_t src_numel, const size_t el_to_sum_per_block,
     rmsnorm<TYPENAME>(src, dst, alpha, n_cols, block_size, eps);               \
   }                                                                            \
 
+#define RMSNORM_CAST_OP(TYPENAME, OUT_TYPENAME, FN_NAME) \
+  extern "C" __global__ void FN_NAME(                                          \
+      const TYPENAME *src, OUT_TYPENAME *dst, const TYPENAME *alpha,           \
+      const int n_cols, const int block_size, const float eps) {               \
+    rmsnorm<TYPENAME, OUT_TYPENAME>(src, dst, alpha, n_cols, block_size, eps); \
+  }                                                                            \
+
 #define LAYERNORM_OP(TYPENAME, FN_NAME) \
   extern "C" __global__ void FN_NAME(                                          \
       const TYPENAME *src, TYPENAME *dst, const TYPENAME *alpha,               \
@@ -539,6 +852,22 @@ fast_argmax(const size_t src_numel, const size_t el_to_sum_per_block,
     layernorm<TYPENAME>(src, dst, alpha, beta, n_cols, block_size, eps);       \
   }                                                                            \
 
+#define GROUPNORM_OP(TYPENAME, FN_NAME) \
+  extern "C" __global__ void FN_NAME(                                          \
+      const TYPENAME *src, TYPENAME *dst, const TYPENAME *weight,              \
+      const TYPENAME *bias, const int group_size, const int spatial,           \
+      const int num_groups, const int block_size, const float eps) {           \
+    groupnorm<TYPENAME>(src, dst, weight, bias, group_size, spatial, num_groups, block_size, eps); \
+  }                                                                            \
+
+#define LAYERNORM2D_OP(TYPENAME, FN_NAME) \
+  extern "C" __global__ void FN_NAME(                                          \
+      const TYPENAME *src, TYPENAME *dst, const TYPENAME *alpha,               \
+      const TYPENAME *beta, const int channels, const int spatial,             \
+      const int n_locations, const float eps) {                                \
+    layernorm2d<TYPENAME>(src, dst, alpha, beta, channels, spatial, n_locations, eps); \
+  }                                                                            \
+
 #define ROPE_OP(TYPENAME, FN_NAME, FN_NAME_I, FN_NAME_THD) \
   extern "C" __global__ void FN_NAME_I( \
       const TYPENAME *src, \
@@ -574,8 +903,16 @@ fast_argmax(const size_t src_numel, const size_t el_to_sum_per_block,
 #if __CUDA_ARCH__ >= 800
 #include "cuda_bf16.h"
 SOFTMAX_OP(__nv_bfloat16, float, softmax_bf16)
+SOFTMAX_STRIDED_OP(__nv_bfloat16, float, softmax_strided_bf16)
+LOG_SOFTMAX_OP(__nv_bfloat16, float, log_softmax_bf16)
+SOFTCAP_SOFTMAX_OP(__nv_bfloat16, softcap_softmax_bf16)
+ATTN_SOFTMAX_OP(__nv_bfloat16, attn_softmax_bf16)
 RMSNORM_OP(__nv_bfloat16, rmsnorm_bf16)
+RMSNORM_CAST_OP(__nv_bfloat16, float, rmsnorm_bf16_f32)
+RMSNORM_CAST_OP(float, __nv_bfloat16, rmsnorm_f32_bf16)
 LAYERNORM_OP(__nv_bfloat16, layernorm_bf16)
+GROUPNORM_OP(__nv_bfloat16, groupnorm_bf16)
+LAYERNORM2D_OP(__nv_bfloat16, layernorm2d_bf16)
 ROPE_OP(__nv_bfloat16, rope_bf16, rope_i_bf16, rope_thd_bf16)
 SUM_OP(__nv_bfloat16, sum_bf16)
 FAST_OP(__nv_bfloat16, fast_min_bf16, fast_max_bf16, fast_argmin_bf16, fast_argmax_bf16, fast_sum_bf16)
@@ -591,8 +928,16 @@ FAST_OP(__nv_bfloat16, fast_min_bf16, fast_max_bf16, fast_argmin_bf16, fast_argm
 
 #if __CUDA_ARCH__ >= 530
 SOFTMAX_OP(__half, float, softmax_f16)
+SOFTMAX_STRIDED_OP(__half, float, softmax_strided_f16)
+LOG_SOFTMAX_OP(__half, float, log_softmax_f16)
+SOFTCAP_SOFTMAX_OP(__half, softcap_softmax_f16)
+ATTN_SOFTMAX_OP(__half, attn_softmax_f16)
 RMSNORM_OP(__half, rmsnorm_f16)
+RMSNORM_CAST_OP(__half, float, rmsnorm_f16_f32)
+RMSNORM_CAST_OP(float, __half, rmsnorm_f32_f16)
 LAYERNORM_OP(__half, layernorm_f16)
+GROUPNORM_OP(__half, groupnorm_f16)
+LAYERNORM2D_OP(__half, layernorm2d_f16)
 ROPE_OP(__half, rope_f16, rope_i_f16, rope_thd_f16)
 SUM_OP(__half, sum_f16)
 FAST_OP(__half, fast_min_f16, fast_max_f16, fast_argmin_f16, fast_argmax_f16, fast_sum_f16)
@@ -603,9 +948,17 @@ SUM_OP(double, sum_f64)
 SUM_OP(uint32_t, sum_u32)
 SOFTMAX_OP(float, float, softmax_f32)
 SOFTMAX_OP(double, double, softmax_f64)
+SOFTMAX_STRIDED_OP(float, float, softmax_strided_f32)
+SOFTMAX_STRIDED_OP(double, double, softmax_strided_f64)
+LOG_SOFTMAX_OP(float, float, log_softmax_f32)
+LOG_SOFTMAX_OP(double, double, log_softmax_f64)
+SOFTCAP_SOFTMAX_OP(float, softcap_softmax_f32)
+ATTN_SOFTMAX_OP(float, attn_softmax_f32)
 RMSNORM_OP(float, rmsnorm_f32)
 RMSNORM_OP(double, rmsnorm_f64)
 LAYERNORM_OP(float, layernorm_f32)
+GROUPNORM_OP(float, groupnorm_f32)
+LAYERNORM2D_OP(float, layernorm2d_f32)
 LAYERNORM_OP(double, layernorm_f64)
 ROPE_OP(float, rope_f32, rope_i_f32, rope_thd_f32)
 ROPE_OP(double, rope_f64, rope_i_f64, rope_thd_f64)
@@ -107,3 +107,169 @@ WHERE_OP(uint32_t, int8_t, where_i8_u32)
 WHERE_OP(int16_t, int8_t, where_i8_i16)
 WHERE_OP(int32_t, int8_t, where_i8_i32)
 WHERE_OP(int64_t, int8_t, where_i8_i64)
+
+#define FMA_OP(TYPENAME, FN_NAME) \
+extern "C" __global__ void FN_NAME(  \
+    const size_t numel,  \
+    const size_t num_dims, \
+    const size_t *info, \
+    const TYPENAME *x, \
+    const TYPENAME *a, \
+    const TYPENAME *b, \
+    TYPENAME *out \
+) {  \
+    const size_t *dims = info; \
+    const size_t *strides_x = info + num_dims; \
+    const size_t *strides_a = info + 2*num_dims; \
+    const size_t *strides_b = info + 3*num_dims; \
+    if (is_contiguous(num_dims, dims, strides_x) \
+        && is_contiguous(num_dims, dims, strides_a) \
+        && is_contiguous(num_dims, dims, strides_b)) { \
+        for (unsigned int i = blockIdx.x * blockDim.x + threadIdx.x; i < numel; i += blockDim.x * gridDim.x) { \
+            out[i] = x[i] * a[i] + b[i]; \
+        } \
+    } \
+    else { \
+        for (unsigned int i = blockIdx.x * blockDim.x + threadIdx.x; i < numel; i += blockDim.x * gridDim.x) { \
+            unsigned strided_i_x = get_strided_index(i, num_dims, dims, strides_x); \
+            unsigned strided_i_a = get_strided_index(i, num_dims, dims, strides_a); \
+            unsigned strided_i_b = get_strided_index(i, num_dims, dims, strides_b); \
+            out[i] = x[strided_i_x] * a[strided_i_a] + b[strided_i_b]; \
+        } \
+    } \
+} \
+
+#if __CUDA_ARCH__ >= 800
+FMA_OP(__nv_bfloat16, fma_bf16)
+#endif
+
+#if __CUDA_ARCH__ >= 530
+FMA_OP(__half, fma_f16)
+#endif
+
+FMA_OP(float, fma_f32)
+FMA_OP(double, fma_f64)
+
+#define SILU_MUL_OP(TYPENAME, ACC, FN_NAME) \
+extern "C" __global__ void FN_NAME(  \
+    const size_t numel,  \
+    const size_t num_dims, \
+    const size_t *info, \
+    const TYPENAME *gate, \
+    const TYPENAME *up, \
+    TYPENAME *out \
+) {  \
+    const size_t *dims = info; \
+    const size_t *strides_gate = info + num_dims; \
+    const size_t *strides_up = info + 2*num_dims; \
+    bool cont = is_contiguous(num_dims, dims, strides_gate) \
+        && is_contiguous(num_dims, dims, strides_up); \
+    for (unsigned int i = blockIdx.x * blockDim.x + threadIdx.x; i < numel; i += blockDim.x * gridDim.x) { \
+        unsigned i_gate = cont ? i : get_strided_index(i, num_dims, dims, strides_gate); \
+        unsigned i_up = cont ? i : get_strided_index(i, num_dims, dims, strides_up); \
+        ACC g = static_cast<ACC>(gate[i_gate]); \
+        out[i] = static_cast<TYPENAME>(g / (ACC(1) + exp(-g)) * static_cast<ACC>(up[i_up])); \
+    } \
+} \
+
+#if __CUDA_ARCH__ >= 800
+SILU_MUL_OP(__nv_bfloat16, float, silu_mul_bf16)
+#endif
+
+#if __CUDA_ARCH__ >= 530
+SILU_MUL_OP(__half, float, silu_mul_f16)
+#endif
+
+SILU_MUL_OP(float, float, silu_mul_f32)
+SILU_MUL_OP(double, double, silu_mul_f64)
+
+// SwiGLU over a contiguous input where each row of `2 * split` elements holds the `gate` half
+// followed by the `up` half, both halves are read in the same pass.
+#define SWIGLU_OP(TYPENAME, ACC, FN_NAME) \
+extern "C" __global__ void FN_NAME(  \
+    const size_t numel,  \
+    const size_t split, \
+    const TYPENAME *src, \
+    TYPENAME *out \
+) {  \
+    for (unsigned int i = blockIdx.x * blockDim.x + threadIdx.x; i < numel; i += blockDim.x * gridDim.x) { \
+        const size_t i_gate = (i / split) * 2 * split + i % split; \
+        ACC g = static_cast<ACC>(src[i_gate]); \
+        out[i] = static_cast<TYPENAME>(g / (ACC(1) + exp(-g)) * static_cast<ACC>(src[i_gate + split])); \
+    } \
+} \
+
+#if __CUDA_ARCH__ >= 800
+SWIGLU_OP(__nv_bfloat16, float, swiglu_bf16)
+#endif
+
+#if __CUDA_ARCH__ >= 530
+SWIGLU_OP(__half, float, swiglu_f16)
+#endif
+
+SWIGLU_OP(float, float, swiglu_f32)
+SWIGLU_OP(double, double, swiglu_f64)
+
+#define EMA_UPDATE_OP(TYPENAME, ACC, FN_NAME) \
+extern "C" __global__ void FN_NAME(  \
+    const size_t numel,  \
+    const size_t num_dims, \
+    const size_t *info, \
+    TYPENAME *ema, \
+    const TYPENAME *model, \
+    const double decay \
+) {  \
+    const size_t *dims = info; \
+    const size_t *strides_model = info + num_dims; \
+    bool cont = is_contiguous(num_dims, dims, strides_model); \
+    const ACC d = static_cast<ACC>(decay); \
+    for (unsigned int i = blockIdx.x * blockDim.x + threadIdx.x; i < numel; i += blockDim.x * gridDim.x) { \
+        unsigned i_model = cont ? i : get_strided_index(i, num_dims, dims, strides_model); \
+        ACC e = static_cast<ACC>(ema[i]); \
+        ACC m = static_cast<ACC>(model[i_model]); \
+        ema[i] = static_cast<TYPENAME>(d * e + (ACC(1) - d) * m); \
+    } \
+} \
+
+#if __CUDA_ARCH__ >= 800
+EMA_UPDATE_OP(__nv_bfloat16, float, ema_update_bf16)
+#endif
+
+#if __CUDA_ARCH__ >= 530
+EMA_UPDATE_OP(__half, float, ema_update_f16)
+#endif
+
+EMA_UPDATE_OP(float, float, ema_update_f32)
+EMA_UPDATE_OP(double, double, ema_update_f64)
+
+#define ADD_SCALED_OP(TYPENAME, ACC, FN_NAME) \
+extern "C" __global__ void FN_NAME(  \
+    const size_t numel,  \
+    const size_t num_dims, \
+    const size_t *info, \
+    TYPENAME *xs, \
+    const TYPENAME *ys, \
+    const double alpha \
+) {  \
+    const size_t *dims = info; \
+    const size_t *strides_ys = info + num_dims; \
+    bool cont = is_contiguous(num_dims, dims, strides_ys); \
+    const ACC a = static_cast<ACC>(alpha); \
+    for (unsigned int i = blockIdx.x * blockDim.x + threadIdx.x; i < numel; i += blockDim.x * gridDim.x) { \
+        unsigned i_ys = cont ? i : get_strided_index(i, num_dims, dims, strides_ys); \
+        ACC x = static_cast<ACC>(xs[i]); \
+        ACC y = static_cast<ACC>(ys[i_ys]); \
+        xs[i] = static_cast<TYPENAME>(x + a * y); \
+    } \
+} \
+
+#if __CUDA_ARCH__ >= 800
+ADD_SCALED_OP(__nv_bfloat16, float, add_scaled_bf16)
+#endif
+
+#if __CUDA_ARCH__ >= 530
+ADD_SCALED_OP(__half, float, add_scaled_f16)
+#endif
+
+ADD_SCALED_OP(float, float, add_scaled_f32)
+ADD_SCALED_OP(double, double, add_scaled_f64)
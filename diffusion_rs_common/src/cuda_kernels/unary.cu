@@ -65,6 +65,17 @@ __device__ __forceinline__ T sigmoid_fwd(T x) {
     return recipg(static_cast<T>(1) + expg(-x));
 }
 
+// softplus(x) is x past 20 in float precision, which avoids overflowing exp.
+__device__ __forceinline__ float mish_fwd(float x) {
+    float sp = x > 20.0f ? x : log1pf(expf(x));
+    return x * tanhf(sp);
+}
+
+__device__ __forceinline__ double mish_fwd(double x) {
+    double sp = x > 20.0 ? x : log1p(exp(x));
+    return x * tanh(sp);
+}
+
 #define UNARY_OP1(TYPENAME, FN_NAME, FUNC) \
 extern "C" __global__ void FN_NAME( \
     const size_t numel, \
@@ -125,6 +136,8 @@ UNARY_OP(__nv_bfloat16, usilu_bf16, silu_fwd(x))
 UNARY_OP1(__nv_bfloat16, upowf_bf16, powg(x, param))
 UNARY_OP(__nv_bfloat16, usign_bf16, sign_(x))
 UNARY_OP(__nv_bfloat16, usigmoid_bf16, sigmoid_fwd(x))
+UNARY_OP(__nv_bfloat16, ursqrt_bf16, recipg(sqrtg(x)))
+UNARY_OP(__nv_bfloat16, umish_bf16, __float2bfloat16(mish_fwd(__bfloat162float(x))))
 
 #define F8E4M3_TO_FLOAT(x) __half2float(__nv_cvt_fp8_to_halfraw(x.__x, __NV_E4M3))
 
@@ -179,6 +192,8 @@ UNARY_OP(__half, usilu_f16, silu_fwd(x))
 UNARY_OP1(__half, upowf_f16, powg(x, param))
 UNARY_OP(__half, usign_f16, sign_(x))
 UNARY_OP(__half, usigmoid_f16, sigmoid_fwd(x))
+UNARY_OP(__half, ursqrt_f16, recipg(sqrtg(x)))
+UNARY_OP(__half, umish_f16, __float2half(mish_fwd(__half2float(x))))
 #endif
 
 UNARY_OP(int8_t, ucopy_i8, x)
@@ -234,4 +249,8 @@ UNARY_OP1(double, upowf_f64, powg(x, param))
 UNARY_OP(float, usign_f32, sign_(x))
 UNARY_OP(double, usign_f64, sign_(x))
 UNARY_OP(float, usigmoid_f32, sigmoid_fwd(x))
+UNARY_OP(float, ursqrt_f32, recipg(sqrtg(x)))
 UNARY_OP(double, usigmoid_f64, sigmoid_fwd(x))
+UNARY_OP(float, umish_f32, mish_fwd(x))
+UNARY_OP(double, umish_f64, mish_fwd(x))
+UNARY_OP(double, ursqrt_f64, recipg(sqrtg(x)))
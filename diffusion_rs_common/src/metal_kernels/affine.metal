@@ -582,6 +582,51 @@ kernel void FN_NAME##_strided( \
     output[id] = TYPENAME(pow(input[get_strided_index(id, num_dims, dims, strides)], TYPENAME(mul))); \
 }
 
+// `pow` is undefined for negative bases, so `nn::ops::powf` uses these kernels rather than `POWF`:
+// integer exponents follow the sign rule of the CPU and CUDA backends and fractional ones give NaN.
+template <typename T> METAL_FUNC T signed_pow(T x, float e) {
+    float v = float(x);
+    float r = pow(fabs(v), e);
+    if (v < 0) {
+        if (floor(e) != e) {
+            return T(NAN);
+        }
+        if (fmod(e, 2.0f) != 0) {
+            r = -r;
+        }
+    }
+    return T(r);
+}
+
+#define SIGNED_POWF(FN_NAME, TYPENAME) \
+kernel void FN_NAME( \
+    constant size_t &dim, \
+    constant float &mul, \
+    device const TYPENAME *input,  \
+    device TYPENAME *output, \
+    uint id [[ thread_position_in_grid ]] \
+) { \
+    if (id >= dim) { \
+        return; \
+    } \
+    output[id] = signed_pow(input[id], mul); \
+} \
+kernel void FN_NAME##_strided( \
+    constant size_t &dim, \
+    constant size_t &num_dims, \
+    constant size_t *dims, \
+    constant size_t *strides, \
+    constant float &mul, \
+    device const TYPENAME *input,  \
+    device TYPENAME *output, \
+    uint id [[ thread_position_in_grid ]] \
+) { \
+    if (id >= dim) { \
+        return; \
+    } \
+    output[id] = signed_pow(input[get_strided_index(id, num_dims, dims, strides)], mul); \
+}
+
 #define ELU(FN_NAME, TYPENAME) \
 kernel void FN_NAME( \
     constant size_t &dim, \
@@ -621,9 +666,12 @@ AFFINE(affine_f32, float)
 AFFINE(affine_f16, half)
 POWF(powf_f32, float)
 POWF(powf_f16, half)
+SIGNED_POWF(signed_powf_f32, float)
+SIGNED_POWF(signed_powf_f16, half)
 ELU(elu_f32, float)
 ELU(elu_f16, half)
 
 AFFINE(affine_bf16, bfloat16_t);
 POWF(powf_bf16, bfloat16_t);
+SIGNED_POWF(signed_powf_bf16, bfloat16_t);
 ELU(elu_bf16, bfloat16_t);
@@ -152,7 +152,7 @@ macro_rules! ops{
 pub mod unary {
     ops!(
         cos, sin, exp, sqr, sqrt, neg, log, gelu, abs, ceil, floor, relu, round, erf, gelu_erf,
-        tanh, recip, silu, sign, sigmoid
+        tanh, recip, silu, sign, sigmoid, rsqrt, mish
     );
 }
 pub mod binary {
@@ -730,6 +730,64 @@ pub fn call_last_softmax(
 }
 
 // Requires continuous input and mask
+#[allow(clippy::too_many_arguments)]
+pub fn call_last_softcap_softmax(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    length: usize,
+    elements_to_sum: usize,
+    scale: f32,
+    softcap: f32,
+    input: &Buffer,
+    input_offset: usize,
+    output: &Buffer,
+    output_offset: usize,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(
+        encoder,
+        (
+            length,
+            elements_to_sum,
+            (input, input_offset),
+            (output, output_offset),
+            scale,
+            softcap
+        )
+    );
+
+    let out_length = length / elements_to_sum;
+
+    let thread_group_count = MTLSize {
+        width: out_length as u64,
+        height: 1,
+        depth: 1,
+    };
+
+    let width = std::cmp::min(
+        pipeline.max_total_threads_per_threadgroup(),
+        elements_to_sum as u64,
+    )
+    .next_power_of_two();
+
+    let thread_group_size = MTLSize {
+        width,
+        height: 1,
+        depth: 1,
+    };
+
+    encoder.use_resource(input, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn call_last_attn_softmax(
     device: &Device,
@@ -749,7 +807,12 @@ pub fn call_last_attn_softmax(
     let ne00 = input_shape[input_shape.len() - 1] as i64;
     let ne01 = input_shape[input_shape.len() - 2] as i64;
     let ne02 = input_shape[input_shape.len() - 3] as i64;
-    let ne03 = input_shape[input_shape.len() - 4] as i64;
+    // Rank-3 inputs `(bs, seq, kv_seq)` have no head dim.
+    let ne03 = if input_shape.len() >= 4 {
+        input_shape[input_shape.len() - 4] as i64
+    } else {
+        1
+    };
 
     let mut nth = 32; // SIMD width
     let name = if ne00 % 4 == 0 {
@@ -927,6 +990,119 @@ pub fn call_layer_norm(
     Ok(())
 }
 
+/// Launches one threadgroup per (batch, group) pair of a contiguous group norm input.
+#[allow(clippy::too_many_arguments)]
+pub fn call_group_norm(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    length: usize,
+    group_size: usize,
+    spatial: usize,
+    num_groups: usize,
+    eps: f32,
+    input: &Buffer,
+    input_offset: usize,
+    weight: &Buffer,
+    weight_offset: usize,
+    bias: &Buffer,
+    bias_offset: usize,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(
+        encoder,
+        (
+            length,
+            group_size,
+            spatial,
+            num_groups,
+            (input, input_offset),
+            output,
+            (weight, weight_offset),
+            (bias, bias_offset),
+            eps
+        )
+    );
+
+    let thread_group_count = MTLSize {
+        width: (length / group_size) as u64,
+        height: 1,
+        depth: 1,
+    };
+
+    let width = std::cmp::min(
+        pipeline.max_total_threads_per_threadgroup(),
+        group_size as u64,
+    )
+    .next_power_of_two();
+
+    let thread_group_size = MTLSize {
+        width,
+        height: 1,
+        depth: 1,
+    };
+
+    encoder.use_resource(input, metal::MTLResourceUsage::Read);
+    encoder.use_resource(weight, metal::MTLResourceUsage::Read);
+    encoder.use_resource(bias, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// Launches one thread per (batch, spatial) location of a contiguous NCHW layer norm input.
+#[allow(clippy::too_many_arguments)]
+pub fn call_layer_norm2d(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    channels: usize,
+    spatial: usize,
+    n_locations: usize,
+    eps: f32,
+    input: &Buffer,
+    input_offset: usize,
+    alpha: &Buffer,
+    alpha_offset: usize,
+    beta: &Buffer,
+    beta_offset: usize,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(
+        encoder,
+        (
+            channels,
+            spatial,
+            n_locations,
+            (input, input_offset),
+            output,
+            (alpha, alpha_offset),
+            (beta, beta_offset),
+            eps
+        )
+    );
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, n_locations);
+    encoder.use_resource(input, metal::MTLResourceUsage::Read);
+    encoder.use_resource(alpha, metal::MTLResourceUsage::Read);
+    encoder.use_resource(beta, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn call_rope_i(
     device: &Device,
@@ -1083,6 +1259,35 @@ pub fn call_affine(
     Ok(())
 }
 
+/// Runs the contiguous `affine` kernel with `buffer` as both the input and the output.
+#[allow(clippy::too_many_arguments)]
+pub fn call_affine_inplace(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    size: usize,
+    buffer: BufferOffset,
+    mul: f32,
+    add: f32,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Affine, name)?;
+
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(encoder, (size, mul, add, &buffer, &buffer));
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
+    encoder.use_resource(
+        buffer.buffer,
+        metal::MTLResourceUsage::Read | metal::MTLResourceUsage::Write,
+    );
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn call_affine_strided(
     device: &Device,
@@ -1288,6 +1493,219 @@ pub fn call_where_cond_strided(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
+pub fn call_fma_strided(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    shape: &[usize],
+    x: BufferOffset,
+    x_stride: &[usize],
+    a: BufferOffset,
+    a_stride: &[usize],
+    b: BufferOffset,
+    b_stride: &[usize],
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Ternary, name)?;
+
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    let size: usize = shape.iter().product();
+    let rank = shape.len();
+
+    set_params!(
+        encoder,
+        (size, rank, shape, x_stride, a_stride, b_stride, &x, &a, &b, output)
+    );
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
+
+    encoder.use_resource(x.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(a.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(b.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn call_swiglu(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    size: usize,
+    split: usize,
+    input: BufferOffset,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Ternary, name)?;
+
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(encoder, (size, split, &input, output));
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
+
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn call_bias_act(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    size: usize,
+    dim: usize,
+    act: i32,
+    alpha: f32,
+    input: BufferOffset,
+    bias: BufferOffset,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Ternary, name)?;
+
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(encoder, (size, dim, act, alpha, &input, &bias, output));
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
+
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(bias.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn call_silu_mul_strided(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    shape: &[usize],
+    gate: BufferOffset,
+    gate_stride: &[usize],
+    up: BufferOffset,
+    up_stride: &[usize],
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Ternary, name)?;
+
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    let size: usize = shape.iter().product();
+    let rank = shape.len();
+
+    set_params!(
+        encoder,
+        (
+            size,
+            rank,
+            shape,
+            gate_stride,
+            up_stride,
+            &gate,
+            &up,
+            output
+        )
+    );
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
+
+    encoder.use_resource(gate.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(up.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn call_ema_update_strided(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    shape: &[usize],
+    ema: BufferOffset,
+    model: BufferOffset,
+    model_stride: &[usize],
+    decay: f32,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Ternary, name)?;
+
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    let size: usize = shape.iter().product();
+    let rank = shape.len();
+
+    set_params!(
+        encoder,
+        (size, rank, shape, model_stride, decay, &ema, &model)
+    );
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
+
+    encoder.use_resource(model.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(
+        ema.buffer,
+        metal::MTLResourceUsage::Read | metal::MTLResourceUsage::Write,
+    );
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn call_add_scaled_strided(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    shape: &[usize],
+    xs: BufferOffset,
+    ys: BufferOffset,
+    ys_stride: &[usize],
+    alpha: f32,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Ternary, name)?;
+
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    let size: usize = shape.iter().product();
+    let rank = shape.len();
+
+    set_params!(encoder, (size, rank, shape, ys_stride, alpha, &xs, &ys));
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
+
+    encoder.use_resource(ys.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(
+        xs.buffer,
+        metal::MTLResourceUsage::Read | metal::MTLResourceUsage::Write,
+    );
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn call_index_select(
     device: &Device,
@@ -1749,7 +2167,7 @@ pub enum SdpaDType {
 
 /// SDPA full is supported when:
 /// - q head dim == 64, 128
-/// - no mask
+/// - the optional additive mask is contiguous with shape (bs, qhead, seq, kv_seq)
 /// - q heads == kv heads
 /// - final type != bf16 (TODO maybe just template this kernel too?)
 /// - q,k,v are contiguous
@@ -1765,6 +2183,7 @@ pub fn call_sdpa_full(
     k_buffer: &Buffer,
     v_offset: usize,
     v_buffer: &Buffer,
+    mask: Option<(&Buffer, usize)>,
     output: &Buffer,
     alpha: f32,
     softcapping: f32,
@@ -1900,6 +2319,9 @@ pub fn call_sdpa_full(
         softcapping,
     };
     let batch_strides = [b_stride_q, b_stride_k, b_stride_v, b_stride_o];
+    // Without a mask the kernel never reads the mask buffer, bind `q` in its place.
+    let has_mask = mask.is_some() as i32;
+    let mask_buffer = mask.unwrap_or((q_buffer, q_offset));
 
     impl EncoderParam for MLXFastAttentionParams {
         fn set_param(encoder: &ComputeCommandEncoderRef, position: u64, data: Self) {
@@ -1920,7 +2342,9 @@ pub fn call_sdpa_full(
             output,
             params,
             &batch_shape[..],
-            &batch_strides[..]
+            &batch_strides[..],
+            has_mask,
+            mask_buffer
         )
     );
 
@@ -1937,6 +2361,7 @@ pub fn call_sdpa_full(
     encoder.use_resource(q_buffer, metal::MTLResourceUsage::Read);
     encoder.use_resource(k_buffer, metal::MTLResourceUsage::Read);
     encoder.use_resource(v_buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(mask_buffer.0, metal::MTLResourceUsage::Read);
     encoder.use_resource(output, metal::MTLResourceUsage::Write);
     encoder.dispatch_thread_groups(grid_dims, group_dims);
     Ok(())
@@ -1944,7 +2369,7 @@ pub fn call_sdpa_full(
 
 /// SDPA vector is supported when:
 /// - q head dim == 64, 96, 128
-/// - no mask
+/// - the optional additive mask is contiguous with shape (bs, qhead, 1, kv_seq)
 /// - q,k,v are contiguous
 #[allow(clippy::too_many_arguments)]
 pub fn call_sdpa_vector(
@@ -1961,6 +2386,7 @@ pub fn call_sdpa_vector(
     v_offset: usize,
     v_stride: &[usize],
     v_buffer: &Buffer,
+    mask: Option<(&Buffer, usize)>,
     output: &Buffer,
     alpha: f32,
     softcapping: f32,
@@ -2005,6 +2431,10 @@ pub fn call_sdpa_vector(
         alpha
     };
 
+    // Without a mask the kernel never reads the mask buffer, bind `q` in its place.
+    let has_mask = mask.is_some() as i32;
+    let mask_buffer = mask.unwrap_or((q_buffer, q_offset));
+
     let pipeline = kernels.load_pipeline(device, Source::Sdpa, name)?;
     let encoder = ep.encoder();
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
@@ -2025,7 +2455,9 @@ pub fn call_sdpa_vector(
             kstride,
             vstride,
             alpha,
-            softcapping
+            softcapping,
+            has_mask,
+            mask_buffer
         )
     );
 
@@ -2042,6 +2474,7 @@ pub fn call_sdpa_vector(
     encoder.use_resource(q_buffer, metal::MTLResourceUsage::Read);
     encoder.use_resource(k_buffer, metal::MTLResourceUsage::Read);
     encoder.use_resource(v_buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(mask_buffer.0, metal::MTLResourceUsage::Read);
     encoder.use_resource(output, metal::MTLResourceUsage::Write);
     encoder.dispatch_thread_groups(grid_dims, group_dims);
     Ok(())
@@ -2051,7 +2484,7 @@ pub const SDPA_2PASS_BLOCKS: usize = 32;
 
 /// SDPA vector 2pass is supported when:
 /// - q head dim == 64, 96, 128
-/// - no mask
+/// - the optional additive mask is contiguous with shape (bs, qhead, 1, kv_seq)
 /// - q,k,v are contiguous
 #[allow(clippy::too_many_arguments)]
 pub fn call_sdpa_vector_2pass(
@@ -2072,6 +2505,7 @@ pub fn call_sdpa_vector_2pass(
     intermediate: &Buffer,
     sums: &Buffer,
     maxs: &Buffer,
+    mask: Option<(&Buffer, usize)>,
     alpha: f32,
     softcapping: f32,
     itype: SdpaDType,
@@ -2117,6 +2551,10 @@ pub fn call_sdpa_vector_2pass(
             alpha
         };
 
+        // Without a mask the kernel never reads the mask buffer, bind `q` in its place.
+        let has_mask = mask.is_some() as i32;
+        let mask_buffer = mask.unwrap_or((q_buffer, q_offset));
+
         let pipeline = kernels.load_pipeline(device, Source::Sdpa, name_pass1)?;
         let encoder = ep.encoder();
         let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
@@ -2139,7 +2577,9 @@ pub fn call_sdpa_vector_2pass(
                 kstride,
                 vstride,
                 alpha,
-                softcapping
+                softcapping,
+                has_mask,
+                mask_buffer
             )
         );
 
@@ -2156,6 +2596,7 @@ pub fn call_sdpa_vector_2pass(
         encoder.use_resource(q_buffer, metal::MTLResourceUsage::Read);
         encoder.use_resource(k_buffer, metal::MTLResourceUsage::Read);
         encoder.use_resource(v_buffer, metal::MTLResourceUsage::Read);
+        encoder.use_resource(mask_buffer.0, metal::MTLResourceUsage::Read);
         encoder.use_resource(intermediate, metal::MTLResourceUsage::Write);
         encoder.use_resource(sums, metal::MTLResourceUsage::Write);
         encoder.use_resource(maxs, metal::MTLResourceUsage::Write);
@@ -516,6 +516,10 @@ METAL_FUNC void softmax(
     threadgroup_barrier(mem_flags::mem_threadgroup);
 
     float _max = shared_memory[0];
+    /* fully masked rows only hold -inf, shift them by 0 so that they sum to 0 rather than NaN */
+    if (_max == -INFINITY) {
+        _max = 0;
+    }
 
     /* prevent tid=0 from overwriting _max before other threads have written it */
     threadgroup_barrier(mem_flags::mem_threadgroup);
@@ -536,7 +540,8 @@ METAL_FUNC void softmax(
         threadgroup_barrier(mem_flags::mem_threadgroup);
     }
 
-    const T inv_acc = T(1.0 / shared_memory[0]);
+    /* fully masked rows output zeros */
+    const T inv_acc = shared_memory[0] == 0 ? T(0) : T(1.0 / shared_memory[0]);
     idx = start_idx + tid;
     while (idx < stop_idx) {
         dst[idx] *= inv_acc;
@@ -560,12 +565,189 @@ kernel void NAME( \
     softmax<T>(src_numel, el_to_sum_per_block, src, dst, id, tid, dst_id, block_dim, shared_memory); \
 } \
 
+/* x - max - log(sum(exp(x - max))), the output is only written in the last pass so that
+   src and dst may alias */
 template<typename T>
-METAL_FUNC void rmsnorm(
+METAL_FUNC void log_softmax(
+    constant size_t & src_numel,
+    constant size_t & el_to_sum_per_block,
+    device const T * src,
+    device T * dst,
+    uint id,
+    uint tid,
+    uint dst_id,
+    uint block_dim,
+    threadgroup float * shared_memory
+) {
+    size_t start_idx = dst_id * el_to_sum_per_block;
+    size_t stop_idx = min(start_idx + el_to_sum_per_block, src_numel);
+    size_t idx = start_idx + tid;
+
+    float tmp = -INFINITY;
+    while (idx < stop_idx) {
+        tmp = MAX(tmp, float(src[idx]));
+        idx += block_dim;
+    }
+    shared_memory[tid] = tmp;
+
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+
+    for (uint s = block_dim / 2; s > 0; s >>= 1) {
+        if (tid < s) {
+            shared_memory[tid] = MAX(shared_memory[tid], shared_memory[tid + s]);
+        }
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }
+
+    /* wait for shared_memory[0] to be filled */
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+
+    float _max = shared_memory[0];
+
+    /* prevent tid=0 from overwriting _max before other threads have written it */
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    shared_memory[tid] = 0;
+
+    idx = start_idx + tid;
+    while (_max != -INFINITY && idx < stop_idx) {
+        shared_memory[tid] += exp(float(src[idx]) - _max);
+        idx += block_dim;
+    }
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    for (uint s = block_dim / 2; s > 0; s >>= 1) {
+        if (tid < s) {
+            shared_memory[tid] += shared_memory[tid + s];
+        }
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }
+
+    /* fully masked rows output -inf, the log of the zeros output by softmax */
+    const float acc = shared_memory[0];
+    const float lse = _max + log(acc);
+    idx = start_idx + tid;
+    while (idx < stop_idx) {
+        dst[idx] = acc == 0 ? T(-INFINITY) : T(float(src[idx]) - lse);
+        idx += block_dim;
+    }
+}
+
+#define LOG_SOFTMAX(NAME, T) \
+kernel void NAME( \
+    constant size_t &src_numel, \
+    constant size_t &el_to_sum_per_block, \
+    device const T *src, \
+    device T *dst, \
+    uint id [[ thread_position_in_grid ]], \
+    uint tid [[ thread_index_in_threadgroup ]], \
+    uint dst_id [[ threadgroup_position_in_grid ]], \
+    uint block_dim [[ threads_per_threadgroup ]] \
+) { \
+    threadgroup float shared_memory[THREADGROUP_SIZE]; \
+    shared_memory[tid] = -INFINITY; \
+    log_softmax<T>(src_numel, el_to_sum_per_block, src, dst, id, tid, dst_id, block_dim, shared_memory); \
+} \
+
+METAL_FUNC float softcap_logit(float v, float scale, float softcap) {
+    v *= scale;
+    return softcap > 0.0f ? softcap * precise::tanh(v / softcap) : v;
+}
+
+// Softmax of `softcap * tanh(x * scale / softcap)`, a `softcap` of 0 disables the cap.
+template<typename T>
+METAL_FUNC void softcap_softmax(
     constant size_t & src_numel,
     constant size_t & el_to_sum_per_block,
     device const T * src,
     device T * dst,
+    constant float & scale,
+    constant float & softcap,
+    uint id,
+    uint tid,
+    uint dst_id,
+    uint block_dim,
+    threadgroup float * shared_memory
+) {
+    size_t start_idx = dst_id * el_to_sum_per_block;
+    size_t stop_idx = min(start_idx + el_to_sum_per_block, src_numel);
+    size_t idx = start_idx + tid;
+
+    float tmp = -INFINITY;
+    while (idx < stop_idx) {
+        tmp = MAX(tmp, softcap_logit(float(src[idx]), scale, softcap));
+        idx += block_dim;
+    }
+    shared_memory[tid] = tmp;
+
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+
+    for (uint s = block_dim / 2; s > 0; s >>= 1) {
+        if (tid < s) {
+            shared_memory[tid] = MAX(shared_memory[tid], shared_memory[tid + s]);
+        }
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }
+
+    /* wait for shared_memory[0] to be filled */
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+
+    float _max = shared_memory[0];
+    /* fully masked rows only hold -inf, shift them by 0 so that they sum to 0 rather than NaN */
+    if (_max == -INFINITY) {
+        _max = 0;
+    }
+
+    /* prevent tid=0 from overwriting _max before other threads have written it */
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    shared_memory[tid] = 0;
+
+    idx = start_idx + tid;
+    while (idx < stop_idx) {
+        const float val = exp(softcap_logit(float(src[idx]), scale, softcap) - _max);
+        dst[idx] = T(val);
+        shared_memory[tid] += val;
+        idx += block_dim;
+    }
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    for (uint s = block_dim / 2; s > 0; s >>= 1) {
+        if (tid < s) {
+            shared_memory[tid] += shared_memory[tid + s];
+        }
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }
+
+    /* fully masked rows output zeros */
+    const float inv_acc = shared_memory[0] == 0 ? 0 : 1.0 / shared_memory[0];
+    idx = start_idx + tid;
+    while (idx < stop_idx) {
+        dst[idx] = T(float(dst[idx]) * inv_acc);
+        idx += block_dim;
+    }
+}
+
+#define SOFTCAP_SOFTMAX(NAME, T) \
+kernel void NAME( \
+    constant size_t &src_numel, \
+    constant size_t &el_to_sum_per_block, \
+    device const T *src, \
+    device T *dst, \
+    constant float &scale, \
+    constant float &softcap, \
+    uint id [[ thread_position_in_grid ]], \
+    uint tid [[ thread_index_in_threadgroup ]], \
+    uint dst_id [[ threadgroup_position_in_grid ]], \
+    uint block_dim [[ threads_per_threadgroup ]] \
+) { \
+    threadgroup float shared_memory[THREADGROUP_SIZE]; \
+    shared_memory[tid] = -INFINITY; \
+    softcap_softmax<T>(src_numel, el_to_sum_per_block, src, dst, scale, softcap, id, tid, dst_id, block_dim, shared_memory); \
+} \
+
+template<typename T, typename O = T>
+METAL_FUNC void rmsnorm(
+    constant size_t & src_numel,
+    constant size_t & el_to_sum_per_block,
+    device const T * src,
+    device O * dst,
     device const T * alpha,
     constant float & eps,
     uint id,
@@ -605,7 +787,7 @@ METAL_FUNC void rmsnorm(
         if (alpha != nullptr) {
             val *= float(alpha[idx - start_idx]);
         }
-        dst[idx] = T(val);
+        dst[idx] = O(val);
         idx += block_dim;
     }
 }
@@ -687,6 +869,24 @@ kernel void NAME( \
     rmsnorm<T>(src_numel, el_to_sum_per_block, src, dst, alpha, eps, id, tid, dst_id, block_dim, shared_memory); \
 } \
 
+#define RMSNORM_CAST(NAME, T, O) \
+kernel void NAME( \
+    constant size_t &src_numel, \
+    constant size_t &el_to_sum_per_block, \
+    device const T *src, \
+    device O *dst, \
+    device const T *alpha, \
+    constant float &eps, \
+    uint id [[ thread_position_in_grid ]], \
+    uint tid [[ thread_index_in_threadgroup ]], \
+    uint dst_id [[ threadgroup_position_in_grid ]], \
+    uint block_dim [[ threads_per_threadgroup ]] \
+) { \
+    threadgroup float shared_memory[THREADGROUP_SIZE]; \
+    shared_memory[tid] = 0; \
+    rmsnorm<T, O>(src_numel, el_to_sum_per_block, src, dst, alpha, eps, id, tid, dst_id, block_dim, shared_memory); \
+} \
+
 #define LAYERNORM(NAME, T) \
 kernel void NAME( \
     constant size_t &src_numel, \
@@ -706,6 +906,121 @@ kernel void NAME( \
     layernorm<T>(src_numel, el_to_sum_per_block, src, dst, alpha, beta, eps, id, tid, dst_id, block_dim, shared_memory); \
 } \
 
+// Each threadgroup normalizes the `group_size = channels / num_groups * spatial` elements of
+// one (batch, group) pair of a contiguous (batch, channels, spatial...) input. The variance is
+// computed over the centered values in a second pass.
+template<typename T>
+METAL_FUNC void groupnorm(
+    constant size_t & src_numel,
+    constant size_t & group_size,
+    constant size_t & spatial,
+    constant size_t & num_groups,
+    device const T * src,
+    device T * dst,
+    device const T * weight,
+    device const T * bias,
+    constant float & eps,
+    uint tid,
+    uint dst_id,
+    uint block_dim,
+    threadgroup float * shared_memory
+) {
+    size_t start_idx = dst_id * group_size;
+    size_t stop_idx = min(start_idx + group_size, src_numel);
+    size_t channel_offset = (dst_id % num_groups) * (group_size / spatial);
+
+    float sum = 0;
+    for (size_t idx = start_idx + tid; idx < stop_idx; idx += block_dim) {
+        sum += float(src[idx]);
+    }
+    shared_memory[tid] = sum;
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    for (uint s = block_dim / 2; s > 0; s >>= 1) {
+        if (tid < s) {
+            shared_memory[tid] += shared_memory[tid + s];
+        }
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }
+    float mean = shared_memory[0] / float(group_size);
+    /* every thread has to read the mean before the memory is reused */
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+
+    float sum_sq = 0;
+    for (size_t idx = start_idx + tid; idx < stop_idx; idx += block_dim) {
+        float d = float(src[idx]) - mean;
+        sum_sq += d * d;
+    }
+    shared_memory[tid] = sum_sq;
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    for (uint s = block_dim / 2; s > 0; s >>= 1) {
+        if (tid < s) {
+            shared_memory[tid] += shared_memory[tid + s];
+        }
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }
+    float inv_std = 1.0f / sqrt(shared_memory[0] / float(group_size) + eps);
+
+    for (size_t idx = start_idx + tid; idx < stop_idx; idx += block_dim) {
+        size_t c = channel_offset + (idx - start_idx) / spatial;
+        float val = (float(src[idx]) - mean) * inv_std;
+        dst[idx] = T(val * float(weight[c]) + float(bias[c]));
+    }
+}
+
+#define GROUPNORM(NAME, T) \
+kernel void NAME( \
+    constant size_t &src_numel, \
+    constant size_t &group_size, \
+    constant size_t &spatial, \
+    constant size_t &num_groups, \
+    device const T *src, \
+    device T *dst, \
+    device const T *weight, \
+    device const T *bias, \
+    constant float &eps, \
+    uint tid [[ thread_index_in_threadgroup ]], \
+    uint dst_id [[ threadgroup_position_in_grid ]], \
+    uint block_dim [[ threads_per_threadgroup ]] \
+) { \
+    threadgroup float shared_memory[THREADGROUP_SIZE]; \
+    groupnorm<T>(src_numel, group_size, spatial, num_groups, src, dst, weight, bias, eps, tid, dst_id, block_dim, shared_memory); \
+} \
+
+// LayerNorm over the channel dim of a contiguous (batch, channels, spatial) input, each thread
+// normalizes the `channels` elements of one (batch, spatial) location.
+#define LAYERNORM2D(NAME, T) \
+kernel void NAME( \
+    constant size_t &channels, \
+    constant size_t &spatial, \
+    constant size_t &n_locations, \
+    device const T *src, \
+    device T *dst, \
+    device const T *alpha, \
+    device const T *beta, \
+    constant float &eps, \
+    uint tid [[ thread_position_in_grid ]] \
+) { \
+    if (tid >= n_locations) { \
+        return; \
+    } \
+    size_t offset = (tid / spatial) * channels * spatial + tid % spatial; \
+    float mean = 0; \
+    for (size_t c = 0; c < channels; c++) { \
+        mean += float(src[offset + c * spatial]); \
+    } \
+    mean /= float(channels); \
+    float var = 0; \
+    for (size_t c = 0; c < channels; c++) { \
+        float d = float(src[offset + c * spatial]) - mean; \
+        var += d * d; \
+    } \
+    float inv_std = 1.0f / sqrt(var / float(channels) + eps); \
+    for (size_t c = 0; c < channels; c++) { \
+        float val = (float(src[offset + c * spatial]) - mean) * inv_std; \
+        dst[offset + c * spatial] = T(val * float(alpha[c]) + float(beta[c])); \
+    } \
+} \
+
 template<typename T>
 METAL_FUNC void ropei(
     constant size_t &bh,
@@ -869,6 +1184,10 @@ kernel void attn_soft_max(
         max_val = buf[tiisg];
         max_val = simd_max(max_val);
     }
+    // fully masked rows only hold -inf, shift them by 0 so that they sum to 0 rather than NaN
+    if (max_val == -INFINITY) {
+        max_val = 0.0f;
+    }
 
     // parallel sum
     float lsum = 0.0f;
@@ -901,7 +1220,8 @@ kernel void attn_soft_max(
         sum = simd_sum(sum);
     }
 
-    const float inv_sum = 1.0f/sum;
+    // fully masked rows output zeros
+    const float inv_sum = sum == 0.0f ? 0.0f : 1.0f/sum;
 
     for (int i00 = tpitg; i00 < ne00; i00 += ntg) {
         pdst[i00] *= static_cast<T>(inv_sum);
@@ -959,6 +1279,10 @@ kernel void attn_soft_max_4(
         max_val = buf[tiisg];
         max_val = simd_max(max_val);
     }
+    // fully masked rows only hold -inf, shift them by 0 so that they sum to 0 rather than NaN
+    if (max_val == -INFINITY) {
+        max_val = 0.0f;
+    }
 
     // parallel sum
     float4 lsum4 = 0.0f;
@@ -993,7 +1317,8 @@ kernel void attn_soft_max_4(
         sum = simd_sum(sum);
     }
 
-    const float inv_sum = 1.0f/sum;
+    // fully masked rows output zeros
+    const float inv_sum = sum == 0.0f ? 0.0f : 1.0f/sum;
 
     for (int i00 = tpitg; i00 < ne00/4; i00 += ntg) {
         pdst4[i00] = pdst4[i00] * static_cast<T>((S)inv_sum);
@@ -1027,6 +1352,10 @@ ARGMAX(fast_argmax_u8_strided, uint8_t, 0)
 
 SOFTMAX(softmax_f32, float)
 SOFTMAX(softmax_f16, half)
+LOG_SOFTMAX(log_softmax_f32, float)
+LOG_SOFTMAX(log_softmax_f16, half)
+SOFTCAP_SOFTMAX(softcap_softmax_f32, float)
+SOFTCAP_SOFTMAX(softcap_softmax_f16, half)
 // Softmax for attention
 typedef decltype(attn_soft_max<float>)    attn_soft_max_t;
 typedef decltype(attn_soft_max_4<float4, float>) attn_soft_max_4_t;
@@ -1036,8 +1365,14 @@ template [[host_name("attn_soft_max_f16_4")]] kernel attn_soft_max_4_t attn_soft
 template [[host_name("attn_soft_max_f32_4")]] kernel attn_soft_max_4_t attn_soft_max_4<float4, float>;
 RMSNORM(rmsnorm_f32, float)
 RMSNORM(rmsnorm_f16, half)
+RMSNORM_CAST(rmsnorm_f32_f16, float, half)
+RMSNORM_CAST(rmsnorm_f16_f32, half, float)
 LAYERNORM(layernorm_f32, float)
 LAYERNORM(layernorm_f16, half)
+GROUPNORM(groupnorm_f32, float)
+GROUPNORM(groupnorm_f16, half)
+LAYERNORM2D(layernorm2d_f32, float)
+LAYERNORM2D(layernorm2d_f16, half)
 ROPE(rope_f32, rope_i_f32, rope_thd_f32, float)
 ROPE(rope_f16, rope_i_f16, rope_thd_f16, half)
 
@@ -1072,11 +1407,17 @@ REDUCE(MIN(x, y), fast_min_bf16_strided, bfloat16_t, HUGE_VALBF)
 ARGMIN(fast_argmin_bf16, bfloat16_t, HUGE_VALBF)
 ARGMAX(fast_argmax_bf16, bfloat16_t, -HUGE_VALBF)
 SOFTMAX(softmax_bf16, bfloat16_t)
+LOG_SOFTMAX(log_softmax_bf16, bfloat16_t)
+SOFTCAP_SOFTMAX(softcap_softmax_bf16, bfloat16_t)
 // // Softmax for attention
 template [[host_name("attn_soft_max_bf16")]]   kernel attn_soft_max_t   attn_soft_max<bfloat16_t>;
 #if defined(__HAVE_BFLOAT__)
 template [[host_name("attn_soft_max_bf16_4")]] kernel attn_soft_max_4_t attn_soft_max_4<bfloat4, bfloat16_t>;
 #endif
 RMSNORM(rmsnorm_bf16, bfloat16_t)
+RMSNORM_CAST(rmsnorm_f32_bf16, float, bfloat16_t)
+RMSNORM_CAST(rmsnorm_bf16_f32, bfloat16_t, float)
 LAYERNORM(layernorm_bf16, bfloat16_t)
+GROUPNORM(groupnorm_bf16, bfloat16_t)
+LAYERNORM2D(layernorm2d_bf16, bfloat16_t)
 ROPE(rope_bf16, rope_i_bf16, rope_thd_bf16, bfloat16_t)
@@ -299,6 +299,12 @@ struct MLXScaledDotProductAttentionParams {
 
 // ============ "mlx/backend/metal/kernels/scaled_dot_product_attention_params.sdpa_vector"
 
+// `exp(x - max)`, zero while every score seen so far is masked out with `-inf` where the
+// difference would be NaN. Fully masked rows then have a zero sum and output zeros.
+METAL_FUNC float exp_from_max(float x, float max) {
+  return max == -INFINITY ? 0.f : fast::exp(x - max);
+}
+
 template <typename T, int D>
 [[kernel]] void sdpa_vector(
     const device T* queries [[buffer(0)]],
@@ -311,6 +317,8 @@ template <typename T, int D>
     const constant size_t& v_stride,
     const constant float& scale,
     const constant float& softcapping,
+    const constant int& has_mask,
+    const device T* mask,
     uint3 tid [[threadgroup_position_in_grid]],
     uint simd_gid [[simdgroup_index_in_threadgroup]],
     uint simd_lid [[thread_index_in_simdgroup]]) {
@@ -337,6 +345,7 @@ template <typename T, int D>
   keys += kv_head_idx * k_stride + simd_gid * D + simd_lid * elem_per_thread;
   values += kv_head_idx * v_stride + simd_gid * D + simd_lid * elem_per_thread;
   out += head_idx * D + simd_gid * elem_per_thread;
+  mask += head_idx * N;
 
   // Read the query and 0 the output accumulator
   for (int i = 0; i < elem_per_thread; i++) {
@@ -366,11 +375,14 @@ template <typename T, int D>
       score = precise::tanh(score);
       score = score * softcapping;
     }
+    if (has_mask) {
+      score += static_cast<U>(mask[i]);
+    }
 
     // Update the accumulators
     U new_max = max(max_score, score);
-    U factor = fast::exp(max_score - new_max);
-    U exp_score = fast::exp(score - new_max);
+    U factor = exp_from_max(max_score, new_max);
+    U exp_score = exp_from_max(score, new_max);
 
     max_score = new_max;
     sum_exp_score = sum_exp_score * factor + exp_score;
@@ -395,14 +407,15 @@ template <typename T, int D>
   threadgroup_barrier(mem_flags::mem_threadgroup);
   max_score = max_scores[simd_lid];
   U new_max = simd_max(max_score);
-  U factor = fast::exp(max_score - new_max);
+  U factor = exp_from_max(max_score, new_max);
   sum_exp_score = simd_sum(sum_exp_scores[simd_lid] * factor);
 
   // Now we need to aggregate all the outputs
   for (int i = 0; i < elem_per_thread; i++) {
     outputs[simd_lid * BD + simd_gid] = o[i];
     threadgroup_barrier(mem_flags::mem_threadgroup);
-    o[i] = simd_sum(outputs[simd_gid * BD + simd_lid] * factor) / sum_exp_score;
+    U output = simd_sum(outputs[simd_gid * BD + simd_lid] * factor);
+    o[i] = sum_exp_score == 0 ? 0 : output / sum_exp_score;
     threadgroup_barrier(mem_flags::mem_threadgroup);
   }
 
@@ -428,6 +441,8 @@ template <typename T, int D>
     const constant size_t& v_stride,
     const constant float& scale,
     const constant float& softcapping,
+    const constant int& has_mask,
+    const device T* mask,
     uint3 tid [[threadgroup_position_in_grid]],
     uint simd_gid [[simdgroup_index_in_threadgroup]],
     uint simd_lid [[thread_index_in_simdgroup]]) {
@@ -459,6 +474,7 @@ template <typename T, int D>
   out += head_idx * blocks * D + block_idx * D + simd_lid * elem_per_thread;
   sums += head_idx * blocks + block_idx;
   maxs += head_idx * blocks + block_idx;
+  mask += head_idx * N;
 
   // Read the query and 0 the output accumulator
   for (int i = 0; i < elem_per_thread; i++) {
@@ -488,11 +504,14 @@ template <typename T, int D>
       score = precise::tanh(score);
       score = score * softcapping;
     }
+    if (has_mask) {
+      score += static_cast<U>(mask[i]);
+    }
 
     // Update the accumulators
     U new_max = max(max_score, score);
-    U factor = fast::exp(max_score - new_max);
-    U exp_score = fast::exp(score - new_max);
+    U factor = exp_from_max(max_score, new_max);
+    U exp_score = exp_from_max(score, new_max);
 
     max_score = new_max;
     sum_exp_score = sum_exp_score * factor + exp_score;
@@ -571,7 +590,8 @@ template <typename T, int D>
   maxs += head_idx * blocks;
   out += head_idx * D + simd_gid * elem_per_thread;
 
-  // First everybody reads the max and sum_exp
+  // First everybody reads the max and sum_exp. Softcapping was already applied
+  // to the scores in the first pass, so the partials only need to be merged.
   U max_score = maxs[simd_lid];
   U new_max = simd_max(max_score);
   U factor = fast::exp(max_score - new_max);
@@ -585,7 +605,8 @@ template <typename T, int D>
   for (int i = 0; i < elem_per_thread; i++) {
     outputs[simd_lid * BD + simd_gid] = o[i];
     threadgroup_barrier(mem_flags::mem_threadgroup);
-    o[i] = simd_sum(outputs[simd_gid * BD + simd_lid] * factor) / sum_exp_score;
+    U output = simd_sum(outputs[simd_gid * BD + simd_lid] * factor);
+    o[i] = sum_exp_score == 0 ? 0 : output / sum_exp_score;
     threadgroup_barrier(mem_flags::mem_threadgroup);
   }
 
@@ -1291,7 +1312,11 @@ struct FastAttentionKernel {
       uint simd_lane_id,
       short2 local_blocks,
       float alpha,
-      float softcapping) {
+      float softcapping,
+      bool has_mask,
+      const device T* mask,
+      int mask_ld,
+      int mask_rows) {
     if (simd_group_id == 0) {
       short row_offset = BM + float_padding;
       threadgroup float* maxes = Corrections;
@@ -1307,6 +1332,9 @@ struct FastAttentionKernel {
         float l_i_new = l_i_old;
 
         short offset = simd_lane_id * (BN + tgp_padding);
+        // Rows past the last query of a partial block have no mask row.
+        const bool read_mask = has_mask && int(simd_lane_id) < mask_rows;
+        const device T* mask_row = mask + simd_lane_id * mask_ld;
 
         float m_ij = -INFINITY;
 
@@ -1316,6 +1344,9 @@ struct FastAttentionKernel {
             val = precise::tanh(val);
             val = val * softcapping;
           }
+          if (read_mask) {
+            val += float(mask_row[j]);
+          }
           m_ij = max(m_ij, val);
         }
 
@@ -1329,19 +1360,26 @@ struct FastAttentionKernel {
             val = precise::tanh(val);
             val = val * softcapping;
           }
-          float P_i_j = exp(val - m_ij);
+          if (read_mask) {
+            val += float(mask_row[j]);
+          }
+          // A maximum of -inf means every score so far is masked out, the weights are then
+          // zero rather than the NaN of `exp(-inf - -inf)`.
+          float P_i_j = m_ij == -INFINITY ? 0.f : exp(val - m_ij);
           rowsum += P_i_j;
-          P_i_j = P_i_j * exp(m_ij - m_i_new);
+          P_i_j = P_i_j * (m_i_new == -INFINITY ? 0.f : exp(m_ij - m_i_new));
           Ss[offset + j] = T(P_i_j);
         }
 
-        l_i_new =
-            exp(m_i_old - m_i_new) * l_i_old + exp(m_ij - m_i_new) * rowsum;
+        float old_scale = m_i_new == -INFINITY ? 0.f : exp(m_i_old - m_i_new);
+        float new_scale = m_i_new == -INFINITY ? 0.f : exp(m_ij - m_i_new);
+        l_i_new = old_scale * l_i_old + new_scale * rowsum;
         maxes[simd_lane_id] = m_i_new;
         sums[simd_lane_id] = l_i_new;
-        float rescale = l_i_old * exp(m_i_old - m_i_new);
+        float rescale = l_i_old * old_scale;
         o_rescale[simd_lane_id] = rescale;
-        output_scales[simd_lane_id] = 1.0 / l_i_new;
+        // Fully masked rows have a zero sum and output zeros.
+        output_scales[simd_lane_id] = l_i_new == 0.f ? 0.f : 1.0 / l_i_new;
       }
     }
   }
@@ -1353,6 +1391,8 @@ struct FastAttentionKernel {
       const device T* V [[buffer(2)]],
       device U* O [[buffer(3)]],
       const constant MLXFastAttentionParams* params [[buffer(4)]],
+      bool has_mask,
+      const device T* mask,
       threadgroup T* Qs [[threadgroup(0)]],
       threadgroup T* Ks [[threadgroup(1)]],
       threadgroup T* Ss [[threadgroup(2)]],
@@ -1389,6 +1429,7 @@ struct FastAttentionKernel {
     initialize_corrections(Corrections, simd_lane_id, simd_group_id);
 
     O += c_row * params->ldo;
+    mask += c_row * params->N;
 
     // Prepare threadgroup mma operation
     thread mma_qk_t mma_qk_op(simd_group_id, simd_lane_id);
@@ -1461,7 +1502,11 @@ struct FastAttentionKernel {
           simd_lane_id,
           short2(tgp_bn_qk, tgp_bm),
           params->alpha,
-          params->softcapping);
+          params->softcapping,
+          has_mask,
+          mask + n_block * BN,
+          params->N,
+          params->M - c_row);
 
       loader_v.load_safe(short2(BK, tgp_bn_qk));
 
@@ -1506,8 +1551,10 @@ template <
     const device T* V [[buffer(2)]],
     device T* O [[buffer(3)]],
     const constant MLXFastAttentionParams* params [[buffer(4)]],
-    const constant int* batch_shape [[buffer(6)]],
-    const constant size_t* batch_strides [[buffer(7)]],
+    const constant int* batch_shape [[buffer(5)]],
+    const constant size_t* batch_strides [[buffer(6)]],
+    const constant int& has_mask [[buffer(7)]],
+    const device T* mask [[buffer(8)]],
     uint simd_lane_id [[thread_index_in_simdgroup]],
     uint simd_group_id [[simdgroup_index_in_threadgroup]],
     uint3 tid [[threadgroup_position_in_grid]],
@@ -1546,6 +1593,7 @@ template <
 
   // same shape as input
   O += params->batch_stride_o * tid.z;
+  mask += params->M * params->N * tid.z;
   threadgroup T Qs[attention_kernel::tgp_mem_size_q];
   threadgroup T Ss[attention_kernel::tgp_mem_size_s];
   threadgroup float Corrections[attention_kernel::tgp_mem_size_corrections];
@@ -1559,6 +1607,8 @@ template <
         V,
         O,
         params,
+        has_mask,
+        mask,
         Qs,
         Ks,
         Ss,
@@ -1577,6 +1627,8 @@ template <
         V,
         O,
         params,
+        has_mask,
+        mask,
         Qs,
         Ks,
         Ss,
@@ -1604,6 +1656,8 @@ template <
       const constant MLXFastAttentionParams* params [[buffer(4)]],          \
       const constant int* batch_shape [[buffer(5)]],                        \
       const constant size_t* batch_strides [[buffer(6)]],                   \
+      const constant int& has_mask [[buffer(7)]],                           \
+      const device itype* mask [[buffer(8)]],                               \
       uint simd_lane_id [[thread_index_in_simdgroup]],                      \
       uint simd_group_id [[simdgroup_index_in_threadgroup]],                \
       uint3 tid [[threadgroup_position_in_grid]],                           \
@@ -1669,6 +1723,8 @@ instantiate_fast_inference_self_attention_kernel(half, half, 16, 16, 256, 2, 2);
       const constant size_t& v_stride,                                       \
       const constant float& scale,                                           \
       const constant float& softcapping,                                     \
+      const constant int& has_mask,                                          \
+      const device type* mask,                                               \
       uint3 tid [[threadgroup_position_in_grid]],                            \
       uint simd_gid [[simdgroup_index_in_threadgroup]],                      \
       uint simd_lid [[thread_index_in_simdgroup]]);                          \
@@ -1686,6 +1742,8 @@ instantiate_fast_inference_self_attention_kernel(half, half, 16, 16, 256, 2, 2);
       const constant size_t& v_stride,                                       \
       const constant float& scale,                                           \
       const constant float& softcapping,                                     \
+      const constant int& has_mask,                                          \
+      const device type* mask,                                               \
       uint3 tid [[threadgroup_position_in_grid]],                            \
       uint simd_gid [[simdgroup_index_in_threadgroup]],                      \
       uint simd_lid [[thread_index_in_simdgroup]]);                          \
@@ -358,3 +358,253 @@ WHERE_OP(bfloat16_t, int16_t, where_i16_bf16)
 
 WHERE_OP(bfloat16_t, uint8_t, where_u8_bf16)
 WHERE_OP(bfloat16_t, uint32_t, where_u32_bf16)
+
+template<typename T>
+METAL_FUNC void fma_strided(
+    constant size_t &numel,
+    constant size_t &num_dims,
+    constant size_t *dims,
+    constant size_t *strides_x,
+    constant size_t *strides_a,
+    constant size_t *strides_b,
+    device const T *x,
+    device const T *a,
+    device const T *b,
+    device T *out,
+    uint i [[ thread_position_in_grid ]]
+) {
+    if (i >= numel){
+       return;
+    }
+    uint strided_i_x = get_strided_index(i, num_dims, dims, strides_x);
+    uint strided_i_a = get_strided_index(i, num_dims, dims, strides_a);
+    uint strided_i_b = get_strided_index(i, num_dims, dims, strides_b);
+    out[i] = T(float(x[strided_i_x]) * float(a[strided_i_a]) + float(b[strided_i_b]));
+}
+
+#define FMA_OP(T, FN_NAME)                                                                      \
+kernel void FN_NAME(                                                                            \
+    constant size_t &numel,                                                                     \
+    constant size_t &num_dims,                                                                  \
+    constant size_t *dims,                                                                      \
+    constant size_t *strides_x,                                                                 \
+    constant size_t *strides_a,                                                                 \
+    constant size_t *strides_b,                                                                 \
+    device const T *x,                                                                          \
+    device const T *a,                                                                          \
+    device const T *b,                                                                          \
+    device T *out,                                                                              \
+    uint i [[ thread_position_in_grid ]]                                                        \
+) {                                                                                             \
+   fma_strided<T>(numel, num_dims, dims, strides_x, strides_a, strides_b, x, a, b, out, i);     \
+}                                                                                               \
+
+FMA_OP(half, fma_f16)
+FMA_OP(float, fma_f32)
+FMA_OP(bfloat16_t, fma_bf16)
+
+template<typename T>
+METAL_FUNC void silu_mul_strided(
+    constant size_t &numel,
+    constant size_t &num_dims,
+    constant size_t *dims,
+    constant size_t *strides_gate,
+    constant size_t *strides_up,
+    device const T *gate,
+    device const T *up,
+    device T *out,
+    uint i [[ thread_position_in_grid ]]
+) {
+    if (i >= numel){
+       return;
+    }
+    uint strided_i_gate = get_strided_index(i, num_dims, dims, strides_gate);
+    uint strided_i_up = get_strided_index(i, num_dims, dims, strides_up);
+    float g = float(gate[strided_i_gate]);
+    out[i] = T(g / (1.0f + exp(-g)) * float(up[strided_i_up]));
+}
+
+#define SILU_MUL_OP(T, FN_NAME)                                                                 \
+kernel void FN_NAME(                                                                            \
+    constant size_t &numel,                                                                     \
+    constant size_t &num_dims,                                                                  \
+    constant size_t *dims,                                                                      \
+    constant size_t *strides_gate,                                                              \
+    constant size_t *strides_up,                                                                \
+    device const T *gate,                                                                       \
+    device const T *up,                                                                         \
+    device T *out,                                                                              \
+    uint i [[ thread_position_in_grid ]]                                                        \
+) {                                                                                             \
+   silu_mul_strided<T>(numel, num_dims, dims, strides_gate, strides_up, gate, up, out, i);      \
+}                                                                                               \
+
+SILU_MUL_OP(half, silu_mul_f16)
+SILU_MUL_OP(float, silu_mul_f32)
+SILU_MUL_OP(bfloat16_t, silu_mul_bf16)
+
+/* each row of 2 * split elements of the contiguous src holds the gate half followed by the up half */
+template<typename T>
+METAL_FUNC void swiglu(
+    constant size_t &numel,
+    constant size_t &split,
+    device const T *src,
+    device T *out,
+    uint i [[ thread_position_in_grid ]]
+) {
+    if (i >= numel){
+       return;
+    }
+    size_t i_gate = (i / split) * 2 * split + i % split;
+    float g = float(src[i_gate]);
+    out[i] = T(g / (1.0f + exp(-g)) * float(src[i_gate + split]));
+}
+
+#define SWIGLU_OP(T, FN_NAME)                                                                   \
+kernel void FN_NAME(                                                                            \
+    constant size_t &numel,                                                                     \
+    constant size_t &split,                                                                     \
+    device const T *src,                                                                        \
+    device T *out,                                                                              \
+    uint i [[ thread_position_in_grid ]]                                                        \
+) {                                                                                             \
+   swiglu<T>(numel, split, src, out, i);                                                        \
+}                                                                                               \
+
+SWIGLU_OP(half, swiglu_f16)
+SWIGLU_OP(float, swiglu_f32)
+SWIGLU_OP(bfloat16_t, swiglu_bf16)
+
+/* the act ids follow `BiasAct::metal_act` in nn/ops.rs, `alpha` is the elu alpha or the leaky relu slope */
+METAL_FUNC float bias_act_erf(float x) {
+    // A&S formula 7.1.26, as the erf of unary.metal
+    float sign = x < 0 ? -1.0f : 1.0f;
+    x = fabs(x);
+    float t = 1.0f / (1.0f + 0.3275911f * x);
+    float y = 1.0f - (((((1.061405429f * t - 1.453152027f) * t) + 1.421413741f) * t - 0.284496736f) * t + 0.254829592f) * t * exp(-x * x);
+    return sign * y;
+}
+
+METAL_FUNC float bias_act_apply(float v, int act, float alpha) {
+    switch (act) {
+        case 0: return 0.5f * v * (1.0f + bias_act_erf(v * M_SQRT1_2_F));
+        case 1: return 0.5f * v * (1.0f + precise::tanh(M_2_SQRTPI_F * M_SQRT1_2_F * v * (1.0f + 0.044715f * v * v)));
+        case 2: return max(v, 0.0f);
+        case 3: return max(v, 0.0f) * max(v, 0.0f);
+        case 4: return clamp(v, 0.0f, 6.0f);
+        case 5: return v / (1.0f + exp(-v));
+        case 6: return 1.0f / (1.0f + exp(-v));
+        case 7: return clamp((v + 3.0f) / 6.0f, 0.0f, 1.0f);
+        case 8: return v * clamp((v + 3.0f) / 6.0f, 0.0f, 1.0f);
+        case 9: return v >= 0 ? v : alpha * (exp(v) - 1.0f);
+        case 10: return max(v, 0.0f) + min(v, 0.0f) * alpha;
+        default: return v;
+    }
+}
+
+template<typename T>
+METAL_FUNC void bias_act(
+    constant size_t &numel,
+    constant size_t &dim,
+    constant int &act,
+    constant float &alpha,
+    device const T *xs,
+    device const T *bias,
+    device T *out,
+    uint i [[ thread_position_in_grid ]]
+) {
+    if (i >= numel){
+       return;
+    }
+    out[i] = T(bias_act_apply(float(xs[i]) + float(bias[i % dim]), act, alpha));
+}
+
+#define BIAS_ACT_OP(T, FN_NAME)                                                                 \
+kernel void FN_NAME(                                                                            \
+    constant size_t &numel,                                                                     \
+    constant size_t &dim,                                                                       \
+    constant int &act,                                                                          \
+    constant float &alpha,                                                                      \
+    device const T *xs,                                                                         \
+    device const T *bias,                                                                       \
+    device T *out,                                                                              \
+    uint i [[ thread_position_in_grid ]]                                                        \
+) {                                                                                             \
+   bias_act<T>(numel, dim, act, alpha, xs, bias, out, i);                                       \
+}                                                                                               \
+
+BIAS_ACT_OP(half, bias_act_f16)
+BIAS_ACT_OP(float, bias_act_f32)
+BIAS_ACT_OP(bfloat16_t, bias_act_bf16)
+
+template<typename T>
+METAL_FUNC void ema_update_strided(
+    constant size_t &numel,
+    constant size_t &num_dims,
+    constant size_t *dims,
+    constant size_t *strides_model,
+    constant float &decay,
+    device T *ema,
+    device const T *model,
+    uint i [[ thread_position_in_grid ]]
+) {
+    if (i >= numel){
+       return;
+    }
+    uint strided_i_model = get_strided_index(i, num_dims, dims, strides_model);
+    ema[i] = T(decay * float(ema[i]) + (1.0f - decay) * float(model[strided_i_model]));
+}
+
+#define EMA_UPDATE_OP(T, FN_NAME)                                                               \
+kernel void FN_NAME(                                                                            \
+    constant size_t &numel,                                                                     \
+    constant size_t &num_dims,                                                                  \
+    constant size_t *dims,                                                                      \
+    constant size_t *strides_model,                                                             \
+    constant float &decay,                                                                      \
+    device T *ema,                                                                              \
+    device const T *model,                                                                      \
+    uint i [[ thread_position_in_grid ]]                                                        \
+) {                                                                                             \
+   ema_update_strided<T>(numel, num_dims, dims, strides_model, decay, ema, model, i);           \
+}                                                                                               \
+
+EMA_UPDATE_OP(half, ema_update_f16)
+EMA_UPDATE_OP(float, ema_update_f32)
+EMA_UPDATE_OP(bfloat16_t, ema_update_bf16)
+
+template<typename T>
+METAL_FUNC void add_scaled_strided(
+    constant size_t &numel,
+    constant size_t &num_dims,
+    constant size_t *dims,
+    constant size_t *strides_ys,
+    constant float &alpha,
+    device T *xs,
+    device const T *ys,
+    uint i [[ thread_position_in_grid ]]
+) {
+    if (i >= numel){
+       return;
+    }
+    uint strided_i_ys = get_strided_index(i, num_dims, dims, strides_ys);
+    xs[i] = T(float(xs[i]) + alpha * float(ys[strided_i_ys]));
+}
+
+#define ADD_SCALED_OP(T, FN_NAME)                                                               \
+kernel void FN_NAME(                                                                            \
+    constant size_t &numel,                                                                     \
+    constant size_t &num_dims,                                                                  \
+    constant size_t *dims,                                                                      \
+    constant size_t *strides_ys,                                                                \
+    constant float &alpha,                                                                      \
+    device T *xs,                                                                               \
+    device const T *ys,                                                                         \
+    uint i [[ thread_position_in_grid ]]                                                        \
+) {                                                                                             \
+   add_scaled_strided<T>(numel, num_dims, dims, strides_ys, alpha, xs, ys, i);                  \
+}                                                                                               \
+
+ADD_SCALED_OP(half, add_scaled_f16)
+ADD_SCALED_OP(float, add_scaled_f32)
+ADD_SCALED_OP(bfloat16_t, add_scaled_bf16)
@@ -322,6 +322,11 @@ template <typename T> METAL_FUNC T silu(T in){
 template <typename T> METAL_FUNC T sigmoid(T in) {
     return recip(static_cast<T>(1) + exp(-in));
 }
+template <typename T> METAL_FUNC T mish(T in) {
+    // softplus(x) is x past 20, which avoids overflowing exp.
+    T sp = in > 20 ? in : T(log(1 + exp(in)));
+    return in * T(precise::tanh(sp));
+}
 
 #define TILE_SIZE 2
 
@@ -411,6 +416,8 @@ UNARY_OP(recip)
 UNARY_OP(relu)
 UNARY_OP(sign)
 UNARY_OP(sigmoid)
+UNARY_OP(rsqrt)
+UNARY_OP(mish)
 UNARY(id, float, copy_f32, copy_f32_strided)
 UNARY(id, half, copy_f16, copy_f16_strided)
 UNARY(id, uint8_t, copy_u8, copy_u8_strided)
@@ -452,6 +459,8 @@ BFLOAT_UNARY_OP(recip)
 BFLOAT_UNARY_OP(relu)
 BFLOAT_UNARY_OP(sign)
 BFLOAT_UNARY_OP(sigmoid)
+BFLOAT_UNARY_OP(rsqrt)
+BFLOAT_UNARY_OP(mish)
 
 UNARY(id, bfloat16_t, copy_bf16, copy_bf16_strided)
 
@@ -24,6 +24,9 @@ pub enum Activation {
     LeakyRelu(f64),
     #[serde(alias = "gelu_pytorch_tanh")]
     GeluPytorchTanh,
+    /// No activation, for configs that always name one.
+    #[serde(alias = "linear", alias = "none")]
+    Identity,
 }
 
 impl super::Module for Activation {
@@ -44,6 +47,7 @@ impl super::Module for Activation {
             &Self::Elu(alpha) => xs.elu(alpha),
             &Self::LeakyRelu(negative_slope) => crate::nn::ops::leaky_relu(xs, negative_slope),
             Self::GeluPytorchTanh => xs.gelu(),
+            Self::Identity => Ok(xs.clone()),
         }
     }
 }
@@ -59,6 +63,25 @@ impl PReLU {
         Self { weight, is_scalar }
     }
 
+    /// Creates a PReLU with `num_parameters` trainable slopes all set to `init`, the PyTorch
+    /// `nn.PReLU(num_parameters, init)` constructor.
+    ///
+    /// A single parameter is shared by every channel, otherwise there is one slope per channel of
+    /// dim 1. The weight is an F32 variable so its gradient can be read with
+    /// `grads.get(prelu.weight())`.
+    pub fn from_init(
+        num_parameters: usize,
+        init: f64,
+        device: &crate::core::Device,
+    ) -> Result<Self> {
+        if num_parameters == 0 {
+            crate::bail!("prelu expects at least one parameter")
+        }
+        let weight = Tensor::full(init as f32, num_parameters, device)?;
+        let weight = crate::core::Var::from_tensor(&weight)?.into_inner();
+        Ok(Self::new(weight, num_parameters == 1))
+    }
+
     pub fn weight(&self) -> &Tensor {
         &self.weight
     }
@@ -1,4 +1,4 @@
-use crate::core::{Result, Tensor};
+use crate::core::{DType, Result, Tensor};
 
 /// Computes (softmax(QK^T*sqrt(d_k)) + M)V. `M` is the attention mask, and is a bias (0 for unmasked, -inf for masked).
 ///
@@ -36,3 +36,110 @@ pub fn scaled_dot_product_attention(
     // Convert to contiguous as matmul doesn't support strided vs for now.
     att.matmul(&v.contiguous()?)
 }
+
+/// Splits the hidden dimension of a `(bs, seq, hidden)` tensor into heads, returning a contiguous
+/// `(bs, num_heads, seq, hidden / num_heads)` tensor as expected by the attention kernels.
+pub fn split_heads(x: &Tensor, num_heads: usize) -> Result<Tensor> {
+    let (b_sz, seq_len, hidden) = x.dims3()?;
+    if num_heads == 0 || hidden % num_heads != 0 {
+        crate::bail!("split_heads: num_heads ({num_heads}) must divide hidden ({hidden})")
+    }
+    x.reshape((b_sz, seq_len, num_heads, hidden / num_heads))?
+        .transpose(1, 2)?
+        .contiguous()
+}
+
+/// Merges the heads of a `(bs, num_heads, seq, head_dim)` tensor back into a
+/// `(bs, seq, num_heads * head_dim)` tensor, the inverse of `split_heads`.
+pub fn merge_heads(x: &Tensor) -> Result<Tensor> {
+    let (b_sz, num_heads, seq_len, head_dim) = x.dims4()?;
+    x.transpose(1, 2)?
+        .reshape((b_sz, seq_len, num_heads * head_dim))
+}
+
+/// Largest learned attention scale, learned log-scales are clamped to `ln(100)` as in CLIP.
+pub const MAX_LEARNED_ATTN_SCALE: f64 = 100.;
+
+/// The scale applied to the attention logits, see `attn_scale`.
+#[derive(Debug, Clone)]
+pub enum AttnScale {
+    /// A fixed scale, usually `1/sqrt(head_dim)`, that can be passed to `sdpa`.
+    Fixed(f32),
+    /// A per-head scale of shape `(num_heads,)`.
+    Learned(Tensor),
+}
+
+impl AttnScale {
+    /// Scales `(bs, num_heads, seq, kv_seq)` attention logits.
+    pub fn apply(&self, att: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Fixed(scale) => att * *scale as f64,
+            Self::Learned(scale) => {
+                let num_heads = scale.dim(0)?;
+                att.broadcast_mul(&scale.reshape((num_heads, 1, 1))?.to_dtype(att.dtype())?)
+            }
+        }
+    }
+}
+
+/// Returns the attention scale: `1/sqrt(head_dim)` by default, or `exp(learned)` clamped to
+/// `MAX_LEARNED_ATTN_SCALE` when a learned `(num_heads,)` log-scale is given.
+pub fn attn_scale(head_dim: usize, learned: Option<&Tensor>) -> Result<AttnScale> {
+    match learned {
+        None => {
+            if head_dim == 0 {
+                crate::bail!("attn_scale: head_dim must be non-zero")
+            }
+            Ok(AttnScale::Fixed((head_dim as f32).sqrt().recip()))
+        }
+        Some(log_scale) => {
+            let log_scale = log_scale.flatten_all()?;
+            let scale = log_scale.minimum(MAX_LEARNED_ATTN_SCALE.ln())?.exp()?;
+            Ok(AttnScale::Learned(scale))
+        }
+    }
+}
+
+/// Gathers a Swin-style relative position bias from a `((2 * window - 1)^2, heads)` table using
+/// a precomputed `(window^2, window^2)` u32 or i64 index map, returning a contiguous
+/// `(heads, window^2, window^2)` bias to add to the attention logits of each window.
+pub fn gather_rel_pos_bias(
+    table: &Tensor,
+    index_map: &Tensor,
+    window: usize,
+    heads: usize,
+) -> Result<Tensor> {
+    let table_size = (2 * window).saturating_sub(1).pow(2);
+    let area = window * window;
+    if table.dims() != [table_size, heads] {
+        crate::bail!(
+            "gather_rel_pos_bias: expected a table of shape ({table_size}, {heads}), got {:?}",
+            table.shape()
+        )
+    }
+    if index_map.elem_count() != area * area {
+        crate::bail!(
+            "gather_rel_pos_bias: expected a ({area}, {area}) index map, got {:?}",
+            index_map.shape()
+        )
+    }
+    let index_map = index_map.flatten_all()?;
+    if area > 0 {
+        // Read the bounds as i64 so that negative I64 entries are not wrapped around.
+        let bounds = Tensor::stack(&[index_map.min(0)?, index_map.max(0)?], 0)?
+            .to_dtype(DType::I64)?
+            .to_vec1::<i64>()?;
+        for index in bounds {
+            if index < 0 || index as usize >= table_size {
+                crate::bail!(
+                    "gather_rel_pos_bias: index {index} out of range for a table of {table_size} rows"
+                )
+            }
+        }
+    }
+    table
+        .index_select(&index_map, 0)?
+        .reshape((area, area, heads))?
+        .permute((2, 0, 1))?
+        .contiguous()
+}
@@ -298,6 +298,57 @@ impl crate::nn::ModuleT for BatchNorm {
     }
 }
 
+/// Batch normalization of `(batch, channels, h, w)` inputs, a `BatchNorm` that also checks the
+/// input rank. In eval mode the running stats are used, in train mode the batch stats are used
+/// and the running stats are updated with `momentum`.
+#[derive(Clone, Debug)]
+pub struct BatchNorm2d {
+    inner: BatchNorm,
+}
+
+impl BatchNorm2d {
+    pub fn new(
+        running_mean: Tensor,
+        running_var: Tensor,
+        weight: Tensor,
+        bias: Tensor,
+        eps: f64,
+        momentum: f64,
+    ) -> Result<Self> {
+        let num_features = running_mean.dims1()?;
+        let inner = BatchNorm::new_with_momentum(
+            num_features,
+            running_mean,
+            running_var,
+            weight,
+            bias,
+            eps,
+            momentum,
+        )?;
+        Ok(Self { inner })
+    }
+
+    pub fn running_mean(&self) -> &Tensor {
+        self.inner.running_mean()
+    }
+
+    pub fn running_var(&self) -> &Tensor {
+        self.inner.running_var()
+    }
+}
+
+impl crate::nn::ModuleT for BatchNorm2d {
+    fn forward_t(&self, x: &Tensor, train: bool) -> Result<Tensor> {
+        if x.rank() != 4 {
+            crate::bail!(
+                "batch-norm-2d expects a (batch, channels, h, w) input, got {:?}",
+                x.shape()
+            )
+        }
+        self.inner.forward_t(x, train)
+    }
+}
+
 pub fn batch_norm<C: Into<BatchNormConfig>>(
     num_features: usize,
     config: C,
@@ -326,3 +377,12 @@ pub fn batch_norm<C: Into<BatchNormConfig>>(
         momentum: config.momentum,
     })
 }
+
+pub fn batch_norm2d<C: Into<BatchNormConfig>>(
+    num_features: usize,
+    config: C,
+    vb: crate::nn::VarBuilder,
+) -> Result<BatchNorm2d> {
+    let inner = batch_norm(num_features, config, vb)?;
+    Ok(BatchNorm2d { inner })
+}
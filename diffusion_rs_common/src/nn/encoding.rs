@@ -121,6 +121,58 @@ pub fn one_hot<D: WithDType>(
     Tensor::from_vec(out, target_shape, indices.device())
 }
 
+/// One-hot encoding with the values and the output dtype given at runtime, e.g. to build
+/// `(batch, num_classes)` class labels in the dtype of a model's label embedding.
+///
+/// Behaves like [`one_hot`]: `on` is written at each index, `off` everywhere else, an index of -1
+/// gives a row of `off` values and indices must be below `num_classes`. `off != 0` gives soft
+/// labels such as label smoothing.
+///
+/// ```rust
+/// use diffusion_rs_common::core::{DType, Device, Tensor};
+/// use diffusion_rs_common::nn::encoding::one_hot_with_dtype;
+///
+/// let indices = Tensor::new(&[2u32, 0], &Device::Cpu)?;
+/// let labels = one_hot_with_dtype(&indices, 3, 0.8, 0.1, DType::F32)?;
+/// assert_eq!(labels.to_vec2::<f32>()?, [[0.1, 0.1, 0.8], [0.8, 0.1, 0.1]]);
+/// # Ok::<(), diffusion_rs_common::core::Error>(())
+/// ```
+pub fn one_hot_with_dtype(
+    indices: &Tensor,
+    num_classes: usize,
+    on: f64,
+    off: f64,
+    dtype: DType,
+) -> Result<Tensor> {
+    fn encode<D: WithDType>(
+        indices: &Tensor,
+        num_classes: usize,
+        on: f64,
+        off: f64,
+    ) -> Result<Tensor> {
+        one_hot(
+            indices.clone(),
+            num_classes,
+            D::from_f64(on),
+            D::from_f64(off),
+        )
+    }
+
+    match dtype {
+        DType::U8 => encode::<u8>(indices, num_classes, on, off),
+        DType::I8 => encode::<i8>(indices, num_classes, on, off),
+        DType::U32 => encode::<u32>(indices, num_classes, on, off),
+        DType::I16 => encode::<i16>(indices, num_classes, on, off),
+        DType::I32 => encode::<i32>(indices, num_classes, on, off),
+        DType::I64 => encode::<i64>(indices, num_classes, on, off),
+        DType::BF16 => encode::<half::bf16>(indices, num_classes, on, off),
+        DType::F16 => encode::<half::f16>(indices, num_classes, on, off),
+        DType::F32 => encode::<f32>(indices, num_classes, on, off),
+        DType::F64 => encode::<f64>(indices, num_classes, on, off),
+        DType::F8E4M3 => encode::<float8::F8E4M3>(indices, num_classes, on, off),
+    }
+}
+
 fn set_at_index<D: WithDType, I: Into<i64>>(
     value: I,
     offset: usize,
@@ -4,6 +4,8 @@
 use crate::core::{DType, Result, Tensor};
 
 // This group norm version handles both weight and bias so removes the mean.
+// The affine parameters are either per-channel `(num_channels,)` or shared within each group
+// `(num_groups,)`, as stored by some checkpoints.
 #[derive(Clone, Debug)]
 pub struct GroupNorm {
     weight: Tensor,
@@ -26,6 +28,14 @@ impl GroupNorm {
                 "GroupNorm: num_groups ({num_groups}) must divide num_channels ({num_channels})"
             )
         }
+        for (name, t) in [("weight", &weight), ("bias", &bias)] {
+            let len = t.dims1()?;
+            if len != num_channels && len != num_groups {
+                crate::bail!(
+                    "GroupNorm: {name} has {len} elements, expected num_channels ({num_channels}) or num_groups ({num_groups})"
+                )
+            }
+        }
         Ok(Self {
             weight,
             bias,
@@ -34,6 +44,20 @@ impl GroupNorm {
             num_groups,
         })
     }
+
+    /// Reshapes a weight or bias to broadcast over the channel dim `w_dims[1]`, repeating
+    /// per-group parameters over the channels of each group.
+    fn affine_param(&self, t: &Tensor, w_dims: &[usize]) -> Result<Tensor> {
+        let t = if t.dim(0)? == self.num_channels {
+            t.clone()
+        } else {
+            let channels_per_group = self.num_channels / self.num_groups;
+            t.reshape((self.num_groups, 1))?
+                .broadcast_as((self.num_groups, channels_per_group))?
+                .flatten_all()?
+        };
+        t.reshape(w_dims)
+    }
 }
 
 impl crate::nn::Module for GroupNorm {
@@ -51,6 +75,16 @@ impl crate::nn::Module for GroupNorm {
             )
         }
         let x_dtype = x.dtype();
+        let fused_dtype = matches!(x_dtype, DType::F32 | DType::F16 | DType::BF16);
+        if x.is_contiguous()
+            && fused_dtype
+            && self.weight.dtype() == x_dtype
+            && self.bias.dtype() == x_dtype
+        {
+            let weight = self.affine_param(&self.weight, &[n_channels])?;
+            let bias = self.affine_param(&self.bias, &[n_channels])?;
+            return crate::nn::ops::group_norm(x, &weight, &bias, self.num_groups, self.eps as f32);
+        }
         let internal_dtype = match x_dtype {
             DType::F16 | DType::BF16 => DType::F32,
             d => d,
@@ -63,8 +97,8 @@ impl crate::nn::Module for GroupNorm {
         let x_normed = x.broadcast_div(&(norm_x + self.eps)?.sqrt()?)?;
         let mut w_dims = vec![1; x_shape.len()];
         w_dims[1] = n_channels;
-        let weight = self.weight.reshape(w_dims.clone())?;
-        let bias = self.bias.reshape(w_dims)?;
+        let weight = self.affine_param(&self.weight, &w_dims)?;
+        let bias = self.affine_param(&self.bias, &w_dims)?;
         x_normed
             .to_dtype(x_dtype)?
             .reshape(x_shape)?
@@ -145,3 +145,46 @@ impl Default for Init {
         Self::Const(0.)
     }
 }
+
+/// Samples a tensor from a normal distribution with the given `mean` and `std` truncated to
+/// `[a, b]`, like `torch.nn.init.trunc_normal_`. Values outside of the interval are redrawn,
+/// using an RNG seeded with `seed` so that initializations are reproducible.
+#[allow(clippy::too_many_arguments)]
+pub fn trunc_normal(
+    shape: &Shape,
+    mean: f64,
+    std: f64,
+    a: f64,
+    b: f64,
+    seed: u64,
+    device: &Device,
+    dtype: DType,
+) -> Result<Tensor> {
+    use rand::SeedableRng;
+    use rand_distr::Distribution;
+
+    // Gives up on intervals carrying almost none of the probability mass.
+    const MAX_REJECTIONS: usize = 10_000;
+
+    if a.is_nan() || b.is_nan() || a >= b {
+        crate::bail!("trunc_normal: expected a < b, got [{a}, {b}]")
+    }
+    let normal = rand_distr::Normal::new(mean, std).map_err(crate::core::Error::wrap)?;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+    let mut data = Vec::with_capacity(shape.elem_count());
+    for _ in 0..shape.elem_count() {
+        let mut rejections = 0;
+        let v = loop {
+            let v = normal.sample(&mut rng);
+            if (a..=b).contains(&v) {
+                break v;
+            }
+            rejections += 1;
+            if rejections == MAX_REJECTIONS {
+                crate::bail!("trunc_normal: [{a}, {b}] is too far in the tails of N({mean}, {std})")
+            }
+        };
+        data.push(v);
+    }
+    Tensor::from_vec(data, shape, device)?.to_dtype(dtype)
+}
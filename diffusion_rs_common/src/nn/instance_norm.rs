@@ -0,0 +1,111 @@
+//! Instance Normalization.
+//!
+//! This layer normalizes every (batch, channel) slice of a `(batch, channels, h, w)` input over
+//! its spatial dims, with optional per-channel affine parameters.
+use crate::core::{DType, Result, Tensor};
+
+#[derive(Clone, Debug)]
+pub struct InstanceNorm2d {
+    weight: Option<Tensor>,
+    bias: Option<Tensor>,
+    eps: f32,
+    num_features: usize,
+}
+
+impl InstanceNorm2d {
+    pub fn new(
+        weight: Option<Tensor>,
+        bias: Option<Tensor>,
+        num_features: usize,
+        eps: f32,
+    ) -> Result<Self> {
+        for (name, t) in [("weight", &weight), ("bias", &bias)] {
+            if let Some(t) = t {
+                let len = t.dims1()?;
+                if len != num_features {
+                    crate::bail!(
+                        "InstanceNorm2d: {name} has {len} elements, expected num_features ({num_features})"
+                    )
+                }
+            }
+        }
+        Ok(Self {
+            weight,
+            bias,
+            eps,
+            num_features,
+        })
+    }
+}
+
+impl crate::nn::Module for InstanceNorm2d {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let (b_sz, n_channels, h, w) = x.dims4()?;
+        if n_channels != self.num_features {
+            crate::bail!(
+                "unexpected num-channels in InstanceNorm2d ({n_channels} <> {})",
+                self.num_features
+            )
+        }
+        let x_dtype = x.dtype();
+        let params_dtype_ok = self
+            .weight
+            .iter()
+            .chain(self.bias.iter())
+            .all(|t| t.dtype() == x_dtype);
+        // An instance norm is a group norm with one group per channel.
+        if x.is_contiguous()
+            && matches!(x_dtype, DType::F32 | DType::F16 | DType::BF16)
+            && params_dtype_ok
+        {
+            let weight = match &self.weight {
+                Some(weight) => weight.clone(),
+                None => Tensor::ones(n_channels, x_dtype, x.device())?,
+            };
+            let bias = match &self.bias {
+                Some(bias) => bias.clone(),
+                None => Tensor::zeros(n_channels, x_dtype, x.device())?,
+            };
+            return crate::nn::ops::group_norm(x, &weight, &bias, n_channels, self.eps);
+        }
+
+        let internal_dtype = match x_dtype {
+            DType::F16 | DType::BF16 => DType::F32,
+            d => d,
+        };
+        let x = x
+            .reshape((b_sz, n_channels, h * w))?
+            .to_dtype(internal_dtype)?;
+        let x = x.broadcast_sub(&x.mean_keepdim(2)?)?;
+        let var = x.sqr()?.mean_keepdim(2)?;
+        let x = x
+            .broadcast_div(&(var + self.eps as f64)?.sqrt()?)?
+            .to_dtype(x_dtype)?
+            .reshape((b_sz, n_channels, h, w))?;
+        let x = match &self.weight {
+            Some(weight) => x.broadcast_mul(&weight.reshape((1, n_channels, 1, 1))?)?,
+            None => x,
+        };
+        match &self.bias {
+            Some(bias) => x.broadcast_add(&bias.reshape((1, n_channels, 1, 1))?),
+            None => Ok(x),
+        }
+    }
+}
+
+/// Creates an `InstanceNorm2d`, loading `weight` and `bias` from `vb` when `affine` is set.
+pub fn instance_norm2d(
+    num_features: usize,
+    eps: f32,
+    affine: bool,
+    vb: crate::nn::VarBuilder,
+) -> Result<InstanceNorm2d> {
+    let (weight, bias) = if affine {
+        let weight = vb.get_with_hints(num_features, "weight", crate::nn::Init::Const(1.))?;
+        let bias = vb.get_with_hints(num_features, "bias", crate::nn::Init::Const(0.))?;
+        (Some(weight), Some(bias))
+    } else {
+        (None, None)
+    };
+    InstanceNorm2d::new(weight, bias, num_features, eps)
+}
@@ -43,6 +43,7 @@ use crate::core::{
 };
 
 use crate::core::{DType, Device, Module, Result, Tensor, D};
+use crate::nn::Linear;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct LayerNormConfig {
@@ -173,6 +174,65 @@ pub fn layer_norm<C: Into<LayerNormConfig>>(
     })
 }
 
+/// The modulation block of an adaptive layer norm: a linear layer maps the SiLU of a conditioning
+/// embedding, e.g. a timestep embedding, to a `(shift, scale)` pair that modulates the output of
+/// `norm` as `norm(xs) * (1 + scale) + shift`.
+#[derive(Clone, Debug)]
+pub struct AdaLayerNorm {
+    norm: LayerNorm,
+    linear: Linear,
+}
+
+impl AdaLayerNorm {
+    pub fn new(norm: LayerNorm, linear: Linear) -> Self {
+        Self { norm, linear }
+    }
+
+    pub fn norm(&self) -> &LayerNorm {
+        &self.norm
+    }
+
+    pub fn linear(&self) -> &Linear {
+        &self.linear
+    }
+
+    /// Applies the block to `xs` of shape `(batch, ..., channels)` conditioned on `emb` of shape
+    /// `(batch, emb_dim)`.
+    pub fn forward(&self, xs: &Tensor, emb: &Tensor) -> Result<Tensor> {
+        let emb = self.linear.forward(&emb.silu()?)?;
+        let ys = emb.chunk(2, D::Minus1)?;
+        if ys.len() != 2 {
+            crate::bail!("unexpected len from chunk {ys:?}")
+        }
+        let (shift, scale) = (&ys[0], &ys[1]);
+        let (b_sz, channels) = scale.dims2()?;
+        let mut mod_dims = vec![1; xs.rank()];
+        mod_dims[0] = b_sz;
+        mod_dims[xs.rank() - 1] = channels;
+        let scale = (scale.reshape(mod_dims.as_slice())? + 1.)?;
+        let shift = shift.reshape(mod_dims)?;
+        self.norm
+            .forward(xs)?
+            .broadcast_mul(&scale)?
+            .broadcast_add(&shift)
+    }
+}
+
+/// Creates an `AdaLayerNorm` over `dim` channels conditioned on an `emb_dim` embedding. The norm
+/// has no learned parameters and the linear layer is loaded from `vb.pp("linear")`.
+pub fn ada_layer_norm(
+    dim: usize,
+    emb_dim: usize,
+    eps: f64,
+    vb: crate::nn::VarBuilder,
+) -> Result<AdaLayerNorm> {
+    let weight = Tensor::ones(dim, vb.dtype(), vb.device())?;
+    let bias = weight.zeros_like()?;
+    let norm = LayerNorm::new(weight, bias, eps);
+    let linear = crate::nn::linear(emb_dim, 2 * dim, vb.pp("linear"))?;
+    Ok(AdaLayerNorm::new(norm, linear))
+}
+
 // This whole non quantized/quantized RmsNorm is a hack. It seems like quantized works without this impl, but it is slower.
 #[derive(Clone, Debug)]
 pub struct RmsNormQuantized;
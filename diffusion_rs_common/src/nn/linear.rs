@@ -54,6 +54,25 @@ impl super::Module for Linear {
     }
 }
 
+/// Applies the linear transformation `x@w.t()` followed by the bias addition and `act`.
+///
+/// This is equivalent to `act.forward(&Linear::new(weight, bias).forward(x)?)` but the bias
+/// addition and the activation are fused into a single pass on the cpu and on Metal, see
+/// [`crate::nn::ops::bias_act`], so no intermediate tensor is allocated for the biased output.
+pub fn fused_linear_act(
+    x: &Tensor,
+    weight: &Tensor,
+    bias: Option<&Tensor>,
+    act: crate::nn::Activation,
+) -> Result<Tensor> {
+    use super::Module;
+    let x = Linear::new(weight.clone(), None).forward(x)?;
+    match bias {
+        Some(bias) => crate::nn::ops::bias_act(&x, bias, act),
+        None => act.forward(&x),
+    }
+}
+
 /// Create or initialize a new linear layer.
 ///
 /// This uses some default names for weights and biases, namely `"weight"` and `"bias"`.
@@ -24,10 +24,12 @@ pub mod encoding;
 pub mod func;
 pub mod group_norm;
 pub mod init;
+pub mod instance_norm;
 pub mod kv_cache;
 pub mod layer_norm;
 pub mod linear;
 pub mod loss;
+pub mod norm;
 pub mod ops;
 pub mod optim;
 pub mod rnn;
@@ -38,8 +40,11 @@ pub mod var_builder;
 pub mod var_map;
 
 pub use activation::{prelu, Activation, PReLU};
-pub use attention::scaled_dot_product_attention;
-pub use batch_norm::{batch_norm, BatchNorm, BatchNormConfig};
+pub use attention::{
+    attn_scale, gather_rel_pos_bias, merge_heads, scaled_dot_product_attention, split_heads,
+    AttnScale,
+};
+pub use batch_norm::{batch_norm, batch_norm2d, BatchNorm, BatchNorm2d, BatchNormConfig};
 pub use conv::{
     conv1d, conv1d_no_bias, conv2d, conv2d_no_bias, conv_transpose1d, conv_transpose1d_no_bias,
     conv_transpose2d, conv_transpose2d_no_bias, Conv1d, Conv1dConfig, Conv2d, Conv2dConfig,
@@ -49,11 +54,14 @@ pub use embedding::{embedding, Embedding};
 pub use func::{func, func_t, Func, FuncT};
 pub use group_norm::{group_norm, GroupNorm};
 pub use init::Init;
+pub use instance_norm::{instance_norm2d, InstanceNorm2d};
 pub use layer_norm::{
-    layer_norm, rms_norm_non_quant, rms_norm_quant, LayerNorm, LayerNormConfig, RmsNorm,
+    ada_layer_norm, layer_norm, rms_norm_non_quant, rms_norm_quant, AdaLayerNorm, LayerNorm,
+    LayerNormConfig, RmsNorm,
 };
-pub use linear::{linear, linear_b, linear_no_bias, Linear};
-pub use ops::{kvconcat, Dropout};
+pub use linear::{fused_linear_act, linear, linear_b, linear_no_bias, Linear};
+pub use norm::{norm, Norm, NormConfig};
+pub use ops::{kvconcat, Dropout, Dropout2d, GeGLU, ReGLU, SwiGLU};
 pub use optim::{AdamW, Optimizer, ParamsAdamW, SGD};
 pub use rnn::{gru, lstm, GRUConfig, LSTMConfig, GRU, LSTM, RNN};
 pub use rope::RotaryEmbedding;
@@ -0,0 +1,72 @@
+//! Config driven normalization layers.
+//!
+//! `NormConfig` can be read from a model configuration and turned into a `Norm` with `norm`,
+//! which then dispatches to the matching normalization module.
+use serde::Deserialize;
+
+use crate::core::{Result, Tensor};
+use crate::nn::{GroupNorm, LayerNorm, LayerNormConfig, RmsNorm};
+
+use super::layer_norm::RmsNormQuantized;
+
+fn default_affine() -> bool {
+    true
+}
+
+/// The normalization type and hyper-parameters, e.g. `{"type": "group_norm", "num_groups": 32,
+/// "eps": 1e-6}` in a JSON config.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NormConfig {
+    RmsNorm {
+        eps: f64,
+    },
+    LayerNorm {
+        eps: f64,
+        /// Whether the layer has a bias, defaults to true.
+        #[serde(default = "default_affine")]
+        affine: bool,
+    },
+    GroupNorm {
+        num_groups: usize,
+        eps: f64,
+    },
+}
+
+/// A normalization layer of any of the supported kinds, each variant holding its weights and
+/// `eps`.
+#[derive(Clone, Debug)]
+pub enum Norm {
+    RmsNorm(RmsNorm<RmsNormQuantized>),
+    LayerNorm(LayerNorm),
+    GroupNorm(GroupNorm),
+}
+
+impl super::Module for Norm {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::RmsNorm(norm) => norm.forward(xs),
+            Self::LayerNorm(norm) => norm.forward(xs),
+            Self::GroupNorm(norm) => norm.forward(xs),
+        }
+    }
+}
+
+/// Builds the normalization layer described by `config` for `size` features (channels for the
+/// group norm).
+pub fn norm(size: usize, config: NormConfig, vb: crate::nn::VarBuilder) -> Result<Norm> {
+    match config {
+        NormConfig::RmsNorm { eps } => Ok(Norm::RmsNorm(crate::nn::rms_norm_quant(size, eps, vb)?)),
+        NormConfig::LayerNorm { eps, affine } => {
+            let config = LayerNormConfig {
+                eps,
+                affine,
+                ..Default::default()
+            };
+            Ok(Norm::LayerNorm(crate::nn::layer_norm(size, config, vb)?))
+        }
+        NormConfig::GroupNorm { num_groups, eps } => Ok(Norm::GroupNorm(crate::nn::group_norm(
+            num_groups, size, eps, vb,
+        )?)),
+    }
+}
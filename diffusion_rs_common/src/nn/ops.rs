@@ -19,8 +19,28 @@ use rayon::prelude::*;
 ///     ]);
 /// # Ok::<(), diffusion_rs_common::core::Error>(())
 /// ```
+///
+/// Floating point inputs use a fused kernel on the cuda and metal backends, where slices holding
+/// only `-inf` output zeros. The cpu uses the composed tensor ops.
 pub fn softmax<D: crate::core::shape::Dim>(xs: &Tensor, dim: D) -> Result<Tensor> {
     let dim = dim.to_index(xs.shape(), "softmax")?;
+    if !xs.device().is_cpu()
+        && matches!(
+            xs.dtype(),
+            DType::F32 | DType::F64 | DType::F16 | DType::BF16
+        )
+    {
+        let last = xs.rank() - 1;
+        if xs.device().is_metal() && dim != last {
+            // The metal kernel only reduces over the last dim.
+            return xs
+                .transpose(dim, last)?
+                .contiguous()?
+                .apply_op1(SoftmaxDim { dim: last })?
+                .transpose(dim, last);
+        }
+        return xs.contiguous()?.apply_op1(SoftmaxDim { dim });
+    }
     let max = xs.max_keepdim(dim)?;
     let diff = xs.broadcast_sub(&max)?;
     let num = diff.exp()?;
@@ -37,13 +57,333 @@ pub fn log_softmax<D: crate::core::shape::Dim>(xs: &Tensor, d: D) -> Result<Tens
     Ok(log_sm)
 }
 
+/// Entropy of the attention distribution `softmax(scores)` over the keys, e.g. to detect collapsed
+/// attention heads. `scores` has shape `(batch, heads, queries, keys)` and the result has shape
+/// `(batch, heads, queries)`, the entropy is in nats.
+///
+/// The entropy is computed from `log_softmax` so that near one-hot rows do not hit `log(0)`. Keys
+/// masked out with `-inf` contribute zero. Half precision inputs are processed in f32.
+pub fn attention_entropy(scores: &Tensor) -> Result<Tensor> {
+    let (_b_sz, _heads, _queries, _keys) = scores.dims4()?;
+    let dtype = scores.dtype();
+    let scores = match dtype {
+        DType::F16 | DType::BF16 => scores.to_dtype(DType::F32)?,
+        _ => scores.clone(),
+    };
+    let log_p = log_softmax(&scores, D::Minus1)?;
+    let p = log_p.exp()?;
+    // Masked keys have p = 0 and log_p = -inf, their product would be NaN.
+    let p_log_p = p.ne(0.)?.where_cond(&(&p * &log_p)?, &p.zeros_like()?)?;
+    p_log_p.sum(D::Minus1)?.neg()?.to_dtype(dtype)
+}
+
 pub fn silu(xs: &Tensor) -> Result<Tensor> {
     xs.silu()
 }
 
 pub fn swiglu(xs: &Tensor) -> Result<Tensor> {
-    let xs = xs.chunk(2, D::Minus1)?;
-    &xs[0].silu()? * &xs[1]
+    use crate::core::shape::Dim;
+    let split_dim = D::Minus1.to_index(xs.shape(), "swiglu")?;
+    SwiGLU::new(split_dim).forward(xs)
+}
+
+/// Splits `xs` in two halves along `dim`, which has to have an even size.
+fn glu_halves(xs: &Tensor, dim: usize, name: &str) -> Result<(Tensor, Tensor)> {
+    let size = xs.dim(dim)?;
+    if size % 2 != 0 {
+        crate::bail!(
+            "{name} expects an even size for dim {dim}, got shape {:?}",
+            xs.shape()
+        )
+    }
+    Ok((
+        xs.narrow(dim, 0, size / 2)?,
+        xs.narrow(dim, size / 2, size / 2)?,
+    ))
+}
+
+/// SwiGLU gating: splits `xs` in two halves `(gate, up)` along `split_dim` and returns
+/// `silu(gate) * up`.
+#[derive(Clone, Copy, Debug)]
+pub struct SwiGLU {
+    split_dim: usize,
+}
+
+impl SwiGLU {
+    pub fn new(split_dim: usize) -> Self {
+        Self { split_dim }
+    }
+}
+
+impl Module for SwiGLU {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (gate, up) = glu_halves(xs, self.split_dim, "swiglu")?;
+        // The gpu backends read both halves in a single kernel rather than materializing
+        // `silu(gate)`.
+        if (xs.device().is_cuda() || xs.device().is_metal())
+            && matches!(xs.dtype(), DType::F32 | DType::F16 | DType::BF16)
+        {
+            let op = SwigluFused {
+                split_dim: self.split_dim,
+            };
+            return xs.contiguous()?.apply_op1(op);
+        }
+        gate.silu()? * up
+    }
+}
+
+/// GEGLU gating with the same layout as `geglu`: splits `xs` in two halves `(hidden, gate)` along
+/// `split_dim` and returns `hidden * gelu_erf(gate)`.
+#[derive(Clone, Copy, Debug)]
+pub struct GeGLU {
+    split_dim: usize,
+}
+
+impl GeGLU {
+    pub fn new(split_dim: usize) -> Self {
+        Self { split_dim }
+    }
+}
+
+impl Module for GeGLU {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (hidden, gate) = glu_halves(xs, self.split_dim, "geglu")?;
+        hidden * gate.gelu_erf()?
+    }
+}
+
+/// ReGLU gating: splits `xs` in two halves `(gate, up)` along `split_dim` and returns
+/// `relu(gate) * up`.
+#[derive(Clone, Copy, Debug)]
+pub struct ReGLU {
+    split_dim: usize,
+}
+
+impl ReGLU {
+    pub fn new(split_dim: usize) -> Self {
+        Self { split_dim }
+    }
+}
+
+impl Module for ReGLU {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (gate, up) = glu_halves(xs, self.split_dim, "reglu")?;
+        gate.relu()? * up
+    }
+}
+
+/// GEGLU gating: splits the last dim of `xs` in two halves `(hidden, gate)` and returns
+/// `hidden * gelu_erf(gate)`.
+pub fn geglu(xs: &Tensor) -> Result<Tensor> {
+    let dim = xs.dim(D::Minus1)?;
+    if dim % 2 != 0 {
+        crate::bail!("geglu expects an even last dim, got {dim}")
+    }
+    let hidden = xs.narrow(D::Minus1, 0, dim / 2)?;
+    let gate = xs.narrow(D::Minus1, dim / 2, dim / 2)?;
+    hidden * gate.gelu_erf()?
+}
+
+/// The GEGLU feedforward of the Stable Diffusion transformer blocks: `proj` projects `x` to twice
+/// the inner dim, `geglu` gates it and `out` projects back.
+///
+/// Weights use the `Linear` layout, `(2 * inner, in)` for `proj_weight` and `(out, inner)` for
+/// `out_weight`.
+pub fn geglu_mlp(
+    x: &Tensor,
+    proj_weight: &Tensor,
+    proj_bias: Option<&Tensor>,
+    out_weight: &Tensor,
+    out_bias: Option<&Tensor>,
+) -> Result<Tensor> {
+    let (proj_dim, _) = proj_weight.dims2()?;
+    if proj_dim % 2 != 0 {
+        crate::bail!("geglu_mlp expects an even projection dim, got {proj_dim}")
+    }
+    let (_, inner_dim) = out_weight.dims2()?;
+    if inner_dim * 2 != proj_dim {
+        crate::bail!(
+            "geglu_mlp output weight {:?} does not match the projection dim {proj_dim}",
+            out_weight.shape()
+        )
+    }
+    let proj = crate::nn::Linear::new(proj_weight.clone(), proj_bias.cloned());
+    let out = crate::nn::Linear::new(out_weight.clone(), out_bias.cloned());
+    out.forward(&geglu(&proj.forward(x)?)?)
+}
+
+/// Runs the `u{kernel}` kernel from `unary.cu` over `storage`.
+#[cfg(feature = "cuda")]
+fn cuda_unary_fwd(
+    kernel: &'static str,
+    storage: &crate::core::CudaStorage,
+    layout: &Layout,
+) -> Result<(crate::core::CudaStorage, Shape)> {
+    use crate::core::backend::BackendStorage;
+    use crate::core::cuda_backend::cudarc::driver::{
+        CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig, ValidAsZeroBits,
+    };
+    use crate::core::cuda_backend::SlicePtrOrNull;
+    use crate::core::cuda_backend::{kernel_name, kernels, Map1, WrapErr};
+    use crate::core::{CudaDevice, WithDType};
+
+    struct S {
+        kernel: &'static str,
+    }
+    impl Map1 for S {
+        fn f<T: DeviceRepr + WithDType + ValidAsZeroBits>(
+            &self,
+            src: &CudaSlice<T>,
+            dev: &CudaDevice,
+            layout: &Layout,
+        ) -> Result<CudaSlice<T>> {
+            let shape = layout.shape();
+            let dims = shape.dims();
+            let el_count = shape.elem_count();
+            let cfg = LaunchConfig::for_num_elems(el_count as u32);
+            let ds = SlicePtrOrNull::params_from_layout(dev, layout)?;
+            let src = &src.slice(layout.start_offset()..);
+            let func = dev.get_or_load_func(&kernel_name::<T>(self.kernel), kernels::UNARY)?;
+            // SAFETY: Set later by running the kernel.
+            let out = unsafe { dev.alloc::<T>(el_count) }.w()?;
+
+            let params = (el_count, dims.len(), &ds, src, &out);
+            // SAFETY: ffi.
+            unsafe { func.launch(cfg, params) }.w()?;
+            Ok(out)
+        }
+    }
+
+    let dev = storage.device();
+    let slice = S { kernel }.map(&storage.slice, dev, layout)?;
+    let dst = crate::core::CudaStorage {
+        slice,
+        device: dev.clone(),
+    };
+    Ok((dst, layout.shape().clone()))
+}
+
+/// Runs the `$op` kernels from `unary.metal` over `$storage`, picking the tiled, contiguous or
+/// strided variant depending on the layout.
+#[cfg(feature = "metal")]
+macro_rules! metal_unary_fwd {
+    ($op:ident, $storage:expr, $layout:expr) => {{
+        use crate::core::backend::BackendStorage;
+        use crate::core::MetalError;
+        let storage: &crate::core::MetalStorage = $storage;
+        let layout: &Layout = $layout;
+        let name = stringify!($op);
+        let device = storage.device();
+        let dtype = storage.dtype();
+        let el_count = layout.shape().elem_count();
+        let buffer = device.new_buffer(el_count, dtype, name)?;
+        let command_buffer = device.command_buffer()?;
+        command_buffer.set_label(name);
+        let src = crate::metal_kernels::BufferOffset {
+            buffer: storage.buffer(),
+            offset_in_bytes: layout.start_offset() * storage.dtype().size_in_bytes(),
+        };
+
+        match (el_count % 2, dtype, layout.is_contiguous()) {
+            (0, DType::BF16 | DType::F16, true) => {
+                use crate::metal_kernels::unary::contiguous_tiled;
+                let kernel_name = match dtype {
+                    DType::F16 => contiguous_tiled::$op::HALF,
+                    DType::BF16 => contiguous_tiled::$op::BFLOAT,
+                    dtype => {
+                        crate::bail!(
+                            "Metal contiguous_tiled unary {name} {dtype:?} not implemented"
+                        )
+                    }
+                };
+                crate::metal_kernels::call_unary_contiguous_tiled(
+                    device.metal_device(),
+                    &command_buffer,
+                    device.kernels(),
+                    kernel_name,
+                    el_count,
+                    src,
+                    &buffer,
+                )
+                .map_err(MetalError::from)?;
+            }
+            (_, _, true) => {
+                use crate::metal_kernels::unary::contiguous;
+                let kernel_name = match dtype {
+                    DType::F16 => contiguous::$op::HALF,
+                    DType::F32 => contiguous::$op::FLOAT,
+                    DType::BF16 => contiguous::$op::BFLOAT,
+                    dtype => {
+                        crate::bail!("Metal contiguous unary {name} {dtype:?} not implemented")
+                    }
+                };
+                crate::metal_kernels::call_unary_contiguous(
+                    device.metal_device(),
+                    &command_buffer,
+                    device.kernels(),
+                    kernel_name,
+                    el_count,
+                    src,
+                    &buffer,
+                )
+                .map_err(MetalError::from)?;
+            }
+            (_, _, false) => {
+                use crate::metal_kernels::unary::strided;
+                let kernel_name = match dtype {
+                    DType::F16 => strided::$op::HALF,
+                    DType::F32 => strided::$op::FLOAT,
+                    DType::BF16 => strided::$op::BFLOAT,
+                    dtype => {
+                        crate::bail!("Metal strided unary {name} {dtype:?} not implemented")
+                    }
+                };
+                let dst = crate::metal_kernels::BufferOffset::zero_offset(&buffer);
+                crate::metal_kernels::call_unary_strided(
+                    device.metal_device(),
+                    &command_buffer,
+                    device.kernels(),
+                    kernel_name,
+                    layout.dims(),
+                    src,
+                    layout.stride(),
+                    dst,
+                )
+                .map_err(MetalError::from)?;
+            }
+        }
+
+        let new_storage = crate::core::MetalStorage::new(buffer, device.clone(), el_count, dtype);
+        Ok((new_storage, layout.shape().clone()))
+    }};
+}
+
+/// Applies `fwd` elementwise to a float cpu storage.
+fn cpu_unary_fwd(
+    name: &'static str,
+    storage: &CpuStorage,
+    layout: &Layout,
+    fwd: impl UnaryFloatFn,
+) -> Result<(CpuStorage, Shape)> {
+    use crate::core::backend::BackendStorage;
+    use crate::core::cpu_backend::unary_map;
+
+    let storage = match storage {
+        CpuStorage::BF16(slice) => CpuStorage::BF16(unary_map(slice, layout, |v| fwd.call(v))),
+        CpuStorage::F16(slice) => CpuStorage::F16(unary_map(slice, layout, |v| fwd.call(v))),
+        CpuStorage::F32(slice) => CpuStorage::F32(unary_map(slice, layout, |v| fwd.call(v))),
+        CpuStorage::F64(slice) => CpuStorage::F64(unary_map(slice, layout, |v| fwd.call(v))),
+        _ => Err(crate::core::Error::UnsupportedDTypeForOp(
+            storage.dtype(),
+            name,
+        ))?,
+    };
+    Ok((storage, layout.shape().clone()))
+}
+
+/// A float function that can be evaluated for every float dtype, used by `cpu_unary_fwd`.
+trait UnaryFloatFn {
+    fn call<T: num_traits::Float>(&self, v: T) -> T;
 }
 
 struct Sigmoid;
@@ -151,7 +491,7 @@ impl crate::core::CustomOp1 for Sigmoid {
         };
 
         match (el_count % 2, dtype, layout.is_contiguous()) {
-            (0, DType::BF16 | DType::F16, true) => {
+            (0, DType::BF16 | DType::F16 | DType::F32, true) => {
                 use crate::metal_kernels::unary::contiguous_tiled;
                 let kernel_name = match dtype {
                     DType::F16 => contiguous_tiled::sigmoid::HALF,
@@ -240,521 +580,7024 @@ pub fn hard_sigmoid(xs: &Tensor) -> Result<Tensor> {
     ((xs + 3.0)? / 6.0)?.clamp(0f32, 1f32)
 }
 
+/// Scalar form of the elementwise activations, `Swiglu` is not elementwise and not handled.
+fn activation_f64(act: crate::nn::Activation, v: f64) -> f64 {
+    use crate::nn::Activation as A;
+    let sigmoid = |v: f64| 1. / (1. + (-v).exp());
+    let hard_sigmoid = |v: f64| ((v + 3.) / 6.).clamp(0., 1.);
+    match act {
+        A::Gelu => 0.5 * v * (1. + crate::core::cpu::erf::erf(v / std::f64::consts::SQRT_2)),
+        A::NewGelu | A::GeluPytorchTanh => {
+            let sqrt_two_over_pi = (2. / std::f64::consts::PI).sqrt();
+            0.5 * v * (1. + (sqrt_two_over_pi * v * (1. + 0.044715 * v * v)).tanh())
+        }
+        A::Relu => v.max(0.),
+        A::Relu2 => v.max(0.).powi(2),
+        A::Relu6 => v.clamp(0., 6.),
+        A::Silu | A::Swish => v * sigmoid(v),
+        A::Sigmoid => sigmoid(v),
+        A::HardSigmoid => hard_sigmoid(v),
+        A::HardSwish => v * hard_sigmoid(v),
+        A::Elu(alpha) => {
+            if v.is_sign_positive() {
+                v
+            } else {
+                alpha * (v.exp() - 1.)
+            }
+        }
+        A::LeakyRelu(negative_slope) => v.max(0.) + v.min(0.) * negative_slope,
+        A::Identity => v,
+        A::Swiglu => unreachable!("swiglu is not an elementwise activation"),
+    }
+}
+
+struct BiasAct {
+    act: crate::nn::Activation,
+}
+
+impl BiasAct {
+    /// The activation id and parameter of the Metal `bias_act` kernels.
+    #[cfg(feature = "metal")]
+    fn metal_act(&self) -> Result<(i32, f32)> {
+        use crate::nn::Activation as A;
+        let act = match self.act {
+            A::Gelu => (0, 0.),
+            A::NewGelu | A::GeluPytorchTanh => (1, 0.),
+            A::Relu => (2, 0.),
+            A::Relu2 => (3, 0.),
+            A::Relu6 => (4, 0.),
+            A::Silu | A::Swish => (5, 0.),
+            A::Sigmoid => (6, 0.),
+            A::HardSigmoid => (7, 0.),
+            A::HardSwish => (8, 0.),
+            A::Elu(alpha) => (9, alpha as f32),
+            A::LeakyRelu(negative_slope) => (10, negative_slope as f32),
+            A::Identity => (11, 0.),
+            A::Swiglu => crate::bail!("swiglu is not an elementwise activation"),
+        };
+        Ok(act)
+    }
+}
+
+impl crate::core::CustomOp2 for BiasAct {
+    fn name(&self) -> &'static str {
+        "bias-act"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        fn inner<T: crate::core::WithDType>(
+            xs: &[T],
+            xs_l: &Layout,
+            bias: &[T],
+            bias_l: &Layout,
+            act: crate::nn::Activation,
+        ) -> Result<(CpuStorage, Shape)> {
+            let xs = match xs_l.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &xs[o1..o2],
+            };
+            let bias = match bias_l.contiguous_offsets() {
+                None => crate::bail!("bias has to be contiguous"),
+                Some((o1, o2)) => &bias[o1..o2],
+            };
+            let mut dst = vec![T::zero(); xs.len()];
+            xs.par_chunks(bias.len())
+                .zip(dst.par_chunks_mut(bias.len()))
+                .for_each(|(src, dst)| {
+                    for ((d, &x), &b) in dst.iter_mut().zip(src).zip(bias) {
+                        *d = T::from_f64(activation_f64(act, x.to_f64() + b.to_f64()));
+                    }
+                });
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, xs_l.shape().clone()))
+        }
+
+        use crate::core::backend::BackendStorage;
+        use CpuStorage as C;
+        match (s1, s2) {
+            (C::BF16(s1), C::BF16(s2)) => inner::<half::bf16>(s1, l1, s2, l2, self.act),
+            (C::F16(s1), C::F16(s2)) => inner::<half::f16>(s1, l1, s2, l2, self.act),
+            (C::F32(s1), C::F32(s2)) => inner::<f32>(s1, l1, s2, l2, self.act),
+            (C::F64(s1), C::F64(s2)) => inner::<f64>(s1, l1, s2, l2, self.act),
+            _ => crate::bail!("unsupported dtype for bias-act {:?}", s1.dtype()),
+        }
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        s1: &crate::core::MetalStorage,
+        l1: &Layout,
+        s2: &crate::core::MetalStorage,
+        l2: &Layout,
+    ) -> Result<(crate::core::MetalStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+        use crate::core::metal_backend::buffer_o;
+        let device = s1.device();
+        let command_buffer = device.command_buffer()?;
+        let kernels = device.kernels();
+        let name = match (s1.dtype(), s2.dtype()) {
+            (DType::F32, DType::F32) => "bias_act_f32",
+            (DType::F16, DType::F16) => "bias_act_f16",
+            (DType::BF16, DType::BF16) => "bias_act_bf16",
+            (dtype, _) => crate::bail!("unsupported dtype for bias-act {dtype:?}"),
+        };
+        if !l1.is_contiguous() || !l2.is_contiguous() {
+            crate::bail!("bias-act expects contiguous inputs");
+        }
+        let (act, alpha) = self.metal_act()?;
+        let elem_count = l1.shape().elem_count();
+        let output = device.new_buffer(elem_count, s1.dtype(), "bias-act")?;
+        crate::metal_kernels::call_bias_act(
+            device.metal_device(),
+            &command_buffer,
+            kernels,
+            name,
+            elem_count,
+            l2.shape().elem_count(),
+            act,
+            alpha,
+            buffer_o(s1.buffer(), l1, s1.dtype()),
+            buffer_o(s2.buffer(), l2, s2.dtype()),
+            &output,
+        )
+        .map_err(crate::core::Error::wrap)?;
+        let newstorage =
+            crate::core::MetalStorage::new(output, device.clone(), elem_count, s1.dtype());
+        Ok((newstorage, l1.shape().clone()))
+    }
+}
+
+/// Computes `act(xs + bias)` with `bias` of shape `(xs.dim(D::Minus1),)`, e.g. for the output
+/// of a linear layer.
+///
+/// On the cpu and on Metal the bias addition and the activation run as a single pass without
+/// materializing `xs + bias`. Other devices, and the non-elementwise `Swiglu` activation, use the
+/// composed ops.
+pub fn bias_act(xs: &Tensor, bias: &Tensor, act: crate::nn::Activation) -> Result<Tensor> {
+    let dim_m1 = xs.dim(D::Minus1)?;
+    if bias.dims() != [dim_m1] {
+        crate::bail!(
+            "bias_act expects a bias of shape ({dim_m1},), got {:?}",
+            bias.shape()
+        )
+    }
+    let fused = xs.device().is_cpu()
+        || (xs.device().is_metal() && matches!(xs.dtype(), DType::F32 | DType::F16 | DType::BF16));
+    if fused && act != crate::nn::Activation::Swiglu {
+        xs.contiguous()?
+            .apply_op2_no_bwd(&bias.contiguous()?, &BiasAct { act })
+    } else {
+        act.forward(&xs.broadcast_add(bias)?)
+    }
+}
+
 pub fn leaky_relu(xs: &Tensor, negative_slope: f64) -> Result<Tensor> {
     let zeros = xs.zeros_like()?;
     xs.maximum(&zeros)? + xs.minimum(&zeros)? * negative_slope
 }
 
-pub fn dropout(xs: &Tensor, drop_p: f32) -> Result<Tensor> {
-    // This implementation is inefficient as it stores the full mask for the backward pass.
-    // Instead we could just store the seed and have a specialized kernel that would both
-    // generate the random mask and apply it.
-    // Another easier optimization would be to be able to generate boolean mask using just a bit of
-    // entropy per element rather than generating a full float per element.
-    if !(0. ..1.).contains(&drop_p) {
-        crate::bail!("dropout probability has to be in [0, 1), got {drop_p}")
-    }
-    let rand = Tensor::rand(0f32, 1f32, xs.shape(), xs.device())?;
-    let scale = 1.0 / (1.0 - drop_p as f64);
-    let drop_p = Tensor::new(drop_p, xs.device())?.broadcast_as(xs.shape())?;
-    let mask = (rand.ge(&drop_p)?.to_dtype(xs.dtype())? * scale)?;
-    xs * mask
+/// Hard swish `x * relu6(x + 3) / 6`, same as `x * hard_sigmoid(x)`.
+pub fn hardswish(xs: &Tensor) -> Result<Tensor> {
+    xs * ((xs + 3.0)?.clamp(0f32, 6f32)? / 6.0)?
 }
 
-#[derive(Clone, Debug)]
-pub struct Dropout {
-    drop_p: f32,
+/// Hard shrinkage, zeroes the elements with `|x| <= lambda` and keeps the others.
+pub fn hardshrink(xs: &Tensor, lambda: f64) -> Result<Tensor> {
+    xs.abs()?.gt(lambda)?.where_cond(xs, &xs.zeros_like()?)
 }
 
-impl Dropout {
-    pub fn new(drop_p: f32) -> Dropout {
-        Self { drop_p }
-    }
+/// Module wrapper for `hardshrink`.
+#[derive(Clone, Copy, Debug)]
+pub struct Hardshrink {
+    lambda: f64,
+}
 
-    pub fn forward(&self, xs: &Tensor, train: bool) -> Result<Tensor> {
-        if train {
-            dropout(xs, self.drop_p)
-        } else {
-            Ok(xs.clone())
-        }
+impl Hardshrink {
+    pub fn new(lambda: f64) -> Self {
+        Self { lambda }
     }
 }
 
-impl crate::core::ModuleT for Dropout {
-    fn forward_t(&self, xs: &Tensor, train: bool) -> Result<Tensor> {
-        self.forward(xs, train)
+impl Module for Hardshrink {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        hardshrink(xs, self.lambda)
     }
 }
 
-struct SoftmaxLastDim;
+struct Fma;
 
-impl crate::core::InplaceOp1 for SoftmaxLastDim {
+impl crate::core::CustomOp3 for Fma {
     fn name(&self) -> &'static str {
-        "softmax-last-dim"
+        "fma"
     }
 
-    fn cpu_fwd(&self, storage: &mut CpuStorage, layout: &Layout) -> Result<()> {
-        fn softmax<T: crate::core::WithDType + num_traits::Float>(
-            src: &mut [T],
-            layout: &Layout,
-        ) -> Result<()> {
-            let src = match layout.contiguous_offsets() {
-                None => crate::bail!("input has to be contiguous"),
-                Some((o1, o2)) => &mut src[o1..o2],
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+        s3: &CpuStorage,
+        l3: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        fn inner<T: crate::core::WithDType + num_traits::Float>(
+            x: &[T],
+            l_x: &Layout,
+            a: &[T],
+            l_a: &Layout,
+            b: &[T],
+            l_b: &Layout,
+        ) -> Result<(CpuStorage, Shape)> {
+            let dst: Vec<T> = match (
+                l_x.contiguous_offsets(),
+                l_a.contiguous_offsets(),
+                l_b.contiguous_offsets(),
+            ) {
+                (Some((x1, x2)), Some((a1, a2)), Some((b1, b2))) => x[x1..x2]
+                    .iter()
+                    .zip(a[a1..a2].iter())
+                    .zip(b[b1..b2].iter())
+                    .map(|((&x, &a), &b)| x * a + b)
+                    .collect(),
+                _ => l_x
+                    .strided_index()
+                    .zip(l_a.strided_index())
+                    .zip(l_b.strided_index())
+                    .map(|((i_x, i_a), i_b)| x[i_x] * a[i_a] + b[i_b])
+                    .collect(),
             };
-            let dims = layout.shape().dims();
-            let dim_m1 = dims[dims.len() - 1];
-            src.par_chunks_mut(dim_m1).for_each(|src| {
-                let mut max = T::neg_infinity();
-                unsafe { T::vec_reduce_max(src.as_ptr(), &mut max, dim_m1) };
-                for s in src.iter_mut() {
-                    *s = (*s - max).exp();
-                }
-                let mut sum_exp = T::zero();
-                unsafe { T::vec_reduce_sum(src.as_ptr(), &mut sum_exp, dim_m1) };
-                for d in src.iter_mut() {
-                    *d /= sum_exp
-                }
-            });
-            Ok(())
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, l_x.shape().clone()))
         }
 
-        match storage {
-            CpuStorage::BF16(slice) => softmax::<half::bf16>(slice, layout),
-            CpuStorage::F16(slice) => softmax::<half::f16>(slice, layout),
-            CpuStorage::F32(slice) => softmax::<f32>(slice, layout),
-            CpuStorage::F64(slice) => softmax::<f64>(slice, layout),
-            _ => crate::bail!("unsupported dtype for softmax {:?}", storage),
+        use CpuStorage as C;
+        match (s1, s2, s3) {
+            (C::BF16(s1), C::BF16(s2), C::BF16(s3)) => inner::<half::bf16>(s1, l1, s2, l2, s3, l3),
+            (C::F16(s1), C::F16(s2), C::F16(s3)) => inner::<half::f16>(s1, l1, s2, l2, s3, l3),
+            (C::F32(s1), C::F32(s2), C::F32(s3)) => inner::<f32>(s1, l1, s2, l2, s3, l3),
+            (C::F64(s1), C::F64(s2), C::F64(s3)) => inner::<f64>(s1, l1, s2, l2, s3, l3),
+            _ => crate::bail!("unsupported dtype for fma {:?}", s1.dtype()),
         }
     }
 
     #[cfg(feature = "cuda")]
-    fn cuda_fwd(&self, storage: &mut crate::core::CudaStorage, layout: &Layout) -> Result<()> {
+    fn cuda_fwd(
+        &self,
+        s1: &crate::core::CudaStorage,
+        l1: &Layout,
+        s2: &crate::core::CudaStorage,
+        l2: &Layout,
+        s3: &crate::core::CudaStorage,
+        l3: &Layout,
+    ) -> Result<(crate::core::CudaStorage, Shape)> {
         use crate::core::cuda_backend::cudarc::driver::{
             CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
         };
-        use crate::core::cuda_backend::{kernel_name, kernels, Map1InPlace, WrapErr};
+        use crate::core::cuda_backend::{kernel_name, kernels, Map3, WrapErr};
         use crate::core::{CudaDevice, WithDType};
 
         struct S;
-        impl Map1InPlace for S {
+        impl Map3 for S {
             fn f<T: DeviceRepr + WithDType>(
                 &self,
-                src: &mut CudaSlice<T>,
+                x: &CudaSlice<T>,
+                l_x: &Layout,
+                a: &CudaSlice<T>,
+                l_a: &Layout,
+                b: &CudaSlice<T>,
+                l_b: &Layout,
                 dev: &CudaDevice,
-                layout: &Layout,
-            ) -> Result<()> {
-                let src = match layout.contiguous_offsets() {
-                    None => crate::bail!("input has to be contiguous"),
-                    Some((o1, o2)) => src.slice(o1..o2),
-                };
-                let el = layout.shape().elem_count();
-                let dims = layout.shape().dims();
-                let dim_m1 = dims[dims.len() - 1];
-                let (n_rows, n_cols) = (el / dim_m1, dim_m1);
-
-                let func = dev.get_or_load_func(&kernel_name::<T>("softmax"), kernels::REDUCE)?;
-                let cfg = LaunchConfig {
-                    grid_dim: (n_rows as u32, 1, 1),
-                    block_dim: (1, 32, 1),
-                    shared_mem_bytes: 0,
-                };
-                let params = (&src, &src, n_cols as i32);
+            ) -> Result<CudaSlice<T>> {
+                let shape = l_x.shape();
+                let dims = shape.dims();
+                let el = shape.elem_count();
+                let cfg = LaunchConfig::for_num_elems(el as u32);
+                let ds = dev
+                    .htod_copy([dims, l_x.stride(), l_a.stride(), l_b.stride()].concat())
+                    .w()?;
+                let x = &x.slice(l_x.start_offset()..);
+                let a = &a.slice(l_a.start_offset()..);
+                let b = &b.slice(l_b.start_offset()..);
+                let func = dev.get_or_load_func(&kernel_name::<T>("fma"), kernels::TERNARY)?;
+                // SAFETY: Set later by running the kernel.
+                let out = unsafe { dev.alloc::<T>(el) }.w()?;
+                let params = (el, dims.len(), &ds, x, a, b, &out);
                 // SAFETY: ffi.
                 unsafe { func.launch(cfg, params) }.w()?;
-                Ok(())
+                Ok(out)
             }
         }
 
         use crate::core::backend::BackendStorage;
-        let dev = storage.device().clone();
-
-        S.map(&mut storage.slice, &dev, layout)?;
-
-        Ok(())
+        let dev = s1.device();
+        let slice = S.map(&s1.slice, l1, &s2.slice, l2, &s3.slice, l3, dev)?;
+        let dst = crate::core::cuda_backend::CudaStorage {
+            slice,
+            device: dev.clone(),
+        };
+        Ok((dst, l1.shape().clone()))
     }
 
     #[cfg(feature = "metal")]
-    fn metal_fwd(&self, storage: &mut crate::core::MetalStorage, layout: &Layout) -> Result<()> {
+    fn metal_fwd(
+        &self,
+        s1: &crate::core::MetalStorage,
+        l1: &Layout,
+        s2: &crate::core::MetalStorage,
+        l2: &Layout,
+        s3: &crate::core::MetalStorage,
+        l3: &Layout,
+    ) -> Result<(crate::core::MetalStorage, Shape)> {
         use crate::core::backend::BackendStorage;
-        let device = storage.device();
+        use crate::core::metal_backend::buffer_o;
+        let device = s1.device();
         let command_buffer = device.command_buffer()?;
         let kernels = device.kernels();
-        let name = match storage.dtype() {
-            DType::F32 => "softmax_f32",
-            DType::F16 => "softmax_f16",
-            DType::BF16 => "softmax_bf16",
-            dtype => crate::bail!("softmax-last-dim is not implemented for {dtype:?}"),
+        let name = match (s1.dtype(), s2.dtype(), s3.dtype()) {
+            (DType::F32, DType::F32, DType::F32) => "fma_f32",
+            (DType::F16, DType::F16, DType::F16) => "fma_f16",
+            (DType::BF16, DType::BF16, DType::BF16) => "fma_bf16",
+            (dt1, dt2, dt3) => {
+                crate::bail!("fma is not implemented for {dt1:?} {dt2:?} {dt3:?}")
+            }
         };
 
-        let n = layout.stride().len();
-        if !(layout.is_contiguous() && layout.stride()[n - 1] == 1) {
-            crate::bail!("Non contiguous softmax-last-dim is not implemented");
-        }
-
-        let last_dim = layout.dims()[layout.shape().rank() - 1];
-        let elem_count = layout.shape().elem_count();
-        crate::metal_kernels::call_last_softmax(
+        let elem_count = l1.shape().elem_count();
+        let output = device.new_buffer(elem_count, s1.dtype(), "fma")?;
+        crate::metal_kernels::call_fma_strided(
             device.metal_device(),
             &command_buffer,
             kernels,
             name,
-            elem_count,
-            last_dim,
-            storage.buffer(),
-            layout.start_offset() * storage.dtype().size_in_bytes(),
-            storage.buffer(),
-            layout.start_offset() * storage.dtype().size_in_bytes(),
+            l1.dims(),
+            buffer_o(s1.buffer(), l1, s1.dtype()),
+            l1.stride(),
+            buffer_o(s2.buffer(), l2, s2.dtype()),
+            l2.stride(),
+            buffer_o(s3.buffer(), l3, s3.dtype()),
+            l3.stride(),
+            &output,
         )
         .map_err(crate::core::Error::wrap)?;
-        Ok(())
+        let newstorage =
+            crate::core::MetalStorage::new(output, device.clone(), elem_count, s1.dtype());
+        Ok((newstorage, l1.shape().clone()))
+    }
+
+    fn bwd(
+        &self,
+        x: &Tensor,
+        a: &Tensor,
+        _b: &Tensor,
+        _res: &Tensor,
+        grad_res: &Tensor,
+    ) -> Result<(Option<Tensor>, Option<Tensor>, Option<Tensor>)> {
+        // d/dx = a, d/da = x, d/db = 1
+        let grad_x = grad_res.mul(a)?;
+        let grad_a = grad_res.mul(x)?;
+        Ok((Some(grad_x), Some(grad_a), Some(grad_res.clone())))
     }
 }
 
-impl crate::core::CustomOp1 for SoftmaxLastDim {
+/// Computes `x * a + b` in a single kernel pass. `a` and `b` are broadcasted to the shape of `x`.
+///
+/// ```rust
+/// use diffusion_rs_common::core::{Tensor, Device};
+/// let x = Tensor::new(&[[1f32, 2.], [3., 4.]], &Device::Cpu)?;
+/// let a = Tensor::new(2f32, &Device::Cpu)?;
+/// let b = Tensor::new(&[[1f32], [-1.]], &Device::Cpu)?;
+/// let ys = diffusion_rs_common::nn::ops::fma(&x, &a, &b)?;
+/// assert_eq!(ys.to_vec2::<f32>()?, &[[3., 5.], [5., 7.]]);
+/// # Ok::<(), diffusion_rs_common::core::Error>(())
+/// ```
+pub fn fma(x: &Tensor, a: &Tensor, b: &Tensor) -> Result<Tensor> {
+    let a = a.broadcast_as(x.shape())?;
+    let b = b.broadcast_as(x.shape())?;
+    x.apply_op3(&a, &b, Fma)
+}
+
+struct SiluMul;
+
+impl crate::core::CustomOp2 for SiluMul {
     fn name(&self) -> &'static str {
-        "softmax-last-dim"
+        "silu-mul"
     }
 
-    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
-        fn softmax<T: crate::core::WithDType + num_traits::Float>(
-            src: &[T],
-            layout: &Layout,
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        fn inner<T: crate::core::WithDType>(
+            gate: &[T],
+            l_gate: &Layout,
+            up: &[T],
+            l_up: &Layout,
         ) -> Result<(CpuStorage, Shape)> {
-            let src = match layout.contiguous_offsets() {
-                None => crate::bail!("input has to be contiguous"),
-                Some((o1, o2)) => &src[o1..o2],
+            let f = |g: T, u: T| {
+                let g = g.to_f64();
+                T::from_f64(g / (1. + (-g).exp()) * u.to_f64())
+            };
+            let dst: Vec<T> = match (l_gate.contiguous_offsets(), l_up.contiguous_offsets()) {
+                (Some((g1, g2)), Some((u1, u2))) => gate[g1..g2]
+                    .par_iter()
+                    .zip(up[u1..u2].par_iter())
+                    .map(|(&g, &u)| f(g, u))
+                    .collect(),
+                _ => l_gate
+                    .strided_index()
+                    .zip(l_up.strided_index())
+                    .map(|(i_g, i_u)| f(gate[i_g], up[i_u]))
+                    .collect(),
             };
-            let el_count = layout.shape().elem_count();
-            let dims = layout.shape().dims();
-            let dim_m1 = dims[dims.len() - 1];
-            let mut dst = vec![T::zero(); el_count];
-            src.par_chunks(dim_m1)
-                .zip(dst.par_chunks_mut(dim_m1))
-                .for_each(|(src, dst)| {
-                    let mut max = T::neg_infinity();
-                    unsafe { T::vec_reduce_max(src.as_ptr(), &mut max, dim_m1) };
-                    for (s, d) in src.iter().zip(dst.iter_mut()) {
-                        *d = (*s - max).exp();
-                    }
-                    let mut sum_exp = T::zero();
-                    unsafe { T::vec_reduce_sum(dst.as_ptr(), &mut sum_exp, dim_m1) };
-                    for d in dst.iter_mut() {
-                        *d /= sum_exp
-                    }
-                });
             let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
-            Ok((storage, Shape::from_dims(dims)))
+            Ok((storage, l_gate.shape().clone()))
         }
 
-        match storage {
-            CpuStorage::BF16(slice) => softmax::<half::bf16>(slice, layout),
-            CpuStorage::F16(slice) => softmax::<half::f16>(slice, layout),
-            CpuStorage::F32(slice) => softmax::<f32>(slice, layout),
-            CpuStorage::F64(slice) => softmax::<f64>(slice, layout),
-            _ => crate::bail!("unsupported dtype for softmax {:?}", storage),
+        use crate::core::backend::BackendStorage;
+        use CpuStorage as C;
+        match (s1, s2) {
+            (C::BF16(s1), C::BF16(s2)) => inner::<half::bf16>(s1, l1, s2, l2),
+            (C::F16(s1), C::F16(s2)) => inner::<half::f16>(s1, l1, s2, l2),
+            (C::F32(s1), C::F32(s2)) => inner::<f32>(s1, l1, s2, l2),
+            (C::F64(s1), C::F64(s2)) => inner::<f64>(s1, l1, s2, l2),
+            _ => crate::bail!("unsupported dtype for silu_mul {:?}", s1.dtype()),
         }
     }
 
     #[cfg(feature = "cuda")]
     fn cuda_fwd(
         &self,
-        storage: &crate::core::CudaStorage,
-        layout: &Layout,
+        s1: &crate::core::CudaStorage,
+        l1: &Layout,
+        s2: &crate::core::CudaStorage,
+        l2: &Layout,
     ) -> Result<(crate::core::CudaStorage, Shape)> {
         use crate::core::cuda_backend::cudarc::driver::{
-            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig, ValidAsZeroBits,
         };
-        use crate::core::cuda_backend::{kernel_name, kernels, Map1, WrapErr};
+        use crate::core::cuda_backend::{kernel_name, kernels, Map2, WrapErr};
         use crate::core::{CudaDevice, WithDType};
 
         struct S;
-        impl Map1 for S {
-            fn f<T: DeviceRepr + WithDType>(
+        impl Map2 for S {
+            fn f<T: DeviceRepr + WithDType + ValidAsZeroBits>(
                 &self,
-                src: &CudaSlice<T>,
+                gate: &CudaSlice<T>,
+                l_gate: &Layout,
+                up: &CudaSlice<T>,
+                l_up: &Layout,
                 dev: &CudaDevice,
-                layout: &Layout,
             ) -> Result<CudaSlice<T>> {
-                let src = match layout.contiguous_offsets() {
-                    None => crate::bail!("input has to be contiguous"),
-                    Some((o1, o2)) => src.slice(o1..o2),
-                };
-                let el = layout.shape().elem_count();
-                let dims = layout.shape().dims();
-                let dim_m1 = dims[dims.len() - 1];
-                let (n_rows, n_cols) = (el / dim_m1, dim_m1);
-
-                let cfg = LaunchConfig {
-                    grid_dim: (n_rows as u32, 1, 1),
-                    block_dim: (1, 32, 1),
-                    shared_mem_bytes: 0,
-                };
-                let func = dev.get_or_load_func(&kernel_name::<T>("softmax"), kernels::REDUCE)?;
+                let shape = l_gate.shape();
+                let dims = shape.dims();
+                let el = shape.elem_count();
+                let cfg = LaunchConfig::for_num_elems(el as u32);
+                let ds = dev
+                    .htod_copy([dims, l_gate.stride(), l_up.stride()].concat())
+                    .w()?;
+                let gate = &gate.slice(l_gate.start_offset()..);
+                let up = &up.slice(l_up.start_offset()..);
+                let func = dev.get_or_load_func(&kernel_name::<T>("silu_mul"), kernels::TERNARY)?;
                 // SAFETY: Set later by running the kernel.
-                let dst = unsafe { dev.alloc::<T>(el) }.w()?;
-                let params = (&src, &dst, n_cols as i32);
+                let out = unsafe { dev.alloc::<T>(el) }.w()?;
+                let params = (el, dims.len(), &ds, gate, up, &out);
                 // SAFETY: ffi.
                 unsafe { func.launch(cfg, params) }.w()?;
-                Ok(dst)
+                Ok(out)
             }
         }
 
         use crate::core::backend::BackendStorage;
-        let dev = storage.device();
-        let slice = S.map(&storage.slice, dev, layout)?;
+        let dev = s1.device();
+        let slice = S.map(&s1.slice, l1, &s2.slice, l2, dev)?;
         let dst = crate::core::cuda_backend::CudaStorage {
             slice,
             device: dev.clone(),
         };
-        Ok((dst, layout.shape().clone()))
+        Ok((dst, l1.shape().clone()))
     }
 
     #[cfg(feature = "metal")]
     fn metal_fwd(
         &self,
-        storage: &crate::core::MetalStorage,
-        layout: &Layout,
+        s1: &crate::core::MetalStorage,
+        l1: &Layout,
+        s2: &crate::core::MetalStorage,
+        l2: &Layout,
     ) -> Result<(crate::core::MetalStorage, Shape)> {
         use crate::core::backend::BackendStorage;
-        let device = storage.device();
+        use crate::core::metal_backend::buffer_o;
+        let device = s1.device();
         let command_buffer = device.command_buffer()?;
         let kernels = device.kernels();
-        let name = match storage.dtype() {
-            DType::F32 => "softmax_f32",
-            DType::F16 => "softmax_f16",
-            DType::BF16 => "softmax_bf16",
-            dtype => crate::bail!("softmax-last-dim is not implemented for {dtype:?}"),
+        let name = match (s1.dtype(), s2.dtype()) {
+            (DType::F32, DType::F32) => "silu_mul_f32",
+            (DType::F16, DType::F16) => "silu_mul_f16",
+            (DType::BF16, DType::BF16) => "silu_mul_bf16",
+            (dt1, dt2) => {
+                crate::bail!("silu_mul is not implemented for {dt1:?} {dt2:?}")
+            }
         };
 
-        let n = layout.stride().len();
-        if !(layout.is_contiguous() && layout.stride()[n - 1] == 1) {
-            crate::bail!("Non contiguous softmax-last-dim is not implemented");
-        }
-
-        let last_dim = layout.dims()[layout.shape().rank() - 1];
-        let elem_count = layout.shape().elem_count();
-        let output = device.new_buffer(elem_count, storage.dtype(), "softmax")?;
-        crate::metal_kernels::call_last_softmax(
+        let elem_count = l1.shape().elem_count();
+        let output = device.new_buffer(elem_count, s1.dtype(), "silu_mul")?;
+        crate::metal_kernels::call_silu_mul_strided(
             device.metal_device(),
             &command_buffer,
             kernels,
             name,
-            elem_count,
-            last_dim,
-            storage.buffer(),
-            layout.start_offset() * storage.dtype().size_in_bytes(),
+            l1.dims(),
+            buffer_o(s1.buffer(), l1, s1.dtype()),
+            l1.stride(),
+            buffer_o(s2.buffer(), l2, s2.dtype()),
+            l2.stride(),
             &output,
-            0,
         )
         .map_err(crate::core::Error::wrap)?;
         let newstorage =
-            crate::core::MetalStorage::new(output, device.clone(), elem_count, storage.dtype());
-        Ok((newstorage, layout.shape().clone()))
-    }
+            crate::core::MetalStorage::new(output, device.clone(), elem_count, s1.dtype());
+        Ok((newstorage, l1.shape().clone()))
+    }
+
+    fn bwd(
+        &self,
+        gate: &Tensor,
+        up: &Tensor,
+        _res: &Tensor,
+        grad_res: &Tensor,
+    ) -> Result<(Option<Tensor>, Option<Tensor>)> {
+        // With s = sigmoid(g): d/dg = u * s * (1 + g * (1 - s)), d/du = g * s
+        let s = sigmoid(gate)?;
+        let silu = (gate * &s)?;
+        let dsilu = (&s * (gate * s.affine(-1., 1.)?)?.affine(1., 1.)?)?;
+        let grad_gate = (grad_res * up)?.mul(&dsilu)?;
+        let grad_up = grad_res.mul(&silu)?;
+        Ok((Some(grad_gate), Some(grad_up)))
+    }
 }
 
-pub fn softmax_last_dim(xs: &Tensor) -> Result<Tensor> {
-    xs.apply_op1_no_bwd(&SoftmaxLastDim)
+/// Computes `silu(gate) * up` in a single kernel pass, without materializing `silu(gate)`.
+///
+/// This is the fused form of the SwiGLU gating for models that produce `gate` and `up` with
+/// separate projections; use [`swiglu`] when both halves live in a single tensor. `gate` and `up`
+/// must have the same dtype and broadcast-compatible shapes.
+///
+/// ```rust
+/// use diffusion_rs_common::core::{Tensor, Device};
+/// let gate = Tensor::new(&[0f32, 1., -1.], &Device::Cpu)?;
+/// let up = Tensor::new(&[2f32, 2., 2.], &Device::Cpu)?;
+/// let ys = diffusion_rs_common::nn::ops::silu_mul(&gate, &up)?;
+/// let expected = (gate.silu()? * &up)?;
+/// assert_eq!(ys.to_vec1::<f32>()?, expected.to_vec1::<f32>()?);
+/// # Ok::<(), diffusion_rs_common::core::Error>(())
+/// ```
+pub fn silu_mul(gate: &Tensor, up: &Tensor) -> Result<Tensor> {
+    if gate.dtype() != up.dtype() {
+        crate::bail!(
+            "silu_mul expects gate and up to share a dtype, got {:?} and {:?}",
+            gate.dtype(),
+            up.dtype()
+        )
+    }
+    if gate.shape() == up.shape() {
+        return gate.apply_op2(up, SiluMul);
+    }
+    let shape = gate
+        .shape()
+        .broadcast_shape_binary_op(up.shape(), "silu_mul")?;
+    let gate = gate.broadcast_as(&shape)?;
+    let up = up.broadcast_as(&shape)?;
+    gate.apply_op2(&up, SiluMul)
 }
 
-pub fn inplace_softmax_last_dim(xs: &mut Tensor) -> Result<()> {
-    xs.inplace_op1(&SoftmaxLastDim)
+/// `silu(gate) * up` where `gate` and `up` are the two halves of a contiguous input along
+/// `split_dim`, computed in a single pass without narrowing the input.
+struct SwigluFused {
+    split_dim: usize,
 }
 
-// TODO: need cpu and cuda impls
-#[allow(dead_code)]
-struct AttnSoftmaxLastDim {
-    scale: f32,
+impl SwigluFused {
+    /// The distance between a `gate` element and its `up` element, i.e. half a row of the input
+    /// viewed as `(outer, 2 * split)`.
+    fn split(&self, shape: &Shape) -> usize {
+        let dims = shape.dims();
+        dims[self.split_dim] / 2 * dims[self.split_dim + 1..].iter().product::<usize>()
+    }
+
+    fn out_shape(&self, shape: &Shape) -> Shape {
+        let mut dims = shape.dims().to_vec();
+        dims[self.split_dim] /= 2;
+        Shape::from(dims)
+    }
 }
 
-impl crate::core::InplaceOp2 for AttnSoftmaxLastDim {
+impl crate::core::CustomOp1 for SwigluFused {
     fn name(&self) -> &'static str {
-        "attn-softmax-last-dim"
+        "swiglu-fused"
     }
 
-    fn cpu_fwd(
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        fn inner<T: crate::core::WithDType>(
+            src: &[T],
+            layout: &Layout,
+            split: usize,
+            out_shape: Shape,
+        ) -> Result<(CpuStorage, Shape)> {
+            let src = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &src[o1..o2],
+            };
+            let mut dst = vec![T::zero(); out_shape.elem_count()];
+            src.par_chunks(2 * split)
+                .zip(dst.par_chunks_mut(split))
+                .for_each(|(src, dst)| {
+                    let (gate, up) = src.split_at(split);
+                    for ((d, g), u) in dst.iter_mut().zip(gate).zip(up) {
+                        let g = g.to_f64();
+                        *d = T::from_f64(g / (1. + (-g).exp()) * u.to_f64())
+                    }
+                });
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, out_shape))
+        }
+
+        let split = self.split(layout.shape());
+        let out_shape = self.out_shape(layout.shape());
+        match storage {
+            CpuStorage::BF16(s) => inner::<half::bf16>(s, layout, split, out_shape),
+            CpuStorage::F16(s) => inner::<half::f16>(s, layout, split, out_shape),
+            CpuStorage::F32(s) => inner::<f32>(s, layout, split, out_shape),
+            CpuStorage::F64(s) => inner::<f64>(s, layout, split, out_shape),
+            _ => crate::bail!("unsupported dtype for swiglu {:?}", storage),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
         &self,
-        _a_s: &mut CpuStorage,
-        _a_l: &Layout,
-        _mask_s: &CpuStorage,
-        _mask_l: &Layout,
-    ) -> Result<()> {
-        crate::bail!("cpu attn-softmax-last-dim is not implemented");
+        storage: &crate::core::CudaStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::CudaStorage, Shape)> {
+        use crate::core::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use crate::core::cuda_backend::{kernel_name, kernels, Map1, WrapErr};
+        use crate::core::{CudaDevice, WithDType};
+
+        struct S {
+            split: usize,
+        }
+        impl Map1 for S {
+            fn f<T: DeviceRepr + WithDType>(
+                &self,
+                src: &CudaSlice<T>,
+                dev: &CudaDevice,
+                layout: &Layout,
+            ) -> Result<CudaSlice<T>> {
+                let src = match layout.contiguous_offsets() {
+                    None => crate::bail!("input has to be contiguous"),
+                    Some((o1, o2)) => src.slice(o1..o2),
+                };
+                let el = layout.shape().elem_count() / 2;
+                let cfg = LaunchConfig::for_num_elems(el as u32);
+                let func = dev.get_or_load_func(&kernel_name::<T>("swiglu"), kernels::TERNARY)?;
+                // SAFETY: Set later by running the kernel.
+                let out = unsafe { dev.alloc::<T>(el) }.w()?;
+                let params = (el, self.split, &src, &out);
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                Ok(out)
+            }
+        }
+
+        use crate::core::backend::BackendStorage;
+        let dev = storage.device();
+        let split = self.split(layout.shape());
+        let slice = S { split }.map(&storage.slice, dev, layout)?;
+        let dst = crate::core::cuda_backend::CudaStorage {
+            slice,
+            device: dev.clone(),
+        };
+        Ok((dst, self.out_shape(layout.shape())))
     }
 
     #[cfg(feature = "metal")]
     fn metal_fwd(
         &self,
-        a_s: &mut crate::core::MetalStorage,
-        a_l: &Layout,
-        mask_s: &crate::core::MetalStorage,
-        mask_l: &Layout,
-    ) -> Result<()> {
+        storage: &crate::core::MetalStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::MetalStorage, Shape)> {
         use crate::core::backend::BackendStorage;
-        let device = a_s.device();
+        use crate::core::metal_backend::buffer_o;
+        let device = storage.device();
         let command_buffer = device.command_buffer()?;
         let kernels = device.kernels();
-
-        let ty = match a_s.dtype() {
-            DType::F32 => crate::metal_kernels::SdpaDType::F32,
-            DType::F16 => crate::metal_kernels::SdpaDType::F16,
-            DType::BF16 => crate::metal_kernels::SdpaDType::BF16,
-            dtype => crate::bail!("attn-softmax-last-dim is not implemented for {dtype:?}"),
+        let name = match storage.dtype() {
+            DType::F32 => "swiglu_f32",
+            DType::F16 => "swiglu_f16",
+            DType::BF16 => "swiglu_bf16",
+            dtype => crate::bail!("swiglu is not implemented for {dtype:?}"),
         };
-
-        if !a_l.is_contiguous() {
-            crate::bail!("Non contiguous xs for attn-softmax-last-dim is not implemented");
-        }
-        if !mask_l.is_contiguous() {
-            crate::bail!("Non contiguous mask for attn-softmax-last-dim is not implemented");
-        }
-
-        if a_l.dims().len() != 4 {
-            crate::bail!("attn-softmax-last-dim expects xs of rank 2");
-        }
-        if mask_l.dims().len() != 2 {
-            crate::bail!("attn-softmax-last-dim expects mask of rank 2");
-        }
-        if mask_l.dim(D::Minus1)? != a_l.dim(D::Minus1)?
-            || mask_l.dim(D::Minus2)? != a_l.dim(D::Minus2)?
-        {
-            crate::bail!("attn-softmax-last-dim expects last 2 dims to match xs last 2 dims");
+        if !layout.is_contiguous() {
+            crate::bail!("Non contiguous swiglu is not implemented");
         }
 
-        crate::metal_kernels::call_last_attn_softmax(
+        let out_shape = self.out_shape(layout.shape());
+        let elem_count = out_shape.elem_count();
+        let output = device.new_buffer(elem_count, storage.dtype(), "swiglu")?;
+        crate::metal_kernels::call_swiglu(
             device.metal_device(),
             &command_buffer,
             kernels,
-            a_s.buffer(),
-            a_l.start_offset() * a_s.dtype().size_in_bytes(),
-            mask_s.buffer(),
-            mask_l.start_offset() * mask_s.dtype().size_in_bytes(),
-            a_l.dims(),
-            self.scale,
-            ty,
-            a_s.buffer(),
-            0,
+            name,
+            elem_count,
+            self.split(layout.shape()),
+            buffer_o(storage.buffer(), layout, storage.dtype()),
+            &output,
         )
         .map_err(crate::core::Error::wrap)?;
+        let newstorage =
+            crate::core::MetalStorage::new(output, device.clone(), elem_count, storage.dtype());
+        Ok((newstorage, out_shape))
+    }
 
-        Ok(())
+    fn bwd(&self, arg: &Tensor, _res: &Tensor, grad_res: &Tensor) -> Result<Option<Tensor>> {
+        // Same derivatives as `SiluMul`, concatenated back along the split dim.
+        let (gate, up) = glu_halves(arg, self.split_dim, "swiglu")?;
+        let s = sigmoid(&gate)?;
+        let silu = (&gate * &s)?;
+        let dsilu = (&s * (&gate * s.affine(-1., 1.)?)?.affine(1., 1.)?)?;
+        let grad_gate = (grad_res * up)?.mul(&dsilu)?;
+        let grad_up = grad_res.mul(&silu)?;
+        Ok(Some(Tensor::cat(&[grad_gate, grad_up], self.split_dim)?))
     }
 }
 
-impl crate::core::CustomOp2 for AttnSoftmaxLastDim {
+struct Rsqrt;
+
+impl UnaryFloatFn for Rsqrt {
+    fn call<T: num_traits::Float>(&self, v: T) -> T {
+        v.sqrt().recip()
+    }
+}
+
+impl crate::core::CustomOp1 for Rsqrt {
     fn name(&self) -> &'static str {
-        "attn-softmax-last-dim"
+        "rsqrt"
     }
 
-    fn cpu_fwd(
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        cpu_unary_fwd(self.name(), storage, layout, Rsqrt)
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
         &self,
-        _a_s: &CpuStorage,
-        _a_l: &Layout,
-        _mask_s: &CpuStorage,
-        _mask_l: &Layout,
-    ) -> Result<(CpuStorage, Shape)> {
-        crate::bail!("cpu attn-softmax-last-dim is not implemented");
+        storage: &crate::core::CudaStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::CudaStorage, Shape)> {
+        cuda_unary_fwd("ursqrt", storage, layout)
     }
 
     #[cfg(feature = "metal")]
     fn metal_fwd(
         &self,
-        a_s: &crate::core::MetalStorage,
-        a_l: &Layout,
-        mask_s: &crate::core::MetalStorage,
-        mask_l: &Layout,
+        storage: &crate::core::MetalStorage,
+        layout: &Layout,
     ) -> Result<(crate::core::MetalStorage, Shape)> {
-        use crate::core::backend::BackendStorage;
-        let device = a_s.device();
-        let command_buffer = device.command_buffer()?;
-        let kernels = device.kernels();
+        metal_unary_fwd!(rsqrt, storage, layout)
+    }
 
-        let ty = match a_s.dtype() {
-            DType::F32 => crate::metal_kernels::SdpaDType::F32,
-            DType::F16 => crate::metal_kernels::SdpaDType::F16,
-            DType::BF16 => crate::metal_kernels::SdpaDType::BF16,
-            dtype => crate::bail!("attn-softmax-last-dim is not implemented for {dtype:?}"),
-        };
+    fn bwd(&self, _arg: &Tensor, res: &Tensor, grad_res: &Tensor) -> Result<Option<Tensor>> {
+        // d/dx x^(-1/2) = -1/2 * x^(-3/2)
+        let d_dx_rsqrt = (res.powf(3.)? * -0.5)?;
+        Ok(Some(grad_res.mul(&d_dx_rsqrt)?))
+    }
+}
 
-        if !a_l.is_contiguous() {
-            crate::bail!("Non contiguous xs for attn-softmax-last-dim is not implemented");
-        }
-        if !mask_l.is_contiguous() {
-            crate::bail!("Non contiguous mask for attn-softmax-last-dim is not implemented");
-        }
+/// Computes `1 / sqrt(xs)` elementwise in a single kernel.
+///
+/// Follows IEEE semantics: zero inputs give `inf` and negative inputs give `NaN`.
+pub fn rsqrt(xs: &Tensor) -> Result<Tensor> {
+    xs.apply_op1(Rsqrt)
+}
 
-        if a_l.dims().len() != 4 {
-            crate::bail!("attn-softmax-last-dim expects xs of rank 2");
-        }
-        if mask_l.dims().len() != 2 {
-            crate::bail!("attn-softmax-last-dim expects mask of rank 2");
-        }
-        if mask_l.dim(D::Minus1)? != a_l.dim(D::Minus1)?
-            || mask_l.dim(D::Minus2)? != a_l.dim(D::Minus2)?
-        {
-            crate::bail!("attn-softmax-last-dim expects last 2 dims to match xs last 2 dims");
-        }
+struct Reciprocal;
 
-        let elem_count = a_l.shape().elem_count();
-        let output = device.new_buffer(elem_count, a_s.dtype(), "attn-softmax")?;
-        crate::metal_kernels::call_last_attn_softmax(
-            device.metal_device(),
-            &command_buffer,
-            kernels,
-            a_s.buffer(),
-            a_l.start_offset() * a_s.dtype().size_in_bytes(),
-            mask_s.buffer(),
-            mask_l.start_offset() * mask_s.dtype().size_in_bytes(),
-            a_l.dims(),
-            self.scale,
-            ty,
-            &output,
-            a_l.start_offset() * a_s.dtype().size_in_bytes(),
-        )
-        .map_err(crate::core::Error::wrap)?;
-        let newstorage =
-            crate::core::MetalStorage::new(output, device.clone(), elem_count, a_s.dtype());
-        Ok((newstorage, a_l.shape().clone()))
+impl UnaryFloatFn for Reciprocal {
+    fn call<T: num_traits::Float>(&self, v: T) -> T {
+        v.recip()
     }
 }
 
-/// Softmax with fused broadcast addition of a mask and scale.
-/// Equivalent to:
-/// ```ignore
-/// diffusion_rs_common::nn::ops::softmax_last_dim(&(xs.broadcast_add(&mask)? * scale as f64)?)?
-/// ```
-/// - `xs` must be a rank-4 tensor
-/// - `mask` must be a rank-2 matrix
-/// - The last 2 dimensions of `xs` must match the dimensions of `mask`.
-///
-/// Note: if the last dim of `xs` is a multiple of 4, a vectorized implementation will be used.
-pub fn attn_softmax_last_dim(xs: &Tensor, mask: &Tensor, scale: f32) -> Result<Tensor> {
-    if xs.device().is_metal() {
-        xs.apply_op2_no_bwd(mask, &AttnSoftmaxLastDim { scale })
-    } else {
-        softmax_last_dim(&(xs.broadcast_add(mask)? * scale as f64)?)
+impl crate::core::CustomOp1 for Reciprocal {
+    fn name(&self) -> &'static str {
+        "reciprocal"
+    }
+
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        cpu_unary_fwd(self.name(), storage, layout, Reciprocal)
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        storage: &crate::core::CudaStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::CudaStorage, Shape)> {
+        cuda_unary_fwd("urecip", storage, layout)
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        storage: &crate::core::MetalStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::MetalStorage, Shape)> {
+        metal_unary_fwd!(recip, storage, layout)
+    }
+
+    fn bwd(&self, _arg: &Tensor, res: &Tensor, grad_res: &Tensor) -> Result<Option<Tensor>> {
+        // d/dx 1/x = -1/x^2
+        let d_dx_recip = res.sqr()?.neg()?;
+        Ok(Some(grad_res.mul(&d_dx_recip)?))
     }
 }
 
-/// Inplace equivalent of `attn_softmax_last_dim`
-pub fn inplace_attn_softmax_last_dim(xs: &mut Tensor, mask: &Tensor, scale: f32) -> Result<()> {
-    if xs.device().is_metal() {
-        xs.inplace_op2(mask, &AttnSoftmaxLastDim { scale })?;
-    } else {
-        *xs = softmax_last_dim(&(xs.broadcast_add(mask)? * scale as f64)?)?;
+/// Computes `1 / xs` elementwise. Zero inputs give `inf` with the sign of the zero.
+pub fn reciprocal(xs: &Tensor) -> Result<Tensor> {
+    xs.apply_op1(Reciprocal)
+}
+
+struct Tanh;
+
+impl UnaryFloatFn for Tanh {
+    fn call<T: num_traits::Float>(&self, v: T) -> T {
+        v.tanh()
     }
-    Ok(())
 }
 
+impl crate::core::CustomOp1 for Tanh {
+    fn name(&self) -> &'static str {
+        "tanh"
+    }
+
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        cpu_unary_fwd(self.name(), storage, layout, Tanh)
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        storage: &crate::core::CudaStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::CudaStorage, Shape)> {
+        cuda_unary_fwd("utanh", storage, layout)
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        storage: &crate::core::MetalStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::MetalStorage, Shape)> {
+        metal_unary_fwd!(tanh, storage, layout)
+    }
+
+    fn bwd(&self, _arg: &Tensor, res: &Tensor, grad_res: &Tensor) -> Result<Option<Tensor>> {
+        // d/dx tanh(x) = 1 - tanh(x)^2
+        let d_dx_tanh = res.sqr()?.affine(-1., 1.)?;
+        Ok(Some(grad_res.mul(&d_dx_tanh)?))
+    }
+}
+
+impl crate::core::InplaceOp1 for Tanh {
+    fn name(&self) -> &'static str {
+        "tanh"
+    }
+
+    fn cpu_fwd(&self, storage: &mut CpuStorage, layout: &Layout) -> Result<()> {
+        fn inner<T: num_traits::Float + Send + Sync>(src: &mut [T], layout: &Layout) -> Result<()> {
+            let src = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &mut src[o1..o2],
+            };
+            src.par_iter_mut().for_each(|v| *v = v.tanh());
+            Ok(())
+        }
+
+        match storage {
+            CpuStorage::BF16(slice) => inner::<half::bf16>(slice, layout),
+            CpuStorage::F16(slice) => inner::<half::f16>(slice, layout),
+            CpuStorage::F32(slice) => inner::<f32>(slice, layout),
+            CpuStorage::F64(slice) => inner::<f64>(slice, layout),
+            _ => crate::bail!("unsupported dtype for tanh {:?}", storage),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(&self, storage: &mut crate::core::CudaStorage, layout: &Layout) -> Result<()> {
+        use crate::core::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use crate::core::cuda_backend::{kernel_name, kernels, Map1InPlace, WrapErr};
+        use crate::core::{CudaDevice, WithDType};
+
+        struct S;
+        impl Map1InPlace for S {
+            fn f<T: DeviceRepr + WithDType>(
+                &self,
+                src: &mut CudaSlice<T>,
+                dev: &CudaDevice,
+                layout: &Layout,
+            ) -> Result<()> {
+                let mut src = match layout.contiguous_offsets() {
+                    None => crate::bail!("input has to be contiguous"),
+                    Some((o1, o2)) => src.slice_mut(o1..o2),
+                };
+                let el = layout.shape().elem_count();
+                let cfg = LaunchConfig::for_num_elems(el as u32);
+                let func = dev.get_or_load_func(&kernel_name::<T>("utanh"), kernels::UNARY)?;
+                // A null input makes the kernel read from the output, the layout info is not
+                // needed either for a contiguous slice.
+                let params = (el, 0usize, 0u64, 0u64, &mut src);
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                Ok(())
+            }
+        }
+
+        use crate::core::backend::BackendStorage;
+        let dev = storage.device().clone();
+        S.map(&mut storage.slice, &dev, layout)
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(&self, storage: &mut crate::core::MetalStorage, layout: &Layout) -> Result<()> {
+        use crate::core::backend::BackendStorage;
+        use crate::metal_kernels::unary::strided;
+        let device = storage.device();
+        let command_buffer = device.command_buffer()?;
+        let kernel_name = match storage.dtype() {
+            DType::F32 => strided::tanh::FLOAT,
+            DType::F16 => strided::tanh::HALF,
+            DType::BF16 => strided::tanh::BFLOAT,
+            dtype => crate::bail!("tanh is not implemented for {dtype:?}"),
+        };
+        if !layout.is_contiguous() {
+            crate::bail!("Non contiguous inplace tanh is not implemented");
+        }
+        // Every element is read and written by the same thread, so the output can alias the input.
+        let offset_in_bytes = layout.start_offset() * storage.dtype().size_in_bytes();
+        let src = crate::metal_kernels::BufferOffset {
+            buffer: storage.buffer(),
+            offset_in_bytes,
+        };
+        let dst = crate::metal_kernels::BufferOffset {
+            buffer: storage.buffer(),
+            offset_in_bytes,
+        };
+        crate::metal_kernels::call_unary_strided(
+            device.metal_device(),
+            &command_buffer,
+            device.kernels(),
+            kernel_name,
+            layout.dims(),
+            src,
+            layout.stride(),
+            dst,
+        )
+        .map_err(crate::core::Error::wrap)?;
+        Ok(())
+    }
+}
+
+/// Hyperbolic tangent as a single elementwise kernel, the gradient is computed from the output
+/// as `1 - tanh(x)^2`.
+pub fn tanh(xs: &Tensor) -> Result<Tensor> {
+    xs.apply_op1(Tanh)
+}
+
+/// Applies `tanh` to `xs` in place.
+///
+/// `xs` must be a contiguous floating point tensor. As with the other in-place ops, every tensor
+/// sharing the storage of `xs` observes the update and no gradient is tracked.
+pub fn tanh_(xs: &mut Tensor) -> Result<()> {
+    if !xs.dtype().is_float() {
+        crate::bail!(
+            "tanh_ expects a floating point tensor, got {:?}",
+            xs.dtype()
+        )
+    }
+    if !xs.is_contiguous() {
+        crate::bail!("tanh_ expects a contiguous tensor")
+    }
+    xs.inplace_op1(&Tanh)
+}
+
+/// GELU with the tanh approximation of the normal CDF.
+struct Gelu;
+
+impl UnaryFloatFn for Gelu {
+    fn call<T: num_traits::Float>(&self, v: T) -> T {
+        let x = v.to_f64().unwrap_or(f64::NAN);
+        let inner = (2. / std::f64::consts::PI).sqrt() * (x + 0.044715 * x * x * x);
+        T::from(0.5 * x * (1. + inner.tanh())).unwrap_or_else(T::nan)
+    }
+}
+
+impl crate::core::CustomOp1 for Gelu {
+    fn name(&self) -> &'static str {
+        "gelu"
+    }
+
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        cpu_unary_fwd(self.name(), storage, layout, Gelu)
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        storage: &crate::core::CudaStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::CudaStorage, Shape)> {
+        cuda_unary_fwd("ugelu", storage, layout)
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        storage: &crate::core::MetalStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::MetalStorage, Shape)> {
+        metal_unary_fwd!(gelu, storage, layout)
+    }
+
+    fn bwd(&self, arg: &Tensor, _res: &Tensor, grad_res: &Tensor) -> Result<Option<Tensor>> {
+        let cube = arg.powf(3.)?;
+        let tanh = (0.0356774 * &cube + (0.797885 * arg)?)?.tanh()?;
+        let d_dx_gelu = (((0.5 * &tanh)?
+            + (0.0535161 * cube + (0.398942 * arg)?)? * (1. - tanh.powf(2.)?))?
+            + 0.5)?;
+        Ok(Some(grad_res.mul(&d_dx_gelu)?))
+    }
+}
+
+/// Exact GELU `x * Φ(x)`, `Φ` being the standard normal CDF.
+struct GeluErf;
+
+impl UnaryFloatFn for GeluErf {
+    fn call<T: num_traits::Float>(&self, v: T) -> T {
+        let x = v.to_f64().unwrap_or(f64::NAN);
+        let y = 0.5 * x * (1. + crate::core::cpu::erf::erf(x * std::f64::consts::FRAC_1_SQRT_2));
+        T::from(y).unwrap_or_else(T::nan)
+    }
+}
+
+impl crate::core::CustomOp1 for GeluErf {
+    fn name(&self) -> &'static str {
+        "gelu-erf"
+    }
+
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        cpu_unary_fwd(self.name(), storage, layout, GeluErf)
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        storage: &crate::core::CudaStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::CudaStorage, Shape)> {
+        cuda_unary_fwd("ugelu_erf", storage, layout)
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        storage: &crate::core::MetalStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::MetalStorage, Shape)> {
+        metal_unary_fwd!(gelu_erf, storage, layout)
+    }
+
+    fn bwd(&self, arg: &Tensor, _res: &Tensor, grad_res: &Tensor) -> Result<Option<Tensor>> {
+        // d/dx x * Φ(x) = Φ(x) + x * φ(x)
+        let cdf = (((arg / 2f64.sqrt())?.erf()? * 0.5)? + 0.5)?;
+        let pdf = ((arg.sqr()? * -0.5)?.exp()? * (2. * std::f64::consts::PI).sqrt().recip())?;
+        let d_dx_gelu = (cdf + (pdf * arg)?)?;
+        Ok(Some(grad_res.mul(&d_dx_gelu)?))
+    }
+}
+
+/// GELU using the tanh approximation
+/// `0.5 * x * (1 + tanh(sqrt(2/π) * (x + 0.044715 * x³)))`.
+pub fn gelu(xs: &Tensor) -> Result<Tensor> {
+    xs.apply_op1(Gelu)
+}
+
+/// The tanh approximated GELU under its Hugging Face name, same as `gelu`.
+pub fn gelu_new(xs: &Tensor) -> Result<Tensor> {
+    xs.apply_op1(Gelu)
+}
+
+/// Exact GELU `x * Φ(x)` computed through the error function.
+pub fn gelu_erf(xs: &Tensor) -> Result<Tensor> {
+    xs.apply_op1(GeluErf)
+}
+
+/// The sigmoid approximation of GELU `x * sigmoid(1.702 * x)` used by CLIP text encoders.
+pub fn quick_gelu(xs: &Tensor) -> Result<Tensor> {
+    xs * sigmoid(&(xs * 1.702f64)?)?
+}
+
+/// How `gelu_with_approx` evaluates the normal CDF of GELU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GeluApproximation {
+    /// Exact GELU, see `gelu_erf`.
+    #[default]
+    None,
+    /// Tanh approximation, see `gelu`.
+    Tanh,
+    /// Sigmoid approximation, see `quick_gelu`.
+    Quick,
+}
+
+/// GELU with the approximation picked at runtime, e.g. from a model config.
+pub fn gelu_with_approx(xs: &Tensor, approx: GeluApproximation) -> Result<Tensor> {
+    match approx {
+        GeluApproximation::None => gelu_erf(xs),
+        GeluApproximation::Tanh => gelu(xs),
+        GeluApproximation::Quick => quick_gelu(xs),
+    }
+}
+
+/// Mish `x * tanh(softplus(x))`.
+struct Mish;
+
+impl UnaryFloatFn for Mish {
+    fn call<T: num_traits::Float>(&self, v: T) -> T {
+        let x = v.to_f64().unwrap_or(f64::NAN);
+        // softplus(x) is x up to rounding past 20, this avoids overflowing exp.
+        let sp = if x > 20. { x } else { x.exp().ln_1p() };
+        T::from(x * sp.tanh()).unwrap_or_else(T::nan)
+    }
+}
+
+impl crate::core::CustomOp1 for Mish {
+    fn name(&self) -> &'static str {
+        "mish"
+    }
+
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        cpu_unary_fwd(self.name(), storage, layout, Mish)
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        storage: &crate::core::CudaStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::CudaStorage, Shape)> {
+        cuda_unary_fwd("umish", storage, layout)
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        storage: &crate::core::MetalStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::MetalStorage, Shape)> {
+        metal_unary_fwd!(mish, storage, layout)
+    }
+
+    fn bwd(&self, arg: &Tensor, _res: &Tensor, grad_res: &Tensor) -> Result<Option<Tensor>> {
+        // d/dx x * tanh(sp(x)) = tanh(sp(x)) + x * sech²(sp(x)) * sigmoid(x), with the stable
+        // softplus sp(x) = max(x, 0) + ln(1 + exp(-|x|)).
+        let sp = (arg.relu()? + (arg.abs()?.neg()?.exp()? + 1.)?.log()?)?;
+        let tanh = sp.tanh()?;
+        let sech2 = (1. - tanh.sqr()?)?;
+        let d_dx_mish = (&tanh + ((arg * sech2)? * sigmoid(arg)?)?)?;
+        Ok(Some(grad_res.mul(&d_dx_mish)?))
+    }
+}
+
+/// Mish `x * tanh(softplus(x))`, with a softplus that does not overflow for large inputs.
+pub fn mish(xs: &Tensor) -> Result<Tensor> {
+    xs.apply_op1(Mish)
+}
+
+/// `x^exponent` for a scalar exponent.
+#[derive(Clone, Copy)]
+struct Powf {
+    exponent: f64,
+}
+
+impl UnaryFloatFn for Powf {
+    fn call<T: num_traits::Float>(&self, v: T) -> T {
+        let x = v.to_f64().unwrap_or(f64::NAN);
+        T::from(x.powf(self.exponent)).unwrap_or_else(T::nan)
+    }
+}
+
+impl crate::core::CustomOp1 for Powf {
+    fn name(&self) -> &'static str {
+        "powf"
+    }
+
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        cpu_unary_fwd(self.name(), storage, layout, *self)
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        storage: &crate::core::CudaStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::CudaStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+        Ok((storage.powf(layout, self.exponent)?, layout.shape().clone()))
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        storage: &crate::core::MetalStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::MetalStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+        use crate::core::MetalError;
+        let device = storage.device();
+        let dtype = storage.dtype();
+        let el_count = layout.shape().elem_count();
+        let buffer = device.new_buffer(el_count, dtype, "signed-powf")?;
+        let command_buffer = device.command_buffer()?;
+        command_buffer.set_label("signed-powf");
+        let src = crate::metal_kernels::BufferOffset {
+            buffer: storage.buffer(),
+            offset_in_bytes: layout.start_offset() * dtype.size_in_bytes(),
+        };
+        if layout.is_contiguous() {
+            let name = match dtype {
+                DType::F32 => "signed_powf_f32",
+                DType::F16 => "signed_powf_f16",
+                DType::BF16 => "signed_powf_bf16",
+                dtype => crate::bail!("Metal contiguous powf {dtype:?} not implemented"),
+            };
+            crate::metal_kernels::call_powf(
+                device.metal_device(),
+                &command_buffer,
+                device.kernels(),
+                name,
+                el_count,
+                src,
+                &buffer,
+                self.exponent as f32,
+            )
+            .map_err(MetalError::from)?;
+        } else {
+            let name = match dtype {
+                DType::F32 => "signed_powf_f32_strided",
+                DType::F16 => "signed_powf_f16_strided",
+                DType::BF16 => "signed_powf_bf16_strided",
+                dtype => crate::bail!("Metal strided powf {dtype:?} not implemented"),
+            };
+            crate::metal_kernels::call_powf_strided(
+                device.metal_device(),
+                &command_buffer,
+                device.kernels(),
+                name,
+                layout.dims(),
+                src,
+                layout.stride(),
+                &buffer,
+                self.exponent as f32,
+            )
+            .map_err(MetalError::from)?;
+        }
+        let storage = crate::core::MetalStorage::new(buffer, device.clone(), el_count, dtype);
+        Ok((storage, layout.shape().clone()))
+    }
+
+    fn bwd(&self, arg: &Tensor, _res: &Tensor, grad_res: &Tensor) -> Result<Option<Tensor>> {
+        // Without this `0 * 0^-1` would give NaN gradients at zero.
+        if self.exponent == 0. {
+            return Ok(Some(grad_res.zeros_like()?));
+        }
+        let d_dx = (powf(arg, self.exponent - 1.)? * self.exponent)?;
+        Ok(Some(grad_res.mul(&d_dx)?))
+    }
+}
+
+/// Elementwise `x^exponent` with a gradient of `exponent * x^(exponent - 1)`.
+///
+/// Unlike `exp(exponent * ln(x))`, negative bases are supported for integer exponents, e.g.
+/// `(-2)^3 = -8`. Fractional exponents of negative bases give NaN.
+pub fn powf(xs: &Tensor, exponent: f64) -> Result<Tensor> {
+    if xs.elem_count() == 0 {
+        return Ok(xs.clone());
+    }
+    xs.apply_op1(Powf { exponent })
+}
+
+struct InplaceScale {
+    factor: f64,
+}
+
+impl crate::core::InplaceOp1 for InplaceScale {
+    fn name(&self) -> &'static str {
+        "inplace-scale"
+    }
+
+    fn cpu_fwd(&self, storage: &mut CpuStorage, layout: &Layout) -> Result<()> {
+        fn inner<T: crate::core::WithDType>(
+            src: &mut [T],
+            layout: &Layout,
+            factor: f64,
+        ) -> Result<()> {
+            let src = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &mut src[o1..o2],
+            };
+            let factor = T::from_f64(factor);
+            src.par_iter_mut().for_each(|v| *v = *v * factor);
+            Ok(())
+        }
+
+        match storage {
+            CpuStorage::BF16(slice) => inner::<half::bf16>(slice, layout, self.factor),
+            CpuStorage::F16(slice) => inner::<half::f16>(slice, layout, self.factor),
+            CpuStorage::F32(slice) => inner::<f32>(slice, layout, self.factor),
+            CpuStorage::F64(slice) => inner::<f64>(slice, layout, self.factor),
+            _ => crate::bail!("unsupported dtype for inplace-scale {:?}", storage),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(&self, storage: &mut crate::core::CudaStorage, layout: &Layout) -> Result<()> {
+        use crate::core::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use crate::core::cuda_backend::{kernel_name, kernels, Map1InPlace, WrapErr};
+        use crate::core::{CudaDevice, WithDType};
+
+        struct S {
+            factor: f64,
+        }
+        impl Map1InPlace for S {
+            fn f<T: DeviceRepr + WithDType>(
+                &self,
+                src: &mut CudaSlice<T>,
+                dev: &CudaDevice,
+                layout: &Layout,
+            ) -> Result<()> {
+                let mut src = match layout.contiguous_offsets() {
+                    None => crate::bail!("input has to be contiguous"),
+                    Some((o1, o2)) => src.slice_mut(o1..o2),
+                };
+                let el = layout.shape().elem_count();
+                let cfg = LaunchConfig::for_num_elems(el as u32);
+                let func = dev.get_or_load_func(&kernel_name::<T>("affine"), kernels::AFFINE)?;
+                // A null input makes the kernel read from the output, the layout info is not
+                // needed either for a contiguous slice.
+                let params = (
+                    el,
+                    0usize,
+                    0u64,
+                    0u64,
+                    &mut src,
+                    T::from_f64(self.factor),
+                    T::zero(),
+                );
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                Ok(())
+            }
+        }
+
+        use crate::core::backend::BackendStorage;
+        let dev = storage.device().clone();
+        S {
+            factor: self.factor,
+        }
+        .map(&mut storage.slice, &dev, layout)
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(&self, storage: &mut crate::core::MetalStorage, layout: &Layout) -> Result<()> {
+        use crate::core::backend::BackendStorage;
+        let device = storage.device();
+        let command_buffer = device.command_buffer()?;
+        let kernels = device.kernels();
+        let name = match storage.dtype() {
+            DType::F32 => "affine_f32",
+            DType::F16 => "affine_f16",
+            DType::BF16 => "affine_bf16",
+            dtype => crate::bail!("inplace-scale is not implemented for {dtype:?}"),
+        };
+        if !layout.is_contiguous() {
+            crate::bail!("Non contiguous inplace-scale is not implemented");
+        }
+        crate::metal_kernels::call_affine_inplace(
+            device.metal_device(),
+            &command_buffer,
+            kernels,
+            name,
+            layout.shape().elem_count(),
+            crate::metal_kernels::BufferOffset {
+                buffer: storage.buffer(),
+                offset_in_bytes: layout.start_offset() * storage.dtype().size_in_bytes(),
+            },
+            self.factor as f32,
+            0.,
+        )
+        .map_err(crate::core::Error::wrap)?;
+        Ok(())
+    }
+}
+
+/// Multiplies `xs` by `factor` in place, the in-place equivalent of `(xs * factor)?`, e.g. to
+/// rescale latents in a sampling loop without allocating a new tensor at every step.
+///
+/// `xs` must be a contiguous floating point tensor. As with the other in-place ops, every tensor
+/// sharing the storage of `xs` observes the update.
+pub fn inplace_scale(xs: &mut Tensor, factor: f64) -> Result<()> {
+    if !xs.dtype().is_float() {
+        crate::bail!(
+            "inplace_scale expects a floating point tensor, got {:?}",
+            xs.dtype()
+        )
+    }
+    if !xs.is_contiguous() {
+        crate::bail!("inplace_scale expects a contiguous tensor")
+    }
+    xs.inplace_op1(&InplaceScale { factor })
+}
+
+pub fn dropout(xs: &Tensor, drop_p: f32) -> Result<Tensor> {
+    // This implementation is inefficient as it stores the full mask for the backward pass.
+    // Instead we could just store the seed and have a specialized kernel that would both
+    // generate the random mask and apply it.
+    // Another easier optimization would be to be able to generate boolean mask using just a bit of
+    // entropy per element rather than generating a full float per element.
+    let mask = dropout_mask(xs, drop_p)?;
+    xs * mask
+}
+
+/// Same as `dropout` but also returns the mask, which holds `0` for the dropped elements and
+/// `1 / (1 - drop_p)` for the kept ones. The mask can be re-applied with `apply_dropout_mask`,
+/// e.g. when recomputing activations for gradient checkpointing.
+pub fn dropout_with_mask(xs: &Tensor, drop_p: f32) -> Result<(Tensor, Tensor)> {
+    let mask = dropout_mask(xs, drop_p)?;
+    let ys = xs.mul(&mask)?;
+    Ok((ys, mask))
+}
+
+/// Applies a mask returned by `dropout_with_mask` to `xs`, which must have the mask's shape.
+pub fn apply_dropout_mask(xs: &Tensor, mask: &Tensor) -> Result<Tensor> {
+    if mask.shape() != xs.shape() {
+        crate::bail!(
+            "shape mismatch in dropout mask, mask: {:?} xs: {:?}",
+            mask.shape(),
+            xs.shape()
+        )
+    }
+    xs.mul(&mask.to_dtype(xs.dtype())?)
+}
+
+/// Generates the scaled keep-mask used by `dropout` for a tensor shaped like `xs`.
+fn dropout_mask(xs: &Tensor, drop_p: f32) -> Result<Tensor> {
+    if !(0. ..1.).contains(&drop_p) {
+        crate::bail!("dropout probability has to be in [0, 1), got {drop_p}")
+    }
+    let rand = Tensor::rand(0f32, 1f32, xs.shape(), xs.device())?;
+    let scale = 1.0 / (1.0 - drop_p as f64);
+    let drop_p = Tensor::new(drop_p, xs.device())?.broadcast_as(xs.shape())?;
+    rand.ge(&drop_p)?.to_dtype(xs.dtype())? * scale
+}
+
+/// The `i`-th output of a splitmix64 generator seeded with `seed`.
+fn splitmix64(seed: u64, i: u64) -> u64 {
+    let mut z = seed.wrapping_add(i.wrapping_add(1).wrapping_mul(0x9E3779B97F4A7C15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// Generates the scaled keep-mask of `seeded_dropout` with the shape and dtype of its input, the
+/// input values are not read.
+struct SeededDropoutMask {
+    drop_p: f32,
+    seed: u64,
+}
+
+impl SeededDropoutMask {
+    fn scale(&self) -> f32 {
+        (1.0 / (1.0 - self.drop_p as f64)) as f32
+    }
+}
+
+impl crate::core::CustomOp1 for SeededDropoutMask {
+    fn name(&self) -> &'static str {
+        "seeded-dropout-mask"
+    }
+
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+
+        fn mask<T: crate::core::WithDType>(
+            layout: &Layout,
+            op: &SeededDropoutMask,
+        ) -> (CpuStorage, Shape) {
+            let (seed, drop_p) = (op.seed, op.drop_p);
+            let (keep, drop) = (T::from_f64(op.scale() as f64), T::zero());
+            let dst = (0..layout.shape().elem_count())
+                .into_par_iter()
+                .map(|i| {
+                    let u = (splitmix64(seed, i as u64) >> 40) as f32 / (1u32 << 24) as f32;
+                    if u >= drop_p {
+                        keep
+                    } else {
+                        drop
+                    }
+                })
+                .collect::<Vec<_>>();
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            (storage, layout.shape().clone())
+        }
+
+        match storage.dtype() {
+            DType::BF16 => Ok(mask::<half::bf16>(layout, self)),
+            DType::F16 => Ok(mask::<half::f16>(layout, self)),
+            DType::F32 => Ok(mask::<f32>(layout, self)),
+            DType::F64 => Ok(mask::<f64>(layout, self)),
+            dtype => crate::bail!("unsupported dtype for seeded-dropout {dtype:?}"),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        storage: &crate::core::CudaStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::CudaStorage, Shape)> {
+        use crate::core::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use crate::core::cuda_backend::{kernel_name, kernels, Map1, WrapErr};
+        use crate::core::{CudaDevice, WithDType};
+
+        struct S<'a>(&'a SeededDropoutMask);
+        impl Map1 for S<'_> {
+            fn f<T: DeviceRepr + WithDType>(
+                &self,
+                _src: &CudaSlice<T>,
+                dev: &CudaDevice,
+                layout: &Layout,
+            ) -> Result<CudaSlice<T>> {
+                let el = layout.shape().elem_count();
+                let cfg = LaunchConfig::for_num_elems(el as u32);
+                let func =
+                    dev.get_or_load_func(&kernel_name::<T>("dropout_mask"), kernels::FILL)?;
+                // SAFETY: Set later by running the kernel.
+                let dst = unsafe { dev.alloc::<T>(el) }.w()?;
+                let params = (&dst, self.0.seed, self.0.drop_p, self.0.scale(), el);
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                Ok(dst)
+            }
+        }
+
+        use crate::core::backend::BackendStorage;
+        let dev = storage.device();
+        let slice = S(self).map(&storage.slice, dev, layout)?;
+        let dst = crate::core::cuda_backend::CudaStorage {
+            slice,
+            device: dev.clone(),
+        };
+        Ok((dst, layout.shape().clone()))
+    }
+}
+
+/// Generates the scaled keep-mask used by `seeded_dropout` for a tensor shaped like `xs`.
+fn seeded_dropout_mask(xs: &Tensor, drop_p: f32, seed: u64) -> Result<Tensor> {
+    if !(0. ..1.).contains(&drop_p) {
+        crate::bail!("dropout probability has to be in [0, 1), got {drop_p}")
+    }
+    let op = SeededDropoutMask { drop_p, seed };
+    if xs.device().is_cpu() || xs.device().is_cuda() {
+        xs.apply_op1_no_bwd(&op)
+    } else {
+        Tensor::zeros(xs.shape(), xs.dtype(), &crate::core::Device::Cpu)?
+            .apply_op1_no_bwd(&op)?
+            .to_device(xs.device())
+    }
+}
+
+/// Dropout with a mask that only depends on `seed`, the shape of `xs` and `drop_p`, so that
+/// calls with the same seed drop the same elements on every device.
+///
+/// The element with row-major index `i` is kept, and scaled by `1 / (1 - drop_p)`, when
+/// `(splitmix64(seed, i) >> 40) as f32 / 2^24 >= drop_p`, where `splitmix64(seed, i)` is the
+/// `i`-th output of the reference splitmix64 generator seeded with `seed`, starting from `i = 0`.
+pub fn seeded_dropout(xs: &Tensor, drop_p: f32, seed: u64) -> Result<Tensor> {
+    let mask = seeded_dropout_mask(xs, drop_p, seed)?;
+    xs * mask
+}
+
+#[derive(Clone, Debug)]
+pub struct Dropout {
+    drop_p: f32,
+    recorded_mask: Option<std::sync::Arc<std::sync::Mutex<Option<Tensor>>>>,
+}
+
+impl Dropout {
+    pub fn new(drop_p: f32) -> Dropout {
+        Self {
+            drop_p,
+            recorded_mask: None,
+        }
+    }
+
+    /// Creates a dropout layer that keeps the mask of the last training forward pass so that
+    /// it can be re-applied with `replay`, e.g. when recomputing activations for gradient
+    /// checkpointing. Clones of this layer share the recorded mask.
+    pub fn with_recorded_mask(drop_p: f32) -> Dropout {
+        Self {
+            drop_p,
+            recorded_mask: Some(std::sync::Arc::new(std::sync::Mutex::new(None))),
+        }
+    }
+
+    pub fn forward(&self, xs: &Tensor, train: bool) -> Result<Tensor> {
+        if !train {
+            return Ok(xs.clone());
+        }
+        match &self.recorded_mask {
+            None => dropout(xs, self.drop_p),
+            Some(recorded_mask) => {
+                let (xs, mask) = dropout_with_mask(xs, self.drop_p)?;
+                *recorded_mask.lock().unwrap() = Some(mask);
+                Ok(xs)
+            }
+        }
+    }
+
+    /// Same as `forward` but the mask is generated from `seed`, see `seeded_dropout`.
+    pub fn forward_seeded(&self, xs: &Tensor, seed: u64, train: bool) -> Result<Tensor> {
+        if !train {
+            return Ok(xs.clone());
+        }
+        let mask = seeded_dropout_mask(xs, self.drop_p, seed)?;
+        let ys = xs.mul(&mask)?;
+        if let Some(recorded_mask) = &self.recorded_mask {
+            *recorded_mask.lock().unwrap() = Some(mask);
+        }
+        Ok(ys)
+    }
+
+    /// Applies the mask recorded by the last training `forward` call to `xs`.
+    pub fn replay(&self, xs: &Tensor) -> Result<Tensor> {
+        let recorded_mask = match &self.recorded_mask {
+            None => crate::bail!("dropout was not created with `with_recorded_mask`"),
+            Some(recorded_mask) => recorded_mask.lock().unwrap(),
+        };
+        match recorded_mask.as_ref() {
+            None => crate::bail!("no dropout mask has been recorded yet"),
+            Some(mask) => apply_dropout_mask(xs, mask),
+        }
+    }
+
+    /// The mask recorded by the last training forward pass, `None` if the layer was not created
+    /// with `with_recorded_mask` or has not been run in training mode yet. The mask is returned
+    /// by value as it lives behind the lock shared by the clones of this layer, cloning a tensor
+    /// does not copy its data.
+    pub fn last_mask(&self) -> Option<Tensor> {
+        self.recorded_mask
+            .as_ref()
+            .and_then(|recorded_mask| recorded_mask.lock().unwrap().clone())
+    }
+}
+
+impl crate::core::ModuleT for Dropout {
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Result<Tensor> {
+        self.forward(xs, train)
+    }
+}
+
+/// Channel dropout of a `(batch, channels, height, width)` tensor: in training mode each channel
+/// of each sample is zeroed as a whole with probability `drop_p`, the kept channels are scaled by
+/// `1 / (1 - drop_p)`.
+#[derive(Clone, Debug)]
+pub struct Dropout2d {
+    drop_p: f32,
+}
+
+impl Dropout2d {
+    pub fn new(drop_p: f32) -> Dropout2d {
+        Self { drop_p }
+    }
+}
+
+impl crate::core::ModuleT for Dropout2d {
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Result<Tensor> {
+        let (b_size, c, _h, _w) = xs.dims4()?;
+        if !(0. ..1.).contains(&self.drop_p) {
+            crate::bail!(
+                "dropout probability has to be in [0, 1), got {}",
+                self.drop_p
+            )
+        }
+        if !train {
+            return Ok(xs.clone());
+        }
+        let rand = Tensor::rand(0f32, 1f32, (b_size, c), xs.device())?;
+        let scale = 1.0 / (1.0 - self.drop_p as f64);
+        let mask = (rand.ge(self.drop_p)?.to_dtype(xs.dtype())? * scale)?;
+        xs.broadcast_mul(&mask.unsqueeze(2)?.unsqueeze(3)?)
+    }
+}
+
+/// Sinusoidal timestep embeddings of shape `(batch, dim)` for the `(batch,)` `timesteps`.
+///
+/// With `half = dim / 2` and `freqs[i] = exp(-ln(max_period) * i / half)`, the embedding is
+/// `[sin(t * freqs), cos(t * freqs)]`; an odd `dim` gets a trailing zero column. The computation
+/// is done in F32, the result has the dtype of `timesteps` if it is a float dtype and F32
+/// otherwise.
+pub fn timestep_embedding(timesteps: &Tensor, dim: usize, max_period: f64) -> Result<Tensor> {
+    let b_size = timesteps.dims1()?;
+    if dim == 0 {
+        crate::bail!("timestep_embedding expects a non-zero dim")
+    }
+    let dtype = if timesteps.dtype().is_float() {
+        timesteps.dtype()
+    } else {
+        DType::F32
+    };
+    let dev = timesteps.device();
+    let half = dim / 2;
+    if half == 0 {
+        return Tensor::zeros((b_size, dim), dtype, dev);
+    }
+    let freqs = (Tensor::arange(0u32, half as u32, dev)?.to_dtype(DType::F32)?
+        * (-max_period.ln() / half as f64))?
+        .exp()?;
+    let args = timesteps
+        .to_dtype(DType::F32)?
+        .unsqueeze(1)?
+        .broadcast_mul(&freqs.unsqueeze(0)?)?;
+    let mut parts = vec![args.sin()?, args.cos()?];
+    if dim % 2 == 1 {
+        parts.push(Tensor::zeros((b_size, 1), DType::F32, dev)?);
+    }
+    Tensor::cat(&parts, 1)?.to_dtype(dtype)
+}
+
+/// Classifier-free guidance combination: `uncond + scale * (cond - uncond)`.
+pub fn cfg_combine(cond: &Tensor, uncond: &Tensor, scale: f64) -> Result<Tensor> {
+    uncond + ((cond - uncond)? * scale)?
+}
+
+/// Classifier-free guidance combination with a per-sample guidance scale: sample `i` along the
+/// first dimension is combined as `uncond + scales[i] * (cond - uncond)`.
+///
+/// `scales` has shape `(batch,)` and is broadcast over the remaining dimensions, so requests
+/// with different guidance strengths can share a batch.
+pub fn cfg_combine_vec(cond: &Tensor, uncond: &Tensor, scales: &Tensor) -> Result<Tensor> {
+    let b_size = cond.dim(0)?;
+    if scales.dims1()? != b_size {
+        crate::bail!(
+            "cfg_combine_vec expects {b_size} scales, got shape {:?}",
+            scales.shape()
+        )
+    }
+    let mut s_shape = vec![1; cond.rank()];
+    s_shape[0] = b_size;
+    let scales = scales.to_dtype(cond.dtype())?.reshape(s_shape)?;
+    uncond + (cond - uncond)?.broadcast_mul(&scales)?
+}
+
+/// Classifier-free guidance combination followed by Imagen-style dynamic thresholding.
+///
+/// For each sample along the first dimension, `s` is the `percentile` quantile of the absolute
+/// values of the combined prediction (clamped to be at least 1), and the prediction is clamped
+/// to `[-s, s]` and divided by `s`.
+///
+/// The per-sample quantile is computed on the host; inputs living on a GPU are copied to the
+/// cpu for this reduction only, the combination and clamping run on the original device.
+pub fn cfg_combine_dynamic(
+    cond: &Tensor,
+    uncond: &Tensor,
+    scale: f64,
+    percentile: f64,
+) -> Result<Tensor> {
+    if !(0. ..=1.).contains(&percentile) {
+        crate::bail!("percentile has to be in [0, 1], got {percentile}")
+    }
+    let combined = cfg_combine(cond, uncond, scale)?;
+    let b_size = combined.dim(0)?;
+    let flat = combined.flatten_from(1)?;
+    if flat.dim(1)? == 0 {
+        crate::bail!(
+            "cfg_combine_dynamic expects non-empty samples, got shape {:?}",
+            combined.shape()
+        )
+    }
+    let flat = flat.abs()?.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+    let thresholds = flat
+        .into_iter()
+        .map(|mut row| {
+            row.sort_by(|a, b| a.total_cmp(b));
+            let pos = percentile * (row.len() - 1) as f64;
+            let (lo, hi) = (pos.floor() as usize, pos.ceil() as usize);
+            let frac = (pos - lo as f64) as f32;
+            let s = row[lo] + (row[hi] - row[lo]) * frac;
+            s.max(1.)
+        })
+        .collect::<Vec<_>>();
+    let mut s_shape = vec![1; combined.rank()];
+    s_shape[0] = b_size;
+    let s = Tensor::from_vec(thresholds, s_shape, combined.device())?.to_dtype(combined.dtype())?;
+    combined
+        .broadcast_minimum(&s)?
+        .broadcast_maximum(&s.neg()?)?
+        .broadcast_div(&s)
+}
+
+/// Min-SNR loss weights `min(snr, gamma) / snr` with `snr = alpha_bar / (1 - alpha_bar)`.
+///
+/// This is evaluated as `min(1, gamma * (1 - alpha_bar) / alpha_bar)` so that both ends of the
+/// schedule stay finite: the weight is 1 for `alpha_bar == 0` and 0 for `alpha_bar == 1`.
+pub fn min_snr_weight(alpha_bar: &Tensor, gamma: f64) -> Result<Tensor> {
+    if gamma <= 0. {
+        crate::bail!("min_snr_weight expects a positive gamma, got {gamma}")
+    }
+    let inv_snr = (alpha_bar.affine(-1., 1.)? / alpha_bar)?;
+    (inv_snr * gamma)?.clamp(0f64, 1f64)
+}
+
+/// `sqrt(alpha_bar)` and `sqrt(1 - alpha_bar)` in the dtype of `xs`.
+fn alpha_sigma(alpha_bar: &Tensor, xs: &Tensor) -> Result<(Tensor, Tensor)> {
+    let alpha_bar = alpha_bar.to_dtype(DType::F32)?;
+    let alpha = alpha_bar.sqrt()?.to_dtype(xs.dtype())?;
+    let sigma = alpha_bar.affine(-1., 1.)?.sqrt()?.to_dtype(xs.dtype())?;
+    Ok((alpha, sigma))
+}
+
+/// Converts a v-prediction to an epsilon-prediction: `eps = sqrt(alpha_bar) * v + sqrt(1 -
+/// alpha_bar) * x_t`.
+///
+/// `alpha_bar` holds the schedule coefficients and is broadcasted to the shape of `v`, e.g. with
+/// a shape of `(b_size, 1, 1, 1)` for one timestep per sample.
+pub fn v_to_eps(v: &Tensor, x_t: &Tensor, alpha_bar: &Tensor) -> Result<Tensor> {
+    let (alpha, sigma) = alpha_sigma(alpha_bar, v)?;
+    fma(v, &alpha, &x_t.broadcast_mul(&sigma)?)
+}
+
+/// Converts an epsilon-prediction to a v-prediction, the inverse of [`v_to_eps`]:
+/// `v = (eps - sqrt(1 - alpha_bar) * x_t) / sqrt(alpha_bar)`.
+///
+/// `alpha_bar` is broadcasted to the shape of `eps` and has to be non-zero.
+pub fn eps_to_v(eps: &Tensor, x_t: &Tensor, alpha_bar: &Tensor) -> Result<Tensor> {
+    let (alpha, sigma) = alpha_sigma(alpha_bar, eps)?;
+    let inv_alpha = alpha.recip()?;
+    fma(
+        eps,
+        &inv_alpha,
+        &x_t.broadcast_mul(&(sigma * &inv_alpha)?.neg()?)?,
+    )
+}
+
+struct Bf16Stochastic {
+    seed: u64,
+}
+
+impl crate::core::CustomOp1 for Bf16Stochastic {
+    fn name(&self) -> &'static str {
+        "to-bf16-stochastic"
+    }
+
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        use rand::{Rng, SeedableRng};
+
+        let src = match storage {
+            CpuStorage::F32(src) => src,
+            _ => crate::bail!(
+                "to-bf16-stochastic expects a f32 input, got {:?}",
+                storage.dtype()
+            ),
+        };
+        let src = match layout.contiguous_offsets() {
+            None => crate::bail!("input has to be contiguous"),
+            Some((o1, o2)) => &src[o1..o2],
+        };
+        let mut rng = rand::rngs::StdRng::seed_from_u64(self.seed);
+        let dst = src
+            .iter()
+            .map(|&v| {
+                if !v.is_finite() {
+                    return half::bf16::from_f32(v);
+                }
+                // Adding uniform noise to the 16 truncated mantissa bits rounds up with a
+                // probability proportional to the distance to the lower bf16 value.
+                let noise = rng.gen::<u16>() as u32;
+                let bits = v.to_bits().saturating_add(noise);
+                half::bf16::from_bits((bits >> 16) as u16)
+            })
+            .collect();
+        Ok((CpuStorage::BF16(dst), layout.shape().clone()))
+    }
+}
+
+/// Casts a F32 tensor to BF16 using stochastic rounding: each value is rounded up or down with
+/// a probability given by its distance to the two neighbouring BF16 values, so the rounding
+/// is unbiased in expectation. The same `seed` always gives the same result.
+///
+/// The rounding runs on the cpu, tensors on other devices are copied there and back.
+pub fn to_bf16_stochastic(xs: &Tensor, seed: u64) -> Result<Tensor> {
+    if xs.dtype() != DType::F32 {
+        crate::bail!(
+            "to_bf16_stochastic expects a f32 input, got {:?}",
+            xs.dtype()
+        )
+    }
+    let op = Bf16Stochastic { seed };
+    if xs.device().is_cpu() {
+        xs.contiguous()?.apply_op1_no_bwd(&op)
+    } else {
+        xs.to_device(&crate::core::Device::Cpu)?
+            .contiguous()?
+            .apply_op1_no_bwd(&op)?
+            .to_device(xs.device())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Saturate {
+    min: f64,
+    max: f64,
+}
+
+impl UnaryFloatFn for Saturate {
+    fn call<T: num_traits::Float>(&self, v: T) -> T {
+        let min = T::from(self.min).unwrap_or(T::neg_infinity());
+        let max = T::from(self.max).unwrap_or(T::infinity());
+        // Comparisons keep NaN untouched, unlike `Float::max`/`Float::min`.
+        if v < min {
+            min
+        } else if v > max {
+            max
+        } else {
+            v
+        }
+    }
+}
+
+impl crate::core::CustomOp1 for Saturate {
+    fn name(&self) -> &'static str {
+        "saturate"
+    }
+
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        cpu_unary_fwd(self.name(), storage, layout, *self)
+    }
+}
+
+/// The finite range of values representable by `dtype`.
+fn dtype_range(dtype: DType) -> (f64, f64) {
+    match dtype {
+        DType::U8 => (u8::MIN as f64, u8::MAX as f64),
+        DType::I8 => (i8::MIN as f64, i8::MAX as f64),
+        DType::U32 => (u32::MIN as f64, u32::MAX as f64),
+        DType::I16 => (i16::MIN as f64, i16::MAX as f64),
+        DType::I32 => (i32::MIN as f64, i32::MAX as f64),
+        DType::I64 => (i64::MIN as f64, i64::MAX as f64),
+        DType::F8E4M3 => (-448., 448.),
+        DType::BF16 => (half::bf16::MIN.to_f64(), half::bf16::MAX.to_f64()),
+        DType::F16 => (half::f16::MIN.to_f64(), half::f16::MAX.to_f64()),
+        DType::F32 => (f32::MIN as f64, f32::MAX as f64),
+        DType::F64 => (f64::MIN, f64::MAX),
+    }
+}
+
+/// Clamps `xs` to the finite range of `dtype`, e.g. `[-65504, 65504]` for F16, so that a
+/// following `to_dtype(dtype)` saturates instead of producing infinities. The result keeps the
+/// dtype of `xs` and NaN values are left untouched.
+///
+/// ```rust
+/// use diffusion_rs_common::core::{DType, Device, Tensor};
+/// let xs = Tensor::new(&[1e5f32, -1e6, 1.5], &Device::Cpu)?;
+/// let xs = diffusion_rs_common::nn::ops::saturate_to_dtype_range(&xs, DType::F16)?;
+/// let xs = xs.to_dtype(DType::F16)?.to_dtype(DType::F32)?;
+/// assert_eq!(xs.to_vec1::<f32>()?, &[65504., -65504., 1.5]);
+/// # Ok::<(), diffusion_rs_common::core::Error>(())
+/// ```
+pub fn saturate_to_dtype_range(xs: &Tensor, dtype: DType) -> Result<Tensor> {
+    let (min, max) = dtype_range(dtype);
+    if xs.device().is_cpu() {
+        xs.apply_op1_no_bwd(&Saturate { min, max })
+    } else {
+        xs.clamp(min, max)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct NanToNum {
+    nan: f64,
+    posinf: f64,
+    neginf: f64,
+}
+
+impl UnaryFloatFn for NanToNum {
+    fn call<T: num_traits::Float>(&self, v: T) -> T {
+        if v.is_nan() {
+            T::from(self.nan).unwrap_or(T::zero())
+        } else if v.is_infinite() && v.is_sign_positive() {
+            T::from(self.posinf).unwrap_or(T::max_value())
+        } else if v.is_infinite() {
+            T::from(self.neginf).unwrap_or(T::min_value())
+        } else {
+            v
+        }
+    }
+}
+
+impl crate::core::CustomOp1 for NanToNum {
+    fn name(&self) -> &'static str {
+        "nan-to-num"
+    }
+
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        cpu_unary_fwd(self.name(), storage, layout, *self)
+    }
+}
+
+/// Replaces NaN, positive infinity and negative infinity in `xs` with `nan`, `posinf` and
+/// `neginf` respectively, like `torch.nan_to_num`. Finite values are passed through.
+pub fn nan_to_num(xs: &Tensor, nan: f64, posinf: f64, neginf: f64) -> Result<Tensor> {
+    if xs.device().is_cpu() {
+        return xs.apply_op1_no_bwd(&NanToNum {
+            nan,
+            posinf,
+            neginf,
+        });
+    }
+    let full = |v: f64| Tensor::full(v, xs.shape(), xs.device())?.to_dtype(xs.dtype());
+    let xs = xs.ne(xs)?.where_cond(&full(nan)?, xs)?;
+    let xs = xs.eq(f64::INFINITY)?.where_cond(&full(posinf)?, &xs)?;
+    xs.eq(f64::NEG_INFINITY)?.where_cond(&full(neginf)?, &xs)
+}
+
+struct DequantizePerChannel {
+    dim: usize,
+}
+
+impl crate::core::CustomOp3 for DequantizePerChannel {
+    fn name(&self) -> &'static str {
+        "dequantize-per-channel"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+        s3: &CpuStorage,
+        l3: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        fn dequant<T: crate::core::WithDType>(
+            q: &[i8],
+            scale: &[T],
+            zero_point: &[T],
+            dims: &[usize],
+            dim: usize,
+        ) -> Vec<T> {
+            let channels = dims[dim];
+            let inner: usize = dims[dim + 1..].iter().product();
+            q.iter()
+                .enumerate()
+                .map(|(i, &v)| {
+                    let c = (i / inner) % channels;
+                    let zp = zero_point[c].to_f64();
+                    T::from_f64((v as f64 - zp) * scale[c].to_f64())
+                })
+                .collect()
+        }
+
+        let q = match (s1, l1.contiguous_offsets()) {
+            (CpuStorage::I8(q), Some((o1, o2))) => &q[o1..o2],
+            (CpuStorage::I8(_), None) => crate::bail!("input has to be contiguous"),
+            _ => crate::bail!(
+                "dequantize-per-channel expects an i8 input, got {:?}",
+                s1.dtype()
+            ),
+        };
+        let dims = l1.shape().dims();
+        let storage = match (s2, s3, l2.contiguous_offsets(), l3.contiguous_offsets()) {
+            (CpuStorage::F16(s), CpuStorage::F16(z), Some((so1, so2)), Some((zo1, zo2))) => {
+                CpuStorage::F16(dequant(q, &s[so1..so2], &z[zo1..zo2], dims, self.dim))
+            }
+            (CpuStorage::F32(s), CpuStorage::F32(z), Some((so1, so2)), Some((zo1, zo2))) => {
+                CpuStorage::F32(dequant(q, &s[so1..so2], &z[zo1..zo2], dims, self.dim))
+            }
+            (_, _, None, _) | (_, _, _, None) => {
+                crate::bail!("scale and zero-point have to be contiguous")
+            }
+            _ => crate::bail!(
+                "unsupported dtype for dequantize-per-channel {:?}",
+                s2.dtype()
+            ),
+        };
+        Ok((storage, l1.shape().clone()))
+    }
+}
+
+/// Dequantizes an I8 tensor with one scale (and optional zero-point) per channel along `dim`,
+/// computing `(q - zero_point) * scale`.
+///
+/// `scale` and `zero_point` must be vectors with as many elements as `q` has on `dim`. The
+/// output has the dtype of `scale`, which must be F16 or F32. On devices other than the cpu this
+/// is computed with broadcasted ops.
+pub fn dequantize_per_channel(
+    q: &Tensor,
+    scale: &Tensor,
+    zero_point: Option<&Tensor>,
+    dim: usize,
+) -> Result<Tensor> {
+    if q.dtype() != DType::I8 {
+        crate::bail!(
+            "dequantize_per_channel expects an i8 input, got {:?}",
+            q.dtype()
+        )
+    }
+    if !matches!(scale.dtype(), DType::F16 | DType::F32) {
+        crate::bail!(
+            "dequantize_per_channel expects a f16 or f32 scale, got {:?}",
+            scale.dtype()
+        )
+    }
+    let channels = q.dim(dim)?;
+    if scale.dims() != [channels] {
+        crate::bail!(
+            "dequantize_per_channel scale shape {:?} does not match dim {dim} of {:?}",
+            scale.shape(),
+            q.shape()
+        )
+    }
+    let zero_point = match zero_point {
+        Some(zp) => {
+            if zp.dims() != [channels] {
+                crate::bail!(
+                    "dequantize_per_channel zero-point shape {:?} does not match dim {dim} of {:?}",
+                    zp.shape(),
+                    q.shape()
+                )
+            }
+            zp.to_dtype(scale.dtype())?
+        }
+        None => scale.zeros_like()?,
+    };
+    if q.device().is_cpu() {
+        q.contiguous()?.apply_op3_no_bwd(
+            &scale.contiguous()?,
+            &zero_point.contiguous()?,
+            &DequantizePerChannel { dim },
+        )
+    } else {
+        let mut shape = vec![1; q.rank()];
+        shape[dim] = channels;
+        q.to_dtype(scale.dtype())?
+            .broadcast_sub(&zero_point.reshape(shape.as_slice())?)?
+            .broadcast_mul(&scale.reshape(shape)?)
+    }
+}
+
+struct FakeQuantizePerChannel {
+    dim: usize,
+    qmin: i64,
+    qmax: i64,
+}
+
+impl FakeQuantizePerChannel {
+    /// The quantized value of `v`, before clamping to `[qmin, qmax]`.
+    fn quantize(v: f64, scale: f64, zero_point: f64) -> f64 {
+        (v / scale).round_ties_even() + zero_point
+    }
+}
+
+impl crate::core::CustomOp3 for FakeQuantizePerChannel {
+    fn name(&self) -> &'static str {
+        "fake-quantize-per-channel"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+        s3: &CpuStorage,
+        l3: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+
+        fn inner<T: crate::core::WithDType>(
+            xs: &[T],
+            xs_l: &Layout,
+            scale: &[T],
+            scale_l: &Layout,
+            zero_point: &[T],
+            zero_point_l: &Layout,
+            op: &FakeQuantizePerChannel,
+        ) -> Result<(CpuStorage, Shape)> {
+            let xs = match xs_l.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &xs[o1..o2],
+            };
+            let (scale, zero_point) = match (
+                scale_l.contiguous_offsets(),
+                zero_point_l.contiguous_offsets(),
+            ) {
+                (Some((so1, so2)), Some((zo1, zo2))) => (&scale[so1..so2], &zero_point[zo1..zo2]),
+                _ => crate::bail!("scale and zero-point have to be contiguous"),
+            };
+            let dims = xs_l.shape().dims();
+            let channels = dims[op.dim];
+            let inner: usize = dims[op.dim + 1..].iter().product();
+            let (qmin, qmax) = (op.qmin as f64, op.qmax as f64);
+            let dst: Vec<T> = xs
+                .par_iter()
+                .enumerate()
+                .map(|(i, &v)| {
+                    let c = (i / inner) % channels;
+                    let (s, zp) = (scale[c].to_f64(), zero_point[c].to_f64());
+                    let q = FakeQuantizePerChannel::quantize(v.to_f64(), s, zp).clamp(qmin, qmax);
+                    T::from_f64((q - zp) * s)
+                })
+                .collect();
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, xs_l.shape().clone()))
+        }
+
+        use CpuStorage as C;
+        match (s1, s2, s3) {
+            (C::BF16(s1), C::BF16(s2), C::BF16(s3)) => {
+                inner::<half::bf16>(s1, l1, s2, l2, s3, l3, self)
+            }
+            (C::F16(s1), C::F16(s2), C::F16(s3)) => {
+                inner::<half::f16>(s1, l1, s2, l2, s3, l3, self)
+            }
+            (C::F32(s1), C::F32(s2), C::F32(s3)) => inner::<f32>(s1, l1, s2, l2, s3, l3, self),
+            (C::F64(s1), C::F64(s2), C::F64(s3)) => inner::<f64>(s1, l1, s2, l2, s3, l3, self),
+            _ => crate::bail!(
+                "unsupported dtype for fake-quantize-per-channel {:?}",
+                s1.dtype()
+            ),
+        }
+    }
+
+    fn bwd(
+        &self,
+        xs: &Tensor,
+        scale: &Tensor,
+        zero_point: &Tensor,
+        _res: &Tensor,
+        grad_res: &Tensor,
+    ) -> Result<(Option<Tensor>, Option<Tensor>, Option<Tensor>)> {
+        // Straight-through estimator: the gradient passes where the quantized value is not
+        // clamped and is zero elsewhere. The scale and zero-point are not trained. The mask is
+        // built on the cpu with the same rounding as the forward pass.
+        let cpu = crate::core::Device::Cpu;
+        let to_f64 = |t: &Tensor| -> Result<Vec<f64>> {
+            t.to_device(&cpu)?
+                .to_dtype(crate::core::DType::F64)?
+                .flatten_all()?
+                .to_vec1::<f64>()
+        };
+        let (xs_v, scale_v, zero_point_v) = (to_f64(xs)?, to_f64(scale)?, to_f64(zero_point)?);
+        let channels = xs.dim(self.dim)?;
+        let inner: usize = xs.dims()[self.dim + 1..].iter().product();
+        let (qmin, qmax) = (self.qmin as f64, self.qmax as f64);
+        let mask: Vec<f32> = xs_v
+            .iter()
+            .enumerate()
+            .map(|(i, &v)| {
+                let c = (i / inner) % channels;
+                let q = FakeQuantizePerChannel::quantize(v, scale_v[c], zero_point_v[c]);
+                if (qmin..=qmax).contains(&q) {
+                    1.
+                } else {
+                    0.
+                }
+            })
+            .collect();
+        let in_range =
+            Tensor::from_vec(mask, xs.shape(), grad_res.device())?.to_dtype(grad_res.dtype())?;
+        Ok((Some(grad_res.mul(&in_range)?), None, None))
+    }
+}
+
+/// Simulates a per-channel quantization round trip for quantization aware training: computes
+/// `q = clamp(round(x / scale) + zero_point, qmin, qmax)` and returns `(q - zero_point) * scale`.
+///
+/// `scale` and `zero_point` are vectors with one value per channel along `dim`, cast to the dtype
+/// of `xs`. The values are rounded half to even. The backward pass is the straight-through
+/// estimator: the gradient flows unchanged where `q` lies in `[qmin, qmax]` and is zero where it
+/// was clamped. On devices other than the cpu the op runs on a cpu copy of the tensors.
+pub fn fake_quantize_per_channel(
+    xs: &Tensor,
+    scale: &Tensor,
+    zero_point: &Tensor,
+    qmin: i64,
+    qmax: i64,
+    dim: usize,
+) -> Result<Tensor> {
+    if qmin > qmax {
+        crate::bail!("fake_quantize_per_channel expects qmin <= qmax, got {qmin} > {qmax}")
+    }
+    let channels = xs.dim(dim)?;
+    for (name, t) in [("scale", scale), ("zero-point", zero_point)] {
+        if t.dims() != [channels] {
+            crate::bail!(
+                "fake_quantize_per_channel {name} shape {:?} does not match dim {dim} of {:?}",
+                t.shape(),
+                xs.shape()
+            )
+        }
+    }
+    let scale = scale.to_dtype(xs.dtype())?.contiguous()?;
+    let zero_point = zero_point.to_dtype(xs.dtype())?.contiguous()?;
+    let op = FakeQuantizePerChannel { dim, qmin, qmax };
+    if xs.device().is_cpu() {
+        xs.contiguous()?.apply_op3(&scale, &zero_point, op)
+    } else {
+        let cpu = crate::core::Device::Cpu;
+        xs.to_device(&cpu)?
+            .contiguous()?
+            .apply_op3(&scale.to_device(&cpu)?, &zero_point.to_device(&cpu)?, op)?
+            .to_device(xs.device())
+    }
+}
+
+/// Quantizes `xs` to signed `bits`-bit integers with a symmetric abs-max scale, dequantizes it and
+/// returns the reconstruction (in the dtype of `xs`) together with the RMS error against `xs`.
+///
+/// With `per_channel_dim` set, one scale is computed for each index along that dim, otherwise a
+/// single scale covers the whole tensor. This is meant for calibrating quantization settings.
+pub fn quant_error(
+    xs: &Tensor,
+    bits: u32,
+    per_channel_dim: Option<usize>,
+) -> Result<(Tensor, f64)> {
+    if !(2..=16).contains(&bits) {
+        crate::bail!("quant_error expects bits in 2..=16, got {bits}")
+    }
+    let qmax = ((1u32 << (bits - 1)) - 1) as f64;
+    let xs_f32 = xs.to_dtype(DType::F32)?;
+    let abs = xs_f32.abs()?;
+    let absmax = match per_channel_dim {
+        None => abs.flatten_all()?.max_keepdim(0)?,
+        Some(dim) => {
+            let rank = xs.rank();
+            if dim >= rank {
+                crate::bail!("quant_error channel dim {dim} out of range for rank {rank}")
+            }
+            let mut absmax = abs;
+            for d in (0..rank).filter(|&d| d != dim) {
+                absmax = absmax.max_keepdim(d)?;
+            }
+            absmax
+        }
+    };
+    // All-zero channels would otherwise divide by zero, any positive scale reconstructs them.
+    let absmax = absmax.clamp(f32::MIN_POSITIVE, f32::INFINITY)?;
+    // Normalizing by the abs-max before multiplying by `qmax` keeps the abs-max values exact.
+    let q = (xs_f32.broadcast_div(&absmax)? * qmax)?
+        .round()?
+        .clamp(-qmax, qmax)?;
+    let reconstructed = (q / qmax)?.broadcast_mul(&absmax)?;
+    let rms = (&reconstructed - &xs_f32)?
+        .sqr()?
+        .mean_all()?
+        .sqrt()?
+        .to_scalar::<f32>()?;
+    Ok((reconstructed.to_dtype(xs.dtype())?, rms as f64))
+}
+
+/// Summary statistics of all the elements of a tensor, see `tensor_stats`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TensorStats {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub absmax: f64,
+}
+
+/// Computes `[min, max, mean, absmax]` as a `(4,)` F64 tensor in a single pass.
+struct TensorStatsOp;
+
+impl crate::core::CustomOp1 for TensorStatsOp {
+    fn name(&self) -> &'static str {
+        "tensor-stats"
+    }
+
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        fn inner<T: crate::core::WithDType>(src: &[T], layout: &Layout) -> Result<[f64; 4]> {
+            let src = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &src[o1..o2],
+            };
+            if src.is_empty() {
+                crate::bail!("tensor_stats expects a non-empty tensor")
+            }
+            // Partial (min, max, sum) per chunk, merged pairwise.
+            let (min, max, sum) = src
+                .par_chunks(4096)
+                .map(|chunk| {
+                    chunk.iter().fold(
+                        (f64::INFINITY, f64::NEG_INFINITY, 0f64),
+                        |(min, max, sum), &v| {
+                            let v = v.to_f64();
+                            (min.min(v), max.max(v), sum + v)
+                        },
+                    )
+                })
+                .reduce(
+                    || (f64::INFINITY, f64::NEG_INFINITY, 0f64),
+                    |a, b| (a.0.min(b.0), a.1.max(b.1), a.2 + b.2),
+                );
+            let mean = sum / src.len() as f64;
+            Ok([min, max, mean, max.max(-min)])
+        }
+
+        use CpuStorage as C;
+        let stats = match storage {
+            C::U8(slice) => inner(slice, layout)?,
+            C::I8(slice) => inner(slice, layout)?,
+            C::U32(slice) => inner(slice, layout)?,
+            C::I16(slice) => inner(slice, layout)?,
+            C::I32(slice) => inner(slice, layout)?,
+            C::I64(slice) => inner(slice, layout)?,
+            C::BF16(slice) => inner(slice, layout)?,
+            C::F16(slice) => inner(slice, layout)?,
+            C::F32(slice) => inner(slice, layout)?,
+            C::F64(slice) => inner(slice, layout)?,
+            C::F8E4M3(slice) => inner(slice, layout)?,
+        };
+        Ok((C::F64(stats.to_vec()), Shape::from_dims(&[4])))
+    }
+}
+
+/// Returns the min, max, mean and abs-max of all the elements of `xs`, e.g. to calibrate FP8/INT8
+/// scales or to profile activations.
+///
+/// On the CPU all four are computed in a single parallel pass with an f64 accumulator, other
+/// devices run separate reductions in F32.
+pub fn tensor_stats(xs: &Tensor) -> Result<TensorStats> {
+    let stats = if xs.device().is_cpu() {
+        xs.contiguous()?
+            .apply_op1_no_bwd(&TensorStatsOp)?
+            .to_vec1::<f64>()?
+    } else {
+        if xs.elem_count() == 0 {
+            crate::bail!("tensor_stats expects a non-empty tensor")
+        }
+        let xs = xs.flatten_all()?.to_dtype(DType::F32)?;
+        let min = xs.min(0)?.to_scalar::<f32>()? as f64;
+        let max = xs.max(0)?.to_scalar::<f32>()? as f64;
+        let mean = xs.mean(0)?.to_scalar::<f32>()? as f64;
+        vec![min, max, mean, max.max(-min)]
+    };
+    Ok(TensorStats {
+        min: stats[0],
+        max: stats[1],
+        mean: stats[2],
+        absmax: stats[3],
+    })
+}
+
+/// Adds a `(C,)` channel bias to a `(N, C, H, W)` tensor, e.g. the output of a convolution.
+///
+/// The bias is viewed as `(1, C, 1, 1)` without any copy so the addition runs as a single
+/// broadcasted binary kernel.
+pub fn bias_add_nchw(xs: &Tensor, bias: &Tensor) -> Result<Tensor> {
+    let (_, c, _, _) = xs.dims4()?;
+    if bias.dims() != [c] {
+        crate::bail!(
+            "bias_add_nchw expects a bias of shape ({c},), got {:?}",
+            bias.shape()
+        )
+    }
+    xs.broadcast_add(&bias.reshape((1, c, 1, 1))?)
+}
+
+/// Multiplies a `(N, C, H, W)` tensor by a per-sample, per-channel gate, e.g. the output of a
+/// squeeze-and-excitation block.
+///
+/// `gate` has shape `(N, C)` or `(N, C, 1, 1)`, a batch size of 1 is broadcast over `N`. The gate
+/// is viewed as `(N, C, 1, 1)` without any copy so the product runs as a single broadcasted
+/// binary kernel.
+pub fn channel_gate(xs: &Tensor, gate: &Tensor) -> Result<Tensor> {
+    let (n, c, _, _) = xs.dims4()?;
+    let gate_n = match gate.dims() {
+        &[gate_n, gate_c] | &[gate_n, gate_c, 1, 1] if gate_c == c => gate_n,
+        _ => crate::bail!(
+            "channel_gate expects a gate of shape ({n}, {c}) or ({n}, {c}, 1, 1), got {:?}",
+            gate.shape()
+        ),
+    };
+    if gate_n != n && gate_n != 1 {
+        crate::bail!("channel_gate expects a gate batch size of {n} or 1, got {gate_n}")
+    }
+    xs.broadcast_mul(&gate.reshape((gate_n, c, 1, 1))?)
+}
+
+/// Flattens a `(N, C, H, W)` feature map into the `(N, H * W, C)` token sequence used by spatial
+/// self-attention, returning it along with `(H, W)` so that `seq_to_spatial` can undo it.
+///
+/// The result is a transposed view of `xs`, no data is copied.
+pub fn spatial_to_seq(xs: &Tensor) -> Result<(Tensor, (usize, usize))> {
+    let (_, _, h, w) = xs.dims4()?;
+    let seq = xs.flatten_from(2)?.transpose(1, 2)?;
+    Ok((seq, (h, w)))
+}
+
+/// The inverse of `spatial_to_seq`, turns a `(N, H * W, C)` sequence back into a `(N, C, H, W)`
+/// feature map.
+pub fn seq_to_spatial(xs: &Tensor, h: usize, w: usize) -> Result<Tensor> {
+    let (n, seq_len, c) = xs.dims3()?;
+    if seq_len != h * w {
+        crate::bail!("seq_to_spatial expects a sequence length of {h}x{w}, got {seq_len}")
+    }
+    xs.transpose(1, 2)?.reshape((n, c, h, w))
+}
+
+/// Mean of `xs` over `dim` taking only the positions where `mask != 0` into account, e.g. to pool
+/// `(batch, seq, hidden)` token embeddings with a `(batch, seq)` padding mask.
+///
+/// `mask` covers the leading dimensions of `xs` up to and including `dim`, trailing dimensions
+/// are broadcast. Rows where every position is masked out are zero.
+pub fn masked_mean(xs: &Tensor, mask: &Tensor, dim: usize) -> Result<Tensor> {
+    let rank = xs.rank();
+    if dim >= rank || mask.rank() <= dim || mask.rank() > rank {
+        crate::bail!(
+            "masked_mean: invalid dim {dim} for xs {:?} and mask {:?}",
+            xs.shape(),
+            mask.shape()
+        )
+    }
+    let mut mask_dims = mask.dims().to_vec();
+    mask_dims.resize(rank, 1);
+    let mask = mask.ne(0u8)?.to_dtype(xs.dtype())?.reshape(mask_dims)?;
+    let sum = xs.broadcast_mul(&mask)?.sum(dim)?;
+    let count = mask.sum(dim)?.clamp(1f64, f64::INFINITY)?;
+    sum.broadcast_div(&count)
+}
+
+/// Repeats each slice of `xs` along `dim` `repeats` times, like `torch.repeat_interleave`:
+/// `[a, b]` becomes `[a, a, b, b]` for `repeats = 2`, whereas `Tensor::repeat` tiles it into
+/// `[a, b, a, b]`. Used e.g. to expand grouped kv heads to the number of query heads.
+///
+/// The repetition is a broadcast, so the only copy is the final reshape.
+pub fn repeat_interleave(xs: &Tensor, repeats: usize, dim: usize) -> Result<Tensor> {
+    let rank = xs.rank();
+    if dim >= rank {
+        crate::bail!("repeat_interleave: dim {dim} out of range for rank {rank}")
+    }
+    if repeats == 0 {
+        crate::bail!("repeat_interleave: repeats must be at least 1")
+    }
+    if repeats == 1 {
+        return Ok(xs.clone());
+    }
+    let dims = xs.dims();
+    let mut expanded = dims.to_vec();
+    expanded.insert(dim + 1, repeats);
+    let mut out = dims.to_vec();
+    out[dim] *= repeats;
+    xs.unsqueeze(dim + 1)?.broadcast_as(expanded)?.reshape(out)
+}
+
+/// Cosine similarity of `a` and `b` along `dim`, i.e. `sum(a * b) / max(||a|| * ||b||, eps)`
+/// with `dim` reduced as in PyTorch, e.g. to compare `(batch, hidden)` embeddings. `a` and `b`
+/// are broadcast together and `eps` keeps zero-norm inputs finite.
+///
+/// F16/BF16 inputs are accumulated in F32, the result keeps the input dtype.
+pub fn cosine_similarity(a: &Tensor, b: &Tensor, dim: usize, eps: f32) -> Result<Tensor> {
+    let dtype = a.dtype();
+    let internal_dtype = match dtype {
+        DType::F16 | DType::BF16 => DType::F32,
+        d => d,
+    };
+    let a = a.to_dtype(internal_dtype)?;
+    let b = b.to_dtype(internal_dtype)?;
+    let dot = a.broadcast_mul(&b)?.sum(dim)?;
+    let norm_a = a.sqr()?.sum(dim)?.sqrt()?;
+    let norm_b = b.sqr()?.sum(dim)?.sqrt()?;
+    let den = norm_a.broadcast_mul(&norm_b)?.maximum(eps as f64)?;
+    dot.broadcast_div(&den)?.to_dtype(dtype)
+}
+
+/// Sequence lengths from which a single scan is split across threads.
+const PAR_SCAN_MIN_LEN: usize = 1 << 14;
+
+/// The linear recurrence `h_t = a_t * h_{t-1} + b_t` along `dim`, with `h_{-1} = 0`.
+struct LinearScan {
+    dim: usize,
+}
+
+impl LinearScan {
+    /// Scans one `(len, inner)` block, `inner` independent recurrences at a time.
+    fn scan_block<T: crate::core::WithDType>(
+        a: &[T],
+        b: &[T],
+        dst: &mut [T],
+        len: usize,
+        inner: usize,
+    ) {
+        let mut h = vec![0f64; inner];
+        for ((a, b), dst) in a
+            .chunks(inner)
+            .zip(b.chunks(inner))
+            .zip(dst.chunks_mut(inner))
+            .take(len)
+        {
+            for (((h, a), b), dst) in h.iter_mut().zip(a).zip(b).zip(dst.iter_mut()) {
+                *h = a.to_f64() * *h + b.to_f64();
+                *dst = T::from_f64(*h);
+            }
+        }
+    }
+
+    /// Same as `scan_block` with the sequence split in chunks scanned in parallel. The
+    /// recurrence is linear, so each chunk is scanned from a zero state and then corrected by
+    /// the incoming state times the running product of `a`.
+    fn par_scan_block<T: crate::core::WithDType>(
+        a: &[T],
+        b: &[T],
+        dst: &mut [T],
+        len: usize,
+        inner: usize,
+    ) {
+        let chunk_len = len.div_ceil(rayon::current_num_threads().max(1));
+        let chunk_el = chunk_len * inner;
+        let mut hs = vec![0f64; len * inner];
+        // Per chunk, the product of `a` and the state reached from zero.
+        let summaries: Vec<(Vec<f64>, Vec<f64>)> = hs
+            .par_chunks_mut(chunk_el)
+            .enumerate()
+            .map(|(c, hs)| {
+                let (a, b) = (&a[c * chunk_el..], &b[c * chunk_el..]);
+                let mut h = vec![0f64; inner];
+                let mut p = vec![1f64; inner];
+                for ((a, b), hs) in a
+                    .chunks(inner)
+                    .zip(b.chunks(inner))
+                    .zip(hs.chunks_mut(inner))
+                {
+                    for ((((h, p), a), b), hs) in
+                        h.iter_mut().zip(p.iter_mut()).zip(a).zip(b).zip(hs)
+                    {
+                        let a = a.to_f64();
+                        *h = a * *h + b.to_f64();
+                        *p *= a;
+                        *hs = *h;
+                    }
+                }
+                (p, h)
+            })
+            .collect();
+        let mut carries = Vec::with_capacity(summaries.len());
+        let mut carry = vec![0f64; inner];
+        for (p, h) in summaries.iter() {
+            carries.push(carry.clone());
+            for ((c, p), h) in carry.iter_mut().zip(p).zip(h) {
+                *c = p * *c + h;
+            }
+        }
+        dst.par_chunks_mut(chunk_el)
+            .zip(hs.par_chunks(chunk_el))
+            .zip(carries.into_par_iter())
+            .enumerate()
+            .for_each(|(c, ((dst, hs), mut carry))| {
+                let a = &a[c * chunk_el..];
+                for ((a, dst), hs) in a
+                    .chunks(inner)
+                    .zip(dst.chunks_mut(inner))
+                    .zip(hs.chunks(inner))
+                {
+                    for (((c, a), dst), h) in carry.iter_mut().zip(a).zip(dst).zip(hs) {
+                        *c *= a.to_f64();
+                        *dst = T::from_f64(h + *c);
+                    }
+                }
+            });
+    }
+}
+
+impl crate::core::CustomOp2 for LinearScan {
+    fn name(&self) -> &'static str {
+        "linear-scan"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+
+        fn inner<T: crate::core::WithDType>(
+            a: &[T],
+            a_l: &Layout,
+            b: &[T],
+            b_l: &Layout,
+            dim: usize,
+        ) -> Result<(CpuStorage, Shape)> {
+            let a = match a_l.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &a[o1..o2],
+            };
+            let b = match b_l.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &b[o1..o2],
+            };
+            let dims = a_l.shape().dims();
+            let len = dims[dim];
+            let inner: usize = dims[dim + 1..].iter().product();
+            let outer: usize = dims[..dim].iter().product();
+            let block = len * inner;
+            let mut dst = vec![T::zero(); a.len()];
+            if block == 0 {
+                // Nothing to scan.
+            } else if outer < rayon::current_num_threads() && len >= PAR_SCAN_MIN_LEN {
+                for ((a, b), dst) in a
+                    .chunks(block)
+                    .zip(b.chunks(block))
+                    .zip(dst.chunks_mut(block))
+                {
+                    LinearScan::par_scan_block(a, b, dst, len, inner);
+                }
+            } else {
+                a.par_chunks(block)
+                    .zip(b.par_chunks(block))
+                    .zip(dst.par_chunks_mut(block))
+                    .for_each(|((a, b), dst)| LinearScan::scan_block(a, b, dst, len, inner));
+            }
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, a_l.shape().clone()))
+        }
+
+        use CpuStorage as C;
+        match (s1, s2) {
+            (C::BF16(s1), C::BF16(s2)) => inner(s1, l1, s2, l2, self.dim),
+            (C::F16(s1), C::F16(s2)) => inner(s1, l1, s2, l2, self.dim),
+            (C::F32(s1), C::F32(s2)) => inner(s1, l1, s2, l2, self.dim),
+            (C::F64(s1), C::F64(s2)) => inner(s1, l1, s2, l2, self.dim),
+            _ => crate::bail!("unsupported dtype for linear-scan {:?}", s1.dtype()),
+        }
+    }
+}
+
+/// The linear recurrence `h_t = a_t * h_{t-1} + b_t` along `dim` starting from `h = 0`, the
+/// core primitive of linear-attention and selective state space (Mamba-style) layers. With
+/// `a_t = exp(dt * A)` this is the discretized SSM scan.
+///
+/// Returns all the states `h`, with the shape of `a` and `b`. The recurrence is accumulated in
+/// F64 and long sequences are split into chunks scanned in parallel. The scan runs on the cpu,
+/// tensors on other devices are copied there and back.
+pub fn cumsum_exp_scan(a: &Tensor, b: &Tensor, dim: usize) -> Result<Tensor> {
+    if a.shape() != b.shape() {
+        crate::bail!(
+            "cumsum_exp_scan: shape mismatch a: {:?} b: {:?}",
+            a.shape(),
+            b.shape()
+        )
+    }
+    if a.dtype() != b.dtype() {
+        crate::bail!(
+            "cumsum_exp_scan: dtype mismatch a: {:?} b: {:?}",
+            a.dtype(),
+            b.dtype()
+        )
+    }
+    if dim >= a.rank() {
+        crate::bail!(
+            "cumsum_exp_scan: dim {dim} out of range for rank {}",
+            a.rank()
+        )
+    }
+    let op = LinearScan { dim };
+    if a.device().is_cpu() {
+        a.contiguous()?.apply_op2_no_bwd(&b.contiguous()?, &op)
+    } else {
+        let cpu = crate::core::Device::Cpu;
+        a.to_device(&cpu)?
+            .contiguous()?
+            .apply_op2_no_bwd(&b.to_device(&cpu)?.contiguous()?, &op)?
+            .to_device(a.device())
+    }
+}
+
+/// How elementwise losses are reduced to the returned tensor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Reduction {
+    /// Keep the elementwise losses.
+    None,
+    /// Average over all elements.
+    #[default]
+    Mean,
+    /// Sum over all elements.
+    Sum,
+}
+
+impl Reduction {
+    pub fn apply(&self, loss: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::None => Ok(loss.clone()),
+            Self::Mean => loss.mean_all(),
+            Self::Sum => loss.sum_all(),
+        }
+    }
+}
+
+struct SmoothL1 {
+    beta: f64,
+}
+
+impl crate::core::CustomOp2 for SmoothL1 {
+    fn name(&self) -> &'static str {
+        "smooth-l1"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+
+        fn inner<T: crate::core::WithDType + num_traits::Float>(
+            pred: &[T],
+            pred_l: &Layout,
+            target: &[T],
+            target_l: &Layout,
+            beta: f64,
+        ) -> Result<(CpuStorage, Shape)> {
+            let pred = match pred_l.contiguous_offsets() {
+                None => crate::bail!("pred has to be contiguous"),
+                Some((o1, o2)) => &pred[o1..o2],
+            };
+            let target = match target_l.contiguous_offsets() {
+                None => crate::bail!("target has to be contiguous"),
+                Some((o1, o2)) => &target[o1..o2],
+            };
+            let beta = T::from_f64(beta);
+            let half = T::from_f64(0.5);
+            let dst: Vec<T> = pred
+                .par_iter()
+                .zip(target.par_iter())
+                .map(|(&p, &t)| {
+                    let x = (p - t).abs();
+                    if x < beta {
+                        half * x * x / beta
+                    } else {
+                        x - half * beta
+                    }
+                })
+                .collect();
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, pred_l.shape().clone()))
+        }
+
+        use CpuStorage as C;
+        match (s1, s2) {
+            (C::BF16(s1), C::BF16(s2)) => inner::<half::bf16>(s1, l1, s2, l2, self.beta),
+            (C::F16(s1), C::F16(s2)) => inner::<half::f16>(s1, l1, s2, l2, self.beta),
+            (C::F32(s1), C::F32(s2)) => inner::<f32>(s1, l1, s2, l2, self.beta),
+            (C::F64(s1), C::F64(s2)) => inner::<f64>(s1, l1, s2, l2, self.beta),
+            _ => crate::bail!("unsupported dtype for smooth-l1 {:?}", s1.dtype()),
+        }
+    }
+
+    fn bwd(
+        &self,
+        pred: &Tensor,
+        target: &Tensor,
+        _res: &Tensor,
+        grad_res: &Tensor,
+    ) -> Result<(Option<Tensor>, Option<Tensor>)> {
+        // d/dx is x/beta inside the quadratic region and sign(x) outside of it.
+        let beta = self.beta.max(f64::MIN_POSITIVE);
+        let grad = ((pred - target)? / beta)?.clamp(-1f64, 1f64)?;
+        let grad_pred = grad.mul(grad_res)?;
+        let grad_target = grad_pred.neg()?;
+        Ok((Some(grad_pred), Some(grad_target)))
+    }
+}
+
+/// The smooth-L1 (Huber style) loss: `0.5 * x^2 / beta` where `|x| < beta` and `|x| - 0.5 * beta`
+/// otherwise, with `x = pred - target`. A `beta` of 0 gives the L1 loss.
+///
+/// ```rust
+/// use diffusion_rs_common::core::{Tensor, Device};
+/// use diffusion_rs_common::nn::ops::{smooth_l1_loss, Reduction};
+/// let pred = Tensor::new(&[0.5f32, 3.], &Device::Cpu)?;
+/// let target = Tensor::new(&[0f32, 0.], &Device::Cpu)?;
+/// let loss = smooth_l1_loss(&pred, &target, 1., Reduction::None)?;
+/// assert_eq!(loss.to_vec1::<f32>()?, &[0.125, 2.5]);
+/// # Ok::<(), diffusion_rs_common::core::Error>(())
+/// ```
+pub fn smooth_l1_loss(
+    pred: &Tensor,
+    target: &Tensor,
+    beta: f64,
+    reduction: Reduction,
+) -> Result<Tensor> {
+    if beta < 0. {
+        crate::bail!("smooth_l1_loss expects a non-negative beta, got {beta}")
+    }
+    if pred.shape() != target.shape() {
+        crate::bail!(
+            "smooth_l1_loss: shape mismatch pred: {:?} target: {:?}",
+            pred.shape(),
+            target.shape()
+        )
+    }
+    let loss = if pred.device().is_cpu() {
+        pred.contiguous()?
+            .apply_op2(&target.contiguous()?, SmoothL1 { beta })?
+    } else {
+        let x = (pred - target)?.abs()?;
+        let quadratic = (x.sqr()? * (0.5 / beta.max(f64::MIN_POSITIVE)))?;
+        let linear = (&x - 0.5 * beta)?;
+        x.lt(beta)?.where_cond(&quadratic, &linear)?
+    };
+    reduction.apply(&loss)
+}
+
+struct SafeDiv {
+    eps: f64,
+}
+
+impl crate::core::CustomOp2 for SafeDiv {
+    fn name(&self) -> &'static str {
+        "safe-div"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+
+        fn inner<T: crate::core::WithDType + num_traits::Float>(
+            a: &[T],
+            a_l: &Layout,
+            b: &[T],
+            b_l: &Layout,
+            eps: f64,
+        ) -> Result<(CpuStorage, Shape)> {
+            let a = match a_l.contiguous_offsets() {
+                None => crate::bail!("a has to be contiguous"),
+                Some((o1, o2)) => &a[o1..o2],
+            };
+            let b = match b_l.contiguous_offsets() {
+                None => crate::bail!("b has to be contiguous"),
+                Some((o1, o2)) => &b[o1..o2],
+            };
+            let eps = T::from_f64(eps);
+            let dst: Vec<T> = a
+                .par_iter()
+                .zip(b.par_iter())
+                .map(|(&a, &b)| a / (b + eps))
+                .collect();
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, a_l.shape().clone()))
+        }
+
+        use CpuStorage as C;
+        match (s1, s2) {
+            (C::BF16(s1), C::BF16(s2)) => inner::<half::bf16>(s1, l1, s2, l2, self.eps),
+            (C::F16(s1), C::F16(s2)) => inner::<half::f16>(s1, l1, s2, l2, self.eps),
+            (C::F32(s1), C::F32(s2)) => inner::<f32>(s1, l1, s2, l2, self.eps),
+            (C::F64(s1), C::F64(s2)) => inner::<f64>(s1, l1, s2, l2, self.eps),
+            _ => crate::bail!("unsupported dtype for safe-div {:?}", s1.dtype()),
+        }
+    }
+
+    fn bwd(
+        &self,
+        _a: &Tensor,
+        b: &Tensor,
+        res: &Tensor,
+        grad_res: &Tensor,
+    ) -> Result<(Option<Tensor>, Option<Tensor>)> {
+        // d/da = 1 / (b + eps), d/db = -a / (b + eps)^2 = -res / (b + eps)
+        let denom = (b + self.eps)?;
+        let grad_a = grad_res.div(&denom)?;
+        let grad_b = grad_res.mul(res)?.div(&denom)?.neg()?;
+        Ok((Some(grad_a), Some(grad_b)))
+    }
+}
+
+/// Computes `a / (b + eps)`, with `a` and `b` broadcasted to a common shape.
+///
+/// A zero denominator gives `a / eps` rather than an infinity or a NaN. On the cpu this runs as a
+/// single fused pass, other devices use the composed ops.
+pub fn safe_div(a: &Tensor, b: &Tensor, eps: f64) -> Result<Tensor> {
+    if eps <= 0. {
+        crate::bail!("safe_div expects a positive eps, got {eps}")
+    }
+    if !a.device().is_cpu() {
+        return a.broadcast_div(&(b + eps)?);
+    }
+    let shape = a.shape().broadcast_shape_binary_op(b.shape(), "safe_div")?;
+    let a = a.broadcast_as(&shape)?.contiguous()?;
+    let b = b.broadcast_as(&shape)?.contiguous()?;
+    a.apply_op2(&b, SafeDiv { eps })
+}
+
+struct EmaUpdate {
+    decay: f64,
+}
+
+impl crate::core::InplaceOp2 for EmaUpdate {
+    fn name(&self) -> &'static str {
+        "ema-update"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &mut CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<()> {
+        fn inner<T: crate::core::WithDType>(
+            ema: &mut [T],
+            ema_l: &Layout,
+            model: &[T],
+            model_l: &Layout,
+            decay: f64,
+        ) -> Result<()> {
+            let ema = match ema_l.contiguous_offsets() {
+                None => crate::bail!("ema has to be contiguous"),
+                Some((o1, o2)) => &mut ema[o1..o2],
+            };
+            let f = |e: T, m: T| T::from_f64(decay * e.to_f64() + (1. - decay) * m.to_f64());
+            match model_l.contiguous_offsets() {
+                Some((o1, o2)) => ema
+                    .par_iter_mut()
+                    .zip(model[o1..o2].par_iter())
+                    .for_each(|(e, &m)| *e = f(*e, m)),
+                None => ema
+                    .iter_mut()
+                    .zip(model_l.strided_index())
+                    .for_each(|(e, i)| *e = f(*e, model[i])),
+            }
+            Ok(())
+        }
+
+        use crate::core::backend::BackendStorage;
+        use CpuStorage as C;
+        let dtype = s1.dtype();
+        match (s1, s2) {
+            (C::BF16(s1), C::BF16(s2)) => inner::<half::bf16>(s1, l1, s2, l2, self.decay),
+            (C::F16(s1), C::F16(s2)) => inner::<half::f16>(s1, l1, s2, l2, self.decay),
+            (C::F32(s1), C::F32(s2)) => inner::<f32>(s1, l1, s2, l2, self.decay),
+            (C::F64(s1), C::F64(s2)) => inner::<f64>(s1, l1, s2, l2, self.decay),
+            _ => crate::bail!("unsupported dtype for ema-update {dtype:?}"),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &mut crate::core::CudaStorage,
+        l1: &Layout,
+        s2: &crate::core::CudaStorage,
+        l2: &Layout,
+    ) -> Result<()> {
+        use crate::core::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig, ValidAsZeroBits,
+        };
+        use crate::core::cuda_backend::{kernel_name, kernels, Map2InPlace, WrapErr};
+        use crate::core::{CudaDevice, WithDType};
+
+        struct S<'a> {
+            ema_l: &'a Layout,
+            decay: f64,
+        }
+        impl Map2InPlace for S<'_> {
+            fn f<T: DeviceRepr + WithDType + ValidAsZeroBits>(
+                &self,
+                ema: &mut CudaSlice<T>,
+                _ema_shape: &Shape,
+                model: &CudaSlice<T>,
+                model_l: &Layout,
+                dev: &CudaDevice,
+            ) -> Result<()> {
+                let mut ema = match self.ema_l.contiguous_offsets() {
+                    None => crate::bail!("ema has to be contiguous"),
+                    Some((o1, o2)) => ema.slice_mut(o1..o2),
+                };
+                let dims = model_l.dims();
+                let el = model_l.shape().elem_count();
+                let cfg = LaunchConfig::for_num_elems(el as u32);
+                let ds = dev.htod_copy([dims, model_l.stride()].concat()).w()?;
+                let model = &model.slice(model_l.start_offset()..);
+                let func =
+                    dev.get_or_load_func(&kernel_name::<T>("ema_update"), kernels::TERNARY)?;
+                let params = (el, dims.len(), &ds, &mut ema, model, self.decay);
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                Ok(())
+            }
+        }
+
+        use crate::core::backend::BackendStorage;
+        let dev = s1.device().clone();
+        S {
+            ema_l: l1,
+            decay: self.decay,
+        }
+        .map(&mut s1.slice, l1.shape(), &s2.slice, l2, &dev)
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        s1: &mut crate::core::MetalStorage,
+        l1: &Layout,
+        s2: &crate::core::MetalStorage,
+        l2: &Layout,
+    ) -> Result<()> {
+        use crate::core::backend::BackendStorage;
+        use crate::core::metal_backend::buffer_o;
+        if !l1.is_contiguous() {
+            crate::bail!("ema has to be contiguous");
+        }
+        let device = s1.device();
+        let command_buffer = device.command_buffer()?;
+        let kernels = device.kernels();
+        let name = match (s1.dtype(), s2.dtype()) {
+            (DType::F32, DType::F32) => "ema_update_f32",
+            (DType::F16, DType::F16) => "ema_update_f16",
+            (DType::BF16, DType::BF16) => "ema_update_bf16",
+            (dt1, dt2) => {
+                crate::bail!("ema-update is not implemented for {dt1:?} {dt2:?}")
+            }
+        };
+        crate::metal_kernels::call_ema_update_strided(
+            device.metal_device(),
+            &command_buffer,
+            kernels,
+            name,
+            l2.dims(),
+            buffer_o(s1.buffer(), l1, s1.dtype()),
+            buffer_o(s2.buffer(), l2, s2.dtype()),
+            l2.stride(),
+            self.decay as f32,
+        )
+        .map_err(crate::core::Error::wrap)?;
+        Ok(())
+    }
+}
+
+/// Updates an exponential moving average of model weights in place:
+/// `ema = decay * ema + (1 - decay) * model`.
+///
+/// `ema` and `model` must have the same shape and dtype and `ema` must be contiguous. If `ema`
+/// shares its storage with `model`, e.g. because it was created with `model.clone()`, it is first
+/// copied so that the update does not write into the model weights.
+pub fn ema_update(ema: &mut Tensor, model: &Tensor, decay: f64) -> Result<()> {
+    if !(0. ..=1.).contains(&decay) {
+        crate::bail!("ema_update expects a decay in [0, 1], got {decay}")
+    }
+    if ema.shape() != model.shape() {
+        crate::bail!(
+            "ema_update shape mismatch, ema: {:?}, model: {:?}",
+            ema.shape(),
+            model.shape()
+        )
+    }
+    if ema.dtype() != model.dtype() {
+        crate::bail!(
+            "ema_update dtype mismatch, ema: {:?}, model: {:?}",
+            ema.dtype(),
+            model.dtype()
+        )
+    }
+    if !ema.is_contiguous() {
+        crate::bail!("ema_update expects a contiguous ema tensor")
+    }
+    if ema.same_storage(model) {
+        *ema = ema.copy()?;
+    }
+    ema.inplace_op2(model, &EmaUpdate { decay })
+}
+
+#[derive(Clone, Copy)]
+struct AddScaled {
+    alpha: f64,
+}
+
+impl crate::core::InplaceOp2 for AddScaled {
+    fn name(&self) -> &'static str {
+        "add-scaled"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &mut CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<()> {
+        fn inner<T: crate::core::WithDType>(
+            xs: &mut [T],
+            xs_l: &Layout,
+            ys: &[T],
+            ys_l: &Layout,
+            alpha: f64,
+        ) -> Result<()> {
+            let xs = match xs_l.contiguous_offsets() {
+                None => crate::bail!("add-scaled: xs has to be contiguous"),
+                Some((o1, o2)) => &mut xs[o1..o2],
+            };
+            let f = |x: T, y: T| T::from_f64(x.to_f64() + alpha * y.to_f64());
+            match ys_l.contiguous_offsets() {
+                Some((o1, o2)) => xs
+                    .par_iter_mut()
+                    .zip(ys[o1..o2].par_iter())
+                    .for_each(|(x, &y)| *x = f(*x, y)),
+                None => xs
+                    .iter_mut()
+                    .zip(ys_l.strided_index())
+                    .for_each(|(x, i)| *x = f(*x, ys[i])),
+            }
+            Ok(())
+        }
+
+        use crate::core::backend::BackendStorage;
+        use CpuStorage as C;
+        let dtype = s1.dtype();
+        match (s1, s2) {
+            (C::BF16(s1), C::BF16(s2)) => inner::<half::bf16>(s1, l1, s2, l2, self.alpha),
+            (C::F16(s1), C::F16(s2)) => inner::<half::f16>(s1, l1, s2, l2, self.alpha),
+            (C::F32(s1), C::F32(s2)) => inner::<f32>(s1, l1, s2, l2, self.alpha),
+            (C::F64(s1), C::F64(s2)) => inner::<f64>(s1, l1, s2, l2, self.alpha),
+            _ => crate::bail!("unsupported dtype for add-scaled {dtype:?}"),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &mut crate::core::CudaStorage,
+        l1: &Layout,
+        s2: &crate::core::CudaStorage,
+        l2: &Layout,
+    ) -> Result<()> {
+        use crate::core::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig, ValidAsZeroBits,
+        };
+        use crate::core::cuda_backend::{kernel_name, kernels, Map2InPlace, WrapErr};
+        use crate::core::{CudaDevice, WithDType};
+
+        struct S<'a> {
+            xs_l: &'a Layout,
+            alpha: f64,
+        }
+        impl Map2InPlace for S<'_> {
+            fn f<T: DeviceRepr + WithDType + ValidAsZeroBits>(
+                &self,
+                xs: &mut CudaSlice<T>,
+                _xs_shape: &Shape,
+                ys: &CudaSlice<T>,
+                ys_l: &Layout,
+                dev: &CudaDevice,
+            ) -> Result<()> {
+                let mut xs = match self.xs_l.contiguous_offsets() {
+                    None => crate::bail!("add-scaled: xs has to be contiguous"),
+                    Some((o1, o2)) => xs.slice_mut(o1..o2),
+                };
+                let dims = ys_l.dims();
+                let el = ys_l.shape().elem_count();
+                let cfg = LaunchConfig::for_num_elems(el as u32);
+                let ds = dev.htod_copy([dims, ys_l.stride()].concat()).w()?;
+                let ys = &ys.slice(ys_l.start_offset()..);
+                let func =
+                    dev.get_or_load_func(&kernel_name::<T>("add_scaled"), kernels::TERNARY)?;
+                let params = (el, dims.len(), &ds, &mut xs, ys, self.alpha);
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                Ok(())
+            }
+        }
+
+        use crate::core::backend::BackendStorage;
+        let dev = s1.device().clone();
+        S {
+            xs_l: l1,
+            alpha: self.alpha,
+        }
+        .map(&mut s1.slice, l1.shape(), &s2.slice, l2, &dev)
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        s1: &mut crate::core::MetalStorage,
+        l1: &Layout,
+        s2: &crate::core::MetalStorage,
+        l2: &Layout,
+    ) -> Result<()> {
+        use crate::core::backend::BackendStorage;
+        use crate::core::metal_backend::buffer_o;
+        if !l1.is_contiguous() {
+            crate::bail!("add-scaled: xs has to be contiguous");
+        }
+        let device = s1.device();
+        let command_buffer = device.command_buffer()?;
+        let kernels = device.kernels();
+        let name = match (s1.dtype(), s2.dtype()) {
+            (DType::F32, DType::F32) => "add_scaled_f32",
+            (DType::F16, DType::F16) => "add_scaled_f16",
+            (DType::BF16, DType::BF16) => "add_scaled_bf16",
+            (dt1, dt2) => {
+                crate::bail!("add-scaled is not implemented for {dt1:?} {dt2:?}")
+            }
+        };
+        crate::metal_kernels::call_add_scaled_strided(
+            device.metal_device(),
+            &command_buffer,
+            kernels,
+            name,
+            l2.dims(),
+            buffer_o(s1.buffer(), l1, s1.dtype()),
+            buffer_o(s2.buffer(), l2, s2.dtype()),
+            l2.stride(),
+            self.alpha as f32,
+        )
+        .map_err(crate::core::Error::wrap)?;
+        Ok(())
+    }
+}
+
+/// Adds a scaled tensor in place: `x += alpha * y`, e.g. to accumulate residual streams without
+/// allocating a new tensor.
+///
+/// `y` is broadcast to the shape of `x` and both must have the same dtype. If `x` is not
+/// contiguous or shares its storage with `y`, it is first copied so that the update does not
+/// write into `y`.
+pub fn inplace_add_scaled(x: &mut Tensor, y: &Tensor, alpha: f64) -> Result<()> {
+    if x.dtype() != y.dtype() {
+        crate::bail!(
+            "inplace_add_scaled dtype mismatch, x: {:?}, y: {:?}",
+            x.dtype(),
+            y.dtype()
+        )
+    }
+    let y = y.broadcast_as(x.shape())?;
+    if !x.is_contiguous() {
+        *x = x.contiguous()?;
+    } else if x.same_storage(&y) {
+        *x = x.copy()?;
+    }
+    x.inplace_op2(&y, &AddScaled { alpha })
+}
+
+struct SoftmaxLastDim;
+
+impl crate::core::InplaceOp1 for SoftmaxLastDim {
+    fn name(&self) -> &'static str {
+        "softmax-last-dim"
+    }
+
+    fn cpu_fwd(&self, storage: &mut CpuStorage, layout: &Layout) -> Result<()> {
+        fn softmax<T: crate::core::WithDType + num_traits::Float>(
+            src: &mut [T],
+            layout: &Layout,
+        ) -> Result<()> {
+            let src = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &mut src[o1..o2],
+            };
+            let dims = layout.shape().dims();
+            let dim_m1 = dims[dims.len() - 1];
+            src.par_chunks_mut(dim_m1).for_each(|src| {
+                let mut max = T::neg_infinity();
+                unsafe { T::vec_reduce_max(src.as_ptr(), &mut max, dim_m1) };
+                // Fully masked rows output zeros rather than NaN.
+                if max == T::neg_infinity() {
+                    src.fill(T::zero());
+                    return;
+                }
+                for s in src.iter_mut() {
+                    *s = (*s - max).exp();
+                }
+                let mut sum_exp = T::zero();
+                unsafe { T::vec_reduce_sum(src.as_ptr(), &mut sum_exp, dim_m1) };
+                for d in src.iter_mut() {
+                    *d /= sum_exp
+                }
+            });
+            Ok(())
+        }
+
+        match storage {
+            CpuStorage::BF16(slice) => softmax::<half::bf16>(slice, layout),
+            CpuStorage::F16(slice) => softmax::<half::f16>(slice, layout),
+            CpuStorage::F32(slice) => softmax::<f32>(slice, layout),
+            CpuStorage::F64(slice) => softmax::<f64>(slice, layout),
+            _ => crate::bail!("unsupported dtype for softmax {:?}", storage),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(&self, storage: &mut crate::core::CudaStorage, layout: &Layout) -> Result<()> {
+        use crate::core::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use crate::core::cuda_backend::{kernel_name, kernels, Map1InPlace, WrapErr};
+        use crate::core::{CudaDevice, WithDType};
+
+        struct S;
+        impl Map1InPlace for S {
+            fn f<T: DeviceRepr + WithDType>(
+                &self,
+                src: &mut CudaSlice<T>,
+                dev: &CudaDevice,
+                layout: &Layout,
+            ) -> Result<()> {
+                let src = match layout.contiguous_offsets() {
+                    None => crate::bail!("input has to be contiguous"),
+                    Some((o1, o2)) => src.slice(o1..o2),
+                };
+                let el = layout.shape().elem_count();
+                let dims = layout.shape().dims();
+                let dim_m1 = dims[dims.len() - 1];
+                let (n_rows, n_cols) = (el / dim_m1, dim_m1);
+
+                let func = dev.get_or_load_func(&kernel_name::<T>("softmax"), kernels::REDUCE)?;
+                let cfg = LaunchConfig {
+                    grid_dim: (n_rows as u32, 1, 1),
+                    block_dim: (1, 32, 1),
+                    shared_mem_bytes: 0,
+                };
+                let params = (&src, &src, n_cols as i32);
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                Ok(())
+            }
+        }
+
+        use crate::core::backend::BackendStorage;
+        let dev = storage.device().clone();
+
+        S.map(&mut storage.slice, &dev, layout)?;
+
+        Ok(())
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(&self, storage: &mut crate::core::MetalStorage, layout: &Layout) -> Result<()> {
+        use crate::core::backend::BackendStorage;
+        let device = storage.device();
+        let command_buffer = device.command_buffer()?;
+        let kernels = device.kernels();
+        let name = match storage.dtype() {
+            DType::F32 => "softmax_f32",
+            DType::F16 => "softmax_f16",
+            DType::BF16 => "softmax_bf16",
+            dtype => crate::bail!("softmax-last-dim is not implemented for {dtype:?}"),
+        };
+
+        let n = layout.stride().len();
+        if !(layout.is_contiguous() && layout.stride()[n - 1] == 1) {
+            crate::bail!("Non contiguous softmax-last-dim is not implemented");
+        }
+
+        let last_dim = layout.dims()[layout.shape().rank() - 1];
+        let elem_count = layout.shape().elem_count();
+        crate::metal_kernels::call_last_softmax(
+            device.metal_device(),
+            &command_buffer,
+            kernels,
+            name,
+            elem_count,
+            last_dim,
+            storage.buffer(),
+            layout.start_offset() * storage.dtype().size_in_bytes(),
+            storage.buffer(),
+            layout.start_offset() * storage.dtype().size_in_bytes(),
+        )
+        .map_err(crate::core::Error::wrap)?;
+        Ok(())
+    }
+}
+
+impl crate::core::CustomOp1 for SoftmaxLastDim {
+    fn name(&self) -> &'static str {
+        "softmax-last-dim"
+    }
+
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        fn softmax<T: crate::core::WithDType + num_traits::Float>(
+            src: &[T],
+            layout: &Layout,
+        ) -> Result<(CpuStorage, Shape)> {
+            let src = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &src[o1..o2],
+            };
+            let el_count = layout.shape().elem_count();
+            let dims = layout.shape().dims();
+            let dim_m1 = dims[dims.len() - 1];
+            let mut dst = vec![T::zero(); el_count];
+            src.par_chunks(dim_m1)
+                .zip(dst.par_chunks_mut(dim_m1))
+                .for_each(|(src, dst)| {
+                    let mut max = T::neg_infinity();
+                    unsafe { T::vec_reduce_max(src.as_ptr(), &mut max, dim_m1) };
+                    // Fully masked rows output zeros rather than NaN, `dst` is zero initialized.
+                    if max == T::neg_infinity() {
+                        return;
+                    }
+                    for (s, d) in src.iter().zip(dst.iter_mut()) {
+                        *d = (*s - max).exp();
+                    }
+                    let mut sum_exp = T::zero();
+                    unsafe { T::vec_reduce_sum(dst.as_ptr(), &mut sum_exp, dim_m1) };
+                    for d in dst.iter_mut() {
+                        *d /= sum_exp
+                    }
+                });
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, Shape::from_dims(dims)))
+        }
+
+        match storage {
+            CpuStorage::BF16(slice) => softmax::<half::bf16>(slice, layout),
+            CpuStorage::F16(slice) => softmax::<half::f16>(slice, layout),
+            CpuStorage::F32(slice) => softmax::<f32>(slice, layout),
+            CpuStorage::F64(slice) => softmax::<f64>(slice, layout),
+            _ => crate::bail!("unsupported dtype for softmax {:?}", storage),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        storage: &crate::core::CudaStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::CudaStorage, Shape)> {
+        use crate::core::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use crate::core::cuda_backend::{kernel_name, kernels, Map1, WrapErr};
+        use crate::core::{CudaDevice, WithDType};
+
+        struct S;
+        impl Map1 for S {
+            fn f<T: DeviceRepr + WithDType>(
+                &self,
+                src: &CudaSlice<T>,
+                dev: &CudaDevice,
+                layout: &Layout,
+            ) -> Result<CudaSlice<T>> {
+                let src = match layout.contiguous_offsets() {
+                    None => crate::bail!("input has to be contiguous"),
+                    Some((o1, o2)) => src.slice(o1..o2),
+                };
+                let el = layout.shape().elem_count();
+                let dims = layout.shape().dims();
+                let dim_m1 = dims[dims.len() - 1];
+                let (n_rows, n_cols) = (el / dim_m1, dim_m1);
+
+                let cfg = LaunchConfig {
+                    grid_dim: (n_rows as u32, 1, 1),
+                    block_dim: (1, 32, 1),
+                    shared_mem_bytes: 0,
+                };
+                let func = dev.get_or_load_func(&kernel_name::<T>("softmax"), kernels::REDUCE)?;
+                // SAFETY: Set later by running the kernel.
+                let dst = unsafe { dev.alloc::<T>(el) }.w()?;
+                let params = (&src, &dst, n_cols as i32);
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                Ok(dst)
+            }
+        }
+
+        use crate::core::backend::BackendStorage;
+        let dev = storage.device();
+        let slice = S.map(&storage.slice, dev, layout)?;
+        let dst = crate::core::cuda_backend::CudaStorage {
+            slice,
+            device: dev.clone(),
+        };
+        Ok((dst, layout.shape().clone()))
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        storage: &crate::core::MetalStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::MetalStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+        let device = storage.device();
+        let command_buffer = device.command_buffer()?;
+        let kernels = device.kernels();
+        let name = match storage.dtype() {
+            DType::F32 => "softmax_f32",
+            DType::F16 => "softmax_f16",
+            DType::BF16 => "softmax_bf16",
+            dtype => crate::bail!("softmax-last-dim is not implemented for {dtype:?}"),
+        };
+
+        let n = layout.stride().len();
+        if !(layout.is_contiguous() && layout.stride()[n - 1] == 1) {
+            crate::bail!("Non contiguous softmax-last-dim is not implemented");
+        }
+
+        let last_dim = layout.dims()[layout.shape().rank() - 1];
+        let elem_count = layout.shape().elem_count();
+        let output = device.new_buffer(elem_count, storage.dtype(), "softmax")?;
+        crate::metal_kernels::call_last_softmax(
+            device.metal_device(),
+            &command_buffer,
+            kernels,
+            name,
+            elem_count,
+            last_dim,
+            storage.buffer(),
+            layout.start_offset() * storage.dtype().size_in_bytes(),
+            &output,
+            0,
+        )
+        .map_err(crate::core::Error::wrap)?;
+        let newstorage =
+            crate::core::MetalStorage::new(output, device.clone(), elem_count, storage.dtype());
+        Ok((newstorage, layout.shape().clone()))
+    }
+}
+
+/// Softmax over the last dim. Fully masked rows, i.e. rows holding only `-inf`, output zeros
+/// rather than `NaN` on every backend.
+pub fn softmax_last_dim(xs: &Tensor) -> Result<Tensor> {
+    xs.apply_op1_no_bwd(&SoftmaxLastDim)
+}
+
+pub fn inplace_softmax_last_dim(xs: &mut Tensor) -> Result<()> {
+    xs.inplace_op1(&SoftmaxLastDim)
+}
+
+/// Softmax over an arbitrary dim of a contiguous input, viewed as `(n_outer, dim_size, inner)`.
+/// The last dim reuses the `softmax` kernels while other dims use a strided kernel, so that no
+/// transposed copy of the input is needed.
+#[derive(Debug, Clone, Copy)]
+struct SoftmaxDim {
+    dim: usize,
+}
+
+impl SoftmaxDim {
+    fn split_dims(&self, layout: &Layout) -> (usize, usize, usize) {
+        let dims = layout.dims();
+        let n_outer = dims[..self.dim].iter().product();
+        let inner = dims[self.dim + 1..].iter().product();
+        (n_outer, dims[self.dim], inner)
+    }
+}
+
+impl crate::core::CustomOp1 for SoftmaxDim {
+    fn name(&self) -> &'static str {
+        "softmax-dim"
+    }
+
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        fn softmax<T: crate::core::WithDType + num_traits::Float>(
+            src: &[T],
+            layout: &Layout,
+            (_n_outer, dim_size, inner): (usize, usize, usize),
+        ) -> Result<(CpuStorage, Shape)> {
+            let src = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &src[o1..o2],
+            };
+            let mut dst = vec![T::zero(); layout.shape().elem_count()];
+            // The running max and sum are kept for a whole `inner` row at once so that the input
+            // is read sequentially.
+            src.par_chunks(dim_size * inner)
+                .zip(dst.par_chunks_mut(dim_size * inner))
+                .for_each(|(src, dst)| {
+                    let mut max = vec![f64::NEG_INFINITY; inner];
+                    for src in src.chunks(inner) {
+                        for (m, s) in max.iter_mut().zip(src) {
+                            *m = m.max(s.to_f64())
+                        }
+                    }
+                    // Fully masked slices output zeros rather than NaN.
+                    for m in max.iter_mut().filter(|m| **m == f64::NEG_INFINITY) {
+                        *m = 0.
+                    }
+                    let mut sum = vec![0f64; inner];
+                    for src in src.chunks(inner) {
+                        for ((s, m), v) in sum.iter_mut().zip(&max).zip(src) {
+                            *s += (v.to_f64() - m).exp()
+                        }
+                    }
+                    for (src, dst) in src.chunks(inner).zip(dst.chunks_mut(inner)) {
+                        for (((d, v), m), s) in dst.iter_mut().zip(src).zip(&max).zip(&sum) {
+                            let p = if *s == 0. {
+                                0.
+                            } else {
+                                (v.to_f64() - m).exp() / s
+                            };
+                            *d = T::from_f64(p)
+                        }
+                    }
+                });
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, layout.shape().clone()))
+        }
+
+        let dims = self.split_dims(layout);
+        match storage {
+            CpuStorage::BF16(slice) => softmax::<half::bf16>(slice, layout, dims),
+            CpuStorage::F16(slice) => softmax::<half::f16>(slice, layout, dims),
+            CpuStorage::F32(slice) => softmax::<f32>(slice, layout, dims),
+            CpuStorage::F64(slice) => softmax::<f64>(slice, layout, dims),
+            _ => crate::bail!("unsupported dtype for softmax {:?}", storage),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        storage: &crate::core::CudaStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::CudaStorage, Shape)> {
+        use crate::core::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use crate::core::cuda_backend::{kernel_name, kernels, Map1, WrapErr};
+        use crate::core::{CudaDevice, WithDType};
+
+        struct S(SoftmaxDim);
+        impl Map1 for S {
+            fn f<T: DeviceRepr + WithDType>(
+                &self,
+                src: &CudaSlice<T>,
+                dev: &CudaDevice,
+                layout: &Layout,
+            ) -> Result<CudaSlice<T>> {
+                let src = match layout.contiguous_offsets() {
+                    None => crate::bail!("input has to be contiguous"),
+                    Some((o1, o2)) => src.slice(o1..o2),
+                };
+                let el = layout.shape().elem_count();
+                let (n_outer, dim_size, inner) = self.0.split_dims(layout);
+                // SAFETY: Set later by running the kernel.
+                let dst = unsafe { dev.alloc::<T>(el) }.w()?;
+                if inner == 1 {
+                    let cfg = LaunchConfig {
+                        grid_dim: (n_outer as u32, 1, 1),
+                        block_dim: (1, 32, 1),
+                        shared_mem_bytes: 0,
+                    };
+                    let func =
+                        dev.get_or_load_func(&kernel_name::<T>("softmax"), kernels::REDUCE)?;
+                    let params = (&src, &dst, dim_size as i32);
+                    // SAFETY: ffi.
+                    unsafe { func.launch(cfg, params) }.w()?;
+                } else {
+                    // One thread per `(outer, inner)` column.
+                    let cfg = LaunchConfig::for_num_elems((n_outer * inner) as u32);
+                    let func = dev
+                        .get_or_load_func(&kernel_name::<T>("softmax_strided"), kernels::REDUCE)?;
+                    let params = (&src, &dst, n_outer as i32, dim_size as i32, inner as i32);
+                    // SAFETY: ffi.
+                    unsafe { func.launch(cfg, params) }.w()?;
+                }
+                Ok(dst)
+            }
+        }
+
+        use crate::core::backend::BackendStorage;
+        let dev = storage.device();
+        let slice = S(*self).map(&storage.slice, dev, layout)?;
+        let dst = crate::core::cuda_backend::CudaStorage {
+            slice,
+            device: dev.clone(),
+        };
+        Ok((dst, layout.shape().clone()))
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        storage: &crate::core::MetalStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::MetalStorage, Shape)> {
+        // `softmax` moves the reduced dim last before applying the op on metal.
+        if self.dim + 1 != layout.shape().rank() {
+            crate::bail!("softmax-dim on metal only supports the last dim")
+        }
+        crate::core::CustomOp1::metal_fwd(&SoftmaxLastDim, storage, layout)
+    }
+
+    fn bwd(&self, _arg: &Tensor, res: &Tensor, grad_res: &Tensor) -> Result<Option<Tensor>> {
+        // d/dx softmax(x) = s * (g - sum(g * s))
+        let dot = (res * grad_res)?.sum_keepdim(self.dim)?;
+        Ok(Some(res.mul(&grad_res.broadcast_sub(&dot)?)?))
+    }
+}
+
+/// Fused `x - max - log(sum(exp(x - max)))` over the last dim.
+struct LogSoftmaxLastDim;
+
+impl LogSoftmaxLastDim {
+    /// The `max + log(sum(exp(x - max)))` term of a row, `None` for fully masked rows.
+    fn cpu_lse<T: crate::core::WithDType + num_traits::Float>(src: &[T]) -> Option<f64> {
+        let mut max = T::neg_infinity();
+        unsafe { T::vec_reduce_max(src.as_ptr(), &mut max, src.len()) };
+        if max == T::neg_infinity() {
+            return None;
+        }
+        let max = max.to_f64();
+        let sum_exp = src.iter().map(|s| (s.to_f64() - max).exp()).sum::<f64>();
+        Some(max + sum_exp.ln())
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_kernel_name(dtype: DType) -> Result<&'static str> {
+        match dtype {
+            DType::F32 => Ok("log_softmax_f32"),
+            DType::F16 => Ok("log_softmax_f16"),
+            DType::BF16 => Ok("log_softmax_bf16"),
+            dtype => crate::bail!("log-softmax-last-dim is not implemented for {dtype:?}"),
+        }
+    }
+}
+
+impl crate::core::InplaceOp1 for LogSoftmaxLastDim {
+    fn name(&self) -> &'static str {
+        "log-softmax-last-dim"
+    }
+
+    fn cpu_fwd(&self, storage: &mut CpuStorage, layout: &Layout) -> Result<()> {
+        fn log_softmax<T: crate::core::WithDType + num_traits::Float>(
+            src: &mut [T],
+            layout: &Layout,
+        ) -> Result<()> {
+            let src = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &mut src[o1..o2],
+            };
+            let dims = layout.shape().dims();
+            let dim_m1 = dims[dims.len() - 1];
+            src.par_chunks_mut(dim_m1).for_each(|src| {
+                // Fully masked rows output -inf, the log of the zeros output by softmax.
+                match LogSoftmaxLastDim::cpu_lse(src) {
+                    None => src.fill(T::neg_infinity()),
+                    Some(lse) => {
+                        for s in src.iter_mut() {
+                            *s = T::from_f64(s.to_f64() - lse)
+                        }
+                    }
+                }
+            });
+            Ok(())
+        }
+
+        match storage {
+            CpuStorage::BF16(slice) => log_softmax::<half::bf16>(slice, layout),
+            CpuStorage::F16(slice) => log_softmax::<half::f16>(slice, layout),
+            CpuStorage::F32(slice) => log_softmax::<f32>(slice, layout),
+            CpuStorage::F64(slice) => log_softmax::<f64>(slice, layout),
+            _ => crate::bail!("unsupported dtype for log-softmax {:?}", storage),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(&self, storage: &mut crate::core::CudaStorage, layout: &Layout) -> Result<()> {
+        use crate::core::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use crate::core::cuda_backend::{kernel_name, kernels, Map1InPlace, WrapErr};
+        use crate::core::{CudaDevice, WithDType};
+
+        struct S;
+        impl Map1InPlace for S {
+            fn f<T: DeviceRepr + WithDType>(
+                &self,
+                src: &mut CudaSlice<T>,
+                dev: &CudaDevice,
+                layout: &Layout,
+            ) -> Result<()> {
+                let src = match layout.contiguous_offsets() {
+                    None => crate::bail!("input has to be contiguous"),
+                    Some((o1, o2)) => src.slice(o1..o2),
+                };
+                let el = layout.shape().elem_count();
+                let dims = layout.shape().dims();
+                let dim_m1 = dims[dims.len() - 1];
+                let (n_rows, n_cols) = (el / dim_m1, dim_m1);
+
+                let func =
+                    dev.get_or_load_func(&kernel_name::<T>("log_softmax"), kernels::REDUCE)?;
+                let cfg = LaunchConfig {
+                    grid_dim: (n_rows as u32, 1, 1),
+                    block_dim: (1, 32, 1),
+                    shared_mem_bytes: 0,
+                };
+                let params = (&src, &src, n_cols as i32);
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                Ok(())
+            }
+        }
+
+        use crate::core::backend::BackendStorage;
+        let dev = storage.device().clone();
+        S.map(&mut storage.slice, &dev, layout)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(&self, storage: &mut crate::core::MetalStorage, layout: &Layout) -> Result<()> {
+        use crate::core::backend::BackendStorage;
+        let device = storage.device();
+        let command_buffer = device.command_buffer()?;
+        let kernels = device.kernels();
+        let name = Self::metal_kernel_name(storage.dtype())?;
+
+        let n = layout.stride().len();
+        if !(layout.is_contiguous() && layout.stride()[n - 1] == 1) {
+            crate::bail!("Non contiguous log-softmax-last-dim is not implemented");
+        }
+
+        let last_dim = layout.dims()[layout.shape().rank() - 1];
+        let elem_count = layout.shape().elem_count();
+        crate::metal_kernels::call_last_softmax(
+            device.metal_device(),
+            &command_buffer,
+            kernels,
+            name,
+            elem_count,
+            last_dim,
+            storage.buffer(),
+            layout.start_offset() * storage.dtype().size_in_bytes(),
+            storage.buffer(),
+            layout.start_offset() * storage.dtype().size_in_bytes(),
+        )
+        .map_err(crate::core::Error::wrap)?;
+        Ok(())
+    }
+}
+
+impl crate::core::CustomOp1 for LogSoftmaxLastDim {
+    fn name(&self) -> &'static str {
+        "log-softmax-last-dim"
+    }
+
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        fn log_softmax<T: crate::core::WithDType + num_traits::Float>(
+            src: &[T],
+            layout: &Layout,
+        ) -> Result<(CpuStorage, Shape)> {
+            let src = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &src[o1..o2],
+            };
+            let dims = layout.shape().dims();
+            let dim_m1 = dims[dims.len() - 1];
+            let mut dst = vec![T::zero(); layout.shape().elem_count()];
+            src.par_chunks(dim_m1)
+                .zip(dst.par_chunks_mut(dim_m1))
+                .for_each(|(src, dst)| match LogSoftmaxLastDim::cpu_lse(src) {
+                    None => dst.fill(T::neg_infinity()),
+                    Some(lse) => {
+                        for (s, d) in src.iter().zip(dst.iter_mut()) {
+                            *d = T::from_f64(s.to_f64() - lse)
+                        }
+                    }
+                });
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, Shape::from_dims(dims)))
+        }
+
+        match storage {
+            CpuStorage::BF16(slice) => log_softmax::<half::bf16>(slice, layout),
+            CpuStorage::F16(slice) => log_softmax::<half::f16>(slice, layout),
+            CpuStorage::F32(slice) => log_softmax::<f32>(slice, layout),
+            CpuStorage::F64(slice) => log_softmax::<f64>(slice, layout),
+            _ => crate::bail!("unsupported dtype for log-softmax {:?}", storage),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        storage: &crate::core::CudaStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::CudaStorage, Shape)> {
+        use crate::core::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use crate::core::cuda_backend::{kernel_name, kernels, Map1, WrapErr};
+        use crate::core::{CudaDevice, WithDType};
+
+        struct S;
+        impl Map1 for S {
+            fn f<T: DeviceRepr + WithDType>(
+                &self,
+                src: &CudaSlice<T>,
+                dev: &CudaDevice,
+                layout: &Layout,
+            ) -> Result<CudaSlice<T>> {
+                let src = match layout.contiguous_offsets() {
+                    None => crate::bail!("input has to be contiguous"),
+                    Some((o1, o2)) => src.slice(o1..o2),
+                };
+                let el = layout.shape().elem_count();
+                let dims = layout.shape().dims();
+                let dim_m1 = dims[dims.len() - 1];
+                let (n_rows, n_cols) = (el / dim_m1, dim_m1);
+
+                let cfg = LaunchConfig {
+                    grid_dim: (n_rows as u32, 1, 1),
+                    block_dim: (1, 32, 1),
+                    shared_mem_bytes: 0,
+                };
+                let func =
+                    dev.get_or_load_func(&kernel_name::<T>("log_softmax"), kernels::REDUCE)?;
+                // SAFETY: Set later by running the kernel.
+                let dst = unsafe { dev.alloc::<T>(el) }.w()?;
+                let params = (&src, &dst, n_cols as i32);
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                Ok(dst)
+            }
+        }
+
+        use crate::core::backend::BackendStorage;
+        let dev = storage.device();
+        let slice = S.map(&storage.slice, dev, layout)?;
+        let dst = crate::core::cuda_backend::CudaStorage {
+            slice,
+            device: dev.clone(),
+        };
+        Ok((dst, layout.shape().clone()))
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        storage: &crate::core::MetalStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::MetalStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+        let device = storage.device();
+        let command_buffer = device.command_buffer()?;
+        let kernels = device.kernels();
+        let name = Self::metal_kernel_name(storage.dtype())?;
+
+        let n = layout.stride().len();
+        if !(layout.is_contiguous() && layout.stride()[n - 1] == 1) {
+            crate::bail!("Non contiguous log-softmax-last-dim is not implemented");
+        }
+
+        let last_dim = layout.dims()[layout.shape().rank() - 1];
+        let elem_count = layout.shape().elem_count();
+        let output = device.new_buffer(elem_count, storage.dtype(), "log-softmax")?;
+        crate::metal_kernels::call_last_softmax(
+            device.metal_device(),
+            &command_buffer,
+            kernels,
+            name,
+            elem_count,
+            last_dim,
+            storage.buffer(),
+            layout.start_offset() * storage.dtype().size_in_bytes(),
+            &output,
+            0,
+        )
+        .map_err(crate::core::Error::wrap)?;
+        let newstorage =
+            crate::core::MetalStorage::new(output, device.clone(), elem_count, storage.dtype());
+        Ok((newstorage, layout.shape().clone()))
+    }
+}
+
+/// Log-softmax over the last dim in a single kernel. Fully masked rows, i.e. rows holding only
+/// `-inf`, output `-inf` on every backend.
+pub fn log_softmax_last_dim(xs: &Tensor) -> Result<Tensor> {
+    xs.apply_op1_no_bwd(&LogSoftmaxLastDim)
+}
+
+pub fn inplace_log_softmax_last_dim(xs: &mut Tensor) -> Result<()> {
+    xs.inplace_op1(&LogSoftmaxLastDim)
+}
+
+/// Softmax over the last dim that records whether any input was NaN or infinite.
+#[derive(Default)]
+struct SoftmaxLastDimChecked {
+    non_finite: std::sync::atomic::AtomicBool,
+}
+
+impl crate::core::CustomOp1 for SoftmaxLastDimChecked {
+    fn name(&self) -> &'static str {
+        "softmax-last-dim-checked"
+    }
+
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        fn softmax<T: crate::core::WithDType + num_traits::Float>(
+            src: &[T],
+            layout: &Layout,
+            non_finite: &AtomicBool,
+        ) -> Result<(CpuStorage, Shape)> {
+            let src = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &src[o1..o2],
+            };
+            let el_count = layout.shape().elem_count();
+            let dims = layout.shape().dims();
+            let dim_m1 = dims[dims.len() - 1];
+            let mut dst = vec![T::zero(); el_count];
+            src.par_chunks(dim_m1)
+                .zip(dst.par_chunks_mut(dim_m1))
+                .for_each(|(src, dst)| {
+                    // The finiteness check rides along the max scan.
+                    let mut max = T::neg_infinity();
+                    let mut finite = true;
+                    for &s in src {
+                        finite &= s.is_finite();
+                        max = max.max(s);
+                    }
+                    if !finite {
+                        non_finite.store(true, Ordering::Relaxed);
+                    }
+                    for (s, d) in src.iter().zip(dst.iter_mut()) {
+                        *d = (*s - max).exp();
+                    }
+                    let mut sum_exp = T::zero();
+                    unsafe { T::vec_reduce_sum(dst.as_ptr(), &mut sum_exp, dim_m1) };
+                    for d in dst.iter_mut() {
+                        *d /= sum_exp
+                    }
+                });
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, Shape::from_dims(dims)))
+        }
+
+        let non_finite = &self.non_finite;
+        match storage {
+            CpuStorage::BF16(slice) => softmax::<half::bf16>(slice, layout, non_finite),
+            CpuStorage::F16(slice) => softmax::<half::f16>(slice, layout, non_finite),
+            CpuStorage::F32(slice) => softmax::<f32>(slice, layout, non_finite),
+            CpuStorage::F64(slice) => softmax::<f64>(slice, layout, non_finite),
+            _ => crate::bail!("unsupported dtype for softmax-checked {:?}", storage),
+        }
+    }
+}
+
+/// Softmax over the last dim that also reports whether any row of `xs` contained a NaN or an
+/// infinity, so that sampling loops can detect divergence instead of propagating NaN silently.
+///
+/// On the CPU the check is fused in the max scan of the softmax kernel, other devices run a
+/// separate reduction.
+pub fn softmax_last_dim_checked(xs: &Tensor) -> Result<(Tensor, bool)> {
+    if xs.device().is_cpu() {
+        let op = SoftmaxLastDimChecked::default();
+        let out = xs.apply_op1_no_bwd(&op)?;
+        Ok((out, op.non_finite.into_inner()))
+    } else {
+        // `x - x` is NaN exactly for NaN and infinite inputs.
+        let non_finite = (xs - xs)?
+            .ne(0.)?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<u8>()?
+            != 0;
+        Ok((softmax_last_dim(xs)?, non_finite))
+    }
+}
+
+/// Softmax over the last dim of `softcap * tanh(xs * scale / softcap)`, a `softcap` of 0
+/// disables the cap.
+struct SoftcapSoftmaxLastDim {
+    scale: f32,
+    softcap: f32,
+}
+
+impl crate::core::CustomOp1 for SoftcapSoftmaxLastDim {
+    fn name(&self) -> &'static str {
+        "softcap-softmax-last-dim"
+    }
+
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        // The capped logits are computed in f64 as tanh near the cap is too coarse in f16/bf16.
+        fn softmax<T: crate::core::WithDType>(
+            src: &[T],
+            layout: &Layout,
+            scale: f32,
+            softcap: f32,
+        ) -> Result<(CpuStorage, Shape)> {
+            let src = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &src[o1..o2],
+            };
+            let el_count = layout.shape().elem_count();
+            let dims = layout.shape().dims();
+            let dim_m1 = dims[dims.len() - 1];
+            let (scale, softcap) = (scale as f64, softcap as f64);
+            let cap = |v: T| {
+                let v = v.to_f64() * scale;
+                if softcap > 0. {
+                    (v / softcap).tanh() * softcap
+                } else {
+                    v
+                }
+            };
+            let mut dst = vec![T::zero(); el_count];
+            src.par_chunks(dim_m1)
+                .zip(dst.par_chunks_mut(dim_m1))
+                .for_each(|(src, dst)| {
+                    let max = src
+                        .iter()
+                        .map(|&s| cap(s))
+                        .fold(f64::NEG_INFINITY, f64::max);
+                    // Fully masked rows output zeros rather than NaN, `dst` is zero initialized.
+                    if max == f64::NEG_INFINITY {
+                        return;
+                    }
+                    let sum_exp = src.iter().map(|&s| (cap(s) - max).exp()).sum::<f64>();
+                    for (s, d) in src.iter().zip(dst.iter_mut()) {
+                        *d = T::from_f64((cap(*s) - max).exp() / sum_exp);
+                    }
+                });
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, Shape::from_dims(dims)))
+        }
+
+        let (scale, softcap) = (self.scale, self.softcap);
+        match storage {
+            CpuStorage::BF16(slice) => softmax::<half::bf16>(slice, layout, scale, softcap),
+            CpuStorage::F16(slice) => softmax::<half::f16>(slice, layout, scale, softcap),
+            CpuStorage::F32(slice) => softmax::<f32>(slice, layout, scale, softcap),
+            CpuStorage::F64(slice) => softmax::<f64>(slice, layout, scale, softcap),
+            _ => crate::bail!("unsupported dtype for softcap-softmax {:?}", storage),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        storage: &crate::core::CudaStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::CudaStorage, Shape)> {
+        use crate::core::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use crate::core::cuda_backend::{kernel_name, kernels, Map1, WrapErr};
+        use crate::core::{CudaDevice, WithDType};
+
+        struct S {
+            scale: f32,
+            softcap: f32,
+        }
+        impl Map1 for S {
+            fn f<T: DeviceRepr + WithDType>(
+                &self,
+                src: &CudaSlice<T>,
+                dev: &CudaDevice,
+                layout: &Layout,
+            ) -> Result<CudaSlice<T>> {
+                let src = match layout.contiguous_offsets() {
+                    None => crate::bail!("input has to be contiguous"),
+                    Some((o1, o2)) => src.slice(o1..o2),
+                };
+                let el = layout.shape().elem_count();
+                let dims = layout.shape().dims();
+                let dim_m1 = dims[dims.len() - 1];
+                let (n_rows, n_cols) = (el / dim_m1, dim_m1);
+
+                let cfg = LaunchConfig {
+                    grid_dim: (n_rows as u32, 1, 1),
+                    block_dim: (1, 32, 1),
+                    shared_mem_bytes: 0,
+                };
+                let func =
+                    dev.get_or_load_func(&kernel_name::<T>("softcap_softmax"), kernels::REDUCE)?;
+                // SAFETY: Set later by running the kernel.
+                let dst = unsafe { dev.alloc::<T>(el) }.w()?;
+                let params = (&src, &dst, n_cols as i32, self.scale, self.softcap);
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                Ok(dst)
+            }
+        }
+
+        use crate::core::backend::BackendStorage;
+        let dev = storage.device();
+        let slice = S {
+            scale: self.scale,
+            softcap: self.softcap,
+        }
+        .map(&storage.slice, dev, layout)?;
+        let dst = crate::core::cuda_backend::CudaStorage {
+            slice,
+            device: dev.clone(),
+        };
+        Ok((dst, layout.shape().clone()))
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        storage: &crate::core::MetalStorage,
+        layout: &Layout,
+    ) -> Result<(crate::core::MetalStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+        let device = storage.device();
+        let command_buffer = device.command_buffer()?;
+        let kernels = device.kernels();
+        let name = match storage.dtype() {
+            DType::F32 => "softcap_softmax_f32",
+            DType::F16 => "softcap_softmax_f16",
+            DType::BF16 => "softcap_softmax_bf16",
+            dtype => crate::bail!("softcap-softmax-last-dim is not implemented for {dtype:?}"),
+        };
+
+        let n = layout.stride().len();
+        if !(layout.is_contiguous() && layout.stride()[n - 1] == 1) {
+            crate::bail!("Non contiguous softcap-softmax-last-dim is not implemented");
+        }
+
+        let last_dim = layout.dims()[layout.shape().rank() - 1];
+        let elem_count = layout.shape().elem_count();
+        let output = device.new_buffer(elem_count, storage.dtype(), "softcap-softmax")?;
+        crate::metal_kernels::call_last_softcap_softmax(
+            device.metal_device(),
+            &command_buffer,
+            kernels,
+            name,
+            elem_count,
+            last_dim,
+            self.scale,
+            self.softcap,
+            storage.buffer(),
+            layout.start_offset() * storage.dtype().size_in_bytes(),
+            &output,
+            0,
+        )
+        .map_err(crate::core::Error::wrap)?;
+        let newstorage =
+            crate::core::MetalStorage::new(output, device.clone(), elem_count, storage.dtype());
+        Ok((newstorage, layout.shape().clone()))
+    }
+}
+
+/// Final-logit softmax with Gemma-style softcapping, computing
+/// `softmax(softcapping * tanh(logits * scale / softcapping))` over the last dim in a single
+/// pass instead of allocating the scaled and capped logits. A `softcapping` of 0 or 1 disables
+/// the cap, as in the attention kernels.
+///
+/// The GPU kernels support F32/F16/BF16 and accumulate in F32, other dtypes are computed with
+/// separate ops.
+pub fn final_logit_softmax(logits: &Tensor, scale: f32, softcapping: f32) -> Result<Tensor> {
+    let softcap = if softcapping == 1. { 0. } else { softcapping };
+    if softcap < 0. {
+        crate::bail!("final_logit_softmax expects a non-negative softcapping, got {softcapping}")
+    }
+    if logits.device().is_cpu() || matches!(logits.dtype(), DType::F32 | DType::F16 | DType::BF16) {
+        return logits
+            .contiguous()?
+            .apply_op1_no_bwd(&SoftcapSoftmaxLastDim { scale, softcap });
+    }
+    let mut logits = (logits * scale as f64)?;
+    if softcap > 0. {
+        logits = ((logits / softcap as f64)?.tanh()? * softcap as f64)?;
+    }
+    softmax_last_dim(&logits)
+}
+
+/// Temperature softmax over the last dim restricted to the nucleus, the smallest set of most
+/// likely tokens whose probability reaches `p`.
+struct NucleusDistribution {
+    p: f32,
+    temperature: f32,
+}
+
+impl crate::core::CustomOp1 for NucleusDistribution {
+    fn name(&self) -> &'static str {
+        "nucleus-distribution"
+    }
+
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+
+        fn inner<
+            T: crate::core::WithDType
+                + num_traits::Float
+                + num_traits::AsPrimitive<f32>
+                + num_traits::FromPrimitive,
+        >(
+            src: &[T],
+            layout: &Layout,
+            op: &NucleusDistribution,
+        ) -> Result<(CpuStorage, Shape)> {
+            let src = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &src[o1..o2],
+            };
+            let dims = layout.shape().dims();
+            let dim_m1 = dims[dims.len() - 1];
+            let mut dst = vec![T::zero(); src.len()];
+            src.par_chunks(dim_m1)
+                .zip(dst.par_chunks_mut(dim_m1))
+                .for_each(|(src, dst)| {
+                    let inv_temp = op.temperature.recip();
+                    let max = src
+                        .iter()
+                        .map(|v| v.as_())
+                        .fold(f32::NEG_INFINITY, f32::max);
+                    let mut probs: Vec<f32> = src
+                        .iter()
+                        .map(|v| ((v.as_() - max) * inv_temp).exp())
+                        .collect();
+                    let sum_exp = probs.iter().sum::<f32>();
+                    probs.iter_mut().for_each(|v| *v /= sum_exp);
+
+                    let mut order: Vec<usize> = (0..dim_m1).collect();
+                    order.sort_unstable_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+                    // The most likely token is always kept, even when it alone exceeds `p`.
+                    let mut kept = 0;
+                    let mut mass = 0f32;
+                    for &i in &order {
+                        mass += probs[i];
+                        kept += 1;
+                        if mass >= op.p {
+                            break;
+                        }
+                    }
+                    for &i in &order[..kept] {
+                        dst[i] = T::from_f32(probs[i] / mass).unwrap_or_else(T::nan);
+                    }
+                });
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, Shape::from_dims(dims)))
+        }
+
+        match storage {
+            CpuStorage::BF16(slice) => inner::<half::bf16>(slice, layout, self),
+            CpuStorage::F16(slice) => inner::<half::f16>(slice, layout, self),
+            CpuStorage::F32(slice) => inner::<f32>(slice, layout, self),
+            CpuStorage::F64(slice) => inner::<f64>(slice, layout, self),
+            _ => crate::bail!(
+                "unsupported dtype for nucleus-distribution {:?}",
+                storage.dtype()
+            ),
+        }
+    }
+}
+
+/// Sampling distribution for nucleus (top-p) sampling: applies `temperature` to `logits`, keeps
+/// the smallest set of most likely tokens whose probability reaches `p` and renormalizes them
+/// over the last dim, all other tokens getting a probability of 0. The most likely token is kept
+/// even if its probability alone exceeds `p`.
+///
+/// This runs as a single pass per row on the CPU, tensors on other devices are copied there and
+/// back.
+pub fn nucleus_distribution(logits: &Tensor, p: f32, temperature: f32) -> Result<Tensor> {
+    if p.is_nan() || p <= 0. || p > 1. {
+        crate::bail!("nucleus_distribution expects p in (0, 1], got {p}")
+    }
+    if temperature.is_nan() || temperature <= 0. {
+        crate::bail!("nucleus_distribution expects a positive temperature, got {temperature}")
+    }
+    let op = NucleusDistribution { p, temperature };
+    if logits.device().is_cpu() {
+        logits.contiguous()?.apply_op1_no_bwd(&op)
+    } else {
+        logits
+            .to_device(&crate::core::Device::Cpu)?
+            .contiguous()?
+            .apply_op1_no_bwd(&op)?
+            .to_device(logits.device())
+    }
+}
+
+struct SoftmaxLastDimTemp;
+
+impl crate::core::CustomOp2 for SoftmaxLastDimTemp {
+    fn name(&self) -> &'static str {
+        "softmax-last-dim-temp"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        fn softmax<T: crate::core::WithDType + num_traits::Float>(
+            src: &[T],
+            layout: &Layout,
+            temps: &[T],
+            temps_l: &Layout,
+        ) -> Result<(CpuStorage, Shape)> {
+            let src = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &src[o1..o2],
+            };
+            let temps = match temps_l.contiguous_offsets() {
+                None => crate::bail!("temps has to be contiguous"),
+                Some((o1, o2)) => &temps[o1..o2],
+            };
+            let el_count = layout.shape().elem_count();
+            let dims = layout.shape().dims();
+            let dim_m1 = dims[dims.len() - 1];
+            let mut dst = vec![T::zero(); el_count];
+            src.par_chunks(dim_m1)
+                .zip(dst.par_chunks_mut(dim_m1))
+                .zip(temps.par_iter())
+                .for_each(|((src, dst), &temp)| {
+                    let inv_temp = temp.recip();
+                    let mut max = T::neg_infinity();
+                    unsafe { T::vec_reduce_max(src.as_ptr(), &mut max, dim_m1) };
+                    for (s, d) in src.iter().zip(dst.iter_mut()) {
+                        *d = ((*s - max) * inv_temp).exp();
+                    }
+                    let mut sum_exp = T::zero();
+                    unsafe { T::vec_reduce_sum(dst.as_ptr(), &mut sum_exp, dim_m1) };
+                    for d in dst.iter_mut() {
+                        *d /= sum_exp
+                    }
+                });
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, Shape::from_dims(dims)))
+        }
+
+        match (s1, s2) {
+            (CpuStorage::BF16(s1), CpuStorage::BF16(s2)) => softmax::<half::bf16>(s1, l1, s2, l2),
+            (CpuStorage::F16(s1), CpuStorage::F16(s2)) => softmax::<half::f16>(s1, l1, s2, l2),
+            (CpuStorage::F32(s1), CpuStorage::F32(s2)) => softmax::<f32>(s1, l1, s2, l2),
+            (CpuStorage::F64(s1), CpuStorage::F64(s2)) => softmax::<f64>(s1, l1, s2, l2),
+            _ => crate::bail!("unsupported dtype for softmax-temp {:?}", s1),
+        }
+    }
+}
+
+/// Softmax over the last dimension with a per-row temperature, i.e. each row of logits is
+/// divided by its own temperature before the softmax.
+///
+/// `temps` must broadcast to the shape of `xs` without its last dimension, e.g. a `(batch,)`
+/// tensor for `(batch, vocab)` logits. Temperatures must be strictly positive.
+pub fn softmax_last_dim_temp_vec(xs: &Tensor, temps: &Tensor) -> Result<Tensor> {
+    let dims = xs.dims();
+    if dims.is_empty() {
+        crate::bail!("softmax-temp expects xs of rank at least 1");
+    }
+    if temps.elem_count() > 0 {
+        let min = temps
+            .flatten_all()?
+            .min(0)?
+            .to_dtype(DType::F32)?
+            .to_scalar::<f32>()?;
+        if min.is_nan() || min <= 0. {
+            crate::bail!("softmax-temp expects positive temperatures, got a minimum of {min}")
+        }
+    }
+    let temps = temps
+        .broadcast_as(&dims[..dims.len() - 1])?
+        .to_dtype(xs.dtype())?;
+    if xs.device().is_cpu() {
+        xs.contiguous()?
+            .apply_op2_no_bwd(&temps.contiguous()?, &SoftmaxLastDimTemp)
+    } else {
+        softmax_last_dim(&xs.broadcast_div(&temps.unsqueeze(D::Minus1)?)?)
+    }
+}
+
+struct SegmentSoftmax {
+    offsets: Vec<usize>,
+}
+
+impl crate::core::CustomOp1 for SegmentSoftmax {
+    fn name(&self) -> &'static str {
+        "segment-softmax"
+    }
+
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        fn softmax<T: crate::core::WithDType + num_traits::Float>(
+            src: &[T],
+            layout: &Layout,
+            offsets: &[usize],
+        ) -> Result<(CpuStorage, Shape)> {
+            let src = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &src[o1..o2],
+            };
+            let el_count = layout.shape().elem_count();
+            let dims = layout.shape().dims();
+            let dim_m1 = dims[dims.len() - 1];
+            let mut dst = vec![T::zero(); el_count];
+            src.par_chunks(dim_m1)
+                .zip(dst.par_chunks_mut(dim_m1))
+                .for_each(|(src, dst)| {
+                    for seg in offsets.windows(2) {
+                        let (start, end) = (seg[0], seg[1]);
+                        if start == end {
+                            continue;
+                        }
+                        let src = &src[start..end];
+                        let dst = &mut dst[start..end];
+                        let mut max = T::neg_infinity();
+                        unsafe { T::vec_reduce_max(src.as_ptr(), &mut max, end - start) };
+                        for (s, d) in src.iter().zip(dst.iter_mut()) {
+                            *d = (*s - max).exp();
+                        }
+                        let mut sum_exp = T::zero();
+                        unsafe { T::vec_reduce_sum(dst.as_ptr(), &mut sum_exp, end - start) };
+                        for d in dst.iter_mut() {
+                            *d /= sum_exp
+                        }
+                    }
+                });
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, Shape::from_dims(dims)))
+        }
+
+        match storage {
+            CpuStorage::BF16(slice) => softmax::<half::bf16>(slice, layout, &self.offsets),
+            CpuStorage::F16(slice) => softmax::<half::f16>(slice, layout, &self.offsets),
+            CpuStorage::F32(slice) => softmax::<f32>(slice, layout, &self.offsets),
+            CpuStorage::F64(slice) => softmax::<f64>(slice, layout, &self.offsets),
+            _ => crate::bail!("unsupported dtype for segment-softmax {:?}", storage),
+        }
+    }
+}
+
+/// Softmax computed independently within segments of the last dimension.
+///
+/// `offsets` holds the segment boundaries: it must start at 0, be non-decreasing and end at the
+/// size of the last dimension, e.g. `[0, 3, 8]` splits a last dimension of 8 into segments of
+/// 3 and 5 elements. Every row of `xs` uses the same segmentation.
+pub fn segment_softmax(xs: &Tensor, offsets: &[usize]) -> Result<Tensor> {
+    let dim_m1 = xs.dim(D::Minus1)?;
+    match (offsets.first(), offsets.last()) {
+        (Some(0), Some(&last)) if last == dim_m1 => {}
+        _ => crate::bail!(
+            "segment-softmax offsets must start at 0 and end at {dim_m1}, got {offsets:?}"
+        ),
+    }
+    if offsets.windows(2).any(|w| w[0] > w[1]) {
+        crate::bail!("segment-softmax offsets must be non-decreasing, got {offsets:?}")
+    }
+    if xs.device().is_cpu() {
+        xs.contiguous()?.apply_op1_no_bwd(&SegmentSoftmax {
+            offsets: offsets.to_vec(),
+        })
+    } else {
+        let segments = offsets
+            .windows(2)
+            .filter(|w| w[0] < w[1])
+            .map(|w| softmax_last_dim(&xs.narrow(D::Minus1, w[0], w[1] - w[0])?))
+            .collect::<Result<Vec<_>>>()?;
+        Tensor::cat(&segments, D::Minus1)
+    }
+}
+
+/// Concatenates variable-length sequences along `dim` into a single packed tensor, avoiding the
+/// padding of a rectangular batch.
+///
+/// Returns the packed tensor together with the sequence offsets, which start at 0 and end at the
+/// packed length (the cumulative sequence lengths), e.g. `[0, 3, 8]` for sequences of 3 and 5
+/// positions. All the other dimensions have to match.
+pub fn pack_sequences(tensors: &[Tensor], dim: usize) -> Result<(Tensor, Vec<usize>)> {
+    if tensors.is_empty() {
+        crate::bail!("pack_sequences expects at least one sequence")
+    }
+    let mut offsets = Vec::with_capacity(tensors.len() + 1);
+    offsets.push(0);
+    for xs in tensors {
+        offsets.push(offsets[offsets.len() - 1] + xs.dim(dim)?);
+    }
+    Ok((Tensor::cat(tensors, dim)?, offsets))
+}
+
+/// Splits a tensor packed by [`pack_sequences`] back into its sequences along `dim`.
+///
+/// `offsets` has to start at 0, be non-decreasing and end at the packed length.
+pub fn unpack_sequences(packed: &Tensor, offsets: &[usize], dim: usize) -> Result<Vec<Tensor>> {
+    let packed_len = packed.dim(dim)?;
+    match (offsets.first(), offsets.last()) {
+        (Some(0), Some(&last)) if last == packed_len => {}
+        _ => crate::bail!(
+            "unpack_sequences offsets must start at 0 and end at {packed_len}, got {offsets:?}"
+        ),
+    }
+    if offsets.windows(2).any(|w| w[0] > w[1]) {
+        crate::bail!("unpack_sequences offsets must be non-decreasing, got {offsets:?}")
+    }
+    offsets
+        .windows(2)
+        .map(|w| packed.narrow(dim, w[0], w[1] - w[0]))
+        .collect()
+}
+
+struct SoftmaxMatmul {
+    scale: f32,
+    /// Additive mask broadcast to the shape of the scores, read through its strides.
+    mask: Option<Tensor>,
+}
+
+impl SoftmaxMatmul {
+    /// Number of keys folded into the output at once.
+    const BLOCK: usize = 64;
+}
+
+impl crate::core::CustomOp2 for SoftmaxMatmul {
+    fn name(&self) -> &'static str {
+        "softmax-matmul"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+        use num_traits::{AsPrimitive, FromPrimitive};
+
+        fn inner<T: crate::core::WithDType + AsPrimitive<f32> + FromPrimitive>(
+            scores: &[T],
+            scores_l: &Layout,
+            v: &[T],
+            v_l: &Layout,
+            mask: Option<(&[T], &Layout)>,
+            scale: f32,
+        ) -> Result<(CpuStorage, Shape)> {
+            let scores = match scores_l.contiguous_offsets() {
+                None => crate::bail!("scores has to be contiguous"),
+                Some((o1, o2)) => &scores[o1..o2],
+            };
+            let v = match v_l.contiguous_offsets() {
+                None => crate::bail!("v has to be contiguous"),
+                Some((o1, o2)) => &v[o1..o2],
+            };
+            let dims = scores_l.dims();
+            let rank = dims.len();
+            let (q_len, kv_len) = (dims[rank - 2], dims[rank - 1]);
+            let d = v_l.dims()[rank - 1];
+            let mut out_dims = dims.to_vec();
+            out_dims[rank - 1] = d;
+
+            let mut dst = vec![T::zero(); scores.len() / kv_len * d];
+            dst.par_chunks_mut(d).enumerate().for_each(|(row, dst)| {
+                let scores = &scores[row * kv_len..(row + 1) * kv_len];
+                let v = &v[(row / q_len) * kv_len * d..(row / q_len + 1) * kv_len * d];
+                // Offset and stride of this row in the (broadcasted) mask.
+                let mask = mask.map(|(mask, mask_l)| {
+                    let stride = mask_l.stride();
+                    let mut offset = mask_l.start_offset();
+                    let mut rem = row;
+                    for i in (0..rank - 1).rev() {
+                        offset += (rem % dims[i]) * stride[i];
+                        rem /= dims[i];
+                    }
+                    (mask, offset, stride[rank - 1])
+                });
+
+                let mut max = f32::NEG_INFINITY;
+                let mut sum = 0f32;
+                let mut acc = vec![0f32; d];
+                let mut block = [0f32; SoftmaxMatmul::BLOCK];
+                for start in (0..kv_len).step_by(SoftmaxMatmul::BLOCK) {
+                    let len = SoftmaxMatmul::BLOCK.min(kv_len - start);
+                    let block = &mut block[..len];
+                    for (j, b) in block.iter_mut().enumerate() {
+                        *b = scores[start + j].as_() * scale;
+                        if let Some((mask, offset, stride)) = mask {
+                            *b += mask[offset + (start + j) * stride].as_();
+                        }
+                    }
+                    let block_max = block.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                    let new_max = max.max(block_max);
+                    if new_max == f32::NEG_INFINITY {
+                        // Everything so far is masked out.
+                        continue;
+                    }
+                    let factor = (max - new_max).exp();
+                    sum *= factor;
+                    acc.iter_mut().for_each(|a| *a *= factor);
+                    for (j, &b) in block.iter().enumerate() {
+                        let p = (b - new_max).exp();
+                        sum += p;
+                        let v = &v[(start + j) * d..(start + j + 1) * d];
+                        for (a, &v) in acc.iter_mut().zip(v) {
+                            *a += p * v.as_();
+                        }
+                    }
+                    max = new_max;
+                }
+                for (dst, a) in dst.iter_mut().zip(acc) {
+                    // Fully masked rows output zeros, as with `softmax_last_dim`.
+                    *dst = if sum == 0. {
+                        T::zero()
+                    } else {
+                        T::from_f32(a / sum).unwrap_or_else(T::zero)
+                    };
+                }
+            });
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, Shape::from_dims(&out_dims)))
+        }
+
+        let mask_storage = self.mask.as_ref().map(|mask| mask.storage_and_layout());
+        let mask = match &mask_storage {
+            Some((storage, layout)) => match &**storage {
+                crate::core::Storage::Cpu(storage) => Some((storage, *layout)),
+                _ => crate::bail!("softmax-matmul mask must be a cpu tensor"),
+            },
+            None => None,
+        };
+
+        use CpuStorage as C;
+        let scale = self.scale;
+        match (s1, s2, mask) {
+            (C::BF16(s), C::BF16(v), None) => inner::<half::bf16>(s, l1, v, l2, None, scale),
+            (C::F16(s), C::F16(v), None) => inner::<half::f16>(s, l1, v, l2, None, scale),
+            (C::F32(s), C::F32(v), None) => inner::<f32>(s, l1, v, l2, None, scale),
+            (C::BF16(s), C::BF16(v), Some((C::BF16(m), m_l))) => {
+                inner::<half::bf16>(s, l1, v, l2, Some((m.as_slice(), m_l)), scale)
+            }
+            (C::F16(s), C::F16(v), Some((C::F16(m), m_l))) => {
+                inner::<half::f16>(s, l1, v, l2, Some((m.as_slice(), m_l)), scale)
+            }
+            (C::F32(s), C::F32(v), Some((C::F32(m), m_l))) => {
+                inner::<f32>(s, l1, v, l2, Some((m.as_slice(), m_l)), scale)
+            }
+            _ => crate::bail!("unsupported dtypes for softmax-matmul {:?}", s1.dtype()),
+        }
+    }
+}
+
+/// Computes `softmax(scores * scale + mask) @ v` where `scores` is `(.., seq, kv_seq)` and `v` is
+/// `(.., kv_seq, hidden)` with the same leading dimensions.
+///
+/// On the cpu the keys are processed in blocks with an online softmax, each block being folded
+/// into the output directly so the normalized attention matrix is never materialized. Other
+/// devices run the softmax and the matmul separately. `mask` must broadcast to the scores shape
+/// and have the same dtype.
+pub fn softmax_matmul(
+    scores: &Tensor,
+    v: &Tensor,
+    scale: f32,
+    mask: Option<&Tensor>,
+) -> Result<Tensor> {
+    let rank = scores.rank();
+    if rank < 2 || v.rank() != rank {
+        crate::bail!(
+            "softmax_matmul expects scores and v of the same rank >= 2, got {:?} and {:?}",
+            scores.shape(),
+            v.shape()
+        )
+    }
+    if scores.dims()[..rank - 2] != v.dims()[..rank - 2]
+        || scores.dim(rank - 1)? != v.dim(rank - 2)?
+    {
+        crate::bail!(
+            "softmax_matmul shape mismatch between scores {:?} and v {:?}",
+            scores.shape(),
+            v.shape()
+        )
+    }
+    if scores.device().is_cpu() {
+        let mask = match mask {
+            Some(mask) => Some(mask.broadcast_as(scores.shape())?),
+            None => None,
+        };
+        scores
+            .contiguous()?
+            .apply_op2_no_bwd(&v.contiguous()?, &SoftmaxMatmul { scale, mask })
+    } else {
+        let mut att = (scores * scale as f64)?;
+        if let Some(mask) = mask {
+            att = att.broadcast_add(mask)?;
+        }
+        softmax_last_dim(&att)?.matmul(v)
+    }
+}
+
+/// `xs` is `(bs, seq, kv_seq)` or `(bs, heads, seq, kv_seq)`; the `(seq, kv_seq)` mask is
+/// shared by every batch and head.
+fn check_attn_softmax_layouts(a_l: &Layout, mask_l: &Layout) -> Result<()> {
+    let rank = a_l.dims().len();
+    if rank != 3 && rank != 4 {
+        crate::bail!("attn-softmax-last-dim expects xs of rank 3 or 4, got rank {rank}");
+    }
+    if mask_l.dims().len() != 2 {
+        crate::bail!(
+            "attn-softmax-last-dim expects mask of rank 2, got rank {}",
+            mask_l.dims().len()
+        );
+    }
+    if mask_l.dim(D::Minus1)? != a_l.dim(D::Minus1)?
+        || mask_l.dim(D::Minus2)? != a_l.dim(D::Minus2)?
+    {
+        crate::bail!("attn-softmax-last-dim expects last 2 dims to match xs last 2 dims");
+    }
+    Ok(())
+}
+
+struct AttnSoftmaxLastDim {
+    scale: f32,
+}
+
+/// Adds `mask` to each `(seq, kv_seq)` block of `xs`, rescales and runs the softmax over the last
+/// dim in place. `xs` holds a whole number of mask blocks.
+fn attn_softmax_cpu<T: crate::core::WithDType + num_traits::Float>(
+    xs: &mut [T],
+    mask: &[T],
+    dim_m1: usize,
+    scale: f32,
+) {
+    if mask.is_empty() {
+        return;
+    }
+    let scale = T::from_f64(scale as f64);
+    xs.par_chunks_mut(mask.len()).for_each(|xs| {
+        for (row, mask) in xs.chunks_mut(dim_m1).zip(mask.chunks(dim_m1)) {
+            for (x, &m) in row.iter_mut().zip(mask) {
+                *x = (*x + m) * scale;
+            }
+            let mut max = T::neg_infinity();
+            unsafe { T::vec_reduce_max(row.as_ptr(), &mut max, dim_m1) };
+            // Fully masked rows output zeros rather than NaN.
+            if max == T::neg_infinity() {
+                row.fill(T::zero());
+                continue;
+            }
+            for x in row.iter_mut() {
+                *x = (*x - max).exp();
+            }
+            let mut sum_exp = T::zero();
+            unsafe { T::vec_reduce_sum(row.as_ptr(), &mut sum_exp, dim_m1) };
+            for x in row.iter_mut() {
+                *x /= sum_exp
+            }
+        }
+    });
+}
+
+impl crate::core::InplaceOp2 for AttnSoftmaxLastDim {
+    fn name(&self) -> &'static str {
+        "attn-softmax-last-dim"
+    }
+
+    fn cpu_fwd(
+        &self,
+        a_s: &mut CpuStorage,
+        a_l: &Layout,
+        mask_s: &CpuStorage,
+        mask_l: &Layout,
+    ) -> Result<()> {
+        fn inner<T: crate::core::WithDType + num_traits::Float>(
+            xs: &mut [T],
+            a_l: &Layout,
+            mask: &[T],
+            mask_l: &Layout,
+            scale: f32,
+        ) -> Result<()> {
+            let xs = match a_l.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &mut xs[o1..o2],
+            };
+            let mask = match mask_l.contiguous_offsets() {
+                None => crate::bail!("mask has to be contiguous"),
+                Some((o1, o2)) => &mask[o1..o2],
+            };
+            attn_softmax_cpu(xs, mask, a_l.dim(D::Minus1)?, scale);
+            Ok(())
+        }
+
+        use crate::core::backend::BackendStorage;
+        check_attn_softmax_layouts(a_l, mask_l)?;
+        use CpuStorage as C;
+        match (a_s, mask_s) {
+            (C::BF16(xs), C::BF16(mask)) => inner(xs, a_l, mask, mask_l, self.scale),
+            (C::F16(xs), C::F16(mask)) => inner(xs, a_l, mask, mask_l, self.scale),
+            (C::F32(xs), C::F32(mask)) => inner(xs, a_l, mask, mask_l, self.scale),
+            (C::F64(xs), C::F64(mask)) => inner(xs, a_l, mask, mask_l, self.scale),
+            (xs, mask) => crate::bail!(
+                "unsupported dtypes for attn-softmax-last-dim {:?} {:?}",
+                xs.dtype(),
+                mask.dtype()
+            ),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        a_s: &mut crate::core::CudaStorage,
+        a_l: &Layout,
+        mask_s: &crate::core::CudaStorage,
+        mask_l: &Layout,
+    ) -> Result<()> {
+        use crate::core::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig, ValidAsZeroBits,
+        };
+        use crate::core::cuda_backend::{kernel_name, kernels, Map2InPlace, WrapErr};
+        use crate::core::{CudaDevice, WithDType};
+
+        struct S<'a> {
+            a_l: &'a Layout,
+            scale: f32,
+        }
+        impl Map2InPlace for S<'_> {
+            fn f<T: DeviceRepr + WithDType + ValidAsZeroBits>(
+                &self,
+                xs: &mut CudaSlice<T>,
+                _xs_shape: &Shape,
+                mask: &CudaSlice<T>,
+                mask_l: &Layout,
+                dev: &CudaDevice,
+            ) -> Result<()> {
+                if !matches!(T::DTYPE, DType::BF16 | DType::F16 | DType::F32) {
+                    crate::bail!(
+                        "attn-softmax-last-dim is not implemented for {:?}",
+                        T::DTYPE
+                    )
+                }
+                let xs = match self.a_l.contiguous_offsets() {
+                    None => crate::bail!("input has to be contiguous"),
+                    Some((o1, o2)) => xs.slice(o1..o2),
+                };
+                let mask = match mask_l.contiguous_offsets() {
+                    None => crate::bail!("mask has to be contiguous"),
+                    Some((o1, o2)) => mask.slice(o1..o2),
+                };
+                let el = self.a_l.shape().elem_count();
+                let n_cols = self.a_l.dim(D::Minus1)?;
+                let (n_rows, mask_rows) = (el / n_cols, mask_l.dim(0)?);
+
+                let block_size = cuda_norm_block_size(n_cols);
+                let cfg = LaunchConfig {
+                    grid_dim: (n_rows as u32, 1, 1),
+                    block_dim: (block_size, 1, 1),
+                    shared_mem_bytes: 0,
+                };
+                let func =
+                    dev.get_or_load_func(&kernel_name::<T>("attn_softmax"), kernels::REDUCE)?;
+                let params = (
+                    &xs,
+                    &mask,
+                    &xs,
+                    n_cols as i32,
+                    mask_rows as i32,
+                    block_size as i32,
+                    self.scale,
+                );
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                Ok(())
+            }
+        }
+
+        check_attn_softmax_layouts(a_l, mask_l)?;
+        if a_l.shape().elem_count() == 0 {
+            return Ok(());
+        }
+        use crate::core::backend::BackendStorage;
+        let dev = a_s.device().clone();
+        S {
+            a_l,
+            scale: self.scale,
+        }
+        .map(&mut a_s.slice, a_l.shape(), &mask_s.slice, mask_l, &dev)
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        a_s: &mut crate::core::MetalStorage,
+        a_l: &Layout,
+        mask_s: &crate::core::MetalStorage,
+        mask_l: &Layout,
+    ) -> Result<()> {
+        use crate::core::backend::BackendStorage;
+        let device = a_s.device();
+        let command_buffer = device.command_buffer()?;
+        let kernels = device.kernels();
+
+        let ty = match a_s.dtype() {
+            DType::F32 => crate::metal_kernels::SdpaDType::F32,
+            DType::F16 => crate::metal_kernels::SdpaDType::F16,
+            DType::BF16 => crate::metal_kernels::SdpaDType::BF16,
+            dtype => crate::bail!("attn-softmax-last-dim is not implemented for {dtype:?}"),
+        };
+
+        if !a_l.is_contiguous() {
+            crate::bail!("Non contiguous xs for attn-softmax-last-dim is not implemented");
+        }
+        if !mask_l.is_contiguous() {
+            crate::bail!("Non contiguous mask for attn-softmax-last-dim is not implemented");
+        }
+
+        check_attn_softmax_layouts(a_l, mask_l)?;
+
+        crate::metal_kernels::call_last_attn_softmax(
+            device.metal_device(),
+            &command_buffer,
+            kernels,
+            a_s.buffer(),
+            a_l.start_offset() * a_s.dtype().size_in_bytes(),
+            mask_s.buffer(),
+            mask_l.start_offset() * mask_s.dtype().size_in_bytes(),
+            a_l.dims(),
+            self.scale,
+            ty,
+            a_s.buffer(),
+            0,
+        )
+        .map_err(crate::core::Error::wrap)?;
+
+        Ok(())
+    }
+}
+
+impl crate::core::CustomOp2 for AttnSoftmaxLastDim {
+    fn name(&self) -> &'static str {
+        "attn-softmax-last-dim"
+    }
+
+    fn cpu_fwd(
+        &self,
+        a_s: &CpuStorage,
+        a_l: &Layout,
+        mask_s: &CpuStorage,
+        mask_l: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        fn inner<T: crate::core::WithDType + num_traits::Float>(
+            xs: &[T],
+            a_l: &Layout,
+            mask: &[T],
+            mask_l: &Layout,
+            scale: f32,
+        ) -> Result<CpuStorage> {
+            let mut dst = match a_l.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => xs[o1..o2].to_vec(),
+            };
+            let mask = match mask_l.contiguous_offsets() {
+                None => crate::bail!("mask has to be contiguous"),
+                Some((o1, o2)) => &mask[o1..o2],
+            };
+            attn_softmax_cpu(&mut dst, mask, a_l.dim(D::Minus1)?, scale);
+            Ok(crate::core::WithDType::to_cpu_storage_owned(dst))
+        }
+
+        use crate::core::backend::BackendStorage;
+        check_attn_softmax_layouts(a_l, mask_l)?;
+        use CpuStorage as C;
+        let storage = match (a_s, mask_s) {
+            (C::BF16(xs), C::BF16(mask)) => inner(xs, a_l, mask, mask_l, self.scale)?,
+            (C::F16(xs), C::F16(mask)) => inner(xs, a_l, mask, mask_l, self.scale)?,
+            (C::F32(xs), C::F32(mask)) => inner(xs, a_l, mask, mask_l, self.scale)?,
+            (C::F64(xs), C::F64(mask)) => inner(xs, a_l, mask, mask_l, self.scale)?,
+            (xs, mask) => crate::bail!(
+                "unsupported dtypes for attn-softmax-last-dim {:?} {:?}",
+                xs.dtype(),
+                mask.dtype()
+            ),
+        };
+        Ok((storage, a_l.shape().clone()))
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        a_s: &crate::core::CudaStorage,
+        a_l: &Layout,
+        mask_s: &crate::core::CudaStorage,
+        mask_l: &Layout,
+    ) -> Result<(crate::core::CudaStorage, Shape)> {
+        use crate::core::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig, ValidAsZeroBits,
+        };
+        use crate::core::cuda_backend::{kernel_name, kernels, Map2, WrapErr};
+        use crate::core::{CudaDevice, WithDType};
+
+        struct S {
+            scale: f32,
+        }
+        impl Map2 for S {
+            fn f<T: DeviceRepr + WithDType + ValidAsZeroBits>(
+                &self,
+                xs: &CudaSlice<T>,
+                xs_l: &Layout,
+                mask: &CudaSlice<T>,
+                mask_l: &Layout,
+                dev: &CudaDevice,
+            ) -> Result<CudaSlice<T>> {
+                if !matches!(T::DTYPE, DType::BF16 | DType::F16 | DType::F32) {
+                    crate::bail!(
+                        "attn-softmax-last-dim is not implemented for {:?}",
+                        T::DTYPE
+                    )
+                }
+                let xs = match xs_l.contiguous_offsets() {
+                    None => crate::bail!("input has to be contiguous"),
+                    Some((o1, o2)) => xs.slice(o1..o2),
+                };
+                let mask = match mask_l.contiguous_offsets() {
+                    None => crate::bail!("mask has to be contiguous"),
+                    Some((o1, o2)) => mask.slice(o1..o2),
+                };
+                let el = xs_l.shape().elem_count();
+                // SAFETY: Set later by running the kernel.
+                let dst = unsafe { dev.alloc::<T>(el) }.w()?;
+                if el == 0 {
+                    return Ok(dst);
+                }
+                let n_cols = xs_l.dim(D::Minus1)?;
+                let (n_rows, mask_rows) = (el / n_cols, mask_l.dim(0)?);
+
+                let block_size = cuda_norm_block_size(n_cols);
+                let cfg = LaunchConfig {
+                    grid_dim: (n_rows as u32, 1, 1),
+                    block_dim: (block_size, 1, 1),
+                    shared_mem_bytes: 0,
+                };
+                let func =
+                    dev.get_or_load_func(&kernel_name::<T>("attn_softmax"), kernels::REDUCE)?;
+                let params = (
+                    &xs,
+                    &mask,
+                    &dst,
+                    n_cols as i32,
+                    mask_rows as i32,
+                    block_size as i32,
+                    self.scale,
+                );
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                Ok(dst)
+            }
+        }
+
+        check_attn_softmax_layouts(a_l, mask_l)?;
+        use crate::core::backend::BackendStorage;
+        let dev = a_s.device();
+        let slice = S { scale: self.scale }.map(&a_s.slice, a_l, &mask_s.slice, mask_l, dev)?;
+        let dst = crate::core::cuda_backend::CudaStorage {
+            slice,
+            device: dev.clone(),
+        };
+        Ok((dst, a_l.shape().clone()))
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        a_s: &crate::core::MetalStorage,
+        a_l: &Layout,
+        mask_s: &crate::core::MetalStorage,
+        mask_l: &Layout,
+    ) -> Result<(crate::core::MetalStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+        let device = a_s.device();
+        let command_buffer = device.command_buffer()?;
+        let kernels = device.kernels();
+
+        let ty = match a_s.dtype() {
+            DType::F32 => crate::metal_kernels::SdpaDType::F32,
+            DType::F16 => crate::metal_kernels::SdpaDType::F16,
+            DType::BF16 => crate::metal_kernels::SdpaDType::BF16,
+            dtype => crate::bail!("attn-softmax-last-dim is not implemented for {dtype:?}"),
+        };
+
+        if !a_l.is_contiguous() {
+            crate::bail!("Non contiguous xs for attn-softmax-last-dim is not implemented");
+        }
+        if !mask_l.is_contiguous() {
+            crate::bail!("Non contiguous mask for attn-softmax-last-dim is not implemented");
+        }
+
+        check_attn_softmax_layouts(a_l, mask_l)?;
+
+        let elem_count = a_l.shape().elem_count();
+        let output = device.new_buffer(elem_count, a_s.dtype(), "attn-softmax")?;
+        crate::metal_kernels::call_last_attn_softmax(
+            device.metal_device(),
+            &command_buffer,
+            kernels,
+            a_s.buffer(),
+            a_l.start_offset() * a_s.dtype().size_in_bytes(),
+            mask_s.buffer(),
+            mask_l.start_offset() * mask_s.dtype().size_in_bytes(),
+            a_l.dims(),
+            self.scale,
+            ty,
+            &output,
+            a_l.start_offset() * a_s.dtype().size_in_bytes(),
+        )
+        .map_err(crate::core::Error::wrap)?;
+        let newstorage =
+            crate::core::MetalStorage::new(output, device.clone(), elem_count, a_s.dtype());
+        Ok((newstorage, a_l.shape().clone()))
+    }
+}
+
+/// Softmax with fused broadcast addition of a mask and scale.
+/// Equivalent to:
+/// ```ignore
+/// diffusion_rs_common::nn::ops::softmax_last_dim(&(xs.broadcast_add(&mask)? * scale as f64)?)?
+/// ```
+/// - `xs` must be a rank-3 `(bs, seq, kv_seq)` or rank-4 `(bs, heads, seq, kv_seq)` tensor
+/// - `mask` must be a rank-2 matrix
+/// - The last 2 dimensions of `xs` must match the dimensions of `mask`.
+///
+/// Note: if the last dim of `xs` is a multiple of 4, a vectorized implementation will be used.
+///
+/// Rows that are fully masked out with `-inf` output zeros rather than `NaN` on every backend.
+/// The cuda kernel only covers f16, bf16 and f32, other dtypes on cuda use the equivalent
+/// composed ops above. On the cpu and cuda, inputs that do not fit the constraints above, e.g.
+/// masks broadcasting per head or non-contiguous tensors, also use the composed ops.
+pub fn attn_softmax_last_dim(xs: &Tensor, mask: &Tensor, scale: f32) -> Result<Tensor> {
+    if has_fused_attn_softmax(xs, mask) {
+        xs.apply_op2_no_bwd(mask, &AttnSoftmaxLastDim { scale })
+    } else {
+        softmax_last_dim(&(xs.broadcast_add(mask)? * scale as f64)?)
+    }
+}
+
+/// Inplace equivalent of `attn_softmax_last_dim`
+pub fn inplace_attn_softmax_last_dim(xs: &mut Tensor, mask: &Tensor, scale: f32) -> Result<()> {
+    if has_fused_attn_softmax(xs, mask) {
+        xs.inplace_op2(mask, &AttnSoftmaxLastDim { scale })?;
+    } else {
+        *xs = softmax_last_dim(&(xs.broadcast_add(mask)? * scale as f64)?)?;
+    }
+    Ok(())
+}
+
+/// Whether `AttnSoftmaxLastDim` has a kernel for `xs` and `mask`. The cpu and cuda kernels take
+/// contiguous rank 3 or 4 inputs with a rank 2 mask matching their last two dims and the cuda
+/// kernel lacks f64, metal always uses its kernel.
+fn has_fused_attn_softmax(xs: &Tensor, mask: &Tensor) -> bool {
+    if xs.device().is_metal() {
+        return true;
+    }
+    if xs.device().is_cuda() && !matches!(xs.dtype(), DType::BF16 | DType::F16 | DType::F32) {
+        return false;
+    }
+    matches!(xs.rank(), 3 | 4)
+        && mask.rank() == 2
+        && xs.dims()[xs.rank() - 2..] == *mask.dims()
+        && xs.is_contiguous()
+        && mask.is_contiguous()
+}
+
+/// Threads per row for the CUDA `rmsnorm`/`layernorm` kernels: the power of two covering
+/// `n_cols`, from a single warp up to 1024 threads.
+#[cfg(feature = "cuda")]
+fn cuda_norm_block_size(n_cols: usize) -> u32 {
+    n_cols.next_power_of_two().clamp(32, 1024) as u32
+}
+
+#[derive(Debug, Clone)]
+struct RmsNorm {
+    eps: f32,
+}
+
+impl crate::core::CustomOp2 for RmsNorm {
+    fn name(&self) -> &'static str {
+        "rms-norm"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+
+        let eps = self.eps;
+        fn inner<
+            T: crate::core::WithDType
+                + num_traits::Float
+                + num_traits::AsPrimitive<f32>
+                + num_traits::FromPrimitive,
+        >(
+            src: &[T],
+            layout: &Layout,
+            alpha: &[T],
+            alpha_layout: &Layout,
+            eps: f32,
+        ) -> Result<(CpuStorage, Shape)> {
+            let src = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &src[o1..o2],
+            };
+            let alpha = match alpha_layout.contiguous_offsets() {
+                None => crate::bail!("alpha has to be contiguous"),
+                Some((o1, o2)) => &alpha[o1..o2],
+            };
+            let el_count = layout.shape().elem_count();
+            let dims = layout.shape().dims();
+            let dim_m1 = dims[dims.len() - 1];
+            let mut dst = vec![T::zero(); el_count];
+            src.par_chunks(dim_m1)
+                .zip(dst.par_chunks_mut(dim_m1))
+                .for_each(|(src, dst)| {
+                    let sum2 = src
+                        .iter()
+                        .map(|&v| {
+                            let v = v.as_();
+                            v * v
+                        })
+                        .sum::<f32>();
+                    // Normalize in f32 too so that f16/bf16 outputs are only rounded once.
+                    let inv_m = (sum2 / dim_m1 as f32 + eps).sqrt().recip();
+                    for ((d, s), alpha) in dst.iter_mut().zip(src.iter()).zip(alpha) {
+                        let v = s.as_() * inv_m * alpha.as_();
+                        *d = T::from_f32(v).unwrap_or_else(T::nan)
+                    }
+                });
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, Shape::from_dims(dims)))
+        }
+
+        use CpuStorage as C;
+        match (s1, s2) {
+            (C::BF16(s1), C::BF16(s2)) => inner::<half::bf16>(s1, l1, s2, l2, eps),
+            (C::F16(s1), C::F16(s2)) => inner::<half::f16>(s1, l1, s2, l2, eps),
+            (C::F32(s1), C::F32(s2)) => inner::<f32>(s1, l1, s2, l2, eps),
+            _ => crate::bail!("unsupported dtype for rmsnorm {:?}", s1.dtype()),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &crate::core::CudaStorage,
+        l1: &Layout,
+        s2: &crate::core::CudaStorage,
+        l2: &Layout,
+    ) -> Result<(crate::core::CudaStorage, Shape)> {
+        use crate::core::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use crate::core::cuda_backend::{kernel_name, kernels, Map2, WrapErr};
+        use crate::core::{CudaDevice, WithDType};
+
+        struct S {
+            eps: f32,
+        }
+        impl Map2 for S {
+            fn f<T: DeviceRepr + WithDType>(
+                &self,
+                src: &CudaSlice<T>,
+                layout: &Layout,
+                alpha: &CudaSlice<T>,
+                alpha_layout: &Layout,
+                dev: &CudaDevice,
+            ) -> Result<CudaSlice<T>> {
+                let src = match layout.contiguous_offsets() {
+                    None => crate::bail!("input has to be contiguous"),
+                    Some((o1, o2)) => src.slice(o1..o2),
+                };
+                let alpha = match alpha_layout.contiguous_offsets() {
+                    None => crate::bail!("alpha has to be contiguous"),
+                    Some((o1, o2)) => alpha.slice(o1..o2),
+                };
+                let el = layout.shape().elem_count();
+                let dims = layout.shape().dims();
+                let dim_m1 = dims[dims.len() - 1];
+                let (n_rows, n_cols) = (el / dim_m1, dim_m1);
+
+                let block_size = cuda_norm_block_size(n_cols);
+                let cfg = LaunchConfig {
+                    grid_dim: (n_rows as u32, 1, 1),
+                    block_dim: (block_size, 1, 1),
+                    shared_mem_bytes: 0,
+                };
+                let func = dev.get_or_load_func(&kernel_name::<T>("rmsnorm"), kernels::REDUCE)?;
+                // SAFETY: Set later by running the kernel.
+                let dst = unsafe { dev.alloc::<T>(el) }.w()?;
+                let params = (
+                    &src,
+                    &dst,
+                    &alpha,
+                    n_cols as i32,
+                    block_size as i32,
+                    self.eps,
+                );
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                Ok(dst)
+            }
+        }
+
+        use crate::core::backend::BackendStorage;
+        let dev = s1.device();
+        let slice = S { eps: self.eps }.map(&s1.slice, l1, &s2.slice, l2, dev)?;
+        let dst = crate::core::cuda_backend::CudaStorage {
+            slice,
+            device: dev.clone(),
+        };
+        Ok((dst, l1.shape().clone()))
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        s1: &crate::core::MetalStorage,
+        l1: &Layout,
+        s2: &crate::core::MetalStorage,
+        l2: &Layout,
+    ) -> Result<(crate::core::MetalStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+        let device = s1.device();
+        let command_buffer = device.command_buffer()?;
+        let kernels = device.kernels();
+        let name = match (s1.dtype(), s2.dtype()) {
+            (DType::F32, DType::F32) => "rmsnorm_f32",
+            (DType::F16, DType::F16) => "rmsnorm_f16",
+            (DType::BF16, DType::BF16) => "rmsnorm_bf16",
+            (dt1, dt2) => crate::bail!("rmsnorm is not implemented for {dt1:?} {dt2:?}"),
+        };
+
+        if !(l1.is_contiguous() && l2.is_contiguous()) {
+            crate::bail!("Non contiguous rmsnorm is not implemented");
+        }
+
+        let last_dim = l1.dims()[l1.shape().rank() - 1];
+        let elem_count = l1.shape().elem_count();
+        let output = device.new_buffer(elem_count, s1.dtype(), "rmsnorm")?;
+        crate::metal_kernels::call_rms_norm(
+            device.metal_device(),
+            &command_buffer,
+            kernels,
+            name,
+            elem_count,
+            last_dim,
+            self.eps,
+            s1.buffer(),
+            l1.start_offset() * s1.dtype().size_in_bytes(),
+            s2.buffer(),
+            l2.start_offset() * s2.dtype().size_in_bytes(),
+            &output,
+        )
+        .map_err(crate::core::Error::wrap)?;
+        let newstorage =
+            crate::core::MetalStorage::new(output, device.clone(), elem_count, s1.dtype());
+        Ok((newstorage, l1.shape().clone()))
+    }
+}
+
+impl crate::core::InplaceOp2 for RmsNorm {
+    fn name(&self) -> &'static str {
+        "rms-norm"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &mut CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<()> {
+        use crate::core::backend::BackendStorage;
+
+        let eps = self.eps;
+        fn inner<
+            T: crate::core::WithDType
+                + num_traits::Float
+                + num_traits::AsPrimitive<f32>
+                + num_traits::FromPrimitive,
+        >(
+            xs: &mut [T],
+            layout: &Layout,
+            alpha: &[T],
+            alpha_layout: &Layout,
+            eps: f32,
+        ) -> Result<()> {
+            let xs = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &mut xs[o1..o2],
+            };
+            let alpha = match alpha_layout.contiguous_offsets() {
+                None => crate::bail!("alpha has to be contiguous"),
+                Some((o1, o2)) => &alpha[o1..o2],
+            };
+            let dims = layout.shape().dims();
+            let dim_m1 = dims[dims.len() - 1];
+            xs.par_chunks_mut(dim_m1).for_each(|xs| {
+                let sum2 = xs
+                    .iter()
+                    .map(|&v| {
+                        let v = v.as_();
+                        v * v
+                    })
+                    .sum::<f32>();
+                let inv_m = (sum2 / dim_m1 as f32 + eps).sqrt().recip();
+                for (x, alpha) in xs.iter_mut().zip(alpha) {
+                    let v = x.as_() * inv_m * alpha.as_();
+                    *x = T::from_f32(v).unwrap_or_else(T::nan)
+                }
+            });
+            Ok(())
+        }
+
+        use CpuStorage as C;
+        let dtype = s1.dtype();
+        match (s1, s2) {
+            (C::BF16(s1), C::BF16(s2)) => inner::<half::bf16>(s1, l1, s2, l2, eps),
+            (C::F16(s1), C::F16(s2)) => inner::<half::f16>(s1, l1, s2, l2, eps),
+            (C::F32(s1), C::F32(s2)) => inner::<f32>(s1, l1, s2, l2, eps),
+            _ => crate::bail!("unsupported dtype for rmsnorm {dtype:?}"),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &mut crate::core::CudaStorage,
+        l1: &Layout,
+        s2: &crate::core::CudaStorage,
+        l2: &Layout,
+    ) -> Result<()> {
+        use crate::core::backend::BackendStorage;
+        use crate::core::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use crate::core::cuda_backend::{kernel_name, kernels, CudaStorageSlice as S, WrapErr};
+        use crate::core::{CudaDevice, WithDType};
+
+        fn launch<T: DeviceRepr + WithDType>(
+            xs: &CudaSlice<T>,
+            layout: &Layout,
+            alpha: &CudaSlice<T>,
+            alpha_layout: &Layout,
+            eps: f32,
+            dev: &CudaDevice,
+        ) -> Result<()> {
+            let xs = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => xs.slice(o1..o2),
+            };
+            let alpha = match alpha_layout.contiguous_offsets() {
+                None => crate::bail!("alpha has to be contiguous"),
+                Some((o1, o2)) => alpha.slice(o1..o2),
+            };
+            let el = layout.shape().elem_count();
+            let dims = layout.shape().dims();
+            let dim_m1 = dims[dims.len() - 1];
+            let (n_rows, n_cols) = (el / dim_m1, dim_m1);
+
+            let block_size = cuda_norm_block_size(n_cols);
+            let cfg = LaunchConfig {
+                grid_dim: (n_rows as u32, 1, 1),
+                block_dim: (block_size, 1, 1),
+                shared_mem_bytes: 0,
+            };
+            let func = dev.get_or_load_func(&kernel_name::<T>("rmsnorm"), kernels::REDUCE)?;
+            // Every element is read and then written by the same thread so the kernel can
+            // write its output over its input.
+            let params = (&xs, &xs, &alpha, n_cols as i32, block_size as i32, eps);
+            // SAFETY: ffi.
+            unsafe { func.launch(cfg, params) }.w()?;
+            Ok(())
+        }
+
+        let dev = s1.device().clone();
+        let eps = self.eps;
+        match (&s1.slice, &s2.slice) {
+            (S::BF16(x), S::BF16(a)) => launch(x, l1, a, l2, eps, &dev),
+            (S::F16(x), S::F16(a)) => launch(x, l1, a, l2, eps, &dev),
+            (S::F32(x), S::F32(a)) => launch(x, l1, a, l2, eps, &dev),
+            _ => crate::bail!("unsupported dtype for rmsnorm {:?}", s1.dtype()),
+        }
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        s1: &mut crate::core::MetalStorage,
+        l1: &Layout,
+        s2: &crate::core::MetalStorage,
+        l2: &Layout,
+    ) -> Result<()> {
+        use crate::core::backend::BackendStorage;
+        let device = s1.device();
+        let command_buffer = device.command_buffer()?;
+        let kernels = device.kernels();
+        let name = match (s1.dtype(), s2.dtype()) {
+            (DType::F32, DType::F32) => "rmsnorm_f32",
+            (DType::F16, DType::F16) => "rmsnorm_f16",
+            (DType::BF16, DType::BF16) => "rmsnorm_bf16",
+            (dt1, dt2) => crate::bail!("rmsnorm is not implemented for {dt1:?} {dt2:?}"),
+        };
+
+        if !(l1.is_contiguous() && l2.is_contiguous()) {
+            crate::bail!("Non contiguous rmsnorm is not implemented");
+        }
+        // The output buffer is written from its start.
+        if l1.start_offset() != 0 {
+            crate::bail!("inplace rmsnorm with a start offset is not implemented");
+        }
+
+        let last_dim = l1.dims()[l1.shape().rank() - 1];
+        let elem_count = l1.shape().elem_count();
+        crate::metal_kernels::call_rms_norm(
+            device.metal_device(),
+            &command_buffer,
+            kernels,
+            name,
+            elem_count,
+            last_dim,
+            self.eps,
+            s1.buffer(),
+            0,
+            s2.buffer(),
+            l2.start_offset() * s2.dtype().size_in_bytes(),
+            s1.buffer(),
+        )
+        .map_err(crate::core::Error::wrap)?;
+        Ok(())
+    }
+}
+
+pub fn rms_norm_slow(x: &Tensor, alpha: &Tensor, eps: f32) -> Result<Tensor> {
+    let x_dtype = x.dtype();
+    let internal_dtype = match x_dtype {
+        DType::F16 | DType::BF16 => DType::F32,
+        d => d,
+    };
+    let hidden_size = x.dim(D::Minus1)?;
+    let x = x.to_dtype(internal_dtype)?;
+    let norm_x = (x.sqr()?.sum_keepdim(D::Minus1)? / hidden_size as f64)?;
+    let x_normed = x.broadcast_div(&(norm_x + eps as f64)?.sqrt()?)?;
+    x_normed.to_dtype(x_dtype)?.broadcast_mul(alpha)
+}
+
+/// RmsNorm over the last dim of `xs`. The sum of squares is always accumulated in f32, including
+/// for f16 and bf16 inputs, as in [`rms_norm_slow`].
+pub fn rms_norm(xs: &Tensor, alpha: &Tensor, eps: f32) -> Result<Tensor> {
+    let hidden_size_xs = xs.dim(D::Minus1)?;
+    let hidden_size_alpha = alpha.dims1()?;
+    if hidden_size_xs != hidden_size_alpha {
+        crate::bail!(
+            "shape mismatch in rms-norm {:?} {:?}",
+            xs.shape(),
+            alpha.shape()
+        )
+    }
+    xs.apply_op2_no_bwd(alpha, &RmsNorm { eps })
+}
+
+/// In place variant of [`rms_norm`], `xs` has to be contiguous. As with
+/// [`inplace_softmax_last_dim`], tensors sharing their storage with `xs` see the update too.
+pub fn inplace_rms_norm(xs: &mut Tensor, alpha: &Tensor, eps: f32) -> Result<()> {
+    let hidden_size_xs = xs.dim(D::Minus1)?;
+    let hidden_size_alpha = alpha.dims1()?;
+    if hidden_size_xs != hidden_size_alpha {
+        crate::bail!(
+            "shape mismatch in rms-norm {:?} {:?}",
+            xs.shape(),
+            alpha.shape()
+        )
+    }
+    xs.inplace_op2(alpha, &RmsNorm { eps })
+}
+
+/// RmsNorm writing its output in `out_dtype` rather than in the input dtype, only used for the
+/// F32 <-> F16/BF16 combinations.
+struct RmsNormCast {
+    eps: f32,
+    out_dtype: DType,
+}
+
+impl crate::core::CustomOp2 for RmsNormCast {
+    fn name(&self) -> &'static str {
+        "rms-norm-cast"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+
+        let eps = self.eps;
+        fn inner<
+            T: crate::core::WithDType + num_traits::AsPrimitive<f32>,
+            O: crate::core::WithDType + num_traits::FromPrimitive,
+        >(
+            src: &[T],
+            layout: &Layout,
+            alpha: &[T],
+            alpha_layout: &Layout,
+            eps: f32,
+        ) -> Result<(CpuStorage, Shape)> {
+            let src = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &src[o1..o2],
+            };
+            let alpha = match alpha_layout.contiguous_offsets() {
+                None => crate::bail!("alpha has to be contiguous"),
+                Some((o1, o2)) => &alpha[o1..o2],
+            };
+            let el_count = layout.shape().elem_count();
+            let dims = layout.shape().dims();
+            let dim_m1 = dims[dims.len() - 1];
+            let mut dst = vec![O::zero(); el_count];
+            src.par_chunks(dim_m1)
+                .zip(dst.par_chunks_mut(dim_m1))
+                .for_each(|(src, dst)| {
+                    let sum2 = src
+                        .iter()
+                        .map(|&v| {
+                            let v = v.as_();
+                            v * v
+                        })
+                        .sum::<f32>();
+                    let inv_m = (sum2 / dim_m1 as f32 + eps).sqrt().recip();
+                    for ((d, s), alpha) in dst.iter_mut().zip(src.iter()).zip(alpha) {
+                        let v = s.as_() * inv_m * alpha.as_();
+                        *d = O::from_f32(v).unwrap_or_else(O::zero)
+                    }
+                });
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, Shape::from_dims(dims)))
+        }
+
+        use CpuStorage as C;
+        match (s1, s2, self.out_dtype) {
+            (C::F32(s1), C::F32(s2), DType::BF16) => inner::<f32, half::bf16>(s1, l1, s2, l2, eps),
+            (C::F32(s1), C::F32(s2), DType::F16) => inner::<f32, half::f16>(s1, l1, s2, l2, eps),
+            (C::BF16(s1), C::BF16(s2), DType::F32) => inner::<half::bf16, f32>(s1, l1, s2, l2, eps),
+            (C::F16(s1), C::F16(s2), DType::F32) => inner::<half::f16, f32>(s1, l1, s2, l2, eps),
+            _ => crate::bail!(
+                "unsupported dtypes for rmsnorm-cast {:?} -> {:?}",
+                s1.dtype(),
+                self.out_dtype
+            ),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &crate::core::CudaStorage,
+        l1: &Layout,
+        s2: &crate::core::CudaStorage,
+        l2: &Layout,
+    ) -> Result<(crate::core::CudaStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+        use crate::core::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use crate::core::cuda_backend::{CudaStorageSlice as S, WrapErr};
+        use crate::core::{CudaDevice, WithDType};
+
+        fn launch<T: DeviceRepr + WithDType, O: DeviceRepr + WithDType>(
+            src: &CudaSlice<T>,
+            layout: &Layout,
+            alpha: &CudaSlice<T>,
+            alpha_layout: &Layout,
+            eps: f32,
+            dev: &CudaDevice,
+        ) -> Result<CudaSlice<O>> {
+            let src = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => src.slice(o1..o2),
+            };
+            let alpha = match alpha_layout.contiguous_offsets() {
+                None => crate::bail!("alpha has to be contiguous"),
+                Some((o1, o2)) => alpha.slice(o1..o2),
+            };
+            let el = layout.shape().elem_count();
+            let dims = layout.shape().dims();
+            let dim_m1 = dims[dims.len() - 1];
+            let (n_rows, n_cols) = (el / dim_m1, dim_m1);
+
+            let block_size = cuda_norm_block_size(n_cols);
+            let cfg = LaunchConfig {
+                grid_dim: (n_rows as u32, 1, 1),
+                block_dim: (block_size, 1, 1),
+                shared_mem_bytes: 0,
+            };
+            let name = format!("rmsnorm_{}_{}", T::DTYPE.as_str(), O::DTYPE.as_str());
+            let func = dev.get_or_load_func(&name, crate::core::cuda_backend::kernels::REDUCE)?;
+            // SAFETY: Set later by running the kernel.
+            let dst = unsafe { dev.alloc::<O>(el) }.w()?;
+            let params = (&src, &dst, &alpha, n_cols as i32, block_size as i32, eps);
+            // SAFETY: ffi.
+            unsafe { func.launch(cfg, params) }.w()?;
+            Ok(dst)
+        }
+
+        let dev = s1.device();
+        let eps = self.eps;
+        let slice = match (&s1.slice, &s2.slice, self.out_dtype) {
+            (S::F32(x), S::F32(a), DType::BF16) => {
+                S::BF16(launch::<f32, half::bf16>(x, l1, a, l2, eps, dev)?)
+            }
+            (S::F32(x), S::F32(a), DType::F16) => {
+                S::F16(launch::<f32, half::f16>(x, l1, a, l2, eps, dev)?)
+            }
+            (S::BF16(x), S::BF16(a), DType::F32) => {
+                S::F32(launch::<half::bf16, f32>(x, l1, a, l2, eps, dev)?)
+            }
+            (S::F16(x), S::F16(a), DType::F32) => {
+                S::F32(launch::<half::f16, f32>(x, l1, a, l2, eps, dev)?)
+            }
+            _ => crate::bail!(
+                "unsupported dtypes for rmsnorm-cast {:?} -> {:?}",
+                s1.dtype(),
+                self.out_dtype
+            ),
+        };
+        let dst = crate::core::cuda_backend::CudaStorage {
+            slice,
+            device: dev.clone(),
+        };
+        Ok((dst, l1.shape().clone()))
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        s1: &crate::core::MetalStorage,
+        l1: &Layout,
+        s2: &crate::core::MetalStorage,
+        l2: &Layout,
+    ) -> Result<(crate::core::MetalStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+        let device = s1.device();
+        let command_buffer = device.command_buffer()?;
+        let kernels = device.kernels();
+        let name = match (s1.dtype(), s2.dtype(), self.out_dtype) {
+            (DType::F32, DType::F32, DType::F16) => "rmsnorm_f32_f16",
+            (DType::F32, DType::F32, DType::BF16) => "rmsnorm_f32_bf16",
+            (DType::F16, DType::F16, DType::F32) => "rmsnorm_f16_f32",
+            (DType::BF16, DType::BF16, DType::F32) => "rmsnorm_bf16_f32",
+            (dt1, dt2, out) => {
+                crate::bail!("rmsnorm-cast is not implemented for {dt1:?} {dt2:?} -> {out:?}")
+            }
+        };
+
+        if !(l1.is_contiguous() && l2.is_contiguous()) {
+            crate::bail!("Non contiguous rmsnorm is not implemented");
+        }
+
+        let last_dim = l1.dims()[l1.shape().rank() - 1];
+        let elem_count = l1.shape().elem_count();
+        let output = device.new_buffer(elem_count, self.out_dtype, "rmsnorm")?;
+        crate::metal_kernels::call_rms_norm(
+            device.metal_device(),
+            &command_buffer,
+            kernels,
+            name,
+            elem_count,
+            last_dim,
+            self.eps,
+            s1.buffer(),
+            l1.start_offset() * s1.dtype().size_in_bytes(),
+            s2.buffer(),
+            l2.start_offset() * s2.dtype().size_in_bytes(),
+            &output,
+        )
+        .map_err(crate::core::Error::wrap)?;
+        let newstorage =
+            crate::core::MetalStorage::new(output, device.clone(), elem_count, self.out_dtype);
+        Ok((newstorage, l1.shape().clone()))
+    }
+}
+
+/// RmsNorm with the output written directly in `out_dtype`, e.g. normalizing F32 activations
+/// into the BF16 input of the next matmul without a separate cast.
+///
+/// The normalization is accumulated in F32. The F32 <-> F16/BF16 combinations run in a single
+/// kernel, other float combinations fall back to `rms_norm` followed by `to_dtype`.
+pub fn rms_norm_cast(xs: &Tensor, alpha: &Tensor, eps: f32, out_dtype: DType) -> Result<Tensor> {
+    if !matches!(out_dtype, DType::F16 | DType::BF16 | DType::F32) {
+        crate::bail!("rms_norm_cast does not support the output dtype {out_dtype:?}")
+    }
+    let hidden_size_xs = xs.dim(D::Minus1)?;
+    let hidden_size_alpha = alpha.dims1()?;
+    if hidden_size_xs != hidden_size_alpha {
+        crate::bail!(
+            "shape mismatch in rms-norm {:?} {:?}",
+            xs.shape(),
+            alpha.shape()
+        )
+    }
+    match (xs.dtype(), out_dtype) {
+        (DType::F32, DType::F16 | DType::BF16) | (DType::F16 | DType::BF16, DType::F32) => {
+            xs.apply_op2_no_bwd(alpha, &RmsNormCast { eps, out_dtype })
+        }
+        _ => rms_norm(xs, alpha, eps)?.to_dtype(out_dtype),
+    }
+}
+
+/// The RmsNorm counterpart of `add_layer_norm_cast` for LLaMA/Gemma style pre-norm blocks: adds
+/// `x` to `residual` in F32, then rms-norms the sum and writes the normed output in `out_dtype`.
+///
+/// Returns `(normed, new_residual)` where `new_residual` is always F32, so the residual stream
+/// accumulates in full precision while `normed` feeds the next matmul in the compute dtype. The
+/// F32 -> F16/BF16 normalization runs as a single `rms_norm_cast` kernel.
+pub fn add_rms_norm_cast(
+    x: &Tensor,
+    residual: &Tensor,
+    alpha: &Tensor,
+    eps: f32,
+    out_dtype: DType,
+) -> Result<(Tensor, Tensor)> {
+    if x.shape() != residual.shape() {
+        crate::bail!(
+            "shape mismatch in add-rms-norm x: {:?} residual: {:?}",
+            x.shape(),
+            residual.shape()
+        )
+    }
+    let new_residual = (x.to_dtype(DType::F32)? + residual.to_dtype(DType::F32)?)?;
+    let normed = rms_norm_cast(&new_residual, &alpha.to_dtype(DType::F32)?, eps, out_dtype)?;
+    Ok((normed, new_residual))
+}
+
+struct GroupedRmsNorm {
+    eps: f32,
+    num_groups: usize,
+}
+
+impl crate::core::CustomOp2 for GroupedRmsNorm {
+    fn name(&self) -> &'static str {
+        "grouped-rms-norm"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+
+        fn inner<T: crate::core::WithDType + num_traits::AsPrimitive<f32>>(
+            src: &[T],
+            layout: &Layout,
+            alpha: &[T],
+            alpha_layout: &Layout,
+            eps: f32,
+            num_groups: usize,
+        ) -> Result<(CpuStorage, Shape)> {
+            let src = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &src[o1..o2],
+            };
+            let alpha = match alpha_layout.contiguous_offsets() {
+                None => crate::bail!("alpha has to be contiguous"),
+                Some((o1, o2)) => &alpha[o1..o2],
+            };
+            let el_count = layout.shape().elem_count();
+            let dims = layout.shape().dims();
+            let group_size = dims[dims.len() - 1] / num_groups;
+            let mut dst = vec![T::zero(); el_count];
+            src.par_chunks(group_size * num_groups)
+                .zip(dst.par_chunks_mut(group_size * num_groups))
+                .for_each(|(src, dst)| {
+                    for ((src, dst), alpha) in src
+                        .chunks(group_size)
+                        .zip(dst.chunks_mut(group_size))
+                        .zip(alpha.chunks(group_size))
+                    {
+                        let sum2 = src
+                            .iter()
+                            .map(|&v| {
+                                let v = v.as_();
+                                v * v
+                            })
+                            .sum::<f32>();
+                        let inv_m = (sum2 / group_size as f32 + eps).sqrt().recip();
+                        for ((d, s), alpha) in dst.iter_mut().zip(src.iter()).zip(alpha) {
+                            *d = T::from_f64((s.as_() * inv_m * alpha.as_()) as f64)
+                        }
+                    }
+                });
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, Shape::from_dims(dims)))
+        }
+
+        let (eps, num_groups) = (self.eps, self.num_groups);
+        use CpuStorage as C;
+        match (s1, s2) {
+            (C::BF16(s1), C::BF16(s2)) => inner::<half::bf16>(s1, l1, s2, l2, eps, num_groups),
+            (C::F16(s1), C::F16(s2)) => inner::<half::f16>(s1, l1, s2, l2, eps, num_groups),
+            (C::F32(s1), C::F32(s2)) => inner::<f32>(s1, l1, s2, l2, eps, num_groups),
+            _ => crate::bail!("unsupported dtype for grouped-rms-norm {:?}", s1.dtype()),
+        }
+    }
+}
+
+/// RmsNorm applied independently to `num_groups` contiguous subvectors of the last dimension,
+/// e.g. to normalize each query group separately. The last dimension has to be divisible by
+/// `num_groups`.
+///
+/// `alpha` is either shared by all the groups, with shape `(hidden / num_groups,)`, or per
+/// element with shape `(hidden,)`. The squared sums are accumulated in F32, or in F64 for F64
+/// inputs which always use the tensor-op path.
+pub fn grouped_rms_norm(
+    xs: &Tensor,
+    num_groups: usize,
+    alpha: &Tensor,
+    eps: f32,
+) -> Result<Tensor> {
+    let hidden = xs.dim(D::Minus1)?;
+    if num_groups == 0 || hidden % num_groups != 0 {
+        crate::bail!(
+            "grouped_rms_norm expects the last dim ({hidden}) to be divisible by num_groups ({num_groups})"
+        )
+    }
+    let group_size = hidden / num_groups;
+    let alpha = match alpha.dims1()? {
+        n if n == hidden => alpha.clone(),
+        n if n == group_size => alpha.repeat(num_groups)?,
+        n => {
+            crate::bail!("grouped_rms_norm expects alpha of size {group_size} or {hidden}, got {n}")
+        }
+    };
+    if xs.device().is_cpu() && xs.dtype() != DType::F64 {
+        xs.contiguous()?
+            .apply_op2_no_bwd(&alpha.contiguous()?, &GroupedRmsNorm { eps, num_groups })
+    } else {
+        let internal_dtype = match xs.dtype() {
+            DType::F64 => DType::F64,
+            _ => DType::F32,
+        };
+        let mut dims = xs.dims()[..xs.rank() - 1].to_vec();
+        dims.extend([num_groups, group_size]);
+        let xs_g = xs.reshape(dims)?.to_dtype(internal_dtype)?;
+        let rms = (xs_g.sqr()?.mean_keepdim(D::Minus1)? + eps as f64)?.sqrt()?;
+        let alpha = alpha
+            .reshape((num_groups, group_size))?
+            .to_dtype(internal_dtype)?;
+        xs_g.broadcast_div(&rms)?
+            .broadcast_mul(&alpha)?
+            .reshape(xs.shape())?
+            .to_dtype(xs.dtype())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LayerNorm {
+    eps: f32,
+}
+
+impl crate::core::CustomOp3 for LayerNorm {
+    fn name(&self) -> &'static str {
+        "layer-norm"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+        s3: &CpuStorage,
+        l3: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+
+        let eps = self.eps;
+        fn inner<
+            T: crate::core::WithDType
+                + num_traits::Float
+                + num_traits::AsPrimitive<f32>
+                + num_traits::FromPrimitive,
+        >(
+            src: &[T],
+            layout: &Layout,
+            alpha: &[T],
+            alpha_layout: &Layout,
+            beta: &[T],
+            beta_layout: &Layout,
+            eps: f32,
+        ) -> Result<(CpuStorage, Shape)> {
+            let src = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &src[o1..o2],
+            };
+            let alpha = match alpha_layout.contiguous_offsets() {
+                None => crate::bail!("alpha has to be contiguous"),
+                Some((o1, o2)) => &alpha[o1..o2],
+            };
+            let beta = match beta_layout.contiguous_offsets() {
+                None => crate::bail!("beta has to be contiguous"),
+                Some((o1, o2)) => &beta[o1..o2],
+            };
+            let el_count = layout.shape().elem_count();
+            let dims = layout.shape().dims();
+            let dim_m1 = dims[dims.len() - 1];
+            let mut dst = vec![T::zero(); el_count];
+            src.par_chunks(dim_m1)
+                .zip(dst.par_chunks_mut(dim_m1))
+                .for_each(|(src, dst)| {
+                    let mut sum = 0f32;
+                    let mut sum2 = 0f32;
+                    for v in src {
+                        let v = v.as_();
+                        sum += v;
+                        sum2 += v * v;
+                    }
+                    let mean = sum / dim_m1 as f32;
+                    let var = sum2 / dim_m1 as f32 - mean * mean;
+                    let inv_std = (var + eps).sqrt().recip();
+                    for ((d, s), (alpha, beta)) in
+                        dst.iter_mut().zip(src.iter()).zip(alpha.iter().zip(beta))
+                    {
+                        let alpha = alpha.as_();
+                        let beta = beta.as_();
+                        let d_ = (s.as_() - mean) * inv_std * alpha + beta;
+                        *d = T::from_f32(d_).unwrap_or_else(T::nan);
+                    }
+                });
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, Shape::from_dims(dims)))
+        }
+
+        use CpuStorage as C;
+        match (s1, s2, s3) {
+            (C::BF16(s1), C::BF16(s2), C::BF16(s3)) => {
+                inner::<half::bf16>(s1, l1, s2, l2, s3, l3, eps)
+            }
+            (C::F16(s1), C::F16(s2), C::F16(s3)) => inner::<half::f16>(s1, l1, s2, l2, s3, l3, eps),
+            (C::F32(s1), C::F32(s2), C::F32(s3)) => inner::<f32>(s1, l1, s2, l2, s3, l3, eps),
+            _ => crate::bail!("unsupported dtype for rmsnorm {:?}", s1.dtype()),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &crate::core::CudaStorage,
+        l1: &Layout,
+        s2: &crate::core::CudaStorage,
+        l2: &Layout,
+        s3: &crate::core::CudaStorage,
+        l3: &Layout,
+    ) -> Result<(crate::core::CudaStorage, Shape)> {
+        use crate::core::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use crate::core::cuda_backend::{kernel_name, kernels, Map3, WrapErr};
+        use crate::core::{CudaDevice, WithDType};
+
+        struct S {
+            eps: f32,
+        }
+        impl Map3 for S {
+            fn f<T: DeviceRepr + WithDType>(
+                &self,
+                src: &CudaSlice<T>,
+                layout: &Layout,
+                alpha: &CudaSlice<T>,
+                alpha_layout: &Layout,
+                beta: &CudaSlice<T>,
+                beta_layout: &Layout,
+                dev: &CudaDevice,
+            ) -> Result<CudaSlice<T>> {
+                let src = match layout.contiguous_offsets() {
+                    None => crate::bail!("input has to be contiguous"),
+                    Some((o1, o2)) => src.slice(o1..o2),
+                };
+                let alpha = match alpha_layout.contiguous_offsets() {
+                    None => crate::bail!("alpha has to be contiguous"),
+                    Some((o1, o2)) => alpha.slice(o1..o2),
+                };
+                let beta = match beta_layout.contiguous_offsets() {
+                    None => crate::bail!("beta has to be contiguous"),
+                    Some((o1, o2)) => beta.slice(o1..o2),
+                };
+                let el = layout.shape().elem_count();
+                let dims = layout.shape().dims();
+                let dim_m1 = dims[dims.len() - 1];
+                let (n_rows, n_cols) = (el / dim_m1, dim_m1);
+
+                let block_size = cuda_norm_block_size(n_cols);
+                let cfg = LaunchConfig {
+                    grid_dim: (n_rows as u32, 1, 1),
+                    block_dim: (block_size, 1, 1),
+                    shared_mem_bytes: 0,
+                };
+                let func = dev.get_or_load_func(&kernel_name::<T>("layernorm"), kernels::REDUCE)?;
+                // SAFETY: Set later by running the kernel.
+                let dst = unsafe { dev.alloc::<T>(el) }.w()?;
+                let params = (
+                    &src,
+                    &dst,
+                    &alpha,
+                    &beta,
+                    n_cols as i32,
+                    block_size as i32,
+                    self.eps,
+                );
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                Ok(dst)
+            }
+        }
+
+        use crate::core::backend::BackendStorage;
+        let dev = s1.device();
+        let slice = S { eps: self.eps }.map(&s1.slice, l1, &s2.slice, l2, &s3.slice, l3, dev)?;
+        let dst = crate::core::cuda_backend::CudaStorage {
+            slice,
+            device: dev.clone(),
+        };
+        Ok((dst, l1.shape().clone()))
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        s1: &crate::core::MetalStorage,
+        l1: &Layout,
+        s2: &crate::core::MetalStorage,
+        l2: &Layout,
+        s3: &crate::core::MetalStorage,
+        l3: &Layout,
+    ) -> Result<(crate::core::MetalStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+        let device = s1.device();
+        let command_buffer = device.command_buffer()?;
+        let kernels = device.kernels();
+        let name = match (s1.dtype(), s2.dtype(), s3.dtype()) {
+            (DType::F32, DType::F32, DType::F32) => "layernorm_f32",
+            (DType::F16, DType::F16, DType::F16) => "layernorm_f16",
+            (DType::BF16, DType::BF16, DType::BF16) => "layernorm_bf16",
+            (dt1, dt2, dt3) => {
+                crate::bail!("layernorm is not implemented for {dt1:?} {dt2:?} {dt3:?}")
+            }
+        };
+
+        if !(l1.is_contiguous() && l2.is_contiguous() && l3.is_contiguous()) {
+            crate::bail!("Non contiguous layernorm is not implemented");
+        }
+
+        let last_dim = l1.dims()[l1.shape().rank() - 1];
+        let elem_count = l1.shape().elem_count();
+        let output = device.new_buffer(elem_count, s1.dtype(), "layernorm")?;
+        crate::metal_kernels::call_layer_norm(
+            device.metal_device(),
+            &command_buffer,
+            kernels,
+            name,
+            elem_count,
+            last_dim,
+            self.eps,
+            s1.buffer(),
+            l1.start_offset() * s1.dtype().size_in_bytes(),
+            s2.buffer(),
+            l2.start_offset() * s2.dtype().size_in_bytes(),
+            s3.buffer(),
+            l3.start_offset() * s3.dtype().size_in_bytes(),
+            &output,
+        )
+        .map_err(crate::core::Error::wrap)?;
+        let newstorage =
+            crate::core::MetalStorage::new(output, device.clone(), elem_count, s1.dtype());
+        Ok((newstorage, l1.shape().clone()))
+    }
+}
+
+impl crate::core::InplaceOp3 for LayerNorm {
+    fn name(&self) -> &'static str {
+        "layer-norm"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &mut CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+        s3: &CpuStorage,
+        l3: &Layout,
+    ) -> Result<()> {
+        use crate::core::backend::BackendStorage;
+
+        let eps = self.eps;
+        fn inner<
+            T: crate::core::WithDType
+                + num_traits::Float
+                + num_traits::AsPrimitive<f32>
+                + num_traits::FromPrimitive,
+        >(
+            xs: &mut [T],
+            layout: &Layout,
+            alpha: &[T],
+            alpha_layout: &Layout,
+            beta: &[T],
+            beta_layout: &Layout,
+            eps: f32,
+        ) -> Result<()> {
+            let xs = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &mut xs[o1..o2],
+            };
+            let alpha = match alpha_layout.contiguous_offsets() {
+                None => crate::bail!("alpha has to be contiguous"),
+                Some((o1, o2)) => &alpha[o1..o2],
+            };
+            let beta = match beta_layout.contiguous_offsets() {
+                None => crate::bail!("beta has to be contiguous"),
+                Some((o1, o2)) => &beta[o1..o2],
+            };
+            let dims = layout.shape().dims();
+            let dim_m1 = dims[dims.len() - 1];
+            xs.par_chunks_mut(dim_m1).for_each(|xs| {
+                let mut sum = 0f32;
+                let mut sum2 = 0f32;
+                for v in xs.iter() {
+                    let v = v.as_();
+                    sum += v;
+                    sum2 += v * v;
+                }
+                let mean = sum / dim_m1 as f32;
+                let var = sum2 / dim_m1 as f32 - mean * mean;
+                let inv_std = (var + eps).sqrt().recip();
+                for (x, (alpha, beta)) in xs.iter_mut().zip(alpha.iter().zip(beta)) {
+                    let v = (x.as_() - mean) * inv_std * alpha.as_() + beta.as_();
+                    *x = T::from_f32(v).unwrap_or_else(T::nan);
+                }
+            });
+            Ok(())
+        }
+
+        use CpuStorage as C;
+        let dtype = s1.dtype();
+        match (s1, s2, s3) {
+            (C::BF16(s1), C::BF16(s2), C::BF16(s3)) => {
+                inner::<half::bf16>(s1, l1, s2, l2, s3, l3, eps)
+            }
+            (C::F16(s1), C::F16(s2), C::F16(s3)) => inner::<half::f16>(s1, l1, s2, l2, s3, l3, eps),
+            (C::F32(s1), C::F32(s2), C::F32(s3)) => inner::<f32>(s1, l1, s2, l2, s3, l3, eps),
+            _ => crate::bail!("unsupported dtype for layernorm {dtype:?}"),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &mut crate::core::CudaStorage,
+        l1: &Layout,
+        s2: &crate::core::CudaStorage,
+        l2: &Layout,
+        s3: &crate::core::CudaStorage,
+        l3: &Layout,
+    ) -> Result<()> {
+        use crate::core::backend::BackendStorage;
+        use crate::core::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use crate::core::cuda_backend::{kernel_name, kernels, CudaStorageSlice as S, WrapErr};
+        use crate::core::{CudaDevice, WithDType};
+
+        #[allow(clippy::too_many_arguments)]
+        fn launch<T: DeviceRepr + WithDType>(
+            xs: &CudaSlice<T>,
+            layout: &Layout,
+            alpha: &CudaSlice<T>,
+            alpha_layout: &Layout,
+            beta: &CudaSlice<T>,
+            beta_layout: &Layout,
+            eps: f32,
+            dev: &CudaDevice,
+        ) -> Result<()> {
+            let xs = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => xs.slice(o1..o2),
+            };
+            let alpha = match alpha_layout.contiguous_offsets() {
+                None => crate::bail!("alpha has to be contiguous"),
+                Some((o1, o2)) => alpha.slice(o1..o2),
+            };
+            let beta = match beta_layout.contiguous_offsets() {
+                None => crate::bail!("beta has to be contiguous"),
+                Some((o1, o2)) => beta.slice(o1..o2),
+            };
+            let el = layout.shape().elem_count();
+            let dims = layout.shape().dims();
+            let dim_m1 = dims[dims.len() - 1];
+            let (n_rows, n_cols) = (el / dim_m1, dim_m1);
+
+            let block_size = cuda_norm_block_size(n_cols);
+            let cfg = LaunchConfig {
+                grid_dim: (n_rows as u32, 1, 1),
+                block_dim: (block_size, 1, 1),
+                shared_mem_bytes: 0,
+            };
+            let func = dev.get_or_load_func(&kernel_name::<T>("layernorm"), kernels::REDUCE)?;
+            // Every element is read and then written by the same thread so the kernel can
+            // write its output over its input.
+            let params = (
+                &xs,
+                &xs,
+                &alpha,
+                &beta,
+                n_cols as i32,
+                block_size as i32,
+                eps,
+            );
+            // SAFETY: ffi.
+            unsafe { func.launch(cfg, params) }.w()?;
+            Ok(())
+        }
+
+        let dev = s1.device().clone();
+        let eps = self.eps;
+        match (&s1.slice, &s2.slice, &s3.slice) {
+            (S::BF16(x), S::BF16(a), S::BF16(b)) => launch(x, l1, a, l2, b, l3, eps, &dev),
+            (S::F16(x), S::F16(a), S::F16(b)) => launch(x, l1, a, l2, b, l3, eps, &dev),
+            (S::F32(x), S::F32(a), S::F32(b)) => launch(x, l1, a, l2, b, l3, eps, &dev),
+            _ => crate::bail!("unsupported dtype for layernorm {:?}", s1.dtype()),
+        }
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        s1: &mut crate::core::MetalStorage,
+        l1: &Layout,
+        s2: &crate::core::MetalStorage,
+        l2: &Layout,
+        s3: &crate::core::MetalStorage,
+        l3: &Layout,
+    ) -> Result<()> {
+        use crate::core::backend::BackendStorage;
+        let device = s1.device();
+        let command_buffer = device.command_buffer()?;
+        let kernels = device.kernels();
+        let name = match (s1.dtype(), s2.dtype(), s3.dtype()) {
+            (DType::F32, DType::F32, DType::F32) => "layernorm_f32",
+            (DType::F16, DType::F16, DType::F16) => "layernorm_f16",
+            (DType::BF16, DType::BF16, DType::BF16) => "layernorm_bf16",
+            (dt1, dt2, dt3) => {
+                crate::bail!("layernorm is not implemented for {dt1:?} {dt2:?} {dt3:?}")
+            }
+        };
+
+        if !(l1.is_contiguous() && l2.is_contiguous() && l3.is_contiguous()) {
+            crate::bail!("Non contiguous layernorm is not implemented");
+        }
+        // The output buffer is written from its start.
+        if l1.start_offset() != 0 {
+            crate::bail!("inplace layernorm with a start offset is not implemented");
+        }
+
+        let last_dim = l1.dims()[l1.shape().rank() - 1];
+        let elem_count = l1.shape().elem_count();
+        crate::metal_kernels::call_layer_norm(
+            device.metal_device(),
+            &command_buffer,
+            kernels,
+            name,
+            elem_count,
+            last_dim,
+            self.eps,
+            s1.buffer(),
+            0,
+            s2.buffer(),
+            l2.start_offset() * s2.dtype().size_in_bytes(),
+            s3.buffer(),
+            l3.start_offset() * s3.dtype().size_in_bytes(),
+            s1.buffer(),
+        )
+        .map_err(crate::core::Error::wrap)?;
+        Ok(())
+    }
+}
+
+pub fn layer_norm_slow(x: &Tensor, alpha: &Tensor, beta: &Tensor, eps: f32) -> Result<Tensor> {
+    let x_dtype = x.dtype();
+    let internal_dtype = match x_dtype {
+        DType::F16 | DType::BF16 => DType::F32,
+        d => d,
+    };
+    let hidden_size = x.dim(D::Minus1)?;
+    let x = x.to_dtype(internal_dtype)?;
+    let x = {
+        let mean_x = (x.sum_keepdim(D::Minus1)? / hidden_size as f64)?;
+        x.broadcast_sub(&mean_x)?
+    };
+    let norm_x = (x.sqr()?.sum_keepdim(D::Minus1)? / hidden_size as f64)?;
+    let x_normed = x.broadcast_div(&(norm_x + eps as f64)?.sqrt()?)?;
+    x_normed
+        .to_dtype(x_dtype)?
+        .broadcast_mul(alpha)?
+        .broadcast_add(beta)
+}
+
+pub fn layer_norm(xs: &Tensor, alpha: &Tensor, beta: &Tensor, eps: f32) -> Result<Tensor> {
+    let hidden_size_xs = xs.dim(D::Minus1)?;
+    let hidden_size_alpha = alpha.dims1()?;
+    let hidden_size_beta = beta.dims1()?;
+    if hidden_size_xs != hidden_size_alpha || hidden_size_xs != hidden_size_beta {
+        crate::bail!(
+            "shape mismatch in layer-norm src: {:?} alpha: {:?} beta: {:?}",
+            xs.shape(),
+            alpha.shape(),
+            beta.shape()
+        )
+    }
+    xs.apply_op3_no_bwd(alpha, beta, &LayerNorm { eps })
+}
+
+/// In place variant of [`layer_norm`], `xs` has to be contiguous. As with
+/// [`inplace_softmax_last_dim`], tensors sharing their storage with `xs` see the update too.
+pub fn inplace_layer_norm(xs: &mut Tensor, alpha: &Tensor, beta: &Tensor, eps: f32) -> Result<()> {
+    let hidden_size_xs = xs.dim(D::Minus1)?;
+    let hidden_size_alpha = alpha.dims1()?;
+    let hidden_size_beta = beta.dims1()?;
+    if hidden_size_xs != hidden_size_alpha || hidden_size_xs != hidden_size_beta {
+        crate::bail!(
+            "shape mismatch in layer-norm src: {:?} alpha: {:?} beta: {:?}",
+            xs.shape(),
+            alpha.shape(),
+            beta.shape()
+        )
+    }
+    xs.inplace_op3(alpha, beta, &LayerNorm { eps })
+}
+
+/// Adaptive layer norm as used for timestep conditioning in diffusion transformers: `xs` is
+/// normalized over its last dim with unit weight and zero bias and then modulated with
+/// `xs_normed * (1 + scale) + shift`.
+///
+/// `xs` has shape `(batch, ..., channels)` while `scale` and `shift` have shape
+/// `(batch, channels)` and are broadcast over the intermediate dims.
+pub fn adaptive_layer_norm(
+    xs: &Tensor,
+    scale: &Tensor,
+    shift: &Tensor,
+    eps: f32,
+) -> Result<Tensor> {
+    let (b_sz, channels) = scale.dims2()?;
+    let dims = xs.dims();
+    if dims.len() < 2
+        || dims[0] != b_sz
+        || dims[dims.len() - 1] != channels
+        || shift.dims() != scale.dims()
+    {
+        crate::bail!(
+            "shape mismatch in adaptive-layer-norm src: {:?} scale: {:?} shift: {:?}",
+            xs.shape(),
+            scale.shape(),
+            shift.shape()
+        )
+    }
+    let alpha = Tensor::ones(channels, xs.dtype(), xs.device())?;
+    let beta = Tensor::zeros(channels, xs.dtype(), xs.device())?;
+    let xs = layer_norm(&xs.contiguous()?, &alpha, &beta, eps)?;
+    let mut mod_dims = vec![1; dims.len()];
+    mod_dims[0] = b_sz;
+    mod_dims[dims.len() - 1] = channels;
+    let scale = (scale.reshape(mod_dims.as_slice())? + 1.)?;
+    let shift = shift.reshape(mod_dims)?;
+    xs.broadcast_mul(&scale)?.broadcast_add(&shift)
+}
+
+/// GroupNorm over a contiguous `(batch, channels, spatial...)` input with per-channel weight
+/// and bias. Each (batch, group) slice is contiguous and normalized on its own.
+#[derive(Debug, Clone)]
+struct GroupNorm {
+    num_groups: usize,
+    eps: f32,
+}
+
+impl GroupNorm {
+    /// Returns `(group_size, spatial)` for a `(batch, channels, spatial...)` shape.
+    fn group_dims(&self, dims: &[usize]) -> (usize, usize) {
+        let spatial = dims[2..].iter().product::<usize>();
+        (dims[1] / self.num_groups * spatial, spatial)
+    }
+}
+
+impl crate::core::CustomOp3 for GroupNorm {
+    fn name(&self) -> &'static str {
+        "group-norm"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+        s3: &CpuStorage,
+        l3: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+
+        #[allow(clippy::too_many_arguments)]
+        fn inner<
+            T: crate::core::WithDType
+                + num_traits::Float
+                + num_traits::AsPrimitive<f32>
+                + num_traits::FromPrimitive,
+        >(
+            src: &[T],
+            layout: &Layout,
+            weight: &[T],
+            weight_layout: &Layout,
+            bias: &[T],
+            bias_layout: &Layout,
+            op: &GroupNorm,
+        ) -> Result<(CpuStorage, Shape)> {
+            let src = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &src[o1..o2],
+            };
+            let weight = match weight_layout.contiguous_offsets() {
+                None => crate::bail!("weight has to be contiguous"),
+                Some((o1, o2)) => &weight[o1..o2],
+            };
+            let bias = match bias_layout.contiguous_offsets() {
+                None => crate::bail!("bias has to be contiguous"),
+                Some((o1, o2)) => &bias[o1..o2],
+            };
+            let dims = layout.shape().dims();
+            let (group_size, spatial) = op.group_dims(dims);
+            let channels_per_group = group_size / spatial;
+            let mut dst = vec![T::zero(); src.len()];
+            src.par_chunks(group_size)
+                .zip(dst.par_chunks_mut(group_size))
+                .enumerate()
+                .for_each(|(i, (src, dst))| {
+                    // Two passes, `E[x²] - E[x]²` is too imprecise in f32 on large activations.
+                    let mean = src.iter().map(|v| v.as_()).sum::<f32>() / group_size as f32;
+                    let var = src
+                        .iter()
+                        .map(|v| {
+                            let d = v.as_() - mean;
+                            d * d
+                        })
+                        .sum::<f32>()
+                        / group_size as f32;
+                    let inv_std = (var + op.eps).sqrt().recip();
+                    let c0 = (i % op.num_groups) * channels_per_group;
+                    for (c, (src, dst)) in
+                        src.chunks(spatial).zip(dst.chunks_mut(spatial)).enumerate()
+                    {
+                        let w = weight[c0 + c].as_();
+                        let b = bias[c0 + c].as_();
+                        for (d, s) in dst.iter_mut().zip(src) {
+                            let d_ = (s.as_() - mean) * inv_std * w + b;
+                            *d = T::from_f32(d_).unwrap_or_else(T::nan);
+                        }
+                    }
+                });
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, Shape::from_dims(dims)))
+        }
+
+        use CpuStorage as C;
+        match (s1, s2, s3) {
+            (C::BF16(s1), C::BF16(s2), C::BF16(s3)) => {
+                inner::<half::bf16>(s1, l1, s2, l2, s3, l3, self)
+            }
+            (C::F16(s1), C::F16(s2), C::F16(s3)) => {
+                inner::<half::f16>(s1, l1, s2, l2, s3, l3, self)
+            }
+            (C::F32(s1), C::F32(s2), C::F32(s3)) => inner::<f32>(s1, l1, s2, l2, s3, l3, self),
+            _ => crate::bail!("unsupported dtype for group-norm {:?}", s1.dtype()),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &crate::core::CudaStorage,
+        l1: &Layout,
+        s2: &crate::core::CudaStorage,
+        l2: &Layout,
+        s3: &crate::core::CudaStorage,
+        l3: &Layout,
+    ) -> Result<(crate::core::CudaStorage, Shape)> {
+        use crate::core::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use crate::core::cuda_backend::{kernel_name, kernels, Map3, WrapErr};
+        use crate::core::{CudaDevice, WithDType};
+
+        struct S<'a>(&'a GroupNorm);
+        impl Map3 for S<'_> {
+            fn f<T: DeviceRepr + WithDType>(
+                &self,
+                src: &CudaSlice<T>,
+                layout: &Layout,
+                weight: &CudaSlice<T>,
+                weight_layout: &Layout,
+                bias: &CudaSlice<T>,
+                bias_layout: &Layout,
+                dev: &CudaDevice,
+            ) -> Result<CudaSlice<T>> {
+                let src = match layout.contiguous_offsets() {
+                    None => crate::bail!("input has to be contiguous"),
+                    Some((o1, o2)) => src.slice(o1..o2),
+                };
+                let weight = match weight_layout.contiguous_offsets() {
+                    None => crate::bail!("weight has to be contiguous"),
+                    Some((o1, o2)) => weight.slice(o1..o2),
+                };
+                let bias = match bias_layout.contiguous_offsets() {
+                    None => crate::bail!("bias has to be contiguous"),
+                    Some((o1, o2)) => bias.slice(o1..o2),
+                };
+                let el = layout.shape().elem_count();
+                let (group_size, spatial) = self.0.group_dims(layout.dims());
+
+                let block_size = cuda_norm_block_size(group_size);
+                let cfg = LaunchConfig {
+                    grid_dim: ((el / group_size) as u32, 1, 1),
+                    block_dim: (block_size, 1, 1),
+                    shared_mem_bytes: 0,
+                };
+                let func = dev.get_or_load_func(&kernel_name::<T>("groupnorm"), kernels::REDUCE)?;
+                // SAFETY: Set later by running the kernel.
+                let dst = unsafe { dev.alloc::<T>(el) }.w()?;
+                let params = (
+                    &src,
+                    &dst,
+                    &weight,
+                    &bias,
+                    group_size as i32,
+                    spatial as i32,
+                    self.0.num_groups as i32,
+                    block_size as i32,
+                    self.0.eps,
+                );
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                Ok(dst)
+            }
+        }
+
+        use crate::core::backend::BackendStorage;
+        let dev = s1.device();
+        let slice = S(self).map(&s1.slice, l1, &s2.slice, l2, &s3.slice, l3, dev)?;
+        let dst = crate::core::cuda_backend::CudaStorage {
+            slice,
+            device: dev.clone(),
+        };
+        Ok((dst, l1.shape().clone()))
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        s1: &crate::core::MetalStorage,
+        l1: &Layout,
+        s2: &crate::core::MetalStorage,
+        l2: &Layout,
+        s3: &crate::core::MetalStorage,
+        l3: &Layout,
+    ) -> Result<(crate::core::MetalStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+        let device = s1.device();
+        let command_buffer = device.command_buffer()?;
+        let kernels = device.kernels();
+        let name = match (s1.dtype(), s2.dtype(), s3.dtype()) {
+            (DType::F32, DType::F32, DType::F32) => "groupnorm_f32",
+            (DType::F16, DType::F16, DType::F16) => "groupnorm_f16",
+            (DType::BF16, DType::BF16, DType::BF16) => "groupnorm_bf16",
+            (dt1, dt2, dt3) => {
+                crate::bail!("groupnorm is not implemented for {dt1:?} {dt2:?} {dt3:?}")
+            }
+        };
+
+        if !(l1.is_contiguous() && l2.is_contiguous() && l3.is_contiguous()) {
+            crate::bail!("Non contiguous groupnorm is not implemented");
+        }
+
+        let (group_size, spatial) = self.group_dims(l1.dims());
+        let elem_count = l1.shape().elem_count();
+        let output = device.new_buffer(elem_count, s1.dtype(), "groupnorm")?;
+        crate::metal_kernels::call_group_norm(
+            device.metal_device(),
+            &command_buffer,
+            kernels,
+            name,
+            elem_count,
+            group_size,
+            spatial,
+            self.num_groups,
+            self.eps,
+            s1.buffer(),
+            l1.start_offset() * s1.dtype().size_in_bytes(),
+            s2.buffer(),
+            l2.start_offset() * s2.dtype().size_in_bytes(),
+            s3.buffer(),
+            l3.start_offset() * s3.dtype().size_in_bytes(),
+            &output,
+        )
+        .map_err(crate::core::Error::wrap)?;
+        let newstorage =
+            crate::core::MetalStorage::new(output, device.clone(), elem_count, s1.dtype());
+        Ok((newstorage, l1.shape().clone()))
+    }
+}
+
+/// Fused GroupNorm of a `(batch, channels, spatial...)` tensor, `weight` and `bias` being
+/// per-channel `(channels,)` tensors with the dtype of `xs`. Statistics are accumulated in f32.
+pub fn group_norm(
+    xs: &Tensor,
+    weight: &Tensor,
+    bias: &Tensor,
+    num_groups: usize,
+    eps: f32,
+) -> Result<Tensor> {
+    let dims = xs.dims();
+    if dims.len() < 3 {
+        crate::bail!(
+            "group-norm expects an input of rank at least 3, got {:?}",
+            dims
+        )
+    }
+    let channels = dims[1];
+    if num_groups == 0 || channels % num_groups != 0 {
+        crate::bail!("group-norm: num_groups ({num_groups}) must divide channels ({channels})")
+    }
+    if weight.dims1()? != channels || bias.dims1()? != channels {
+        crate::bail!(
+            "shape mismatch in group-norm src: {:?} weight: {:?} bias: {:?}",
+            xs.shape(),
+            weight.shape(),
+            bias.shape()
+        )
+    }
+    if xs.elem_count() == 0 {
+        return Ok(xs.clone());
+    }
+    xs.contiguous()?.apply_op3_no_bwd(
+        &weight.contiguous()?,
+        &bias.contiguous()?,
+        &GroupNorm { num_groups, eps },
+    )
+}
+
+/// LayerNorm over the channel dim of a contiguous `(batch, channels, h, w)` input, i.e. every
+/// spatial location is normalized over its `channels` values.
 #[derive(Debug, Clone)]
-struct RmsNorm {
+struct LayerNorm2d {
     eps: f32,
 }
 
-impl crate::core::CustomOp2 for RmsNorm {
+impl crate::core::CustomOp3 for LayerNorm2d {
     fn name(&self) -> &'static str {
-        "rms-norm"
+        "layer-norm-2d"
     }
 
     fn cpu_fwd(
@@ -763,6 +7606,8 @@ impl crate::core::CustomOp2 for RmsNorm {
         l1: &Layout,
         s2: &CpuStorage,
         l2: &Layout,
+        s3: &CpuStorage,
+        l3: &Layout,
     ) -> Result<(CpuStorage, Shape)> {
         use crate::core::backend::BackendStorage;
 
@@ -777,6 +7622,8 @@ impl crate::core::CustomOp2 for RmsNorm {
             layout: &Layout,
             alpha: &[T],
             alpha_layout: &Layout,
+            beta: &[T],
+            beta_layout: &Layout,
             eps: f32,
         ) -> Result<(CpuStorage, Shape)> {
             let src = match layout.contiguous_offsets() {
@@ -787,36 +7634,52 @@ impl crate::core::CustomOp2 for RmsNorm {
                 None => crate::bail!("alpha has to be contiguous"),
                 Some((o1, o2)) => &alpha[o1..o2],
             };
-            let el_count = layout.shape().elem_count();
+            let beta = match beta_layout.contiguous_offsets() {
+                None => crate::bail!("beta has to be contiguous"),
+                Some((o1, o2)) => &beta[o1..o2],
+            };
             let dims = layout.shape().dims();
-            let dim_m1 = dims[dims.len() - 1];
-            let mut dst = vec![T::zero(); el_count];
-            src.par_chunks(dim_m1)
-                .zip(dst.par_chunks_mut(dim_m1))
-                .for_each(|(src, dst)| {
-                    let sum2 = src
-                        .iter()
-                        .map(|&v| {
-                            let v = v.as_();
-                            v * v
-                        })
-                        .sum::<f32>();
-                    let m = (sum2 / dim_m1 as f32 + eps).sqrt();
-                    let m = T::from_f32(m).unwrap_or_else(T::nan);
-                    for ((d, s), alpha) in dst.iter_mut().zip(src.iter()).zip(alpha) {
-                        *d = *s / m * *alpha
-                    }
-                });
+            let (channels, spatial) = (dims[1], dims[2] * dims[3]);
+            let mut dst = vec![T::zero(); src.len()];
+            for (src, dst) in src
+                .chunks(channels * spatial)
+                .zip(dst.chunks_mut(channels * spatial))
+            {
+                // Per location `(mean, inv_std)`, the channel values are `spatial` apart.
+                let stats: Vec<(f32, f32)> = (0..spatial)
+                    .into_par_iter()
+                    .map(|s| {
+                        let col = src[s..].iter().step_by(spatial).map(|v| v.as_());
+                        let mean = col.clone().sum::<f32>() / channels as f32;
+                        let var =
+                            col.map(|v| (v - mean) * (v - mean)).sum::<f32>() / channels as f32;
+                        (mean, (var + eps).sqrt().recip())
+                    })
+                    .collect();
+                src.par_chunks(spatial)
+                    .zip(dst.par_chunks_mut(spatial))
+                    .zip(alpha.par_iter().zip(beta.par_iter()))
+                    .for_each(|((src, dst), (alpha, beta))| {
+                        let alpha = alpha.as_();
+                        let beta = beta.as_();
+                        for ((d, s), (mean, inv_std)) in dst.iter_mut().zip(src).zip(&stats) {
+                            let d_ = (s.as_() - mean) * inv_std * alpha + beta;
+                            *d = T::from_f32(d_).unwrap_or_else(T::nan);
+                        }
+                    });
+            }
             let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
             Ok((storage, Shape::from_dims(dims)))
         }
 
         use CpuStorage as C;
-        match (s1, s2) {
-            (C::BF16(s1), C::BF16(s2)) => inner::<half::bf16>(s1, l1, s2, l2, eps),
-            (C::F16(s1), C::F16(s2)) => inner::<half::f16>(s1, l1, s2, l2, eps),
-            (C::F32(s1), C::F32(s2)) => inner::<f32>(s1, l1, s2, l2, eps),
-            _ => crate::bail!("unsupported dtype for rmsnorm {:?}", s1.dtype()),
+        match (s1, s2, s3) {
+            (C::BF16(s1), C::BF16(s2), C::BF16(s3)) => {
+                inner::<half::bf16>(s1, l1, s2, l2, s3, l3, eps)
+            }
+            (C::F16(s1), C::F16(s2), C::F16(s3)) => inner::<half::f16>(s1, l1, s2, l2, s3, l3, eps),
+            (C::F32(s1), C::F32(s2), C::F32(s3)) => inner::<f32>(s1, l1, s2, l2, s3, l3, eps),
+            _ => crate::bail!("unsupported dtype for layer-norm-2d {:?}", s1.dtype()),
         }
     }
 
@@ -827,23 +7690,27 @@ impl crate::core::CustomOp2 for RmsNorm {
         l1: &Layout,
         s2: &crate::core::CudaStorage,
         l2: &Layout,
+        s3: &crate::core::CudaStorage,
+        l3: &Layout,
     ) -> Result<(crate::core::CudaStorage, Shape)> {
         use crate::core::cuda_backend::cudarc::driver::{
             CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
         };
-        use crate::core::cuda_backend::{kernel_name, kernels, Map2, WrapErr};
+        use crate::core::cuda_backend::{kernel_name, kernels, Map3, WrapErr};
         use crate::core::{CudaDevice, WithDType};
 
         struct S {
             eps: f32,
         }
-        impl Map2 for S {
+        impl Map3 for S {
             fn f<T: DeviceRepr + WithDType>(
                 &self,
                 src: &CudaSlice<T>,
                 layout: &Layout,
                 alpha: &CudaSlice<T>,
                 alpha_layout: &Layout,
+                beta: &CudaSlice<T>,
+                beta_layout: &Layout,
                 dev: &CudaDevice,
             ) -> Result<CudaSlice<T>> {
                 let src = match layout.contiguous_offsets() {
@@ -854,26 +7721,28 @@ impl crate::core::CustomOp2 for RmsNorm {
                     None => crate::bail!("alpha has to be contiguous"),
                     Some((o1, o2)) => alpha.slice(o1..o2),
                 };
+                let beta = match beta_layout.contiguous_offsets() {
+                    None => crate::bail!("beta has to be contiguous"),
+                    Some((o1, o2)) => beta.slice(o1..o2),
+                };
                 let el = layout.shape().elem_count();
-                let dims = layout.shape().dims();
-                let dim_m1 = dims[dims.len() - 1];
-                let (n_rows, n_cols) = (el / dim_m1, dim_m1);
+                let dims = layout.dims();
+                let (channels, spatial) = (dims[1], dims[2] * dims[3]);
+                let n_locations = el / channels;
 
-                let block_size = if n_cols < 1024 { 32 } else { 1024 };
-                let cfg = LaunchConfig {
-                    grid_dim: (n_rows as u32, 1, 1),
-                    block_dim: (block_size, 1, 1),
-                    shared_mem_bytes: 0,
-                };
-                let func = dev.get_or_load_func(&kernel_name::<T>("rmsnorm"), kernels::REDUCE)?;
+                let cfg = LaunchConfig::for_num_elems(n_locations as u32);
+                let func =
+                    dev.get_or_load_func(&kernel_name::<T>("layernorm2d"), kernels::REDUCE)?;
                 // SAFETY: Set later by running the kernel.
                 let dst = unsafe { dev.alloc::<T>(el) }.w()?;
                 let params = (
                     &src,
                     &dst,
                     &alpha,
-                    n_cols as i32,
-                    block_size as i32,
+                    &beta,
+                    channels as i32,
+                    spatial as i32,
+                    n_locations as i32,
                     self.eps,
                 );
                 // SAFETY: ffi.
@@ -884,7 +7753,7 @@ impl crate::core::CustomOp2 for RmsNorm {
 
         use crate::core::backend::BackendStorage;
         let dev = s1.device();
-        let slice = S { eps: self.eps }.map(&s1.slice, l1, &s2.slice, l2, dev)?;
+        let slice = S { eps: self.eps }.map(&s1.slice, l1, &s2.slice, l2, &s3.slice, l3, dev)?;
         let dst = crate::core::cuda_backend::CudaStorage {
             slice,
             device: dev.clone(),
@@ -899,37 +7768,45 @@ impl crate::core::CustomOp2 for RmsNorm {
         l1: &Layout,
         s2: &crate::core::MetalStorage,
         l2: &Layout,
+        s3: &crate::core::MetalStorage,
+        l3: &Layout,
     ) -> Result<(crate::core::MetalStorage, Shape)> {
         use crate::core::backend::BackendStorage;
         let device = s1.device();
         let command_buffer = device.command_buffer()?;
         let kernels = device.kernels();
-        let name = match (s1.dtype(), s2.dtype()) {
-            (DType::F32, DType::F32) => "rmsnorm_f32",
-            (DType::F16, DType::F16) => "rmsnorm_f16",
-            (DType::BF16, DType::BF16) => "rmsnorm_bf16",
-            (dt1, dt2) => crate::bail!("rmsnorm is not implemented for {dt1:?} {dt2:?}"),
+        let name = match (s1.dtype(), s2.dtype(), s3.dtype()) {
+            (DType::F32, DType::F32, DType::F32) => "layernorm2d_f32",
+            (DType::F16, DType::F16, DType::F16) => "layernorm2d_f16",
+            (DType::BF16, DType::BF16, DType::BF16) => "layernorm2d_bf16",
+            (dt1, dt2, dt3) => {
+                crate::bail!("layernorm2d is not implemented for {dt1:?} {dt2:?} {dt3:?}")
+            }
         };
 
-        if !(l1.is_contiguous() && l2.is_contiguous()) {
-            crate::bail!("Non contiguous rmsnorm is not implemented");
+        if !(l1.is_contiguous() && l2.is_contiguous() && l3.is_contiguous()) {
+            crate::bail!("Non contiguous layernorm2d is not implemented");
         }
 
-        let last_dim = l1.dims()[l1.shape().rank() - 1];
+        let dims = l1.dims();
+        let (channels, spatial) = (dims[1], dims[2] * dims[3]);
         let elem_count = l1.shape().elem_count();
-        let output = device.new_buffer(elem_count, s1.dtype(), "rmsnorm")?;
-        crate::metal_kernels::call_rms_norm(
+        let output = device.new_buffer(elem_count, s1.dtype(), "layernorm2d")?;
+        crate::metal_kernels::call_layer_norm2d(
             device.metal_device(),
             &command_buffer,
             kernels,
             name,
-            elem_count,
-            last_dim,
+            channels,
+            spatial,
+            elem_count / channels,
             self.eps,
             s1.buffer(),
             l1.start_offset() * s1.dtype().size_in_bytes(),
             s2.buffer(),
             l2.start_offset() * s2.dtype().size_in_bytes(),
+            s3.buffer(),
+            l3.start_offset() * s3.dtype().size_in_bytes(),
             &output,
         )
         .map_err(crate::core::Error::wrap)?;
@@ -939,332 +7816,689 @@ impl crate::core::CustomOp2 for RmsNorm {
     }
 }
 
-pub fn rms_norm_slow(x: &Tensor, alpha: &Tensor, eps: f32) -> Result<Tensor> {
-    let x_dtype = x.dtype();
-    let internal_dtype = match x_dtype {
-        DType::F16 | DType::BF16 => DType::F32,
-        d => d,
-    };
-    let hidden_size = x.dim(D::Minus1)?;
-    let x = x.to_dtype(internal_dtype)?;
-    let norm_x = (x.sqr()?.sum_keepdim(D::Minus1)? / hidden_size as f64)?;
-    let x_normed = x.broadcast_div(&(norm_x + eps as f64)?.sqrt()?)?;
-    x_normed.to_dtype(x_dtype)?.broadcast_mul(alpha)
+/// LayerNorm over the channel dim of a `(batch, channels, h, w)` tensor as used by ConvNeXt style
+/// blocks, `alpha` and `beta` are `(channels,)`. Equivalent to a layer norm of the NHWC permuted
+/// input but without the two transposes.
+pub fn layer_norm2d(xs: &Tensor, alpha: &Tensor, beta: &Tensor, eps: f32) -> Result<Tensor> {
+    let (_, c, _, _) = xs.dims4()?;
+    if alpha.dims1()? != c || beta.dims1()? != c {
+        crate::bail!(
+            "shape mismatch in layer-norm-2d src: {:?} alpha: {:?} beta: {:?}",
+            xs.shape(),
+            alpha.shape(),
+            beta.shape()
+        )
+    }
+    if xs.elem_count() == 0 {
+        return Ok(xs.clone());
+    }
+    xs.contiguous()?.apply_op3_no_bwd(
+        &alpha.contiguous()?,
+        &beta.contiguous()?,
+        &LayerNorm2d { eps },
+    )
+}
+
+/// LayerNorm writing its output in `out_dtype` rather than in the input dtype, only used for the
+/// F32 <-> F16/BF16 combinations.
+struct LayerNormCast {
+    eps: f32,
+    out_dtype: DType,
+}
+
+impl crate::core::CustomOp3 for LayerNormCast {
+    fn name(&self) -> &'static str {
+        "layer-norm-cast"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+        s3: &CpuStorage,
+        l3: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+
+        let eps = self.eps;
+        fn inner<
+            T: crate::core::WithDType + num_traits::AsPrimitive<f32>,
+            O: crate::core::WithDType + num_traits::FromPrimitive,
+        >(
+            src: &[T],
+            layout: &Layout,
+            alpha: &[T],
+            alpha_layout: &Layout,
+            beta: &[T],
+            beta_layout: &Layout,
+            eps: f32,
+        ) -> Result<(CpuStorage, Shape)> {
+            let src = match layout.contiguous_offsets() {
+                None => crate::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &src[o1..o2],
+            };
+            let alpha = match alpha_layout.contiguous_offsets() {
+                None => crate::bail!("alpha has to be contiguous"),
+                Some((o1, o2)) => &alpha[o1..o2],
+            };
+            let beta = match beta_layout.contiguous_offsets() {
+                None => crate::bail!("beta has to be contiguous"),
+                Some((o1, o2)) => &beta[o1..o2],
+            };
+            let el_count = layout.shape().elem_count();
+            let dims = layout.shape().dims();
+            let dim_m1 = dims[dims.len() - 1];
+            let mut dst = vec![O::zero(); el_count];
+            src.par_chunks(dim_m1)
+                .zip(dst.par_chunks_mut(dim_m1))
+                .for_each(|(src, dst)| {
+                    let mut sum = 0f32;
+                    let mut sum2 = 0f32;
+                    for v in src {
+                        let v = v.as_();
+                        sum += v;
+                        sum2 += v * v;
+                    }
+                    let mean = sum / dim_m1 as f32;
+                    let var = sum2 / dim_m1 as f32 - mean * mean;
+                    let inv_std = (var + eps).sqrt().recip();
+                    for ((d, s), (alpha, beta)) in
+                        dst.iter_mut().zip(src.iter()).zip(alpha.iter().zip(beta))
+                    {
+                        let v = (s.as_() - mean) * inv_std * alpha.as_() + beta.as_();
+                        *d = O::from_f32(v).unwrap_or_else(O::zero);
+                    }
+                });
+            let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, Shape::from_dims(dims)))
+        }
+
+        use CpuStorage as C;
+        match (s1, s2, s3, self.out_dtype) {
+            (C::F32(s1), C::F32(s2), C::F32(s3), DType::BF16) => {
+                inner::<f32, half::bf16>(s1, l1, s2, l2, s3, l3, eps)
+            }
+            (C::F32(s1), C::F32(s2), C::F32(s3), DType::F16) => {
+                inner::<f32, half::f16>(s1, l1, s2, l2, s3, l3, eps)
+            }
+            (C::BF16(s1), C::BF16(s2), C::BF16(s3), DType::F32) => {
+                inner::<half::bf16, f32>(s1, l1, s2, l2, s3, l3, eps)
+            }
+            (C::F16(s1), C::F16(s2), C::F16(s3), DType::F32) => {
+                inner::<half::f16, f32>(s1, l1, s2, l2, s3, l3, eps)
+            }
+            _ => crate::bail!(
+                "unsupported dtypes for layernorm-cast {:?} -> {:?}",
+                s1.dtype(),
+                self.out_dtype
+            ),
+        }
+    }
+}
+
+/// The pre-norm transformer block prologue in mixed precision: adds `x` to `residual`, then
+/// layer-norms the sum and writes the normed output in `out_dtype`.
+///
+/// Returns `(normed, new_residual)`. The addition is accumulated in F32 and `new_residual` keeps
+/// the dtype of `residual`, so an F32 residual stream stays in F32 across blocks. On the CPU the
+/// F32 <-> F16/BF16 combinations normalize and cast in a single pass.
+pub fn add_layer_norm_cast(
+    x: &Tensor,
+    residual: &Tensor,
+    alpha: &Tensor,
+    beta: &Tensor,
+    eps: f32,
+    out_dtype: DType,
+) -> Result<(Tensor, Tensor)> {
+    if !matches!(out_dtype, DType::F16 | DType::BF16 | DType::F32) {
+        crate::bail!("add_layer_norm_cast does not support the output dtype {out_dtype:?}")
+    }
+    if x.shape() != residual.shape() {
+        crate::bail!(
+            "shape mismatch in add-layer-norm x: {:?} residual: {:?}",
+            x.shape(),
+            residual.shape()
+        )
+    }
+    let hidden_size_xs = x.dim(D::Minus1)?;
+    let hidden_size_alpha = alpha.dims1()?;
+    let hidden_size_beta = beta.dims1()?;
+    if hidden_size_xs != hidden_size_alpha || hidden_size_xs != hidden_size_beta {
+        crate::bail!(
+            "shape mismatch in layer-norm src: {:?} alpha: {:?} beta: {:?}",
+            x.shape(),
+            alpha.shape(),
+            beta.shape()
+        )
+    }
+    let new_residual =
+        (x.to_dtype(DType::F32)? + residual.to_dtype(DType::F32)?)?.to_dtype(residual.dtype())?;
+    let alpha = alpha.to_dtype(new_residual.dtype())?;
+    let beta = beta.to_dtype(new_residual.dtype())?;
+    let normed = match (new_residual.dtype(), out_dtype) {
+        (DType::F32, DType::F16 | DType::BF16) | (DType::F16 | DType::BF16, DType::F32)
+            if new_residual.device().is_cpu() =>
+        {
+            new_residual.apply_op3_no_bwd(&alpha, &beta, &LayerNormCast { eps, out_dtype })?
+        }
+        _ => layer_norm(&new_residual, &alpha, &beta, eps)?.to_dtype(out_dtype)?,
+    };
+    Ok((normed, new_residual))
+}
+
+// https://pytorch.org/docs/stable/generated/torch.nn.PixelShuffle.html
+pub fn pixel_shuffle(xs: &Tensor, upscale_factor: usize) -> Result<Tensor> {
+    let (b_size, c, h, w) = xs.dims4()?;
+    if upscale_factor == 0 || c % (upscale_factor * upscale_factor) != 0 {
+        crate::bail!(
+            "pixel_shuffle expects channels divisible by {upscale_factor}^2, got {:?}",
+            xs.shape()
+        )
+    }
+    let out_c = c / upscale_factor / upscale_factor;
+    xs.reshape((b_size, out_c, upscale_factor, upscale_factor, h, w))?
+        .permute((0, 1, 4, 2, 5, 3))?
+        .reshape((b_size, out_c, h * upscale_factor, w * upscale_factor))
+}
+
+pub fn pixel_unshuffle(xs: &Tensor, downscale_factor: usize) -> Result<Tensor> {
+    let (b_size, c, h, w) = xs.dims4()?;
+    if downscale_factor == 0 || h % downscale_factor != 0 || w % downscale_factor != 0 {
+        crate::bail!(
+            "pixel_unshuffle expects height and width divisible by {downscale_factor}, got {:?}",
+            xs.shape()
+        )
+    }
+    let out_c = c * downscale_factor * downscale_factor;
+    xs.reshape((
+        b_size,
+        c,
+        h / downscale_factor,
+        downscale_factor,
+        w / downscale_factor,
+        downscale_factor,
+    ))?
+    .permute((0, 1, 3, 5, 2, 4))?
+    .reshape((b_size, out_c, h / downscale_factor, w / downscale_factor))
+}
+
+/// Temporal pixel shuffle, rearranges a `(batch, channels * r, time)` tensor into a
+/// `(batch, channels, time * r)` one where `r` is `upscale_factor`.
+pub fn pixel_shuffle_1d(xs: &Tensor, upscale_factor: usize) -> Result<Tensor> {
+    let (b_size, c, t) = xs.dims3()?;
+    if upscale_factor == 0 || c % upscale_factor != 0 {
+        crate::bail!(
+            "pixel_shuffle_1d expects channels divisible by {upscale_factor}, got {:?}",
+            xs.shape()
+        )
+    }
+    let out_c = c / upscale_factor;
+    xs.reshape((b_size, out_c, upscale_factor, t))?
+        .permute((0, 1, 3, 2))?
+        .reshape((b_size, out_c, t * upscale_factor))
+}
+
+/// Inverse of `pixel_shuffle_1d`, rearranges a `(batch, channels, time * r)` tensor into a
+/// `(batch, channels * r, time)` one where `r` is `downscale_factor`.
+pub fn pixel_unshuffle_1d(xs: &Tensor, downscale_factor: usize) -> Result<Tensor> {
+    let (b_size, c, t) = xs.dims3()?;
+    if downscale_factor == 0 || t % downscale_factor != 0 {
+        crate::bail!(
+            "pixel_unshuffle_1d expects time divisible by {downscale_factor}, got {:?}",
+            xs.shape()
+        )
+    }
+    xs.reshape((b_size, c, t / downscale_factor, downscale_factor))?
+        .permute((0, 1, 3, 2))?
+        .reshape((b_size, c * downscale_factor, t / downscale_factor))
+}
+
+/// Converts a `(batch, channels, height, width)` tensor to a contiguous
+/// `(batch, height, width, channels)` one.
+pub fn nchw_to_nhwc(xs: &Tensor) -> Result<Tensor> {
+    if xs.rank() != 4 {
+        crate::bail!("nchw_to_nhwc expects a rank 4 input, got {:?}", xs.shape())
+    }
+    xs.permute((0, 2, 3, 1))?.contiguous()
+}
+
+/// Converts a `(batch, height, width, channels)` tensor to a contiguous
+/// `(batch, channels, height, width)` one.
+pub fn nhwc_to_nchw(xs: &Tensor) -> Result<Tensor> {
+    if xs.rank() != 4 {
+        crate::bail!("nhwc_to_nchw expects a rank 4 input, got {:?}", xs.shape())
+    }
+    xs.permute((0, 3, 1, 2))?.contiguous()
+}
+
+// https://pytorch.org/docs/stable/generated/torch.nn.ReplicationPad2d.html
+/// Pads the last two dims of a `(batch, channels, height, width)` tensor by replicating its edge
+/// values, `pad` is `[left, right, top, bottom]` as in PyTorch.
+pub fn replication_pad2d(xs: &Tensor, pad: [usize; 4]) -> Result<Tensor> {
+    let [left, right, top, bottom] = pad;
+    let (_b_size, _c, h, w) = xs.dims4()?;
+    if pad == [0; 4] {
+        return Ok(xs.clone());
+    }
+    if h == 0 || w == 0 {
+        crate::bail!("replication-pad cannot pad an empty input {:?}", xs.shape())
+    }
+    // The edges are broadcast rather than concatenated once per padded column or row.
+    let pad_dim = |xs: &Tensor, dim: usize, before: usize, after: usize| -> Result<Tensor> {
+        let size = xs.dim(dim)?;
+        let mut parts = Vec::with_capacity(3);
+        let mut edge_shape = xs.dims().to_vec();
+        if before > 0 {
+            edge_shape[dim] = before;
+            parts.push(xs.narrow(dim, 0, 1)?.broadcast_as(edge_shape.as_slice())?);
+        }
+        parts.push(xs.clone());
+        if after > 0 {
+            edge_shape[dim] = after;
+            parts.push(
+                xs.narrow(dim, size - 1, 1)?
+                    .broadcast_as(edge_shape.as_slice())?,
+            );
+        }
+        Tensor::cat(&parts, dim)
+    };
+    let xs = pad_dim(xs, 3, left, right)?;
+    pad_dim(&xs, 2, top, bottom)
+}
+
+/// Same as [`replication_pad2d`] with the same padding on every side.
+pub fn replication_pad2d_uniform(xs: &Tensor, pad: usize) -> Result<Tensor> {
+    replication_pad2d(xs, [pad; 4])
+}
+
+/// Pads `xs` with `value`. As in PyTorch's `F.pad`, `pad` lists `(before, after)` pairs starting
+/// from the last dim: `[last_left, last_right, second_last_left, second_last_right, ...]`.
+pub fn constant_pad_nd(xs: &Tensor, pad: &[usize], value: f64) -> Result<Tensor> {
+    if pad.len() % 2 != 0 || pad.len() / 2 > xs.rank() {
+        crate::bail!(
+            "constant-pad expects pairs of pads for at most {} dims, got {pad:?}",
+            xs.rank()
+        )
+    }
+    let rank = xs.rank();
+    let mut xs = xs.clone();
+    for (i, pair) in pad.chunks(2).enumerate() {
+        let (dim, before, after) = (rank - 1 - i, pair[0], pair[1]);
+        if before == 0 && after == 0 {
+            continue;
+        }
+        let full = |size: usize| {
+            let mut dims = xs.dims().to_vec();
+            dims[dim] = size;
+            // Built in the dtype of `xs` since metal cannot cast from f64.
+            Tensor::ones(dims, xs.dtype(), xs.device())?.affine(0., value)
+        };
+        let mut parts = Vec::with_capacity(3);
+        if before > 0 {
+            parts.push(full(before)?);
+        }
+        parts.push(xs.clone());
+        if after > 0 {
+            parts.push(full(after)?);
+        }
+        xs = Tensor::cat(&parts, dim)?;
+    }
+    Ok(xs)
+}
+
+/// Pads the last two dims of a `(batch, channels, height, width)` tensor with `value`, `pad` is
+/// `[left, right, top, bottom]` as in PyTorch.
+pub fn constant_pad2d(xs: &Tensor, pad: [usize; 4], value: f64) -> Result<Tensor> {
+    let (_b_size, _c, _h, _w) = xs.dims4()?;
+    constant_pad_nd(xs, &pad, value)
 }
 
-pub fn rms_norm(xs: &Tensor, alpha: &Tensor, eps: f32) -> Result<Tensor> {
-    let hidden_size_xs = xs.dim(D::Minus1)?;
-    let hidden_size_alpha = alpha.dims1()?;
-    if hidden_size_xs != hidden_size_alpha {
+// https://pytorch.org/docs/stable/generated/torch.nn.ReflectionPad2d.html
+/// Pads the last two dims of a `(batch, channels, height, width)` tensor with a reflection of its
+/// values that does not repeat the edge, `pad` is `[left, right, top, bottom]` as in PyTorch. Each
+/// pad has to be smaller than the size of the dim it applies to.
+pub fn reflection_pad2d(xs: &Tensor, pad: [usize; 4]) -> Result<Tensor> {
+    let [left, right, top, bottom] = pad;
+    let (_b_size, _c, h, w) = xs.dims4()?;
+    if left >= w || right >= w || top >= h || bottom >= h {
         crate::bail!(
-            "shape mismatch in rms-norm {:?} {:?}",
-            xs.shape(),
-            alpha.shape()
+            "reflection-pad {pad:?} has to be smaller than the padded dims of {:?}",
+            xs.shape()
         )
     }
-    xs.apply_op2_no_bwd(alpha, &RmsNorm { eps })
+    // There is no flip op, the reflected slices are gathered with a single index-select per dim.
+    let pad_dim = |xs: &Tensor, dim: usize, before: usize, after: usize| -> Result<Tensor> {
+        if before == 0 && after == 0 {
+            return Ok(xs.clone());
+        }
+        let size = xs.dim(dim)?;
+        let ids = (1..=before)
+            .rev()
+            .chain(0..size)
+            .chain((size - 1 - after..size - 1).rev())
+            .map(|i| i as u32)
+            .collect::<Vec<_>>();
+        let ids = Tensor::new(ids, xs.device())?;
+        xs.index_select(&ids, dim)
+    };
+    let xs = pad_dim(xs, 3, left, right)?;
+    pad_dim(&xs, 2, top, bottom)
 }
 
-#[derive(Debug, Clone)]
-struct LayerNorm {
-    eps: f32,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PoolKind {
+    Max,
+    Avg { count_include_pad: bool },
 }
 
-impl crate::core::CustomOp3 for LayerNorm {
-    fn name(&self) -> &'static str {
-        "layer-norm"
+/// 2D pooling over a `(batch, channels, height, width)` input surrounded by `padding` virtual
+/// rows/cols, the kernel, stride and padding are all `[height, width]`.
+#[derive(Debug, Clone, Copy)]
+struct Pool2d {
+    kind: PoolKind,
+    kernel: [usize; 2],
+    stride: [usize; 2],
+    padding: [usize; 2],
+}
+
+impl Pool2d {
+    fn out_dims(&self, h: usize, w: usize) -> (usize, usize) {
+        let [k_h, k_w] = self.kernel;
+        let [s_h, s_w] = self.stride;
+        let [p_h, p_w] = self.padding;
+        ((h + 2 * p_h - k_h) / s_h + 1, (w + 2 * p_w - k_w) / s_w + 1)
     }
+}
 
-    fn cpu_fwd(
-        &self,
-        s1: &CpuStorage,
-        l1: &Layout,
-        s2: &CpuStorage,
-        l2: &Layout,
-        s3: &CpuStorage,
-        l3: &Layout,
-    ) -> Result<(CpuStorage, Shape)> {
-        use crate::core::backend::BackendStorage;
+impl crate::core::CustomOp1 for Pool2d {
+    fn name(&self) -> &'static str {
+        match self.kind {
+            PoolKind::Max => "max-pool2d",
+            PoolKind::Avg { .. } => "avg-pool2d",
+        }
+    }
 
-        let eps = self.eps;
-        fn inner<
-            T: crate::core::WithDType
-                + num_traits::Float
-                + num_traits::AsPrimitive<f32>
-                + num_traits::FromPrimitive,
-        >(
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        fn inner<T: crate::core::WithDType + num_traits::Float>(
             src: &[T],
             layout: &Layout,
-            alpha: &[T],
-            alpha_layout: &Layout,
-            beta: &[T],
-            beta_layout: &Layout,
-            eps: f32,
+            op: &Pool2d,
         ) -> Result<(CpuStorage, Shape)> {
             let src = match layout.contiguous_offsets() {
                 None => crate::bail!("input has to be contiguous"),
                 Some((o1, o2)) => &src[o1..o2],
             };
-            let alpha = match alpha_layout.contiguous_offsets() {
-                None => crate::bail!("alpha has to be contiguous"),
-                Some((o1, o2)) => &alpha[o1..o2],
-            };
-            let beta = match beta_layout.contiguous_offsets() {
-                None => crate::bail!("beta has to be contiguous"),
-                Some((o1, o2)) => &beta[o1..o2],
-            };
-            let el_count = layout.shape().elem_count();
-            let dims = layout.shape().dims();
-            let dim_m1 = dims[dims.len() - 1];
-            let mut dst = vec![T::zero(); el_count];
-            src.par_chunks(dim_m1)
-                .zip(dst.par_chunks_mut(dim_m1))
+            let (b_size, c, h, w) = layout.shape().dims4()?;
+            let (h_out, w_out) = op.out_dims(h, w);
+            let [k_h, k_w] = op.kernel;
+            let [s_h, s_w] = op.stride;
+            let [p_h, p_w] = op.padding;
+            let mut dst = vec![T::zero(); b_size * c * h_out * w_out];
+            src.par_chunks(h * w)
+                .zip(dst.par_chunks_mut(h_out * w_out))
                 .for_each(|(src, dst)| {
-                    let mut sum = 0f32;
-                    let mut sum2 = 0f32;
-                    for v in src {
-                        let v = v.as_();
-                        sum += v;
-                        sum2 += v * v;
-                    }
-                    let mean = sum / dim_m1 as f32;
-                    let var = sum2 / dim_m1 as f32 - mean * mean;
-                    let inv_std = (var + eps).sqrt().recip();
-                    for ((d, s), (alpha, beta)) in
-                        dst.iter_mut().zip(src.iter()).zip(alpha.iter().zip(beta))
-                    {
-                        let alpha = alpha.as_();
-                        let beta = beta.as_();
-                        let d_ = (s.as_() - mean) * inv_std * alpha + beta;
-                        *d = T::from_f32(d_).unwrap_or_else(T::nan);
+                    for (dst_h, dst) in dst.chunks_mut(w_out).enumerate() {
+                        // Window bounds in padded coordinates, then clamped to the input.
+                        let h_start = dst_h * s_h;
+                        let h_end = (h_start + k_h).min(h + 2 * p_h);
+                        let (h_lo, h_hi) = (h_start.max(p_h) - p_h, h_end.min(h + p_h) - p_h);
+                        for (dst_w, d) in dst.iter_mut().enumerate() {
+                            let w_start = dst_w * s_w;
+                            let w_end = (w_start + k_w).min(w + 2 * p_w);
+                            let (w_lo, w_hi) = (w_start.max(p_w) - p_w, w_end.min(w + p_w) - p_w);
+                            let window = (h_lo..h_hi)
+                                .flat_map(|i| src[i * w + w_lo..i * w + w_hi].iter().copied());
+                            *d = match op.kind {
+                                PoolKind::Max => window.fold(T::neg_infinity(), T::max),
+                                PoolKind::Avg { count_include_pad } => {
+                                    let sum = window.map(|v| v.to_f64()).sum::<f64>();
+                                    let count = if count_include_pad {
+                                        (h_end - h_start) * (w_end - w_start)
+                                    } else {
+                                        (h_hi - h_lo) * (w_hi - w_lo)
+                                    };
+                                    T::from_f64(sum / count as f64)
+                                }
+                            };
+                        }
                     }
                 });
             let storage = crate::core::WithDType::to_cpu_storage_owned(dst);
-            Ok((storage, Shape::from_dims(dims)))
+            Ok((storage, Shape::from((b_size, c, h_out, w_out))))
         }
 
-        use CpuStorage as C;
-        match (s1, s2, s3) {
-            (C::BF16(s1), C::BF16(s2), C::BF16(s3)) => {
-                inner::<half::bf16>(s1, l1, s2, l2, s3, l3, eps)
-            }
-            (C::F16(s1), C::F16(s2), C::F16(s3)) => inner::<half::f16>(s1, l1, s2, l2, s3, l3, eps),
-            (C::F32(s1), C::F32(s2), C::F32(s3)) => inner::<f32>(s1, l1, s2, l2, s3, l3, eps),
-            _ => crate::bail!("unsupported dtype for rmsnorm {:?}", s1.dtype()),
+        match storage {
+            CpuStorage::BF16(slice) => inner::<half::bf16>(slice, layout, self),
+            CpuStorage::F16(slice) => inner::<half::f16>(slice, layout, self),
+            CpuStorage::F32(slice) => inner::<f32>(slice, layout, self),
+            CpuStorage::F64(slice) => inner::<f64>(slice, layout, self),
+            _ => crate::bail!(
+                "unsupported dtype for {} {:?}",
+                self.name(),
+                storage.dtype()
+            ),
         }
     }
 
     #[cfg(feature = "cuda")]
     fn cuda_fwd(
         &self,
-        s1: &crate::core::CudaStorage,
-        l1: &Layout,
-        s2: &crate::core::CudaStorage,
-        l2: &Layout,
-        s3: &crate::core::CudaStorage,
-        l3: &Layout,
+        storage: &crate::core::CudaStorage,
+        layout: &Layout,
     ) -> Result<(crate::core::CudaStorage, Shape)> {
         use crate::core::cuda_backend::cudarc::driver::{
             CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
         };
-        use crate::core::cuda_backend::{kernel_name, kernels, Map3, WrapErr};
+        use crate::core::cuda_backend::{kernel_name, kernels, Map1, WrapErr};
         use crate::core::{CudaDevice, WithDType};
 
-        struct S {
-            eps: f32,
-        }
-        impl Map3 for S {
+        struct S(Pool2d);
+        impl Map1 for S {
             fn f<T: DeviceRepr + WithDType>(
                 &self,
                 src: &CudaSlice<T>,
-                layout: &Layout,
-                alpha: &CudaSlice<T>,
-                alpha_layout: &Layout,
-                beta: &CudaSlice<T>,
-                beta_layout: &Layout,
                 dev: &CudaDevice,
+                layout: &Layout,
             ) -> Result<CudaSlice<T>> {
-                let src = match layout.contiguous_offsets() {
-                    None => crate::bail!("input has to be contiguous"),
-                    Some((o1, o2)) => src.slice(o1..o2),
-                };
-                let alpha = match alpha_layout.contiguous_offsets() {
-                    None => crate::bail!("alpha has to be contiguous"),
-                    Some((o1, o2)) => alpha.slice(o1..o2),
-                };
-                let beta = match beta_layout.contiguous_offsets() {
-                    None => crate::bail!("beta has to be contiguous"),
-                    Some((o1, o2)) => beta.slice(o1..o2),
-                };
-                let el = layout.shape().elem_count();
-                let dims = layout.shape().dims();
-                let dim_m1 = dims[dims.len() - 1];
-                let (n_rows, n_cols) = (el / dim_m1, dim_m1);
-
-                let block_size = if n_cols < 1024 { 32 } else { 1024 };
-                let cfg = LaunchConfig {
-                    grid_dim: (n_rows as u32, 1, 1),
-                    block_dim: (block_size, 1, 1),
-                    shared_mem_bytes: 0,
-                };
-                let func = dev.get_or_load_func(&kernel_name::<T>("layernorm"), kernels::REDUCE)?;
+                let op = &self.0;
+                let (b_size, c, h, w) = layout.shape().dims4()?;
+                let (h_out, w_out) = op.out_dims(h, w);
+                let el = b_size * c * h_out * w_out;
+                let src = &src.slice(layout.start_offset()..);
+                let ds = dev
+                    .htod_copy([layout.dims(), layout.stride()].concat())
+                    .w()?;
+                // One thread per output element.
+                let cfg = LaunchConfig::for_num_elems(el as u32);
+                let [k_h, k_w] = op.kernel;
+                let [s_h, s_w] = op.stride;
+                let [p_h, p_w] = op.padding;
                 // SAFETY: Set later by running the kernel.
                 let dst = unsafe { dev.alloc::<T>(el) }.w()?;
-                let params = (
-                    &src,
-                    &dst,
-                    &alpha,
-                    &beta,
-                    n_cols as i32,
-                    block_size as i32,
-                    self.eps,
-                );
-                // SAFETY: ffi.
-                unsafe { func.launch(cfg, params) }.w()?;
+                match op.kind {
+                    PoolKind::Max => {
+                        let func = dev.get_or_load_func(
+                            &kernel_name::<T>("max_pool2d_padded"),
+                            kernels::CONV,
+                        )?;
+                        let params = (k_h, k_w, s_h, s_w, p_h, p_w, &ds, src, &dst);
+                        // SAFETY: ffi.
+                        unsafe { func.launch(cfg, params) }.w()?;
+                    }
+                    PoolKind::Avg { count_include_pad } => {
+                        let func = dev.get_or_load_func(
+                            &kernel_name::<T>("avg_pool2d_padded"),
+                            kernels::CONV,
+                        )?;
+                        let params = (
+                            k_h,
+                            k_w,
+                            s_h,
+                            s_w,
+                            p_h,
+                            p_w,
+                            count_include_pad as i32,
+                            &ds,
+                            src,
+                            &dst,
+                        );
+                        // SAFETY: ffi.
+                        unsafe { func.launch(cfg, params) }.w()?;
+                    }
+                }
                 Ok(dst)
             }
         }
 
         use crate::core::backend::BackendStorage;
-        let dev = s1.device();
-        let slice = S { eps: self.eps }.map(&s1.slice, l1, &s2.slice, l2, &s3.slice, l3, dev)?;
+        let dev = storage.device();
+        let slice = S(*self).map(&storage.slice, dev, layout)?;
+        let (b_size, c, h, w) = layout.shape().dims4()?;
+        let (h_out, w_out) = self.out_dims(h, w);
         let dst = crate::core::cuda_backend::CudaStorage {
             slice,
             device: dev.clone(),
         };
-        Ok((dst, l1.shape().clone()))
-    }
-
-    #[cfg(feature = "metal")]
-    fn metal_fwd(
-        &self,
-        s1: &crate::core::MetalStorage,
-        l1: &Layout,
-        s2: &crate::core::MetalStorage,
-        l2: &Layout,
-        s3: &crate::core::MetalStorage,
-        l3: &Layout,
-    ) -> Result<(crate::core::MetalStorage, Shape)> {
-        use crate::core::backend::BackendStorage;
-        let device = s1.device();
-        let command_buffer = device.command_buffer()?;
-        let kernels = device.kernels();
-        let name = match (s1.dtype(), s2.dtype(), s3.dtype()) {
-            (DType::F32, DType::F32, DType::F32) => "layernorm_f32",
-            (DType::F16, DType::F16, DType::F16) => "layernorm_f16",
-            (DType::BF16, DType::BF16, DType::BF16) => "layernorm_bf16",
-            (dt1, dt2, dt3) => {
-                crate::bail!("layernorm is not implemented for {dt1:?} {dt2:?} {dt3:?}")
-            }
-        };
-
-        if !(l1.is_contiguous() && l2.is_contiguous() && l3.is_contiguous()) {
-            crate::bail!("Non contiguous layernorm is not implemented");
-        }
-
-        let last_dim = l1.dims()[l1.shape().rank() - 1];
-        let elem_count = l1.shape().elem_count();
-        let output = device.new_buffer(elem_count, s1.dtype(), "layernorm")?;
-        crate::metal_kernels::call_layer_norm(
-            device.metal_device(),
-            &command_buffer,
-            kernels,
-            name,
-            elem_count,
-            last_dim,
-            self.eps,
-            s1.buffer(),
-            l1.start_offset() * s1.dtype().size_in_bytes(),
-            s2.buffer(),
-            l2.start_offset() * s2.dtype().size_in_bytes(),
-            s3.buffer(),
-            l3.start_offset() * s3.dtype().size_in_bytes(),
-            &output,
-        )
-        .map_err(crate::core::Error::wrap)?;
-        let newstorage =
-            crate::core::MetalStorage::new(output, device.clone(), elem_count, s1.dtype());
-        Ok((newstorage, l1.shape().clone()))
+        Ok((dst, Shape::from((b_size, c, h_out, w_out))))
     }
 }
 
-pub fn layer_norm_slow(x: &Tensor, alpha: &Tensor, beta: &Tensor, eps: f32) -> Result<Tensor> {
-    let x_dtype = x.dtype();
-    let internal_dtype = match x_dtype {
-        DType::F16 | DType::BF16 => DType::F32,
-        d => d,
-    };
-    let hidden_size = x.dim(D::Minus1)?;
-    let x = x.to_dtype(internal_dtype)?;
-    let x = {
-        let mean_x = (x.sum_keepdim(D::Minus1)? / hidden_size as f64)?;
-        x.broadcast_sub(&mean_x)?
-    };
-    let norm_x = (x.sqr()?.sum_keepdim(D::Minus1)? / hidden_size as f64)?;
-    let x_normed = x.broadcast_div(&(norm_x + eps as f64)?.sqrt()?)?;
-    x_normed
-        .to_dtype(x_dtype)?
-        .broadcast_mul(alpha)?
-        .broadcast_add(beta)
-}
-
-pub fn layer_norm(xs: &Tensor, alpha: &Tensor, beta: &Tensor, eps: f32) -> Result<Tensor> {
-    let hidden_size_xs = xs.dim(D::Minus1)?;
-    let hidden_size_alpha = alpha.dims1()?;
-    let hidden_size_beta = beta.dims1()?;
-    if hidden_size_xs != hidden_size_alpha || hidden_size_xs != hidden_size_beta {
+fn pool2d(xs: &Tensor, op: Pool2d) -> Result<Tensor> {
+    let name = crate::core::CustomOp1::name(&op);
+    let (_b_size, _c, h, w) = xs.dims4()?;
+    let Pool2d {
+        kernel,
+        stride,
+        padding,
+        ..
+    } = op;
+    if kernel.contains(&0) || stride.contains(&0) {
+        crate::bail!("{name}: kernel {kernel:?} and stride {stride:?} have to be positive")
+    }
+    if padding[0] > kernel[0] / 2 || padding[1] > kernel[1] / 2 {
+        crate::bail!("{name}: padding {padding:?} has to be at most half of the kernel {kernel:?}")
+    }
+    if h + 2 * padding[0] < kernel[0] || w + 2 * padding[1] < kernel[1] {
         crate::bail!(
-            "shape mismatch in layer-norm src: {:?} alpha: {:?} beta: {:?}",
-            xs.shape(),
-            alpha.shape(),
-            beta.shape()
+            "{name}: kernel {kernel:?} is larger than the padded input of {:?}",
+            xs.shape()
         )
     }
-    xs.apply_op3_no_bwd(alpha, beta, &LayerNorm { eps })
-}
+    if !matches!(
+        xs.dtype(),
+        DType::F32 | DType::F64 | DType::F16 | DType::BF16
+    ) {
+        crate::bail!("{name} is not implemented for {:?}", xs.dtype())
+    }
+    if xs.device().is_cpu() || xs.device().is_cuda() {
+        return xs.contiguous()?.apply_op1_no_bwd(&op);
+    }
 
-// https://pytorch.org/docs/stable/generated/torch.nn.PixelShuffle.html
-pub fn pixel_shuffle(xs: &Tensor, upscale_factor: usize) -> Result<Tensor> {
-    let (b_size, c, h, w) = xs.dims4()?;
-    let out_c = c / upscale_factor / upscale_factor;
-    xs.reshape((b_size, out_c, upscale_factor, upscale_factor, h, w))?
-        .permute((0, 1, 4, 2, 5, 3))?
-        .reshape((b_size, out_c, h * upscale_factor, w * upscale_factor))
+    // Other backends pad explicitly and use the unpadded pooling ops.
+    let [p_h, p_w] = padding;
+    let pad = [p_w, p_w, p_h, p_h];
+    let (k, s) = ((kernel[0], kernel[1]), (stride[0], stride[1]));
+    match op.kind {
+        PoolKind::Max => constant_pad2d(xs, pad, f64::NEG_INFINITY)?.max_pool2d_with_stride(k, s),
+        PoolKind::Avg { count_include_pad } => {
+            let ys = constant_pad2d(xs, pad, 0.)?.avg_pool2d_with_stride(k, s)?;
+            if count_include_pad || padding == [0, 0] {
+                return Ok(ys);
+            }
+            // Rescale by the fraction of each window that lies within the input.
+            let ones = Tensor::ones((1, 1, h, w), xs.dtype(), xs.device())?;
+            let frac = constant_pad2d(&ones, pad, 0.)?.avg_pool2d_with_stride(k, s)?;
+            ys.broadcast_div(&frac)
+        }
+    }
 }
 
-pub fn pixel_unshuffle(xs: &Tensor, downscale_factor: usize) -> Result<Tensor> {
-    let (b_size, c, h, w) = xs.dims4()?;
-    let out_c = c * downscale_factor * downscale_factor;
-    xs.reshape((
-        b_size,
-        c,
-        h / downscale_factor,
-        downscale_factor,
-        w / downscale_factor,
-        downscale_factor,
-    ))?
-    .permute((0, 1, 3, 5, 2, 4))?
-    .reshape((b_size, out_c, h / downscale_factor, w / downscale_factor))
+/// Max pooling over the last two dims of a `(batch, channels, height, width)` tensor, as in
+/// PyTorch's `F.max_pool2d`. `kernel`, `stride` and `padding` are `[height, width]`, padded
+/// positions never win the max and the padding has to be at most half of the kernel.
+pub fn max_pool2d(
+    xs: &Tensor,
+    kernel: [usize; 2],
+    stride: [usize; 2],
+    padding: [usize; 2],
+) -> Result<Tensor> {
+    let op = Pool2d {
+        kind: PoolKind::Max,
+        kernel,
+        stride,
+        padding,
+    };
+    pool2d(xs, op)
 }
 
-// https://pytorch.org/docs/stable/generated/torch.nn.ReplicationPad2d.html
-pub fn replication_pad2d(xs: &Tensor, pad: usize) -> Result<Tensor> {
-    match pad {
-        0 => Ok(xs.clone()),
-        1 => {
-            let (_b_size, _c, h, w) = xs.dims4()?;
-            let (first, last) = (xs.narrow(3, 0, 1)?, xs.narrow(3, w - 1, 1)?);
-            let xs = Tensor::cat(&[&first, xs, &last], 3)?;
-            let (first, last) = (xs.narrow(2, 0, 1)?, xs.narrow(2, h - 1, 1)?);
-            Tensor::cat(&[&first, &xs, &last], 2)
-        }
-        n => crate::bail!("replication-pad with a size of {n} is not supported"),
+/// Average pooling over the last two dims of a `(batch, channels, height, width)` tensor, as in
+/// PyTorch's `F.avg_pool2d`. Padded positions count as zeros, the divisor is the full window when
+/// `count_include_pad` is set and the number of in-bounds elements otherwise.
+pub fn avg_pool2d(
+    xs: &Tensor,
+    kernel: [usize; 2],
+    stride: [usize; 2],
+    padding: [usize; 2],
+    count_include_pad: bool,
+) -> Result<Tensor> {
+    let op = Pool2d {
+        kind: PoolKind::Avg { count_include_pad },
+        kernel,
+        stride,
+        padding,
+    };
+    pool2d(xs, op)
+}
+
+// https://pytorch.org/docs/stable/generated/torch.nn.AdaptiveAvgPool2d.html
+/// Average pooling of a `(batch, channels, height, width)` tensor to `output_size`
+/// (`[height, width]`). Output bin `i` averages the input range
+/// `floor(i * in / out)..ceil((i + 1) * in / out)` along each spatial dim, bins may overlap.
+pub fn adaptive_avg_pool2d(xs: &Tensor, output_size: [usize; 2]) -> Result<Tensor> {
+    let (b_size, c, h, w) = xs.dims4()?;
+    let [h_out, w_out] = output_size;
+    if h_out == 0 || w_out == 0 {
+        crate::bail!("adaptive-avg-pool2d: output size {output_size:?} has to be positive")
+    }
+    let dtype = xs.dtype();
+    let internal_dtype = match dtype {
+        DType::F16 | DType::BF16 => DType::F32,
+        d => d,
+    };
+    let xs = xs.to_dtype(internal_dtype)?;
+    if output_size == [1, 1] {
+        // Global pooling is a single reduction over the flattened spatial dims.
+        return xs
+            .flatten_from(2)?
+            .mean_keepdim(2)?
+            .reshape((b_size, c, 1, 1))?
+            .to_dtype(dtype);
     }
+    // The bins are rectangles so the mean is separable, pool the rows and then the cols.
+    let pool_dim = |xs: &Tensor, dim: usize, size: usize, out: usize| -> Result<Tensor> {
+        if size == out {
+            return Ok(xs.clone());
+        }
+        let bins = (0..out)
+            .map(|i| {
+                let start = i * size / out;
+                let end = ((i + 1) * size).div_ceil(out);
+                xs.narrow(dim, start, end - start)?.mean_keepdim(dim)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Tensor::cat(&bins, dim)
+    };
+    let xs = pool_dim(&xs, 2, h, h_out)?;
+    pool_dim(&xs, 3, w, w_out)?.to_dtype(dtype)
 }
 
 #[cfg(feature = "cuda")]
 pub fn kvconcat(ltensor: &Tensor, rtensor: &Tensor, concat_dim: usize) -> Result<Tensor> {
     if !ltensor.device().is_cuda() {
+        // `contiguous` does not copy when `cat` already returned a contiguous tensor, which is
+        // the case unless the inputs had to be concatenated through a transposition.
         return Tensor::cat(&[ltensor, rtensor], concat_dim)?.contiguous();
     }
     use crate::core::cuda_backend::KVConcat;
@@ -1286,6 +8520,7 @@ pub fn kvconcat(ltensor: &Tensor, rtensor: &Tensor, concat_dim: usize) -> Result
 
 #[cfg(not(feature = "cuda"))]
 pub fn kvconcat(ltensor: &Tensor, rtensor: &Tensor, concat_dim: i32) -> Result<Tensor> {
+    // `contiguous` does not copy when `cat` already returned a contiguous tensor.
     Tensor::cat(&[ltensor, rtensor], concat_dim as usize)?.contiguous()
 }
 
@@ -1314,27 +8549,16 @@ impl Module for Identity {
 struct Sdpa {
     scale: f32,
     softcapping: f32,
+    /// Contiguous additive mask of shape (bs, qhead, seq, kv_seq), same dtype as `q`.
+    mask: Option<Tensor>,
 }
 
-impl crate::core::CustomOp3 for Sdpa {
-    fn name(&self) -> &'static str {
-        "metal-sdpa"
-    }
-
-    fn cpu_fwd(
-        &self,
-        _s1: &CpuStorage,
-        _l1: &Layout,
-        _s2: &CpuStorage,
-        _l2: &Layout,
-        _s3: &CpuStorage,
-        _l3: &Layout,
-    ) -> Result<(CpuStorage, Shape)> {
-        crate::bail!("SDPA has no cpu impl")
-    }
-
+impl Sdpa {
+    /// Runs the fused kernels, writing the (bs, qhead, seq, v_hidden) result to the start of
+    /// `output`.
     #[cfg(feature = "metal")]
-    fn metal_fwd(
+    #[allow(clippy::too_many_arguments)]
+    fn run_metal(
         &self,
         q: &crate::core::MetalStorage,
         q_l: &Layout,
@@ -1342,16 +8566,14 @@ impl crate::core::CustomOp3 for Sdpa {
         k_l: &Layout,
         v: &crate::core::MetalStorage,
         v_l: &Layout,
-    ) -> Result<(crate::core::MetalStorage, Shape)> {
+        output: &metal::Buffer,
+    ) -> Result<()> {
         use crate::core::backend::BackendStorage;
         use crate::metal_kernels::SdpaDType;
 
         let device = q.device();
 
         let out_dims = vec![q_l.dim(0)?, q_l.dim(1)?, q_l.dim(2)?, v_l.dim(3)?];
-        let elem_count: usize = out_dims.iter().product();
-
-        let output = device.new_buffer(elem_count, q.dtype(), "sdpa_o")?;
 
         // q,k must have matching emb dim
         if q_l.dim(D::Minus1)? != k_l.dim(D::Minus1)? {
@@ -1414,6 +8636,18 @@ impl crate::core::CustomOp3 for Sdpa {
             other => crate::bail!("unsupported sdpa type {other:?}"),
         };
 
+        let mask_storage = self.mask.as_ref().map(|mask| mask.storage_and_layout());
+        let mask = match &mask_storage {
+            Some((storage, layout)) => match &**storage {
+                crate::core::Storage::Metal(storage) => Some((
+                    storage.buffer(),
+                    layout.start_offset() * storage.dtype().size_in_bytes(),
+                )),
+                _ => crate::bail!("sdpa mask must be a metal tensor"),
+            },
+            None => None,
+        };
+
         let command_buffer = q.device().command_buffer()?;
         if supports_sdpa_vector {
             // Route to the 2 pass fused attention if the k seqlen is large.
@@ -1462,6 +8696,7 @@ impl crate::core::CustomOp3 for Sdpa {
                     &intermediate,
                     &sums,
                     &maxs,
+                    mask,
                     self.scale,
                     self.softcapping,
                     itype,
@@ -1483,6 +8718,7 @@ impl crate::core::CustomOp3 for Sdpa {
                     v_l.start_offset(),
                     v_l.stride(),
                     v.buffer(),
+                    mask,
                     &output,
                     self.scale,
                     self.softcapping,
@@ -1507,6 +8743,7 @@ impl crate::core::CustomOp3 for Sdpa {
                 k.buffer(),
                 v_l.start_offset(),
                 v.buffer(),
+                mask,
                 &output,
                 self.scale,
                 self.softcapping,
@@ -1516,6 +8753,46 @@ impl crate::core::CustomOp3 for Sdpa {
         } else {
             crate::bail!("must be vector or full sdpa kernel");
         }
+        Ok(())
+    }
+}
+
+impl crate::core::CustomOp3 for Sdpa {
+    fn name(&self) -> &'static str {
+        "metal-sdpa"
+    }
+
+    fn cpu_fwd(
+        &self,
+        _s1: &CpuStorage,
+        _l1: &Layout,
+        _s2: &CpuStorage,
+        _l2: &Layout,
+        _s3: &CpuStorage,
+        _l3: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        crate::bail!("SDPA has no cpu impl")
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        q: &crate::core::MetalStorage,
+        q_l: &Layout,
+        k: &crate::core::MetalStorage,
+        k_l: &Layout,
+        v: &crate::core::MetalStorage,
+        v_l: &Layout,
+    ) -> Result<(crate::core::MetalStorage, Shape)> {
+        use crate::core::backend::BackendStorage;
+
+        let device = q.device();
+
+        let out_dims = vec![q_l.dim(0)?, q_l.dim(1)?, q_l.dim(2)?, v_l.dim(3)?];
+        let elem_count: usize = out_dims.iter().product();
+
+        let output = device.new_buffer(elem_count, q.dtype(), "sdpa_o")?;
+        self.run_metal(q, q_l, k, k_l, v, v_l, &output)?;
 
         let newstorage =
             crate::core::MetalStorage::new(output, device.clone(), elem_count, q.dtype());
@@ -1523,6 +8800,62 @@ impl crate::core::CustomOp3 for Sdpa {
     }
 }
 
+/// Fused attention writing to an existing output tensor, `q` is held here as in-place ops only
+/// take two extra inputs.
+#[allow(dead_code)]
+struct SdpaInto {
+    sdpa: Sdpa,
+    q: Tensor,
+}
+
+impl crate::core::InplaceOp3 for SdpaInto {
+    fn name(&self) -> &'static str {
+        "metal-sdpa-into"
+    }
+
+    fn cpu_fwd(
+        &self,
+        _s1: &mut CpuStorage,
+        _l1: &Layout,
+        _s2: &CpuStorage,
+        _l2: &Layout,
+        _s3: &CpuStorage,
+        _l3: &Layout,
+    ) -> Result<()> {
+        crate::bail!("SDPA has no cpu impl")
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        out: &mut crate::core::MetalStorage,
+        out_l: &Layout,
+        k: &crate::core::MetalStorage,
+        k_l: &Layout,
+        v: &crate::core::MetalStorage,
+        v_l: &Layout,
+    ) -> Result<()> {
+        use crate::core::backend::BackendStorage;
+
+        let (q_storage, q_l) = self.q.storage_and_layout();
+        let q = match &*q_storage {
+            crate::core::Storage::Metal(q) => q,
+            _ => crate::bail!("sdpa q must be a metal tensor"),
+        };
+        if !out_l.is_contiguous() || out_l.start_offset() != 0 {
+            crate::bail!("sdpa output has to be contiguous and start at offset 0")
+        }
+        if out.dtype() != q.dtype() {
+            crate::bail!(
+                "sdpa output dtype {:?} does not match q dtype {:?}",
+                out.dtype(),
+                q.dtype()
+            )
+        }
+        self.sdpa.run_metal(q, q_l, k, k_l, v, v_l, out.buffer())
+    }
+}
+
 /// Scaled dot product attention with a fused kernel.
 ///
 /// Computes softmax(qk^T*scale)v.
@@ -1548,6 +8881,554 @@ impl crate::core::CustomOp3 for Sdpa {
 ///     - Use an alternate kernel
 ///     - Requires `seq` == `kv_seq`
 ///     - GQA is not supported (requires `qhead` == `kv_head`)
+///
+/// ## On other devices:
+/// - Use the unfused computation
+/// - On the CPU, `qk^T`, the softmax and the value accumulation are done in F32 so that F16
+///   scores cannot overflow before being scaled, other devices compute in the input dtype
+/// - Supports GQA when `qhead` is a multiple of `kv_head`
 pub fn sdpa(q: &Tensor, k: &Tensor, v: &Tensor, scale: f32, softcapping: f32) -> Result<Tensor> {
-    q.apply_op3_no_bwd(k, v, &Sdpa { scale, softcapping })
+    if !q.device().is_metal() {
+        return sdpa_unfused(q, k, v, scale, softcapping, None);
+    }
+    q.apply_op3_no_bwd(
+        k,
+        v,
+        &Sdpa {
+            scale,
+            softcapping,
+            mask: None,
+        },
+    )
+}
+
+/// Applies the rotary embedding `rope` to `q` and `k` and then runs [`sdpa`], the usual attention
+/// prologue of rotary transformers.
+///
+/// `cos` and `sin` have shape `(max_seq, hidden / 2)` with `max_seq` covering both `seq` and
+/// `kv_seq`, the positions of `q` and `k` both start at 0. They are cast to the dtype of `q`.
+pub fn sdpa_rope(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    cos: &Tensor,
+    sin: &Tensor,
+    scale: f32,
+    softcapping: f32,
+) -> Result<Tensor> {
+    let (_, _, seq_len, q_hidden) = q.dims4()?;
+    let (_, _, kv_seq_len, k_hidden) = k.dims4()?;
+    if q_hidden != k_hidden {
+        crate::bail!("sdpa_rope head dim mismatch, q: {q_hidden}, k: {k_hidden}")
+    }
+    for (name, t) in [("cos", cos), ("sin", sin)] {
+        let (t_seq_len, t_hidden) = t.dims2()?;
+        if t_hidden * 2 != q_hidden || t_seq_len < seq_len.max(kv_seq_len) {
+            crate::bail!(
+                "sdpa_rope expects {name} of shape (>= {}, {}), got {:?}",
+                seq_len.max(kv_seq_len),
+                q_hidden / 2,
+                t.shape()
+            )
+        }
+    }
+    let cos = cos.to_dtype(q.dtype())?.contiguous()?;
+    let sin = sin.to_dtype(q.dtype())?.contiguous()?;
+    let q = crate::nn::rotary_emb::rope(&q.contiguous()?, &cos, &sin)?;
+    let k = crate::nn::rotary_emb::rope(&k.contiguous()?, &cos, &sin)?;
+    sdpa(&q, &k, v, scale, softcapping)
+}
+
+/// Same as [`sdpa`], with `num_sinks` attention sink (register) tokens prepended to the keys and
+/// values of every head.
+///
+/// **Inputs shapes:**
+/// - `q`, `k`, `v`: as in [`sdpa`]
+/// - `sink_k`: (bs, kv_head, num_sinks, hidden)
+/// - `sink_v`: (bs, kv_head, num_sinks, v_hidden)
+///
+/// The attention runs over `num_sinks + kv_seq` keys, so on Metal the `seq` == `kv_seq`
+/// restriction of the non-vectorized kernel applies to the extended key length. With zero sinks
+/// this is exactly [`sdpa`].
+#[allow(clippy::too_many_arguments)]
+pub fn sdpa_with_sinks(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    sink_k: &Tensor,
+    sink_v: &Tensor,
+    scale: f32,
+    softcapping: f32,
+) -> Result<Tensor> {
+    let (k_bs, k_heads, _, k_hidden) = k.dims4()?;
+    let (v_bs, v_heads, _, v_hidden) = v.dims4()?;
+    let (sk_bs, sk_heads, num_sinks, sk_hidden) = sink_k.dims4()?;
+    let (sv_bs, sv_heads, sv_sinks, sv_hidden) = sink_v.dims4()?;
+    if (sk_bs, sk_heads, sk_hidden) != (k_bs, k_heads, k_hidden) {
+        crate::bail!(
+            "sdpa_with_sinks: sink_k {:?} does not match k {:?}",
+            sink_k.shape(),
+            k.shape()
+        )
+    }
+    if (sv_bs, sv_heads, sv_hidden) != (v_bs, v_heads, v_hidden) {
+        crate::bail!(
+            "sdpa_with_sinks: sink_v {:?} does not match v {:?}",
+            sink_v.shape(),
+            v.shape()
+        )
+    }
+    if sv_sinks != num_sinks {
+        crate::bail!("sdpa_with_sinks: sink_k has {num_sinks} sinks but sink_v has {sv_sinks}")
+    }
+    if num_sinks == 0 {
+        return sdpa(q, k, v, scale, softcapping);
+    }
+    let k = kvconcat(sink_k, k, 2)?;
+    let v = kvconcat(sink_v, v, 2)?;
+    sdpa(q, &k, &v, scale, softcapping)
+}
+
+/// Block-sparse scaled dot product attention, see `sdpa` for the shape requirements.
+///
+/// The queries and keys are split in blocks of `block_size` positions, the last block being
+/// possibly shorter. `block_mask` has shape `(ceil(seq / block_size), ceil(kv_seq / block_size))`
+/// and query block `i` only attends to the key blocks `j` where `block_mask[i][j]` is non-zero,
+/// the skipped blocks are never computed. Query blocks without any selected key block are zero.
+///
+/// This uses the unfused computation with F32 scores on all devices.
+pub fn sdpa_block_sparse(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    block_mask: &Tensor,
+    block_size: usize,
+    scale: f32,
+) -> Result<Tensor> {
+    if block_size == 0 {
+        crate::bail!("sdpa_block_sparse expects a non-zero block_size")
+    }
+    let (b_sz, q_heads, q_seq, _) = q.dims4()?;
+    let kv_seq = k.dim(2)?;
+    let v_hidden = v.dim(3)?;
+    let (n_q_blocks, n_kv_blocks) = (q_seq.div_ceil(block_size), kv_seq.div_ceil(block_size));
+    if block_mask.dims() != [n_q_blocks, n_kv_blocks] {
+        crate::bail!(
+            "sdpa_block_sparse expects a block_mask of shape ({n_q_blocks}, {n_kv_blocks}), got {:?}",
+            block_mask.shape()
+        )
+    }
+    let block_mask = block_mask.ne(0.)?.to_vec2::<u8>()?;
+
+    let mut outs = Vec::with_capacity(n_q_blocks);
+    for (i, row) in block_mask.iter().enumerate() {
+        let q_start = i * block_size;
+        let q_len = block_size.min(q_seq - q_start);
+        let q_blk = q.narrow(2, q_start, q_len)?;
+        // Merge consecutive selected key blocks so that they are narrowed as a single range.
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for (j, _) in row.iter().enumerate().filter(|(_, &m)| m != 0) {
+            let start = j * block_size;
+            let end = (start + block_size).min(kv_seq);
+            match ranges.last_mut() {
+                Some((_, last_end)) if *last_end == start => *last_end = end,
+                _ => ranges.push((start, end)),
+            }
+        }
+        let out = if ranges.is_empty() {
+            Tensor::zeros((b_sz, q_heads, q_len, v_hidden), q.dtype(), q.device())?
+        } else {
+            let gather = |xs: &Tensor| -> Result<Tensor> {
+                let parts = ranges
+                    .iter()
+                    .map(|&(start, end)| xs.narrow(2, start, end - start))
+                    .collect::<Result<Vec<_>>>()?;
+                Tensor::cat(&parts, 2)
+            };
+            let (k, v) = (gather(k)?, gather(v)?);
+            sdpa_unfused_in(&q_blk, &k, &v, scale, 1.0, None, DType::F32)?.to_dtype(q.dtype())?
+        };
+        outs.push(out);
+    }
+    Tensor::cat(&outs, 2)
+}
+
+/// Same as `sdpa` but writes the result to `out` instead of allocating a new tensor, so that
+/// decode loops can reuse a single output buffer across steps.
+///
+/// `out` must be a contiguous Metal tensor of shape (bs, qhead, seq, v_hidden) with the dtype of
+/// `q`, and must not share its storage with `q`, `k` or `v`.
+pub fn sdpa_into(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    scale: f32,
+    softcapping: f32,
+    out: &Tensor,
+) -> Result<()> {
+    let (b_sz, q_heads, q_seq, _) = q.dims4()?;
+    let v_hidden = v.dim(D::Minus1)?;
+    if out.dims() != [b_sz, q_heads, q_seq, v_hidden] {
+        crate::bail!(
+            "sdpa_into expects an output of shape {:?}, got {:?}",
+            (b_sz, q_heads, q_seq, v_hidden),
+            out.shape()
+        )
+    }
+    out.inplace_op3(
+        k,
+        v,
+        &SdpaInto {
+            sdpa: Sdpa {
+                scale,
+                softcapping,
+                mask: None,
+            },
+            q: q.clone(),
+        },
+    )
+}
+
+/// Optional inputs for `sdpa_with_params`.
+#[derive(Debug, Clone, Default)]
+pub struct SdpaParams {
+    /// Additive mask applied to the attention logits, broadcastable to (bs, qhead, seq, kv_seq).
+    pub mask: Option<Tensor>,
+    /// Only attend to keys up to the query position, aligned to the end of the key sequence.
+    pub causal: bool,
+}
+
+/// Scaled dot product attention with an optional mask, see `sdpa` for the shape requirements.
+///
+/// Computes softmax(qk^T*scale + mask)v, the mask being added after softcapping. On Metal the
+/// mask is applied inside the fused kernels, other devices use the unfused computation.
+/// Mask values are clamped to -1e9 in the fused kernels so that fully masked rows stay finite.
+pub fn sdpa_with_params(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    scale: f32,
+    softcapping: f32,
+    params: &SdpaParams,
+) -> Result<Tensor> {
+    let (b_sz, q_heads, q_seq, _) = q.dims4()?;
+    let kv_seq = k.dim(2)?;
+    let mask_shape = (b_sz, q_heads, q_seq, kv_seq);
+
+    let mut mask = match &params.mask {
+        Some(mask) => Some(mask.broadcast_as(mask_shape)?.to_dtype(q.dtype())?),
+        None => None,
+    };
+    if params.causal {
+        if kv_seq < q_seq {
+            crate::bail!("causal sdpa requires kv_seq ({kv_seq}) >= seq ({q_seq})")
+        }
+        let offset = kv_seq - q_seq;
+        let causal: Vec<f32> = (0..q_seq)
+            .flat_map(|i| {
+                (0..kv_seq).map(move |j| {
+                    if j > i + offset {
+                        f32::NEG_INFINITY
+                    } else {
+                        0.
+                    }
+                })
+            })
+            .collect();
+        let causal = Tensor::from_vec(causal, (q_seq, kv_seq), q.device())?
+            .to_dtype(q.dtype())?
+            .broadcast_as(mask_shape)?;
+        mask = Some(match mask {
+            Some(mask) => (mask + causal)?,
+            None => causal,
+        });
+    }
+
+    if q.device().is_metal() {
+        let mask = match mask {
+            Some(mask) => Some(mask.contiguous()?),
+            None => None,
+        };
+        q.apply_op3_no_bwd(
+            k,
+            v,
+            &Sdpa {
+                scale,
+                softcapping,
+                mask,
+            },
+        )
+    } else {
+        sdpa_unfused(q, k, v, scale, softcapping, mask.as_ref())
+    }
+}
+
+/// Attention without a fused kernel, used on devices other than Metal.
+///
+/// On the CPU, half precision inputs are upcast to F32 before the `qk^T` matmul: with large head
+/// dims the unscaled scores easily exceed the F16 range. Other devices keep the input dtype as
+/// their matmul kernels already accumulate in F32. The result has the dtype of `q`.
+fn sdpa_unfused(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    scale: f32,
+    softcapping: f32,
+    mask: Option<&Tensor>,
+) -> Result<Tensor> {
+    let internal_dtype = if q.device().is_cpu() {
+        DType::F32
+    } else {
+        q.dtype()
+    };
+    sdpa_unfused_in(q, k, v, scale, softcapping, mask, internal_dtype)?.to_dtype(q.dtype())
+}
+
+/// The unfused attention computed in `dtype`, the result is not cast back to the dtype of `q`.
+#[allow(clippy::too_many_arguments)]
+fn sdpa_unfused_in(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    scale: f32,
+    softcapping: f32,
+    mask: Option<&Tensor>,
+    dtype: DType,
+) -> Result<Tensor> {
+    let (q, k, v) = sdpa_inputs(q, k, v, dtype)?;
+    let mut att = (q.matmul(&k.t()?)? * (scale as f64))?;
+    if softcapping != 1.0 {
+        att = (att / softcapping as f64)?;
+        att = att.tanh()?;
+        att = (att * softcapping as f64)?;
+    }
+    if let Some(mask) = mask {
+        att = att.broadcast_add(&mask.to_dtype(dtype)?)?;
+    }
+    softmax_last_dim(&att)?.matmul(&v)
+}
+
+/// Casts the attention inputs to `dtype` and repeats the kv heads to match the query heads.
+fn sdpa_inputs(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    dtype: DType,
+) -> Result<(Tensor, Tensor, Tensor)> {
+    let q_heads = q.dim(1)?;
+    let kv_heads = k.dim(1)?;
+    if kv_heads == 0 || q_heads % kv_heads != 0 {
+        crate::bail!("sdpa expects qhead ({q_heads}) to be a multiple of kv_head ({kv_heads})")
+    }
+    let q = q.to_dtype(dtype)?;
+    let (k, v) = (k.to_dtype(dtype)?, v.to_dtype(dtype)?);
+    if q_heads == kv_heads {
+        return Ok((q, k, v));
+    }
+    let n_rep = q_heads / kv_heads;
+    Ok((
+        q,
+        repeat_interleave(&k, n_rep, 1)?,
+        repeat_interleave(&v, n_rep, 1)?,
+    ))
+}
+
+/// Attention over a single chunk of the keys and values, returning the state needed to merge it
+/// with the other chunks through [`sdpa_combine`]. Processing the kv sequence chunk by chunk keeps
+/// the memory bounded for very long sequences, see `sdpa` for the shape requirements.
+///
+/// Returns `(partial_out, running_max, running_sum)`:
+/// - `partial_out`: (bs, qhead, seq, v_hidden), the attention output normalized over this chunk
+///   only, in the dtype of `q`
+/// - `running_max`: (bs, qhead, seq, 1), the F32 maximum of the scaled scores
+/// - `running_sum`: (bs, qhead, seq, 1), the F32 sum of `exp(score - running_max)`
+pub fn sdpa_chunk(
+    q: &Tensor,
+    k_chunk: &Tensor,
+    v_chunk: &Tensor,
+    scale: f32,
+) -> Result<(Tensor, Tensor, Tensor)> {
+    if k_chunk.dim(2)? == 0 {
+        crate::bail!("sdpa_chunk expects a non-empty kv chunk")
+    }
+    let dtype = q.dtype();
+    let (q, k, v) = sdpa_inputs(q, k_chunk, v_chunk, DType::F32)?;
+    let att = (q.matmul(&k.t()?)? * (scale as f64))?;
+    let max = att.max_keepdim(D::Minus1)?;
+    let exp = att.broadcast_sub(&max)?.exp()?;
+    let sum = exp.sum_keepdim(D::Minus1)?;
+    let out = exp.matmul(&v)?.broadcast_div(&sum)?.to_dtype(dtype)?;
+    Ok((out, max, sum))
+}
+
+/// Merges the per-chunk states returned by [`sdpa_chunk`] into the attention output over the
+/// concatenation of all the chunks, using the online softmax rescaling: with `m` the maximum of
+/// the `running_max`, chunk `i` is weighted by `running_sum_i * exp(running_max_i - m)`.
+///
+/// The merge runs in F32 and the result has the dtype of the partial outputs.
+pub fn sdpa_combine(states: &[(Tensor, Tensor, Tensor)]) -> Result<Tensor> {
+    let (first_out, first_max, _) = match states.first() {
+        Some(state) => state,
+        None => crate::bail!("sdpa_combine expects at least one chunk state"),
+    };
+    let mut max = first_max.clone();
+    for (_, chunk_max, _) in &states[1..] {
+        max = max.maximum(chunk_max)?;
+    }
+    let mut acc = first_out.to_dtype(DType::F32)?.zeros_like()?;
+    let mut total = first_max.zeros_like()?;
+    for (out, chunk_max, sum) in states {
+        let weight = ((chunk_max - &max)?.exp()? * sum)?;
+        acc = (acc + out.to_dtype(DType::F32)?.broadcast_mul(&weight)?)?;
+        total = (total + weight)?;
+    }
+    acc.broadcast_div(&total)?.to_dtype(first_out.dtype())
+}
+
+/// The attention logits `qk^T*scale` of shape (bs, qhead, seq, kv_seq), before any mask or
+/// softmax, see `sdpa` for the shape requirements of `q` and `k`.
+///
+/// If `softcapping` != 1.0 the logits are capped as `tanh(qk^T*scale/cap)*cap`. GQA is supported
+/// when `qhead` is a multiple of `kv_head`. The matmul runs in F32 so that half precision inputs
+/// cannot overflow before being scaled, the result has the dtype of `q`.
+pub fn attention_scores(q: &Tensor, k: &Tensor, scale: f32, softcapping: f32) -> Result<Tensor> {
+    let (_, q_heads, _, q_hidden) = q.dims4()?;
+    let (_, kv_heads, _, k_hidden) = k.dims4()?;
+    if q_hidden != k_hidden {
+        crate::bail!("attention_scores head dim mismatch, q: {q_hidden}, k: {k_hidden}")
+    }
+    if kv_heads == 0 || q_heads % kv_heads != 0 {
+        crate::bail!(
+            "attention_scores expects qhead ({q_heads}) to be a multiple of kv_head ({kv_heads})"
+        )
+    }
+    let k = k.to_dtype(DType::F32)?;
+    let k = if q_heads != kv_heads {
+        repeat_interleave(&k, q_heads / kv_heads, 1)?
+    } else {
+        k
+    };
+    let mut att = (q.to_dtype(DType::F32)?.matmul(&k.t()?)? * (scale as f64))?;
+    if softcapping != 1.0 {
+        att = ((att / softcapping as f64)?.tanh()? * softcapping as f64)?;
+    }
+    att.to_dtype(q.dtype())
+}
+
+/// Scaled dot product attention on F16/BF16 inputs returning a F32 output, see `sdpa` for the
+/// shape requirements.
+///
+/// On the CPU the unfused computation already runs in F32 and its result is returned without
+/// being rounded back to half precision, which helps when the output feeds a precision sensitive
+/// op such as a residual add. Other devices run `sdpa` in the input dtype and only cast its
+/// output to F32, so `q`, `k` and `v` are never materialized in F32.
+pub fn sdpa_f32_out(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    scale: f32,
+    softcapping: f32,
+) -> Result<Tensor> {
+    for (name, xs) in [("q", q), ("k", k), ("v", v)] {
+        if !matches!(xs.dtype(), DType::F16 | DType::BF16) {
+            crate::bail!(
+                "sdpa_f32_out expects f16 or bf16 inputs, got {name}: {:?}",
+                xs.dtype()
+            )
+        }
+    }
+    if q.dtype() != k.dtype() || q.dtype() != v.dtype() {
+        crate::bail!(
+            "sdpa_f32_out dtype mismatch q: {:?}, k: {:?}, v: {:?}",
+            q.dtype(),
+            k.dtype(),
+            v.dtype()
+        )
+    }
+    if !q.device().is_cpu() {
+        return sdpa(q, k, v, scale, softcapping)?.to_dtype(DType::F32);
+    }
+    sdpa_unfused_in(q, k, v, scale, softcapping, None, DType::F32)
+}
+
+/// Attention statistics reported by `attn_debug_stats`.
+#[cfg(feature = "debug")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttnStats {
+    /// Largest scaled (and masked) attention logit.
+    pub max_logit: f32,
+    /// Smallest scaled (and masked) attention logit, `-inf` if some position is masked out.
+    pub min_logit: f32,
+    /// Mean softmax entropy over the rows that have at least one finite logit.
+    pub entropy: f32,
+    /// Largest absolute value of the attention output, ignoring non-finite values.
+    pub out_max_abs: f32,
+    /// Number of rows whose logits are all `-inf`, the softmax outputs zeros for these.
+    pub masked_rows: usize,
+    /// Number of non-finite values in the attention output.
+    pub out_non_finite: usize,
+}
+
+/// Computes attention statistics on the unfused path to help diagnose `NaN`s or overflows
+/// hidden by the fused kernels. `q`, `k` and `v` use the same layout as `sdpa` and `mask` is an
+/// optional additive mask broadcastable to the attention logits.
+///
+/// Everything is computed in F32 and copied back to the host, this is only meant for debugging.
+#[cfg(feature = "debug")]
+pub fn attn_debug_stats(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    scale: f32,
+    mask: Option<&Tensor>,
+) -> Result<AttnStats> {
+    let q = q.to_dtype(DType::F32)?;
+    let k = k.to_dtype(DType::F32)?;
+    let v = v.to_dtype(DType::F32)?;
+    let mut logits = (q.matmul(&k.t()?)? * scale as f64)?;
+    if let Some(mask) = mask {
+        logits = logits.broadcast_add(&mask.to_dtype(DType::F32)?)?;
+    }
+    let probs = softmax_last_dim(&logits)?;
+    let out = probs.matmul(&v)?;
+
+    let kv_seq = logits.dim(D::Minus1)?;
+    let logits = logits.flatten_all()?.to_vec1::<f32>()?;
+    let probs = probs.flatten_all()?.to_vec1::<f32>()?;
+    let out = out.flatten_all()?.to_vec1::<f32>()?;
+
+    let max_logit = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let min_logit = logits.iter().copied().fold(f32::INFINITY, f32::min);
+    let mut masked_rows = 0;
+    let mut entropy_sum = 0f64;
+    for (logits, probs) in logits.chunks(kv_seq).zip(probs.chunks(kv_seq)) {
+        if logits.iter().all(|&l| l == f32::NEG_INFINITY) {
+            masked_rows += 1;
+            continue;
+        }
+        entropy_sum -= probs
+            .iter()
+            .filter(|&&p| p > 0.)
+            .map(|&p| p as f64 * (p as f64).ln())
+            .sum::<f64>();
+    }
+    let rows = logits.len() / kv_seq.max(1);
+    let entropy = if rows > masked_rows {
+        (entropy_sum / (rows - masked_rows) as f64) as f32
+    } else {
+        f32::NAN
+    };
+    let out_max_abs = out
+        .iter()
+        .filter(|o| o.is_finite())
+        .fold(0f32, |acc, o| acc.max(o.abs()));
+    let out_non_finite = out.iter().filter(|o| !o.is_finite()).count();
+    Ok(AttnStats {
+        max_logit,
+        min_logit,
+        entropy,
+        out_max_abs,
+        masked_rows,
+        out_non_finite,
+    })
 }
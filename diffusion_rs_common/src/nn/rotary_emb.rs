@@ -482,6 +482,30 @@ pub fn rope(xs: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
     xs.apply_op3_no_bwd(cos, sin, &RotaryEmb)
 }
 
+/// Same as `rope` but only rotates the first `rotary_dim` channels of each head, the remaining
+/// `head_dim - rotary_dim` channels are passed through unchanged (partial rotary embeddings as
+/// used by GPT-NeoX style models).
+///
+/// `cos` and `sin` have shape `(seq_len, rotary_dim / 2)`. `rotary_dim` has to be even and at most
+/// `head_dim`, a `rotary_dim` of 0 returns `xs` unchanged.
+pub fn rope_partial(xs: &Tensor, cos: &Tensor, sin: &Tensor, rotary_dim: usize) -> Result<Tensor> {
+    let (_b_sz, _n_head, _seq_len, head_dim) = xs.dims4()?;
+    if rotary_dim > head_dim || rotary_dim % 2 != 0 {
+        crate::bail!(
+            "rope_partial expects an even rotary_dim of at most {head_dim}, got {rotary_dim}"
+        )
+    }
+    if rotary_dim == 0 {
+        return Ok(xs.clone());
+    }
+    if rotary_dim == head_dim {
+        return rope(&xs.contiguous()?, cos, sin);
+    }
+    let rot = xs.narrow(D::Minus1, 0, rotary_dim)?.contiguous()?;
+    let pass = xs.narrow(D::Minus1, rotary_dim, head_dim - rotary_dim)?;
+    Tensor::cat(&[&rope(&rot, cos, sin)?, &pass], D::Minus1)
+}
+
 fn rotate_half(xs: &Tensor) -> Result<Tensor> {
     let last_dim = xs.dim(D::Minus1)?;
     let xs1 = xs.narrow(D::Minus1, 0, last_dim / 2)?;
@@ -0,0 +1,86 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use crate::core::{Device, Tensor};
+use anyhow::Result;
+use diffusion_rs_common::nn::{attn_scale, merge_heads, split_heads, AttnScale};
+
+#[test]
+fn split_merge_heads() -> Result<()> {
+    let device = &Device::Cpu;
+    let xs = Tensor::arange(0f32, 48., device)?.reshape((2, 3, 8))?;
+    let heads = split_heads(&xs, 4)?;
+    assert_eq!(heads.dims(), &[2, 4, 3, 2]);
+    assert!(heads.is_contiguous());
+    // Head 1 of the first token of the first batch holds hidden features 2..4.
+    assert_eq!(heads.get(0)?.get(1)?.get(0)?.to_vec1::<f32>()?, [2., 3.]);
+
+    let merged = merge_heads(&heads)?;
+    assert_eq!(merged.dims(), xs.dims());
+    assert_eq!(merged.to_vec3::<f32>()?, xs.to_vec3::<f32>()?);
+
+    assert!(split_heads(&xs, 3).is_err());
+    Ok(())
+}
+
+#[test]
+fn attn_scale_default_and_learned() -> Result<()> {
+    let device = &Device::Cpu;
+    match attn_scale(64, None)? {
+        AttnScale::Fixed(scale) => assert_eq!(scale, 0.125),
+        AttnScale::Learned(_) => panic!("expected a fixed scale"),
+    }
+
+    let log_scale = Tensor::new(&[0f32, 1., 10.], device)?;
+    let scale = attn_scale(64, Some(&log_scale))?;
+    let values = match &scale {
+        AttnScale::Learned(scale) => scale.to_vec1::<f32>()?,
+        AttnScale::Fixed(_) => panic!("expected a learned scale"),
+    };
+    assert_eq!(values[0], 1.);
+    assert!((values[1] - 1f32.exp()).abs() < 1e-5);
+    // exp(10) is clamped to 100.
+    assert!((values[2] - 100.).abs() < 1e-3);
+
+    let att = Tensor::ones((2, 3, 4, 4), crate::core::DType::F32, device)?;
+    let scaled = scale.apply(&att)?;
+    assert!((scaled.get(1)?.get(1)?.get(2)?.get(3)?.to_scalar::<f32>()? - 1f32.exp()).abs() < 1e-5);
+    Ok(())
+}
+
+#[test]
+fn gather_rel_pos_bias() -> Result<()> {
+    use diffusion_rs_common::nn::gather_rel_pos_bias;
+
+    let device = &Device::Cpu;
+    let (window, heads) = (2, 2);
+    // (2 * 2 - 1)^2 = 9 rows, row r holds [r, 10 * r].
+    let table = Tensor::arange(0f32, 9., device)?
+        .unsqueeze(1)?
+        .broadcast_mul(&Tensor::new(&[[1f32, 10.]], device)?)?;
+    // The Swin index map for a 2x2 window.
+    let index_map = Tensor::new(
+        &[[4u32, 3, 1, 0], [5, 4, 2, 1], [7, 6, 4, 3], [8, 7, 5, 4]],
+        device,
+    )?;
+    let bias = gather_rel_pos_bias(&table, &index_map, window, heads)?;
+    assert_eq!(bias.dims(), &[2, 4, 4]);
+    let expected = index_map.to_dtype(crate::core::DType::F32)?;
+    assert_eq!(bias.get(0)?.to_vec2::<f32>()?, expected.to_vec2::<f32>()?);
+    assert_eq!(
+        bias.get(1)?.to_vec2::<f32>()?,
+        (expected * 10.)?.to_vec2::<f32>()?
+    );
+
+    let bad = index_map.affine(1., 5.)?;
+    assert!(gather_rel_pos_bias(&table, &bad, window, heads).is_err());
+    let negative = index_map
+        .to_dtype(crate::core::DType::I64)?
+        .affine(1., -1.)?;
+    assert!(gather_rel_pos_bias(&table, &negative, window, heads).is_err());
+    assert!(gather_rel_pos_bias(&table, &index_map, 3, heads).is_err());
+    Ok(())
+}
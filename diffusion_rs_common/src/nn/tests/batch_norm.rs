@@ -126,3 +126,60 @@ fn train_batch_norm() -> Result<()> {
     );
     Ok(())
 }
+
+// Once the running stats are frozen after a training pass, the eval output of a sample does not
+// depend on the rest of the batch.
+#[test]
+fn batch_norm2d_frozen_stats() -> Result<()> {
+    use diffusion_rs_common::nn::{BatchNorm2d, ModuleT};
+    let dev = &Device::Cpu;
+    let bn = BatchNorm2d::new(
+        Tensor::zeros(3, DType::F32, dev)?,
+        Tensor::ones(3, DType::F32, dev)?,
+        Tensor::new(&[1f32, 2., 0.5], dev)?,
+        Tensor::new(&[0f32, -1., 0.25], dev)?,
+        1e-5,
+        0.1,
+    )?;
+    let warmup = (Tensor::randn(0f32, 2., (4, 3, 5, 5), dev)? + 3.)?;
+    bn.forward_t(&warmup, true)?;
+    let running_mean = bn.running_mean().copy()?;
+    let running_var = bn.running_var().copy()?;
+    assert_ne!(running_mean.to_vec1::<f32>()?, [0f32; 3]);
+    assert_ne!(running_var.to_vec1::<f32>()?, [1f32; 3]);
+
+    let a = Tensor::randn(0f32, 1., (2, 3, 4, 4), dev)?;
+    let b = Tensor::randn(5f32, 3., (3, 3, 4, 4), dev)?;
+    let alone = bn.forward_t(&a, false)?;
+    let batched = bn
+        .forward_t(&Tensor::cat(&[&a, &b], 0)?, false)?
+        .narrow(0, 0, 2)?;
+    assert_eq!(
+        alone.flatten_all()?.to_vec1::<f32>()?,
+        batched.flatten_all()?.to_vec1::<f32>()?
+    );
+    assert_eq!(
+        bn.running_mean().to_vec1::<f32>()?,
+        running_mean.to_vec1::<f32>()?
+    );
+    assert_eq!(
+        bn.running_var().to_vec1::<f32>()?,
+        running_var.to_vec1::<f32>()?
+    );
+
+    let shape = (1, 3, 1, 1);
+    let expected = a
+        .broadcast_sub(&running_mean.reshape(shape)?)?
+        .broadcast_div(&(running_var.reshape(shape)? + 1e-5)?.sqrt()?)?
+        .broadcast_mul(&Tensor::new(&[1f32, 2., 0.5], dev)?.reshape(shape)?)?
+        .broadcast_add(&Tensor::new(&[0f32, -1., 0.25], dev)?.reshape(shape)?)?;
+    let diff = (alone - expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-5);
+
+    assert!(bn.forward_t(&a.flatten_from(2)?, false).is_err());
+    Ok(())
+}
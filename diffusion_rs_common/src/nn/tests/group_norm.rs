@@ -103,3 +103,36 @@ fn group_norm() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn group_norm_per_group_affine() -> Result<()> {
+    let device = &Device::Cpu;
+    let input = Tensor::randn(0f32, 1., (2, 6, 3), device)?;
+
+    let w_group = Tensor::new(&[2f32, 3.], device)?;
+    let b_group = Tensor::new(&[0.5f32, -1.], device)?;
+    let w_channel = Tensor::new(&[2f32, 2., 2., 3., 3., 3.], device)?;
+    let b_channel = Tensor::new(&[0.5f32, 0.5, 0.5, -1., -1., -1.], device)?;
+
+    let per_group = GroupNorm::new(w_group.clone(), b_group, 6, 2, 1e-5)?;
+    let per_channel = GroupNorm::new(w_channel, b_channel.clone(), 6, 2, 1e-5)?;
+    assert_eq!(
+        to_vec3_round(&per_group.forward(&input)?, 4)?,
+        to_vec3_round(&per_channel.forward(&input)?, 4)?
+    );
+
+    // Mixed layouts are allowed too.
+    let mixed = GroupNorm::new(w_group.clone(), b_channel, 6, 2, 1e-5)?;
+    assert_eq!(
+        to_vec3_round(&mixed.forward(&input)?, 4)?,
+        to_vec3_round(&per_channel.forward(&input)?, 4)?
+    );
+
+    let bad = Tensor::new(&[1f32, 1., 1., 1.], device)?;
+    let err = GroupNorm::new(bad, w_group, 6, 2, 1e-5).unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("expected num_channels (6) or num_groups (2)"));
+
+    Ok(())
+}
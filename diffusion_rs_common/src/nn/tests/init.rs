@@ -0,0 +1,33 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use crate::core::{DType, Device, Shape};
+use anyhow::Result;
+use diffusion_rs_common::nn::init::trunc_normal;
+
+#[test]
+fn trunc_normal_bounds_and_moments() -> Result<()> {
+    let shape = Shape::from((100, 100));
+    let xs = trunc_normal(&shape, 0.5, 1., -1.5, 2.5, 42, &Device::Cpu, DType::F32)?;
+    assert_eq!(xs.dims(), &[100, 100]);
+    let values = xs.flatten_all()?.to_vec1::<f32>()?;
+    assert!(values.iter().all(|&v| (-1.5..=2.5).contains(&v)));
+
+    // [-1.5, 2.5] is mean -/+ 2 std, so the truncated moments stay close to the targets:
+    // the mean is unchanged by symmetry and the std is about 0.88.
+    let n = values.len() as f32;
+    let mean = values.iter().sum::<f32>() / n;
+    let std = (values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / n).sqrt();
+    assert!((mean - 0.5).abs() < 0.05, "{mean}");
+    assert!((std - 0.88).abs() < 0.05, "{std}");
+
+    // The same seed gives the same values.
+    let ys = trunc_normal(&shape, 0.5, 1., -1.5, 2.5, 42, &Device::Cpu, DType::F32)?;
+    assert_eq!(ys.flatten_all()?.to_vec1::<f32>()?, values);
+
+    assert!(trunc_normal(&shape, 0., 1., 1., -1., 0, &Device::Cpu, DType::F32).is_err());
+    Ok(())
+}
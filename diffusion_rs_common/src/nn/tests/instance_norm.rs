@@ -0,0 +1,66 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use crate::core::{DType, Device, Tensor};
+use anyhow::Result;
+use diffusion_rs_common::nn::{InstanceNorm2d, Module};
+
+#[test]
+fn instance_norm2d_constant_input() -> Result<()> {
+    let device = &Device::Cpu;
+    let norm = InstanceNorm2d::new(None, None, 3, 1e-5)?;
+    let xs = Tensor::full(2.5f32, (2, 3, 4, 5), device)?;
+    let ys = norm.forward(&xs)?;
+    assert_eq!(ys.dims(), xs.dims());
+    assert_eq!(ys.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()?, 0.);
+    Ok(())
+}
+
+#[test]
+fn instance_norm2d_single_location() -> Result<()> {
+    let device = &Device::Cpu;
+    let xs = Tensor::new(&[3f32, -1., 7.], device)?.reshape((1, 3, 1, 1))?;
+    let norm = InstanceNorm2d::new(None, None, 3, 1e-5)?;
+    assert_eq!(
+        norm.forward(&xs)?.flatten_all()?.to_vec1::<f32>()?,
+        [0f32; 3]
+    );
+
+    // Every value is its own mean, only the bias is left.
+    let weight = Tensor::new(&[2f32, 3., 4.], device)?;
+    let bias = Tensor::new(&[0.5f32, -1., 2.], device)?;
+    let norm = InstanceNorm2d::new(Some(weight), Some(bias), 3, 1e-5)?;
+    assert_eq!(
+        norm.forward(&xs)?.flatten_all()?.to_vec1::<f32>()?,
+        [0.5f32, -1., 2.]
+    );
+    Ok(())
+}
+
+#[test]
+fn instance_norm2d_fused_matches_fallback() -> Result<()> {
+    let device = &Device::Cpu;
+    let weight = Tensor::new(&[2f32, 0.5, -1.], device)?;
+    let bias = Tensor::new(&[0.1f32, 0., 3.], device)?;
+    let norm = InstanceNorm2d::new(Some(weight), Some(bias), 3, 1e-5)?;
+    // The transposed input is not contiguous and takes the tensor-op path.
+    let xs = Tensor::randn(0f32, 2., (2, 3, 5, 4), device)?.transpose(2, 3)?;
+    let fallback = norm.forward(&xs)?;
+    let fused = norm.forward(&xs.contiguous()?)?;
+    let diff = (fallback - fused)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-5);
+
+    let xs = Tensor::zeros((1, 4, 2, 2), DType::F32, device)?;
+    assert!(norm.forward(&xs).is_err());
+    assert!(
+        InstanceNorm2d::new(Some(Tensor::ones(2, DType::F32, device)?), None, 3, 1e-5).is_err()
+    );
+    Ok(())
+}
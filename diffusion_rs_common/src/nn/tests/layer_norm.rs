@@ -4,9 +4,9 @@ extern crate intel_mkl_src;
 #[cfg(feature = "accelerate")]
 extern crate accelerate_src;
 
+use crate::core::{test_utils, DType, Device, Tensor};
 use anyhow::Result;
-use crate::core::{test_utils, Device, Tensor};
-use diffusion_rs_common::nn::{LayerNorm, Module};
+use diffusion_rs_common::nn::{AdaLayerNorm, LayerNorm, Linear, Module};
 
 #[test]
 fn layer_norm() -> Result<()> {
@@ -53,3 +53,85 @@ fn layer_norm() -> Result<()> {
     );
     Ok(())
 }
+
+// Reference values computed in float64 from the formula, with `norm` a layer norm over the last
+// dim without weight and bias and eps=1e-5:
+// norm(xs) * (1 + scale[:, None]) + shift[:, None]
+#[test]
+fn adaptive_layer_norm() -> Result<()> {
+    let device = &Device::Cpu;
+    let xs = Tensor::new(
+        &[
+            [[1f32, 2., 3.], [4., -5., 6.]],
+            [[0.5, 0., -0.5], [9., 8., 7.]],
+        ],
+        device,
+    )?;
+    let scale = Tensor::new(&[[0.5f32, -1., 2.], [0., 0.25, -0.5]], device)?;
+    let shift = Tensor::new(&[[1f32, 0., -1.], [0.1, 0.2, 0.3]], device)?;
+    let ys = diffusion_rs_common::nn::ops::adaptive_layer_norm(&xs, &scale, &shift, 1e-5)?;
+    let expected = Tensor::new(
+        &[
+            [[-0.8371f32, 0.0, 2.6742], [1.7316, 0.0, 1.7173]],
+            [[1.3247, 0.2, -0.3124], [1.3247, 0.2, -0.3124]],
+        ],
+        device,
+    )?;
+    let diff = (ys - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-4);
+
+    let bad_shift = Tensor::zeros((2, 2), DType::F32, device)?;
+    assert!(
+        diffusion_rs_common::nn::ops::adaptive_layer_norm(&xs, &scale, &bad_shift, 1e-5).is_err()
+    );
+    let bad_scale = Tensor::zeros((3, 3), DType::F32, device)?;
+    assert!(
+        diffusion_rs_common::nn::ops::adaptive_layer_norm(&xs, &bad_scale, &shift, 1e-5).is_err()
+    );
+    Ok(())
+}
+
+// Reference values computed in float64 from the formula:
+// shift, scale = linear(silu(emb)).chunk(2, dim=1)
+// norm(xs) * (1 + scale[:, None]) + shift[:, None]
+#[test]
+fn ada_layer_norm() -> Result<()> {
+    let device = &Device::Cpu;
+    let xs = Tensor::new(
+        &[
+            [[1f32, 2., 3.], [4., -5., 6.]],
+            [[0.5, 0., -0.5], [9., 8., 7.]],
+        ],
+        device,
+    )?;
+    let emb = Tensor::new(&[[0.3f32, -1.2], [2., 0.5]], device)?;
+    let w = Tensor::new(
+        &[
+            [0.1f32, -0.2],
+            [0.3, 0.4],
+            [-0.5, 0.6],
+            [0.7, -0.8],
+            [0.9, 1.0],
+            [-0.1, 0.2],
+        ],
+        device,
+    )?;
+    let b = Tensor::new(&[0.01f32, -0.02, 0.03, -0.04, 0.05, -0.06], device)?;
+    let norm = LayerNorm::new(
+        Tensor::ones(3, DType::F32, device)?,
+        Tensor::zeros(3, DType::F32, device)?,
+        1e-5,
+    );
+    let ada = AdaLayerNorm::new(norm, Linear::new(w, Some(b)));
+    let ys = ada.forward(&xs, &emb)?;
+    let expected = Tensor::new(
+        &[
+            [[-1.5129f32, -0.0794, 0.8393], [0.7182, -1.3716, 0.5627]],
+            [[2.5049, 0.633, -1.6758], [2.505, 0.633, -1.6758]],
+        ],
+        device,
+    )?;
+    let diff = (ys - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-4);
+    Ok(())
+}
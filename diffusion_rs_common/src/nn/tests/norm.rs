@@ -0,0 +1,90 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use crate::core::{test_utils, DType, Device, Tensor};
+use anyhow::Result;
+use diffusion_rs_common::nn::{norm, Module, Norm, NormConfig, VarBuilder, VarMap};
+
+#[test]
+fn norm_config() -> Result<()> {
+    let cfg: NormConfig = serde_json::from_str(r#"{"type": "rms_norm", "eps": 1e-6}"#)?;
+    assert_eq!(cfg, NormConfig::RmsNorm { eps: 1e-6 });
+    let cfg: NormConfig = serde_json::from_str(r#"{"type": "layer_norm", "eps": 1e-5}"#)?;
+    assert_eq!(
+        cfg,
+        NormConfig::LayerNorm {
+            eps: 1e-5,
+            affine: true
+        }
+    );
+    let cfg: NormConfig =
+        serde_json::from_str(r#"{"type": "group_norm", "num_groups": 2, "eps": 1e-5}"#)?;
+    assert_eq!(
+        cfg,
+        NormConfig::GroupNorm {
+            num_groups: 2,
+            eps: 1e-5
+        }
+    );
+    Ok(())
+}
+
+#[test]
+fn norm_forward() -> Result<()> {
+    let device = &Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, device);
+    let xs = Tensor::new(&[[[1f32, 2., 3., 4.], [4., 5., 6., 7.]]], device)?;
+    let weight = Tensor::ones(4, DType::F32, device)?;
+    let bias = Tensor::zeros(4, DType::F32, device)?;
+
+    let rms = norm(4, NormConfig::RmsNorm { eps: 1e-5 }, vb.pp("rms"))?;
+    assert!(matches!(rms, Norm::RmsNorm(_)));
+    let expected = diffusion_rs_common::nn::ops::rms_norm(&xs, &weight, 1e-5)?;
+    assert_eq!(
+        test_utils::to_vec3_round(&rms.forward(&xs)?, 4)?,
+        test_utils::to_vec3_round(&expected, 4)?
+    );
+
+    let ln = norm(
+        4,
+        NormConfig::LayerNorm {
+            eps: 1e-5,
+            affine: true,
+        },
+        vb.pp("ln"),
+    )?;
+    assert!(matches!(ln, Norm::LayerNorm(_)));
+    let expected = diffusion_rs_common::nn::ops::layer_norm(&xs, &weight, &bias, 1e-5)?;
+    assert_eq!(
+        test_utils::to_vec3_round(&ln.forward(&xs)?, 4)?,
+        test_utils::to_vec3_round(&expected, 4)?
+    );
+
+    let xs = xs.reshape((1, 2, 2, 2))?;
+    let gn = norm(
+        2,
+        NormConfig::GroupNorm {
+            num_groups: 1,
+            eps: 1e-5,
+        },
+        vb.pp("gn"),
+    )?;
+    assert!(matches!(gn, Norm::GroupNorm(_)));
+    let expected = diffusion_rs_common::nn::GroupNorm::new(
+        Tensor::ones(2, DType::F32, device)?,
+        Tensor::zeros(2, DType::F32, device)?,
+        2,
+        1,
+        1e-5,
+    )?
+    .forward(&xs)?;
+    assert_eq!(
+        gn.forward(&xs)?.flatten_all()?.to_vec1::<f32>()?,
+        expected.flatten_all()?.to_vec1::<f32>()?
+    );
+    Ok(())
+}
@@ -118,3 +118,34 @@ fn test_iter() -> Result<()> {
     }
     Ok(())
 }
+
+#[test]
+fn test_one_hot_with_dtype() -> Result<()> {
+    use crate::core::DType;
+    use diffusion_rs_common::nn::encoding::one_hot_with_dtype;
+
+    let device = crate::core::Device::Cpu;
+    let indices = Tensor::new(&[[1u32, 0], [2, 1]], &device)?;
+
+    let one_hot = one_hot_with_dtype(&indices, 3, 1., 0., DType::F32)?;
+    assert_eq!(one_hot.dtype(), DType::F32);
+    assert_eq!(one_hot.shape(), &Shape::from((2, 2, 3)));
+    assert_eq!(
+        one_hot.to_vec3::<f32>()?,
+        [[[0., 1., 0.], [1., 0., 0.]], [[0., 0., 1.], [0., 1., 0.]]]
+    );
+
+    // Soft labels.
+    let soft = one_hot_with_dtype(&indices, 3, 0.5, 0.25, DType::BF16)?;
+    assert_eq!(soft.dtype(), DType::BF16);
+    assert_eq!(
+        soft.to_dtype(DType::F32)?.to_vec3::<f32>()?,
+        [
+            [[0.25, 0.5, 0.25], [0.5, 0.25, 0.25]],
+            [[0.25, 0.25, 0.5], [0.25, 0.5, 0.25]]
+        ]
+    );
+
+    assert!(one_hot_with_dtype(&indices, 2, 1., 0., DType::F32).is_err());
+    Ok(())
+}
@@ -4,7 +4,11 @@ extern crate intel_mkl_src;
 #[cfg(feature = "accelerate")]
 extern crate accelerate_src;
 
-use crate::core::{test_device, test_utils::to_vec3_round, Device, Result, Tensor};
+use crate::core::{
+    test_device,
+    test_utils::{to_vec1_round, to_vec2_round, to_vec3_round},
+    DType, Device, Result, Tensor, D,
+};
 
 fn softmax(device: &Device) -> Result<()> {
     let data = &[[[3f32, 1., 4.], [1., 5., 9.]], [[2., 1., 7.], [8., 2., 8.]]];
@@ -52,6 +56,78 @@ fn softmax(device: &Device) -> Result<()> {
     Ok(())
 }
 
+fn softmax_dim(device: &Device) -> Result<()> {
+    use diffusion_rs_common::nn::ops::softmax;
+    fn softmax_ref(xs: &Tensor, dim: usize) -> Result<Tensor> {
+        let num = xs.broadcast_sub(&xs.max_keepdim(dim)?)?.exp()?;
+        num.broadcast_div(&num.sum_keepdim(dim)?)
+    }
+    let xs = Tensor::randn(0f32, 3., (2, 3, 5, 4), device)?;
+    for dim in 0..4 {
+        let diff = (softmax(&xs, dim)? - softmax_ref(&xs, dim)?)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?;
+        assert!(diff.to_scalar::<f32>()? < 1e-6, "{dim}");
+    }
+    let xs_t = xs.transpose(1, 3)?;
+    let diff = (softmax(&xs_t, 2)? - softmax_ref(&xs_t, 2)?)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-6);
+
+    // Fully masked slices along a middle dim output zeros with the fused kernels, the cpu uses
+    // the tensor-op formula.
+    if !device.is_cpu() {
+        let xs = Tensor::new(
+            &[[1f32, f32::NEG_INFINITY], [3., f32::NEG_INFINITY]],
+            device,
+        )?;
+        let ys = softmax(&xs, 0)?;
+        assert_eq!(to_vec2_round(&ys, 4)?, [[0.1192f32, 0.], [0.8808, 0.]]);
+    }
+
+    // The gradient of `sum(softmax(x) * w)` matches the one of the tensor-op formula.
+    let xs = Tensor::randn(0f32, 1., (3, 4, 2), device)?;
+    let w = Tensor::randn(0f32, 1., (3, 4, 2), device)?;
+    let var = diffusion_rs_common::core::Var::from_tensor(&xs)?;
+    let grads = (softmax(&var, 1)? * &w)?.sum_all()?.backward()?;
+    let grad = grads.get(&var).unwrap().clone();
+    let grads = (softmax_ref(&var, 1)? * &w)?.sum_all()?.backward()?;
+    let diff = (grad - grads.get(&var).unwrap())?
+        .abs()?
+        .flatten_all()?
+        .max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-6);
+    Ok(())
+}
+
+fn log_softmax_last_dim(device: &Device) -> Result<()> {
+    use diffusion_rs_common::nn::ops::{
+        inplace_log_softmax_last_dim, log_softmax, log_softmax_last_dim,
+    };
+    let xs = Tensor::randn(0f32, 4., (2, 3, 17), device)?;
+    let ys = log_softmax_last_dim(&xs)?;
+    let expected = log_softmax(&xs, D::Minus1)?;
+    let diff = (&ys - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5);
+    let sums = ys.exp()?.sum(D::Minus1)?.flatten_all()?.to_vec1::<f32>()?;
+    assert!(sums.iter().all(|s| (s - 1.).abs() < 1e-5), "{sums:?}");
+
+    let mut inplace = xs.copy()?;
+    inplace_log_softmax_last_dim(&mut inplace)?;
+    let diff = (&ys - inplace)?.abs()?.flatten_all()?.max(0)?;
+    assert_eq!(diff.to_scalar::<f32>()?, 0.);
+
+    // Large logits do not overflow and fully masked rows output -inf.
+    let inf = f32::NEG_INFINITY;
+    let xs = Tensor::new(&[[1000f32, 0., inf], [inf, inf, inf]], device)?;
+    let ys = log_softmax_last_dim(&xs)?.to_vec2::<f32>()?;
+    assert_eq!(ys, [[0f32, -1000., inf], [inf, inf, inf]]);
+    Ok(())
+}
+
 fn inplace_softmax(device: &Device) -> Result<()> {
     let data = &[[[3f32, 1., 4.], [1., 5., 9.]], [[2., 1., 7.], [8., 2., 8.]]];
     let mut tensor = Tensor::new(data, device)?.log()?;
@@ -68,106 +144,2768 @@ fn inplace_softmax(device: &Device) -> Result<()> {
     Ok(())
 }
 
-fn rms_norm(device: &Device) -> Result<()> {
-    let data = &[[[3f32, 1., 4.], [1., 5., 9.]], [[2., 1., 7.], [8., 2., 8.]]];
-    let tensor = Tensor::new(data, device)?;
-    let alpha = Tensor::new(&[1f32, 2f32, 3f32], device)?;
-    let t = diffusion_rs_common::nn::ops::rms_norm(&tensor, &alpha, 1e-5)?;
-    assert_eq!(
-        to_vec3_round(&t, 4)?,
+fn attention_entropy(device: &Device) -> Result<()> {
+    let scores = Tensor::randn(0f32, 2f32, (2, 3, 4, 5), device)?;
+    let entropy = diffusion_rs_common::nn::ops::attention_entropy(&scores)?;
+    assert_eq!(entropy.dims(), [2, 3, 4]);
+    let probs = diffusion_rs_common::nn::ops::softmax(&scores, 3)?;
+    let probs = probs.flatten_to(2)?.to_vec2::<f32>()?;
+    let expected = probs
+        .iter()
+        .map(|row| -row.iter().map(|p| p * p.ln()).sum::<f32>())
+        .collect::<Vec<_>>();
+    let entropy = entropy.flatten_all()?.to_vec1::<f32>()?;
+    for (e, x) in entropy.iter().zip(expected.iter()) {
+        assert!((e - x).abs() < 1e-5, "{e} {x}");
+    }
+
+    // A near one-hot row, a uniform row and a row with masked keys.
+    let ninf = f32::NEG_INFINITY;
+    let scores = Tensor::new(
+        &[[[
+            [100f32, 0., 0., 0.],
+            [1., 1., 1., 1.],
+            [0.5, ninf, 0.5, ninf],
+        ]]],
+        device,
+    )?;
+    let entropy = diffusion_rs_common::nn::ops::attention_entropy(&scores)?;
+    let entropy = entropy.flatten_all()?.to_vec1::<f32>()?;
+    assert!(entropy[0].abs() < 1e-6);
+    assert!((entropy[1] - 4f32.ln()).abs() < 1e-6);
+    assert!((entropy[2] - 2f32.ln()).abs() < 1e-6);
+
+    assert!(diffusion_rs_common::nn::ops::attention_entropy(&scores.squeeze(0)?).is_err());
+    Ok(())
+}
+
+fn softmax_fully_masked(device: &Device) -> Result<()> {
+    let ninf = f32::NEG_INFINITY;
+    // Short rows and rows longer than a cuda warp or a vectorized metal block.
+    for n in [3, 8, 100] {
+        let mut rows = vec![0f32; 3 * n];
+        rows[n..2 * n].fill(ninf);
+        for (i, v) in rows[2 * n..].iter_mut().enumerate() {
+            if i % 2 == 1 {
+                *v = ninf;
+            }
+        }
+        let xs = Tensor::from_vec(rows, (1, 1, 3, n), device)?;
+        let check = |ys: &Tensor| -> Result<()> {
+            let ys = ys.reshape((3, n))?.to_vec2::<f32>()?;
+            assert!(ys.iter().flatten().all(|v| v.is_finite()), "{ys:?}");
+            assert!(ys[1].iter().all(|&v| v == 0.), "{:?}", ys[1]);
+            let sums = [ys[0].iter().sum::<f32>(), ys[2].iter().sum::<f32>()];
+            assert!(sums.iter().all(|s| (s - 1.).abs() < 1e-5), "{sums:?}");
+            Ok(())
+        };
+        check(&diffusion_rs_common::nn::ops::softmax_last_dim(&xs)?)?;
+        let mut ys = xs.copy()?;
+        diffusion_rs_common::nn::ops::inplace_softmax_last_dim(&mut ys)?;
+        check(&ys)?;
+
+        let mask = xs.reshape((3, n))?;
+        let zeros = xs.zeros_like()?;
+        check(&diffusion_rs_common::nn::ops::attn_softmax_last_dim(
+            &zeros, &mask, 0.5,
+        )?)?;
+        let mut ys = zeros.copy()?;
+        diffusion_rs_common::nn::ops::inplace_attn_softmax_last_dim(&mut ys, &mask, 0.5)?;
+        check(&ys)?;
+    }
+    Ok(())
+}
+
+fn rms_norm(device: &Device) -> Result<()> {
+    let data = &[[[3f32, 1., 4.], [1., 5., 9.]], [[2., 1., 7.], [8., 2., 8.]]];
+    let tensor = Tensor::new(data, device)?;
+    let alpha = Tensor::new(&[1f32, 2f32, 3f32], device)?;
+    let t = diffusion_rs_common::nn::ops::rms_norm(&tensor, &alpha, 1e-5)?;
+    assert_eq!(
+        to_vec3_round(&t, 4)?,
+        &[
+            [[1.019, 0.6794, 4.0762], [0.1674, 1.6744, 4.521]],
+            [[0.4714, 0.4714, 4.9497], [1.206, 0.603, 3.6181]]
+        ]
+    );
+    let t2 = diffusion_rs_common::nn::ops::rms_norm_slow(&tensor, &alpha, 1e-5)?;
+    assert_eq!(
+        to_vec3_round(&t2, 4)?,
+        &[
+            [[1.019, 0.6794, 4.0762], [0.1674, 1.6744, 4.521]],
+            [[0.4714, 0.4714, 4.9497], [1.206, 0.603, 3.6181]]
+        ]
+    );
+    let diff = (t - t2)?.abs()?.sum_all()?.to_vec0::<f32>()?;
+    assert!(diff < 1e-5);
+    Ok(())
+}
+
+fn rms_norm_bf16_accum(device: &Device) -> Result<()> {
+    // Large values whose squares would lose most of their precision if summed in bf16.
+    let tensor = Tensor::randn(0f32, 1000f32, (4, 4096), device)?.to_dtype(DType::BF16)?;
+    let alpha = Tensor::ones(4096, DType::BF16, device)?;
+    let fast = diffusion_rs_common::nn::ops::rms_norm(&tensor, &alpha, 1e-5)?;
+    let slow = diffusion_rs_common::nn::ops::rms_norm_slow(&tensor, &alpha, 1e-5)?;
+    let fast = fast.to_dtype(DType::F32)?;
+    let slow = slow.to_dtype(DType::F32)?;
+    let err = (&fast - &slow)?.abs()?.sum_all()?.to_scalar::<f32>()?;
+    let norm = slow.abs()?.sum_all()?.to_scalar::<f32>()?;
+    assert!(err / norm < 1e-3, "relative error {}", err / norm);
+    // The rms of every normalized row is 1.
+    let rms = fast.sqr()?.mean(1)?.sqrt()?.to_vec1::<f32>()?;
+    for rms in rms {
+        assert!((rms - 1.).abs() < 1e-2, "{rms}");
+    }
+    Ok(())
+}
+
+fn inplace_norms(device: &Device) -> Result<()> {
+    let xs = Tensor::randn(0f32, 3f32, (2, 5, 40), device)?;
+    let alpha = Tensor::randn(0f32, 1f32, 40, device)?;
+    let beta = Tensor::randn(0f32, 1f32, 40, device)?;
+
+    let expected = diffusion_rs_common::nn::ops::rms_norm(&xs, &alpha, 1e-5)?;
+    let mut ys = xs.copy()?;
+    diffusion_rs_common::nn::ops::inplace_rms_norm(&mut ys, &alpha, 1e-5)?;
+    assert_eq!(to_vec3_round(&ys, 5)?, to_vec3_round(&expected, 5)?);
+
+    let expected = diffusion_rs_common::nn::ops::layer_norm(&xs, &alpha, &beta, 1e-5)?;
+    let mut ys = xs.copy()?;
+    diffusion_rs_common::nn::ops::inplace_layer_norm(&mut ys, &alpha, &beta, 1e-5)?;
+    assert_eq!(to_vec3_round(&ys, 5)?, to_vec3_round(&expected, 5)?);
+
+    let xs = xs.to_dtype(DType::BF16)?;
+    let alpha = alpha.to_dtype(DType::BF16)?;
+    let beta = beta.to_dtype(DType::BF16)?;
+    let expected = diffusion_rs_common::nn::ops::layer_norm(&xs, &alpha, &beta, 1e-5)?;
+    let mut ys = xs.copy()?;
+    diffusion_rs_common::nn::ops::inplace_layer_norm(&mut ys, &alpha, &beta, 1e-5)?;
+    let diff = (expected.to_dtype(DType::F32)? - ys.to_dtype(DType::F32)?)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert_eq!(diff, 0.);
+
+    let mut ys = xs.copy()?;
+    assert!(
+        diffusion_rs_common::nn::ops::inplace_rms_norm(&mut ys, &alpha.narrow(0, 0, 4)?, 1e-5)
+            .is_err()
+    );
+    Ok(())
+}
+
+fn norm_widths(device: &Device) -> Result<()> {
+    // Hidden sizes below, at and above a full 1024 thread block on cuda.
+    for hidden in [96, 256, 512, 2048] {
+        let xs = Tensor::randn(0f32, 1f32, (3, 5, hidden), device)?;
+        let alpha = Tensor::randn(1f32, 0.1, hidden, device)?;
+        let beta = Tensor::randn(0f32, 0.1, hidden, device)?;
+        let t = diffusion_rs_common::nn::ops::rms_norm(&xs, &alpha, 1e-5)?;
+        let t2 = diffusion_rs_common::nn::ops::rms_norm_slow(&xs, &alpha, 1e-5)?;
+        let diff = (t - t2)?.abs()?.flatten_all()?.max(0)?.to_vec0::<f32>()?;
+        assert!(diff < 1e-4, "rms_norm {hidden}: {diff}");
+        let t = diffusion_rs_common::nn::ops::layer_norm(&xs, &alpha, &beta, 1e-5)?;
+        let t2 = diffusion_rs_common::nn::ops::layer_norm_slow(&xs, &alpha, &beta, 1e-5)?;
+        let diff = (t - t2)?.abs()?.flatten_all()?.max(0)?.to_vec0::<f32>()?;
+        assert!(diff < 1e-4, "layer_norm {hidden}: {diff}");
+    }
+    Ok(())
+}
+
+fn rms_norm_cast(device: &Device) -> Result<()> {
+    let xs = Tensor::randn(0f32, 1f32, (2, 3, 64), device)?;
+    let alpha = Tensor::randn(1f32, 0.1, 64, device)?;
+    for out_dtype in [DType::F16, DType::BF16] {
+        let t = diffusion_rs_common::nn::ops::rms_norm_cast(&xs, &alpha, 1e-5, out_dtype)?;
+        let t2 = diffusion_rs_common::nn::ops::rms_norm(&xs, &alpha, 1e-5)?.to_dtype(out_dtype)?;
+        assert_eq!(t.dtype(), out_dtype);
+        let diff = (t.to_dtype(DType::F32)? - t2.to_dtype(DType::F32)?)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_vec0::<f32>()?;
+        assert!(diff < 1e-2, "{out_dtype:?}: {diff}");
+
+        // Half precision inputs normalized into a f32 output.
+        let xs = xs.to_dtype(out_dtype)?;
+        let alpha = alpha.to_dtype(out_dtype)?;
+        let t = diffusion_rs_common::nn::ops::rms_norm_cast(&xs, &alpha, 1e-5, DType::F32)?;
+        let t2 = diffusion_rs_common::nn::ops::rms_norm(&xs, &alpha, 1e-5)?.to_dtype(DType::F32)?;
+        assert_eq!(t.dtype(), DType::F32);
+        let diff = (t - t2)?.abs()?.flatten_all()?.max(0)?.to_vec0::<f32>()?;
+        assert!(diff < 1e-2, "{out_dtype:?}: {diff}");
+    }
+    assert!(diffusion_rs_common::nn::ops::rms_norm_cast(&xs, &alpha, 1e-5, DType::U8).is_err());
+    Ok(())
+}
+
+fn rms_norml(device: &Device) -> Result<()> {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    let (b_size, seq_len, head_dim) = (24, 70, 64);
+    let el_count = b_size * seq_len * head_dim;
+    let mut rng = StdRng::seed_from_u64(299792458);
+    let src: Vec<f32> = (0..el_count).map(|_| rng.gen::<f32>()).collect();
+    let tensor = Tensor::new(src, device)?.reshape((b_size, seq_len, head_dim))?;
+    let alpha = Tensor::ones(head_dim, crate::core::DType::F32, device)?;
+    let t = diffusion_rs_common::nn::ops::rms_norm(&tensor, &alpha, 1e-5)?;
+    let t2 = diffusion_rs_common::nn::ops::rms_norm_slow(&tensor, &alpha, 1e-5)?;
+    let diff = (t - t2)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .reshape(())?
+        .to_vec0::<f32>()?;
+    assert!(diff < 1e-5);
+    Ok(())
+}
+
+fn layer_norm(device: &Device) -> Result<()> {
+    let data = &[[[3f32, 1., 4.], [1., 5., 9.]], [[2., 1., 7.], [8., 2., 8.]]];
+    let tensor = Tensor::new(data, device)?;
+    let alpha = Tensor::new(&[1f32, 2f32, 3f32], device)?;
+    let beta = Tensor::new(&[0.5f32, 0f32, -0.2f32], device)?;
+    let t = diffusion_rs_common::nn::ops::layer_norm(&tensor, &alpha, &beta, 1e-5)?;
+    assert_eq!(
+        to_vec3_round(&t, 4)?,
+        &[
+            [[0.7673, -2.6726, 3.0071], [-0.7247, 0.0, 3.4742]],
+            [[-0.008, -1.778, 3.991], [1.2071, -2.8284, 1.9213]]
+        ]
+    );
+    let t2 = diffusion_rs_common::nn::ops::layer_norm_slow(&tensor, &alpha, &beta, 1e-5)?;
+    assert_eq!(
+        to_vec3_round(&t2, 4)?,
+        &[
+            [[0.7673, -2.6726, 3.0071], [-0.7247, 0.0, 3.4742]],
+            [[-0.008, -1.778, 3.991], [1.2071, -2.8284, 1.9213]]
+        ]
+    );
+    let diff = (t - t2)?.abs()?.sum_all()?.to_vec0::<f32>()?;
+    assert!(diff < 1e-5);
+    Ok(())
+}
+
+fn layer_norml(device: &Device) -> Result<()> {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    let (b_size, seq_len, head_dim) = (24, 70, 64);
+    let el_count = b_size * seq_len * head_dim;
+    let mut rng = StdRng::seed_from_u64(299792458);
+    let src: Vec<f32> = (0..el_count).map(|_| rng.gen::<f32>()).collect();
+    let tensor = Tensor::new(src, device)?.reshape((b_size, seq_len, head_dim))?;
+    let alpha = Tensor::ones(head_dim, crate::core::DType::F32, device)?;
+    let beta = Tensor::zeros(head_dim, crate::core::DType::F32, device)?;
+    let t = diffusion_rs_common::nn::ops::layer_norm(&tensor, &alpha, &beta, 1e-5)?;
+    let t2 = diffusion_rs_common::nn::ops::layer_norm_slow(&tensor, &alpha, &beta, 1e-5)?;
+    let diff = (t - t2)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .reshape(())?
+        .to_vec0::<f32>()?;
+    assert!(diff < 1e-5);
+    Ok(())
+}
+
+fn group_norm_fused(device: &Device) -> Result<()> {
+    use diffusion_rs_common::nn::ops::{group_norm, layer_norm_slow};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    let (b, c, h, w) = (2, 4, 3, 5);
+    let mut rng = StdRng::seed_from_u64(299792458);
+    let src: Vec<f32> = (0..b * c * h * w).map(|_| rng.gen::<f32>() * 4.).collect();
+    let xs = Tensor::new(src, device)?.reshape((b, c, h, w))?;
+    let max_diff = |a: &Tensor, b: &Tensor| -> Result<f32> {
+        (a.to_dtype(DType::F32)? - b)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()
+    };
+
+    // A single group is a layer norm over all the dims but the batch one.
+    let ones = Tensor::ones(c, DType::F32, device)?;
+    let zeros = Tensor::zeros(c, DType::F32, device)?;
+    let ys = group_norm(&xs, &ones, &zeros, 1, 1e-5)?;
+    let expected = layer_norm_slow(
+        &xs.flatten_from(1)?,
+        &Tensor::ones(c * h * w, DType::F32, device)?,
+        &Tensor::zeros(c * h * w, DType::F32, device)?,
+        1e-5,
+    )?
+    .reshape((b, c, h, w))?;
+    assert!(max_diff(&ys, &expected)? < 1e-4);
+
+    // One group per channel is an instance norm.
+    let weight = Tensor::new(&[0.5f32, 1., 2., -1.], device)?;
+    let bias = Tensor::new(&[0f32, 0.1, -0.2, 3.], device)?;
+    let ys = group_norm(&xs, &weight, &bias, c, 1e-5)?;
+    let flat = xs.flatten_from(2)?;
+    let centered = flat.broadcast_sub(&flat.mean_keepdim(2)?)?;
+    let std = (centered.sqr()?.mean_keepdim(2)? + 1e-5)?.sqrt()?;
+    let expected = centered
+        .broadcast_div(&std)?
+        .broadcast_mul(&weight.reshape((1, c, 1))?)?
+        .broadcast_add(&bias.reshape((1, c, 1))?)?
+        .reshape((b, c, h, w))?;
+    assert!(max_diff(&ys, &expected)? < 1e-4);
+
+    let ys = group_norm(
+        &xs.to_dtype(DType::BF16)?,
+        &weight.to_dtype(DType::BF16)?,
+        &bias.to_dtype(DType::BF16)?,
+        c,
+        1e-5,
+    )?;
+    assert_eq!(ys.dtype(), DType::BF16);
+    assert!(max_diff(&ys, &expected)? < 1e-1);
+
+    assert!(group_norm(&xs, &weight, &bias, 3, 1e-5).is_err());
+    assert!(group_norm(&xs.flatten_from(1)?, &weight, &bias, 2, 1e-5).is_err());
+    Ok(())
+}
+
+fn layer_norm2d(device: &Device) -> Result<()> {
+    use diffusion_rs_common::nn::ops::{layer_norm2d, layer_norm_slow};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    let (b, c, h, w) = (2, 6, 3, 5);
+    let mut rng = StdRng::seed_from_u64(299792458);
+    let src: Vec<f32> = (0..b * c * h * w).map(|_| rng.gen::<f32>() * 4.).collect();
+    let xs = Tensor::new(src, device)?.reshape((b, c, h, w))?;
+    let alpha = Tensor::new(&[1f32, 2., 0.5, -1., 3., 0.], device)?;
+    let beta = Tensor::new(&[0f32, 0.5, -0.2, 1., 0., 2.], device)?;
+
+    let ys = layer_norm2d(&xs, &alpha, &beta, 1e-5)?;
+    let expected =
+        layer_norm_slow(&xs.permute((0, 2, 3, 1))?, &alpha, &beta, 1e-5)?.permute((0, 3, 1, 2))?;
+    let diff = (ys - &expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-4);
+
+    let ys = layer_norm2d(
+        &xs.to_dtype(DType::F16)?,
+        &alpha.to_dtype(DType::F16)?,
+        &beta.to_dtype(DType::F16)?,
+        1e-5,
+    )?;
+    assert_eq!(ys.dtype(), DType::F16);
+    let diff = (ys.to_dtype(DType::F32)? - expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 2e-2);
+
+    assert!(layer_norm2d(&xs, &alpha.narrow(0, 0, 4)?, &beta, 1e-5).is_err());
+    assert!(layer_norm2d(&xs.flatten_from(2)?, &alpha, &beta, 1e-5).is_err());
+    Ok(())
+}
+
+#[test]
+fn softmax_numerical_stability() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::new(&[1234f32, 0.], dev)?;
+    let softmax = diffusion_rs_common::nn::ops::softmax(&xs, 0)?;
+    assert_eq!(softmax.to_vec1::<f32>()?, &[1f32, 0.]);
+    Ok(())
+}
+
+#[test]
+fn nucleus_distribution() -> Result<()> {
+    use diffusion_rs_common::nn::ops::nucleus_distribution;
+    let dev = &Device::Cpu;
+    let probs = [0.1f32, 0.5, 0.15, 0.25];
+    let logits = Tensor::new(&probs.map(f32::ln), dev)?.reshape((1, 4))?;
+
+    let check = |p: f32, temperature: f32, nucleus: &[usize]| -> Result<Vec<f32>> {
+        let dist = nucleus_distribution(&logits, p, temperature)?;
+        assert_eq!(dist.dims(), [1, 4]);
+        let dist = dist.flatten_all()?.to_vec1::<f32>()?;
+        assert!((dist.iter().sum::<f32>() - 1.).abs() < 1e-6);
+        for (i, v) in dist.iter().enumerate() {
+            assert_eq!(*v > 0., nucleus.contains(&i), "p={p} {dist:?}");
+        }
+        Ok(dist)
+    };
+    let dist = check(0.7, 1., &[1, 3])?;
+    assert!((dist[1] - 2. / 3.).abs() < 1e-6);
+    check(1., 1., &[0, 1, 2, 3])?;
+    // The top token alone exceeds `p`.
+    let dist = check(0.3, 1., &[1])?;
+    assert_eq!(dist[1], 1.);
+    // A high temperature flattens the distribution so more tokens are needed.
+    check(0.7, 100., &[1, 2, 3])?;
+
+    assert!(nucleus_distribution(&logits, 0., 1.).is_err());
+    assert!(nucleus_distribution(&logits, 0.5, 0.).is_err());
+    Ok(())
+}
+
+// Narrowed logits, e.g. the last position of a (b, seq, vocab) tensor, match their contiguous copy.
+fn nucleus_distribution_narrowed(device: &Device) -> Result<()> {
+    use diffusion_rs_common::nn::ops::nucleus_distribution;
+    let logits = Tensor::randn(0f32, 1f32, (2, 3, 8), device)?;
+    let last = logits.narrow(1, 2, 1)?.squeeze(1)?;
+    assert!(!last.is_contiguous());
+    let dist = nucleus_distribution(&last, 0.9, 0.7)?;
+    assert!(dist.device().same_device(device));
+    let expected = nucleus_distribution(&last.contiguous()?, 0.9, 0.7)?;
+    assert_eq!(dist.to_vec2::<f32>()?, expected.to_vec2::<f32>()?);
+    Ok(())
+}
+
+fn softmax_temp_vec(device: &Device) -> Result<()> {
+    let logits = Tensor::new(&[[1f32, 2., 3.], [1., 2., 3.]], device)?;
+    let temps = Tensor::new(&[1f32, 2.], device)?;
+    let sm = diffusion_rs_common::nn::ops::softmax_last_dim_temp_vec(&logits, &temps)?;
+    let row0 = diffusion_rs_common::nn::ops::softmax_last_dim(&logits.get(0)?)?;
+    let row1 = diffusion_rs_common::nn::ops::softmax_last_dim(&(logits.get(1)? / 2.)?)?;
+    let expected = Tensor::stack(&[row0, row1], 0)?;
+    let diff = (sm - expected)?.abs()?.sum_all()?.to_vec0::<f32>()?;
+    assert!(diff < 1e-5);
+
+    for bad in [[1f32, 0.], [-1., 2.]] {
+        let temps = Tensor::new(&bad, device)?;
+        assert!(diffusion_rs_common::nn::ops::softmax_last_dim_temp_vec(&logits, &temps).is_err());
+    }
+    Ok(())
+}
+
+fn fma(device: &Device) -> Result<()> {
+    let x = Tensor::new(
+        &[[[3f32, 1., 4.], [1., 5., 9.]], [[2., 1., 7.], [8., 2., 8.]]],
+        device,
+    )?;
+    let a = Tensor::new(0.5f32, device)?;
+    let b = Tensor::new(&[[1f32], [-2.]], device)?;
+    let fused = diffusion_rs_common::nn::ops::fma(&x, &a, &b)?;
+    let composed = x.broadcast_mul(&a)?.broadcast_add(&b)?;
+    assert_eq!(to_vec3_round(&fused, 4)?, to_vec3_round(&composed, 4)?);
+
+    let a = Tensor::new(&[2f32, 3., 4.], device)?;
+    let fused = diffusion_rs_common::nn::ops::fma(&x.transpose(0, 1)?, &a, &b)?;
+    let composed = x.transpose(0, 1)?.broadcast_mul(&a)?.broadcast_add(&b)?;
+    assert_eq!(to_vec3_round(&fused, 4)?, to_vec3_round(&composed, 4)?);
+    Ok(())
+}
+
+fn seeded_dropout(device: &Device) -> Result<()> {
+    use diffusion_rs_common::nn::ops::seeded_dropout;
+    // The first outputs of splitmix64 seeded with 0 are 0xe220a8397b1dcdaf, 0x6e789e6aa1b965f4
+    // and 0x06c45d188009454f, i.e. uniforms of 0.8833, 0.4315 and 0.0264.
+    let ones = Tensor::ones(3, DType::F32, device)?;
+    let ys = seeded_dropout(&ones, 0.5, 0)?;
+    assert_eq!(ys.to_vec1::<f32>()?, [2f32, 0., 0.]);
+
+    let xs = Tensor::rand(1f32, 2f32, (4, 2500), device)?;
+    let ys1 = seeded_dropout(&xs, 0.3, 42)?;
+    let ys2 = seeded_dropout(&xs, 0.3, 42)?;
+    let diff = (&ys1 - &ys2)?.abs()?.sum_all()?.to_scalar::<f32>()?;
+    assert_eq!(diff, 0.);
+    // The mask only depends on the seed so it is the same on every device.
+    let ys_cpu = seeded_dropout(&xs.to_device(&Device::Cpu)?, 0.3, 42)?;
+    let diff = (ys1.to_device(&Device::Cpu)? - ys_cpu)?
+        .abs()?
+        .sum_all()?
+        .to_scalar::<f32>()?;
+    assert_eq!(diff, 0.);
+
+    let kept1 = ys1.ne(0f32)?.to_dtype(DType::F32)?;
+    let kept2 = seeded_dropout(&xs, 0.3, 43)?
+        .ne(0f32)?
+        .to_dtype(DType::F32)?;
+    let keep_ratio = kept1.mean_all()?.to_scalar::<f32>()?;
+    assert!((keep_ratio - 0.7).abs() < 0.03, "{keep_ratio}");
+    // Independent masks agree on about 0.7^2 + 0.3^2 = 58% of the elements.
+    let agree = kept1.eq(&kept2)?.to_dtype(DType::F32)?.mean_all()?;
+    let agree = agree.to_scalar::<f32>()?;
+    assert!((agree - 0.58).abs() < 0.03, "{agree}");
+    let scaled = ((ys1 * 0.7)? - (&xs * &kept1)?)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?;
+    assert!(scaled.to_scalar::<f32>()? < 1e-5);
+
+    let dropout = diffusion_rs_common::nn::Dropout::with_recorded_mask(0.3);
+    let ys = dropout.forward_seeded(&xs, 42, true)?;
+    let diff = (&ys - seeded_dropout(&xs, 0.3, 42)?)?.abs()?.sum_all()?;
+    assert_eq!(diff.to_scalar::<f32>()?, 0.);
+    let diff = (ys - dropout.replay(&xs)?)?.abs()?.sum_all()?;
+    assert_eq!(diff.to_scalar::<f32>()?, 0.);
+    let ys = dropout.forward_seeded(&xs, 42, false)?;
+    assert_eq!(ys.to_vec2::<f32>()?, xs.to_vec2::<f32>()?);
+
+    assert!(seeded_dropout(&xs, 1., 42).is_err());
+    Ok(())
+}
+
+fn dropout2d(device: &Device) -> Result<()> {
+    use diffusion_rs_common::core::ModuleT;
+    let dropout = diffusion_rs_common::nn::Dropout2d::new(0.5);
+    let xs = Tensor::rand(1f32, 2f32, (4, 16, 3, 5), device)?;
+    let ys = dropout.forward_t(&xs, true)?;
+    assert_eq!(ys.dims(), xs.dims());
+    let ratio = (ys / &xs)?.flatten_from(2)?.to_vec3::<f32>()?;
+    let mut n_dropped = 0;
+    for channel in ratio.iter().flatten() {
+        // Every channel is either fully dropped or fully scaled by 1 / (1 - p).
+        let expected = if channel[0] == 0. { 0. } else { 2. };
+        n_dropped += (expected == 0.) as usize;
+        assert!(
+            channel.iter().all(|&r| (r - expected).abs() < 1e-5),
+            "{channel:?}"
+        );
+    }
+    // 64 channels, the probability that none or all of them are dropped is negligible.
+    assert!(0 < n_dropped && n_dropped < 64, "{n_dropped}");
+
+    let ys = dropout.forward_t(&xs, false)?;
+    assert_eq!(
+        ys.flatten_all()?.to_vec1::<f32>()?,
+        xs.flatten_all()?.to_vec1::<f32>()?
+    );
+    assert!(dropout.forward_t(&xs.flatten_from(2)?, true).is_err());
+    Ok(())
+}
+
+#[test]
+fn dropout_replay() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::rand(0f32, 1f32, (4, 16), dev)?;
+    let dropout = diffusion_rs_common::nn::Dropout::with_recorded_mask(0.5);
+    let ys = dropout.forward(&xs, true)?;
+    let replayed = dropout.replay(&xs)?;
+    let diff = (ys - replayed)?.abs()?.sum_all()?.to_vec0::<f32>()?;
+    assert_eq!(diff, 0.);
+    assert!(dropout.replay(&xs.narrow(0, 0, 2)?).is_err());
+    Ok(())
+}
+
+#[test]
+fn dropout_with_mask() -> Result<()> {
+    use diffusion_rs_common::nn::ops::{apply_dropout_mask, dropout_with_mask};
+    let dev = &Device::Cpu;
+    let xs = Tensor::rand(1f32, 2f32, (8, 32), dev)?;
+    let (ys, mask) = dropout_with_mask(&xs, 0.25)?;
+    assert_eq!(mask.dims(), xs.dims());
+    let values = mask.flatten_all()?.to_vec1::<f32>()?;
+    assert!(values
+        .iter()
+        .all(|&v| v == 0. || (v - 4. / 3.).abs() < 1e-6));
+    assert!(values.contains(&0.));
+    let diff = (&ys - apply_dropout_mask(&xs, &mask)?)?.abs()?.sum_all()?;
+    assert_eq!(diff.to_scalar::<f32>()?, 0.);
+    assert!(apply_dropout_mask(&xs.t()?, &mask).is_err());
+
+    let dropout = diffusion_rs_common::nn::Dropout::with_recorded_mask(0.25);
+    assert!(dropout.last_mask().is_none());
+    let ys = dropout.forward(&xs, true)?;
+    let mask = dropout.last_mask().unwrap();
+    let diff = (ys - apply_dropout_mask(&xs, &mask)?)?.abs()?.sum_all()?;
+    assert_eq!(diff.to_scalar::<f32>()?, 0.);
+    assert!(diffusion_rs_common::nn::Dropout::new(0.25)
+        .last_mask()
+        .is_none());
+    Ok(())
+}
+
+#[test]
+fn cfg_combine_vec() -> Result<()> {
+    let dev = &Device::Cpu;
+    let cond = Tensor::randn(0f32, 1., (2, 4, 3, 3), dev)?;
+    let uncond = Tensor::randn(0f32, 1., (2, 4, 3, 3), dev)?;
+    let scales = Tensor::new(&[1.5f32, 7.5], dev)?;
+    let ys = diffusion_rs_common::nn::ops::cfg_combine_vec(&cond, &uncond, &scales)?;
+    assert_eq!(ys.dims(), cond.dims());
+    for (i, scale) in [1.5, 7.5].into_iter().enumerate() {
+        let expected =
+            diffusion_rs_common::nn::ops::cfg_combine(&cond.get(i)?, &uncond.get(i)?, scale)?;
+        assert_eq!(to_vec3_round(&ys.get(i)?, 5)?, to_vec3_round(&expected, 5)?);
+    }
+    let bad_scales = Tensor::new(&[1f32, 2., 3.], dev)?;
+    assert!(diffusion_rs_common::nn::ops::cfg_combine_vec(&cond, &uncond, &bad_scales).is_err());
+    Ok(())
+}
+
+#[test]
+fn cfg_combine_dynamic() -> Result<()> {
+    let dev = &Device::Cpu;
+    let cond = Tensor::new(&[[1f32, -1., 0.4, 0.2], [5., -3., 1., -1.]], dev)?;
+    let uncond = Tensor::new(&[[0f32, 0., 0., 0.], [-1., 1., -1., -1.]], dev)?;
+    // combined = uncond + 0.5 * (cond - uncond)
+    // = [[0.5, -0.5, 0.2, 0.1], [2, -1, 0, -1]]
+    let ys = diffusion_rs_common::nn::ops::cfg_combine_dynamic(&cond, &uncond, 0.5, 1.0)?;
+    assert_eq!(
+        ys.to_vec2::<f32>()?,
+        &[[0.5, -0.5, 0.2, 0.1], [1., -0.5, 0., -0.5]]
+    );
+    // The 0.5 quantile of [0, 1, 1, 2] is 1, so the second sample gets clamped to [-1, 1].
+    let ys = diffusion_rs_common::nn::ops::cfg_combine_dynamic(&cond, &uncond, 0.5, 0.5)?;
+    assert_eq!(
+        ys.to_vec2::<f32>()?,
+        &[[0.5, -0.5, 0.2, 0.1], [1., -1., 0., -1.]]
+    );
+
+    let empty = Tensor::zeros((2, 0), DType::F32, dev)?;
+    assert!(diffusion_rs_common::nn::ops::cfg_combine_dynamic(&empty, &empty, 0.5, 0.5).is_err());
+    Ok(())
+}
+
+fn glu_modules(device: &Device) -> Result<()> {
+    use diffusion_rs_common::nn::{GeGLU, Module, ReGLU, SwiGLU};
+    let xs = Tensor::new(&[[-1f32, 2., 0.5, 3.], [1., -2., 4., -0.5]], device)?;
+    let (left, right) = (xs.narrow(1, 0, 2)?, xs.narrow(1, 2, 2)?);
+    let ys = SwiGLU::new(1).forward(&xs)?;
+    let expected = (left.silu()? * &right)?;
+    assert_eq!(to_vec2_round(&ys, 5)?, to_vec2_round(&expected, 5)?);
+    let ys = diffusion_rs_common::nn::ops::swiglu(&xs)?;
+    assert_eq!(to_vec2_round(&ys, 5)?, to_vec2_round(&expected, 5)?);
+    let ys = ReGLU::new(1).forward(&xs)?;
+    assert_eq!(ys.to_vec2::<f32>()?, [[0f32, 6.], [4., 0.]]);
+    let ys = GeGLU::new(1).forward(&xs)?;
+    let expected = diffusion_rs_common::nn::ops::geglu(&xs)?;
+    assert_eq!(to_vec2_round(&ys, 5)?, to_vec2_round(&expected, 5)?);
+
+    // Splitting the first dim gates the first row with the second one.
+    let ys = SwiGLU::new(0).forward(&xs)?;
+    let expected = (xs.get(0)?.silu()? * xs.get(1)?)?.unsqueeze(0)?;
+    assert_eq!(to_vec2_round(&ys, 5)?, to_vec2_round(&expected, 5)?);
+
+    let odd = xs.narrow(1, 0, 3)?;
+    assert!(SwiGLU::new(1).forward(&odd).is_err());
+    assert!(GeGLU::new(1).forward(&odd).is_err());
+    assert!(ReGLU::new(1).forward(&odd).is_err());
+    assert!(diffusion_rs_common::nn::ops::swiglu(&odd).is_err());
+    assert!(SwiGLU::new(2).forward(&xs).is_err());
+    Ok(())
+}
+
+fn swiglu_fused(device: &Device) -> Result<()> {
+    use diffusion_rs_common::nn::{Module, SwiGLU};
+    fn swiglu_ref(xs: &Tensor, dim: usize) -> Result<Tensor> {
+        let half = xs.dim(dim)? / 2;
+        xs.narrow(dim, 0, half)?.silu()? * xs.narrow(dim, half, half)?
+    }
+    let xs = Tensor::randn(0f32, 2., (3, 4, 10), device)?;
+    for dim in 1..3 {
+        let ys = SwiGLU::new(dim).forward(&xs)?;
+        let diff = (ys - swiglu_ref(&xs, dim)?)?.abs()?.flatten_all()?.max(0)?;
+        assert!(diff.to_scalar::<f32>()? < 1e-5, "{dim}");
+    }
+    // Non-contiguous and half precision inputs.
+    let ys = SwiGLU::new(2).forward(&xs.transpose(1, 2)?)?;
+    let diff = (ys - swiglu_ref(&xs.transpose(1, 2)?, 2)?)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5);
+    let ys = SwiGLU::new(2).forward(&xs.to_dtype(DType::BF16)?)?;
+    assert_eq!(ys.dtype(), DType::BF16);
+    let diff = (ys.to_dtype(DType::F32)? - swiglu_ref(&xs, 2)?)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 0.1);
+
+    // The gradient matches the one of the two-op version.
+    let var = diffusion_rs_common::core::Var::from_tensor(&xs)?;
+    let w = Tensor::randn(0f32, 1., (3, 2, 10), device)?;
+    let grads = (SwiGLU::new(1).forward(&var)? * &w)?
+        .sum_all()?
+        .backward()?;
+    let grad = grads.get(&var).unwrap().clone();
+    let grads = (swiglu_ref(&var, 1)? * &w)?.sum_all()?.backward()?;
+    let diff = (grad - grads.get(&var).unwrap())?
+        .abs()?
+        .flatten_all()?
+        .max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5);
+    Ok(())
+}
+
+fn geglu_mlp(device: &Device) -> Result<()> {
+    let x = Tensor::randn(0f32, 1., (2, 5, 8), device)?;
+    let proj_weight = Tensor::randn(0f32, 0.3, (12, 8), device)?;
+    let proj_bias = Tensor::randn(0f32, 0.1, 12, device)?;
+    let out_weight = Tensor::randn(0f32, 0.3, (8, 6), device)?;
+    let out_bias = Tensor::randn(0f32, 0.1, 8, device)?;
+    let ys = diffusion_rs_common::nn::ops::geglu_mlp(
+        &x,
+        &proj_weight,
+        Some(&proj_bias),
+        &out_weight,
+        Some(&out_bias),
+    )?;
+
+    // Reference GEGLU: `hidden, gate = proj(x).chunk(2, -1); out(hidden * gelu(gate))`.
+    let h = x
+        .broadcast_matmul(&proj_weight.t()?)?
+        .broadcast_add(&proj_bias)?;
+    let chunks = h.chunk(2, 2)?;
+    let gated = (&chunks[0] * chunks[1].gelu_erf()?)?;
+    let expected = gated
+        .broadcast_matmul(&out_weight.t()?)?
+        .broadcast_add(&out_bias)?;
+    assert_eq!(ys.dims(), &[2, 5, 8]);
+    let diff = (ys - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-4);
+
+    let odd_weight = Tensor::randn(0f32, 0.3, (11, 8), device)?;
+    assert!(
+        diffusion_rs_common::nn::ops::geglu_mlp(&x, &odd_weight, None, &out_weight, None).is_err()
+    );
+    assert!(diffusion_rs_common::nn::ops::geglu(&odd_weight).is_err());
+    Ok(())
+}
+
+fn tanh(device: &Device) -> Result<()> {
+    let xs = Tensor::new(&[[-3f32, -0.7, 0.], [0.2, 1.1, 4.]], device)?;
+    let ys = diffusion_rs_common::nn::ops::tanh(&xs)?;
+    assert_eq!(to_vec2_round(&ys, 5)?, to_vec2_round(&xs.tanh()?, 5)?);
+    let ys = diffusion_rs_common::nn::ops::tanh(&xs.t()?)?;
+    assert_eq!(to_vec2_round(&ys, 5)?, to_vec2_round(&xs.t()?.tanh()?, 5)?);
+
+    let mut inplace = xs.copy()?;
+    diffusion_rs_common::nn::ops::tanh_(&mut inplace)?;
+    assert_eq!(to_vec2_round(&inplace, 5)?, to_vec2_round(&xs.tanh()?, 5)?);
+    assert!(diffusion_rs_common::nn::ops::tanh_(&mut xs.t()?).is_err());
+
+    // Central finite differences of the summed output.
+    let var = diffusion_rs_common::core::Var::from_tensor(&xs)?;
+    let grads = diffusion_rs_common::nn::ops::tanh(&var)?
+        .sum_all()?
+        .backward()?;
+    let grad = grads.get(&var).unwrap();
+    let h = 1e-2;
+    let numeric = ((xs.affine(1., h)?.tanh()? - xs.affine(1., -h)?.tanh()?)? / (2. * h))?;
+    let diff = (grad - numeric)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-3);
+    Ok(())
+}
+
+fn mish(device: &Device) -> Result<()> {
+    fn mish_ref(x: f64) -> f64 {
+        x * x.exp().ln_1p().tanh()
+    }
+    let values = [-30f32, -4., -1.3, -0.2, 0., 0.4, 1.7, 5., 30.];
+    let xs = Tensor::new(&values, device)?;
+    let ys = diffusion_rs_common::nn::ops::mish(&xs)?.to_vec1::<f32>()?;
+    for (&x, y) in values.iter().zip(ys) {
+        assert!((y as f64 - mish_ref(x as f64)).abs() < 1e-5, "{x} {y}");
+    }
+    let ys = diffusion_rs_common::nn::ops::mish(&xs.reshape((3, 3))?.t()?)?;
+    let expected = diffusion_rs_common::nn::ops::mish(&xs.reshape((3, 3))?.t()?.contiguous()?)?;
+    assert_eq!(to_vec2_round(&ys, 5)?, to_vec2_round(&expected, 5)?);
+
+    // Central finite differences of the f64 reference.
+    let var = diffusion_rs_common::core::Var::from_tensor(&xs)?;
+    let grads = diffusion_rs_common::nn::ops::mish(&var)?
+        .sum_all()?
+        .backward()?;
+    let grad = grads.get(&var).unwrap().to_vec1::<f32>()?;
+    let h = 1e-5;
+    for (&x, g) in values.iter().zip(grad) {
+        let x = x as f64;
+        let numeric = (mish_ref(x + h) - mish_ref(x - h)) / (2. * h);
+        assert!((g as f64 - numeric).abs() < 1e-4, "{x} {g} {numeric}");
+    }
+    Ok(())
+}
+
+fn powf(device: &Device) -> Result<()> {
+    use diffusion_rs_common::nn::ops::powf;
+    let xs = Tensor::new(&[0f32, 0.25, 1., 4., 9.], device)?;
+    assert_eq!(
+        to_vec1_round(&powf(&xs, 0.5)?, 5)?,
+        to_vec1_round(&xs.sqrt()?, 5)?
+    );
+    assert_eq!(
+        to_vec1_round(&powf(&xs, 2.)?, 5)?,
+        to_vec1_round(&xs.sqr()?, 5)?
+    );
+    assert_eq!(to_vec1_round(&powf(&xs, 0.)?, 5)?, [1f32; 5]);
+
+    // Negative bases with integer exponents, NaN for fractional ones.
+    let xs = Tensor::new(&[-2f32, -0.5, 3.], device)?;
+    assert_eq!(to_vec1_round(&powf(&xs, 3.)?, 5)?, [-8f32, -0.125, 27.]);
+    assert_eq!(to_vec1_round(&powf(&xs, -2.)?, 5)?, [0.25f32, 4., 0.11111]);
+    let ys = powf(&xs, 1.5)?.to_vec1::<f32>()?;
+    assert!(ys[0].is_nan() && ys[1].is_nan());
+    assert!((ys[2] - 27f32.sqrt()).abs() < 1e-5);
+
+    let var = diffusion_rs_common::core::Var::from_tensor(&xs)?;
+    let grads = powf(&var, 3.)?.sum_all()?.backward()?;
+    let grad = grads.get(&var).unwrap();
+    assert_eq!(to_vec1_round(grad, 5)?, [12f32, 0.75, 27.]);
+    Ok(())
+}
+
+#[test]
+fn gelu_with_approx() -> Result<()> {
+    use diffusion_rs_common::nn::ops::{gelu_with_approx, GeluApproximation};
+    let dev = &Device::Cpu;
+    let xs = Tensor::new(&[-3f32, -1., -0.5, 0., 0.5, 1., 2., 3.], dev)?;
+    // Reference values from torch.nn.functional.gelu and `x * torch.sigmoid(1.702 * x)`.
+    let cases = [
+        (
+            GeluApproximation::None,
+            [
+                -0.00405, -0.158655, -0.154269, 0.0, 0.345731, 0.841345, 1.9545, 2.99595,
+            ],
+        ),
+        (
+            GeluApproximation::Tanh,
+            [
+                -0.003637, -0.158808, -0.154286, 0.0, 0.345714, 0.841192, 1.954598, 2.996363,
+            ],
+        ),
+        (
+            GeluApproximation::Quick,
+            [
+                -0.018071, -0.154204, -0.149612, 0.0, 0.350388, 0.845796, 1.935659, 2.981929,
+            ],
+        ),
+    ];
+    for (approx, expected) in cases {
+        let expected = Tensor::new(&expected, dev)?;
+        for (dtype, tol) in [(DType::F32, 1e-5), (DType::BF16, 3e-2)] {
+            let ys = gelu_with_approx(&xs.to_dtype(dtype)?, approx)?;
+            assert_eq!(ys.dtype(), dtype);
+            let diff = (ys.to_dtype(DType::F32)? - &expected)?
+                .abs()?
+                .max(0)?
+                .to_scalar::<f32>()?;
+            assert!(diff < tol, "{approx:?} {dtype:?}: {diff}");
+        }
+    }
+    let ys = diffusion_rs_common::nn::ops::quick_gelu(&xs)?;
+    let expected = gelu_with_approx(&xs, GeluApproximation::Quick)?;
+    assert_eq!(ys.to_vec1::<f32>()?, expected.to_vec1::<f32>()?);
+    Ok(())
+}
+
+#[test]
+fn hardswish_hardshrink() -> Result<()> {
+    use diffusion_rs_common::core::Module;
+    use diffusion_rs_common::nn::ops::{hard_sigmoid, hardshrink, hardswish, Hardshrink};
+    let dev = &Device::Cpu;
+    let xs = Tensor::new(&[-4f32, -3., -1.5, -0.5, 0., 0.5, 1.5, 3., 4.], dev)?;
+    let diff = (hardswish(&xs)? - (&xs * hard_sigmoid(&xs)?)?)?
+        .abs()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-6);
+
+    // The boundary of the interval is zeroed too.
+    let expected = [-4f32, -3., -1.5, 0., 0., 0., 1.5, 3., 4.];
+    assert_eq!(hardshrink(&xs, 0.5)?.to_vec1::<f32>()?, expected);
+    let ys = Hardshrink::new(1.5).forward(&xs)?;
+    assert_eq!(
+        ys.to_vec1::<f32>()?,
+        [-4f32, -3., 0., 0., 0., 0., 0., 3., 4.]
+    );
+    Ok(())
+}
+
+fn gelu_variants(device: &Device) -> Result<()> {
+    let xs = Tensor::new(&[[-6f32, -2., -0.5, 0.], [0.3, 1., 2.5, 7.]], device)?;
+    let ys = diffusion_rs_common::nn::ops::gelu(&xs)?;
+    assert_eq!(to_vec2_round(&ys, 4)?, to_vec2_round(&xs.gelu()?, 4)?);
+    let ys = diffusion_rs_common::nn::ops::gelu_new(&xs)?;
+    assert_eq!(to_vec2_round(&ys, 4)?, to_vec2_round(&xs.gelu()?, 4)?);
+    let ys = diffusion_rs_common::nn::ops::gelu_erf(&xs)?;
+    assert_eq!(to_vec2_round(&ys, 4)?, to_vec2_round(&xs.gelu_erf()?, 4)?);
+
+    // Strided inputs and half precision.
+    let ys = diffusion_rs_common::nn::ops::gelu(&xs.t()?)?;
+    assert_eq!(to_vec2_round(&ys, 4)?, to_vec2_round(&xs.t()?.gelu()?, 4)?);
+    let xs_f16 = xs.to_dtype(DType::F16)?;
+    let ys = diffusion_rs_common::nn::ops::gelu_erf(&xs_f16)?.to_dtype(DType::F32)?;
+    let diff = (ys - xs.gelu_erf()?)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-2);
+
+    // The gradients match the ones of the built-in unary ops.
+    let var = diffusion_rs_common::core::Var::from_tensor(&xs)?;
+    let grad = diffusion_rs_common::nn::ops::gelu(&var)?
+        .sum_all()?
+        .backward()?;
+    let expected = var.gelu()?.sum_all()?.backward()?;
+    let diff = (grad.get(&var).unwrap() - expected.get(&var).unwrap())?.abs()?;
+    assert!(diff.flatten_all()?.max(0)?.to_scalar::<f32>()? < 1e-4);
+    let grad = diffusion_rs_common::nn::ops::gelu_erf(&var)?
+        .sum_all()?
+        .backward()?;
+    let expected = var.gelu_erf()?.sum_all()?.backward()?;
+    let diff = (grad.get(&var).unwrap() - expected.get(&var).unwrap())?.abs()?;
+    assert!(diff.flatten_all()?.max(0)?.to_scalar::<f32>()? < 1e-4);
+    Ok(())
+}
+
+fn rsqrt_reciprocal(device: &Device) -> Result<()> {
+    let data = &[[[3f32, 1., 4.], [1., 5., 9.]], [[2., 1., 7.], [8., 2., 8.]]];
+    let tensor = Tensor::new(data, device)?;
+    let r1 = diffusion_rs_common::nn::ops::rsqrt(&tensor)?;
+    let r2 = tensor.sqrt()?.recip()?;
+    let diff = (r1 - r2)?.abs()?.sum_all()?.to_vec0::<f32>()?;
+    assert!(diff < 1e-5);
+    let r1 = diffusion_rs_common::nn::ops::reciprocal(&tensor)?;
+    let r2 = (1. / tensor)?;
+    let diff = (r1 - r2)?.abs()?.sum_all()?.to_vec0::<f32>()?;
+    assert!(diff < 1e-5);
+    Ok(())
+}
+
+fn segment_softmax(device: &Device) -> Result<()> {
+    let xs = Tensor::new(&[[1f32, 2., 3., 4., 5.], [0., -1., 2., 2., 1.]], device)?;
+    let ys = diffusion_rs_common::nn::ops::segment_softmax(&xs, &[0, 2, 5])?;
+    let expected = Tensor::cat(
+        &[
+            diffusion_rs_common::nn::ops::softmax_last_dim(&xs.narrow(1, 0, 2)?)?,
+            diffusion_rs_common::nn::ops::softmax_last_dim(&xs.narrow(1, 2, 3)?)?,
+        ],
+        1,
+    )?;
+    assert_eq!(to_vec2_round(&ys, 4)?, to_vec2_round(&expected, 4)?);
+    assert!(diffusion_rs_common::nn::ops::segment_softmax(&xs, &[0, 3, 2, 5]).is_err());
+    assert!(diffusion_rs_common::nn::ops::segment_softmax(&xs, &[0, 2, 4]).is_err());
+    Ok(())
+}
+
+#[cfg(feature = "debug")]
+#[test]
+fn attn_debug_stats() -> Result<()> {
+    let dev = &Device::Cpu;
+    let q = Tensor::new(&[[[[1f32, 0.], [0., 1.]]]], dev)?;
+    let k = Tensor::new(&[[[[1f32, 0.], [0., 1.]]]], dev)?;
+    let v = Tensor::new(&[[[[1f32, -2.], [3., 4.]]]], dev)?;
+    let stats = diffusion_rs_common::nn::ops::attn_debug_stats(&q, &k, &v, 1., None)?;
+    assert_eq!(stats.max_logit, 1.);
+    assert_eq!(stats.min_logit, 0.);
+    assert_eq!(stats.masked_rows, 0);
+    assert_eq!(stats.out_non_finite, 0);
+    assert!(stats.entropy > 0. && stats.entropy < 2f32.ln());
+    assert!(stats.out_max_abs > 2. && stats.out_max_abs < 4.);
+
+    // The second query row is fully masked out.
+    let mask = Tensor::new(&[[0f32, f32::NEG_INFINITY], [f32::NEG_INFINITY; 2]], dev)?;
+    let stats = diffusion_rs_common::nn::ops::attn_debug_stats(&q, &k, &v, 1., Some(&mask))?;
+    assert_eq!(stats.masked_rows, 1);
+    assert_eq!(stats.min_logit, f32::NEG_INFINITY);
+    assert_eq!(stats.out_non_finite, 0);
+    assert_eq!(stats.entropy, 0.);
+    assert_eq!(stats.out_max_abs, 2.);
+    Ok(())
+}
+
+#[test]
+fn to_bf16_stochastic() -> Result<()> {
+    let dev = &Device::Cpu;
+    // 1.001 lies between the bf16 values 1.0 and 1.0078125.
+    let target = 1.001f32;
+    let xs = Tensor::full(target, 10_000, dev)?;
+    let ys = diffusion_rs_common::nn::ops::to_bf16_stochastic(&xs, 42)?;
+    assert_eq!(ys.dtype(), DType::BF16);
+    let values = ys.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    assert!(values.iter().all(|&v| v == 1. || v == 1.0078125));
+
+    let stochastic_mean = values.iter().sum::<f32>() / values.len() as f32;
+    let truncated = half::bf16::from_bits((target.to_bits() >> 16) as u16).to_f32();
+    assert!((stochastic_mean - target).abs() < (truncated - target).abs() / 4.);
+
+    let ys2 = diffusion_rs_common::nn::ops::to_bf16_stochastic(&xs, 42)?;
+    assert_eq!(values, ys2.to_dtype(DType::F32)?.to_vec1::<f32>()?);
+    Ok(())
+}
+
+#[test]
+fn dequantize_per_channel() -> Result<()> {
+    let dev = &Device::Cpu;
+    // Two output channels quantized with scales 0.5 and 0.1, the second one asymmetric.
+    let q = Tensor::new(&[[-4i8, 0, 6], [10, 20, -10]], dev)?;
+    let scale = Tensor::new(&[0.5f32, 0.1], dev)?;
+    let zero_point = Tensor::new(&[0f32, 10.], dev)?;
+    let xs =
+        diffusion_rs_common::nn::ops::dequantize_per_channel(&q, &scale, Some(&zero_point), 0)?;
+    assert_eq!(to_vec2_round(&xs, 4)?, &[[-2., 0., 3.], [0., 1., -2.]]);
+
+    let scale = Tensor::new(&[1f32, 2., 0.5], dev)?;
+    let xs = diffusion_rs_common::nn::ops::dequantize_per_channel(&q, &scale, None, 1)?;
+    assert_eq!(to_vec2_round(&xs, 4)?, &[[-4., 0., 3.], [10., 40., -5.]]);
+
+    let xs = diffusion_rs_common::nn::ops::dequantize_per_channel(
+        &q,
+        &scale.to_dtype(DType::F16)?,
+        None,
+        1,
+    )?;
+    assert_eq!(xs.dtype(), DType::F16);
+    assert!(diffusion_rs_common::nn::ops::dequantize_per_channel(&q, &scale, None, 0).is_err());
+    Ok(())
+}
+
+fn bias_add_nchw(device: &Device) -> Result<()> {
+    let xs = Tensor::arange(0f32, 24., device)?.reshape((2, 3, 2, 2))?;
+    let bias = Tensor::new(&[1f32, -2., 0.5], device)?;
+    let ys = diffusion_rs_common::nn::ops::bias_add_nchw(&xs, &bias)?;
+    let expected = xs.broadcast_add(&bias.reshape((1, 3, 1, 1))?)?;
+    assert_eq!(
+        ys.flatten_all()?.to_vec1::<f32>()?,
+        expected.flatten_all()?.to_vec1::<f32>()?
+    );
+    let bias = Tensor::new(&[1f32, -2.], device)?;
+    assert!(diffusion_rs_common::nn::ops::bias_add_nchw(&xs, &bias).is_err());
+    Ok(())
+}
+
+fn spatial_seq_roundtrip(device: &Device) -> Result<()> {
+    let xs = Tensor::arange(0f32, 60., device)?.reshape((2, 5, 2, 3))?;
+    let (seq, (h, w)) = diffusion_rs_common::nn::ops::spatial_to_seq(&xs)?;
+    assert_eq!((h, w), (2, 3));
+    assert_eq!(seq.dims(), &[2, 6, 5]);
+    // Token `y * w + x` holds the channels of pixel `(y, x)`.
+    let token = seq.get(1)?.get(4)?.to_vec1::<f32>()?;
+    let pixel = xs.get(1)?.narrow(1, 1, 1)?.narrow(2, 1, 1)?.flatten_all()?;
+    assert_eq!(token, pixel.to_vec1::<f32>()?);
+
+    let ys = diffusion_rs_common::nn::ops::seq_to_spatial(&seq, h, w)?;
+    assert_eq!(ys.dims(), xs.dims());
+    assert_eq!(
+        ys.flatten_all()?.to_vec1::<f32>()?,
+        xs.flatten_all()?.to_vec1::<f32>()?
+    );
+    assert!(diffusion_rs_common::nn::ops::seq_to_spatial(&seq, 3, 3).is_err());
+    Ok(())
+}
+
+fn channel_gate(device: &Device) -> Result<()> {
+    let xs = Tensor::arange(0f32, 24., device)?.reshape((2, 3, 2, 2))?;
+    let gate = Tensor::new(&[[1f32, -2., 0.5], [0., 3., 1.]], device)?;
+    let expected = xs.broadcast_mul(&gate.reshape((2, 3, 1, 1))?)?;
+    let ys = diffusion_rs_common::nn::ops::channel_gate(&xs, &gate)?;
+    assert_eq!(
+        ys.flatten_all()?.to_vec1::<f32>()?,
+        expected.flatten_all()?.to_vec1::<f32>()?
+    );
+    let ys = diffusion_rs_common::nn::ops::channel_gate(&xs, &gate.reshape((2, 3, 1, 1))?)?;
+    assert_eq!(
+        ys.flatten_all()?.to_vec1::<f32>()?,
+        expected.flatten_all()?.to_vec1::<f32>()?
+    );
+
+    let shared = gate.narrow(0, 0, 1)?;
+    let ys = diffusion_rs_common::nn::ops::channel_gate(&xs, &shared)?;
+    let expected = xs.broadcast_mul(&shared.reshape((1, 3, 1, 1))?)?;
+    assert_eq!(
+        ys.flatten_all()?.to_vec1::<f32>()?,
+        expected.flatten_all()?.to_vec1::<f32>()?
+    );
+
+    let bad_channels = Tensor::zeros((2, 2), DType::F32, device)?;
+    assert!(diffusion_rs_common::nn::ops::channel_gate(&xs, &bad_channels).is_err());
+    let bad_batch = Tensor::zeros((3, 3), DType::F32, device)?;
+    assert!(diffusion_rs_common::nn::ops::channel_gate(&xs, &bad_batch).is_err());
+    let bad_spatial = Tensor::zeros((2, 3, 2, 1), DType::F32, device)?;
+    assert!(diffusion_rs_common::nn::ops::channel_gate(&xs, &bad_spatial).is_err());
+    Ok(())
+}
+
+#[test]
+fn safe_div() -> Result<()> {
+    let dev = &Device::Cpu;
+    let a = diffusion_rs_common::core::Var::new(&[[1f32, -2., 3.], [0.5, 4., -1.]], dev)?;
+    let b = diffusion_rs_common::core::Var::new(&[0f32, 2., -0.5], dev)?;
+    let ys = diffusion_rs_common::nn::ops::safe_div(&a, &b, 1e-3)?;
+    let expected = a.broadcast_div(&(b.as_tensor() + 1e-3)?)?;
+    assert_eq!(to_vec2_round(&ys, 3)?, to_vec2_round(&expected, 3)?);
+    assert_eq!(ys.to_vec2::<f32>()?[0][0], 1. / 1e-3);
+    assert!(ys
+        .flatten_all()?
+        .to_vec1::<f32>()?
+        .iter()
+        .all(|x| x.is_finite()));
+
+    let grads = ys.sum_all()?.backward()?;
+    let expected_grads = expected.sum_all()?.backward()?;
+    for var in [a.as_tensor(), b.as_tensor()] {
+        let grad = grads.get(var).unwrap().flatten_all()?;
+        let expected_grad = expected_grads.get(var).unwrap().flatten_all()?;
+        let rel = ((&grad - &expected_grad)?.abs()? / expected_grad.abs()?.affine(1., 1.)?)?;
+        assert!(rel.max(0)?.to_scalar::<f32>()? < 1e-5);
+    }
+
+    assert!(diffusion_rs_common::nn::ops::safe_div(&a, &b, 0.).is_err());
+    Ok(())
+}
+
+#[test]
+fn fake_quantize_per_channel() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = diffusion_rs_common::core::Var::new(
+        &[[-3f32, -0.6, 0.3, 2.0], [-5., -1.4, 0.6, 0.9]],
+        dev,
+    )?;
+    let scale = Tensor::new(&[0.5f32, 1.], dev)?;
+    let zero_point = Tensor::new(&[0f32, 2.], dev)?;
+    let ys = diffusion_rs_common::nn::ops::fake_quantize_per_channel(
+        &xs,
+        &scale,
+        &zero_point,
+        -2,
+        3,
+        0,
+    )?;
+    // Row 0 quantizes to [-6, -1, 1, 4] and row 1 to [-3, 1, 3, 3] before clamping to [-2, 3].
+    assert_eq!(
+        ys.to_vec2::<f32>()?,
+        &[[-1., -0.5, 0.5, 1.5], [-4., -1., 1., 1.]]
+    );
+
+    let grads = ys.sum_all()?.backward()?;
+    let grad = grads.get(&xs).unwrap();
+    assert_eq!(
+        grad.to_vec2::<f32>()?,
+        &[[0., 1., 1., 0.], [0., 1., 1., 1.]]
+    );
+
+    // Per-channel along the last dim.
+    let ys = diffusion_rs_common::nn::ops::fake_quantize_per_channel(
+        &xs.t()?,
+        &scale,
+        &zero_point,
+        -2,
+        3,
+        1,
+    )?;
+    assert_eq!(
+        ys.to_vec2::<f32>()?,
+        &[[-1., -4.], [-0.5, -1.], [0.5, 1.], [1.5, 1.]]
+    );
+
+    // 1.25 / 0.5 = 2.5 rounds to 2, so it is not clamped in the forward or the backward pass.
+    let tie = diffusion_rs_common::core::Var::new(&[[1.25f32]], dev)?;
+    let ys = diffusion_rs_common::nn::ops::fake_quantize_per_channel(
+        &tie,
+        &Tensor::new(&[0.5f32], dev)?,
+        &Tensor::new(&[0f32], dev)?,
+        -2,
+        2,
+        0,
+    )?;
+    assert_eq!(ys.to_vec2::<f32>()?, &[[1.]]);
+    let grads = ys.sum_all()?.backward()?;
+    assert_eq!(grads.get(&tie).unwrap().to_vec2::<f32>()?, &[[1.]]);
+
+    assert!(diffusion_rs_common::nn::ops::fake_quantize_per_channel(
+        &xs,
+        &scale,
+        &zero_point,
+        3,
+        -2,
+        0
+    )
+    .is_err());
+    assert!(diffusion_rs_common::nn::ops::fake_quantize_per_channel(
+        &xs,
+        &scale,
+        &zero_point,
+        -2,
+        3,
+        1
+    )
+    .is_err());
+    Ok(())
+}
+
+#[test]
+fn saturate_to_dtype_range() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::new(&[70000f32, -1e9, f32::INFINITY, 3.25, f32::NAN], dev)?;
+    let naive = xs
+        .to_dtype(DType::F16)?
+        .to_dtype(DType::F32)?
+        .to_vec1::<f32>()?;
+    assert_eq!(naive[0], f32::INFINITY);
+
+    let ys = diffusion_rs_common::nn::ops::saturate_to_dtype_range(&xs, DType::F16)?;
+    assert_eq!(ys.dtype(), DType::F32);
+    let ys = ys
+        .to_dtype(DType::F16)?
+        .to_dtype(DType::F32)?
+        .to_vec1::<f32>()?;
+    assert_eq!(ys[..4], [65504., -65504., 65504., 3.25]);
+    assert!(ys[4].is_nan());
+    Ok(())
+}
+
+fn masked_mean(device: &Device) -> Result<()> {
+    let xs = Tensor::new(
+        &[
+            [[1f32, 2.], [3., 4.], [5., 6.]],
+            [[7., 8.], [9., 10.], [11., 12.]],
+            [[1., 1.], [1., 1.], [1., 1.]],
+        ],
+        device,
+    )?;
+    let mask = Tensor::new(&[[1u8, 1, 0], [0, 1, 0], [0, 0, 0]], device)?;
+    let ys = diffusion_rs_common::nn::ops::masked_mean(&xs, &mask, 1)?;
+    // The last row is fully masked out and pools to zeros.
+    assert_eq!(ys.to_vec2::<f32>()?, &[[2., 3.], [9., 10.], [0., 0.]]);
+
+    let mask = mask.to_dtype(DType::F32)?;
+    let ys = diffusion_rs_common::nn::ops::masked_mean(&xs, &mask, 1)?;
+    assert_eq!(ys.to_vec2::<f32>()?, &[[2., 3.], [9., 10.], [0., 0.]]);
+    Ok(())
+}
+
+fn kvconcat(device: &Device) -> Result<()> {
+    let l = Tensor::randn(0f32, 1f32, (2, 4, 3, 8), device)?;
+    let r = Tensor::randn(0f32, 1f32, (2, 4, 5, 8), device)?;
+    let kv = diffusion_rs_common::nn::ops::kvconcat(&l, &r, 2)?;
+    assert!(kv.is_contiguous());
+    let expected = Tensor::cat(&[&l, &r], 2)?;
+    assert_eq!(
+        kv.flatten_all()?.to_vec1::<f32>()?,
+        expected.flatten_all()?.to_vec1::<f32>()?
+    );
+
+    let r = Tensor::randn(0f32, 1f32, (1, 4, 3, 8), device)?;
+    let kv = diffusion_rs_common::nn::ops::kvconcat(&l, &r, 0)?;
+    assert!(kv.is_contiguous());
+    let expected = Tensor::cat(&[&l, &r], 0)?;
+    assert_eq!(
+        kv.flatten_all()?.to_vec1::<f32>()?,
+        expected.flatten_all()?.to_vec1::<f32>()?
+    );
+    Ok(())
+}
+
+#[test]
+fn smooth_l1_loss() -> Result<()> {
+    use diffusion_rs_common::nn::ops::Reduction;
+
+    let dev = &Device::Cpu;
+    let pred = diffusion_rs_common::core::Var::new(&[-3f32, -0.5, 0., 1., 1.5], dev)?;
+    let target = Tensor::zeros(5, DType::F32, dev)?;
+    let loss = diffusion_rs_common::nn::ops::smooth_l1_loss(&pred, &target, 1., Reduction::None)?;
+    // Both branches agree at |x| = beta.
+    assert_eq!(loss.to_vec1::<f32>()?, &[2.5, 0.125, 0., 0.5, 1.]);
+    let sum = diffusion_rs_common::nn::ops::smooth_l1_loss(&pred, &target, 1., Reduction::Sum)?;
+    assert_eq!(sum.to_scalar::<f32>()?, 4.125);
+    let mean = diffusion_rs_common::nn::ops::smooth_l1_loss(&pred, &target, 1., Reduction::Mean)?;
+    assert_eq!(mean.to_scalar::<f32>()?, 0.825);
+
+    let grads = sum.backward()?;
+    let grad = grads.get(&pred).unwrap();
+    assert_eq!(grad.to_vec1::<f32>()?, &[-1., -0.5, 0., 1., 1.]);
+
+    let l1 = diffusion_rs_common::nn::ops::smooth_l1_loss(&pred, &target, 0., Reduction::None)?;
+    assert_eq!(l1.to_vec1::<f32>()?, &[3., 0.5, 0., 1., 1.5]);
+
+    for target in [
+        Tensor::zeros(4, DType::F32, dev)?,
+        Tensor::zeros((1, 5), DType::F32, dev)?,
+    ] {
+        assert!(
+            diffusion_rs_common::nn::ops::smooth_l1_loss(&pred, &target, 1., Reduction::None)
+                .is_err()
+        );
+    }
+    Ok(())
+}
+
+fn softmax_matmul(device: &Device) -> Result<()> {
+    // More keys than a single block, with a partial last block.
+    let scores = Tensor::randn(0f32, 3f32, (2, 3, 5, 150), device)?;
+    let v = Tensor::randn(0f32, 1f32, (2, 3, 150, 16), device)?;
+    // The last query row is fully masked out.
+    let mask: Vec<f32> = (0..5 * 150)
+        .map(|i| {
+            if i / 150 == 4 || i % 150 > 100 + i / 150 {
+                f32::NEG_INFINITY
+            } else {
+                0.
+            }
+        })
+        .collect();
+    let mask = Tensor::from_vec(mask, (5, 150), device)?;
+
+    let fused = diffusion_rs_common::nn::ops::softmax_matmul(&scores, &v, 0.5, None)?;
+    let expected = diffusion_rs_common::nn::ops::softmax_last_dim(&(&scores * 0.5)?)?.matmul(&v)?;
+    let diff = (fused - expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_vec0::<f32>()?;
+    assert!(diff < 1e-5, "{diff}");
+
+    let fused = diffusion_rs_common::nn::ops::softmax_matmul(&scores, &v, 0.5, Some(&mask))?;
+    let masked_rows = fused.narrow(2, 4, 1)?.flatten_all()?.to_vec1::<f32>()?;
+    assert!(masked_rows.iter().all(|&v| v == 0.), "{masked_rows:?}");
+    let expected =
+        diffusion_rs_common::nn::ops::softmax_last_dim(&(&scores * 0.5)?.broadcast_add(&mask)?)?
+            .matmul(&v)?;
+    let diff = (fused - expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_vec0::<f32>()?;
+    assert!(diff < 1e-5, "{diff}");
+    Ok(())
+}
+
+fn nan_to_num(device: &Device) -> Result<()> {
+    let xs = Tensor::new(
+        &[f32::NAN, f32::INFINITY, f32::NEG_INFINITY, 1.5, -2., 0.],
+        device,
+    )?;
+    let ys = diffusion_rs_common::nn::ops::nan_to_num(&xs, 0.5, 100., -100.)?;
+    assert_eq!(ys.to_vec1::<f32>()?, &[0.5, 100., -100., 1.5, -2., 0.]);
+
+    let ys = diffusion_rs_common::nn::ops::nan_to_num(&xs.to_dtype(DType::F16)?, -1., 7., -7.)?;
+    assert_eq!(ys.dtype(), DType::F16);
+    assert_eq!(
+        ys.to_dtype(DType::F32)?.to_vec1::<f32>()?,
+        &[-1., 7., -7., 1.5, -2., 0.]
+    );
+    Ok(())
+}
+
+fn add_layer_norm_cast(device: &Device) -> Result<()> {
+    let x = Tensor::randn(0f32, 1., (2, 3, 64), device)?.to_dtype(DType::BF16)?;
+    let residual = Tensor::randn(0f32, 1., (2, 3, 64), device)?;
+    let alpha = Tensor::randn(1f32, 0.1, 64, device)?;
+    let beta = Tensor::randn(0f32, 0.1, 64, device)?;
+
+    let (normed, new_residual) = diffusion_rs_common::nn::ops::add_layer_norm_cast(
+        &x,
+        &residual,
+        &alpha,
+        &beta,
+        1e-5,
+        DType::BF16,
+    )?;
+    let expected_residual = (x.to_dtype(DType::F32)? + &residual)?;
+    let expected =
+        diffusion_rs_common::nn::ops::layer_norm(&expected_residual, &alpha, &beta, 1e-5)?
+            .to_dtype(DType::BF16)?;
+    assert_eq!(new_residual.dtype(), DType::F32);
+    assert_eq!(normed.dtype(), DType::BF16);
+    let diff = (new_residual - expected_residual)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert_eq!(diff, 0.);
+    let diff = (normed.to_dtype(DType::F32)? - expected.to_dtype(DType::F32)?)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 5e-2, "{diff}");
+
+    // A BF16 residual stream comes back in BF16, with the normed output in F32.
+    let residual = residual.to_dtype(DType::BF16)?;
+    let (normed, new_residual) = diffusion_rs_common::nn::ops::add_layer_norm_cast(
+        &x,
+        &residual,
+        &alpha,
+        &beta,
+        1e-5,
+        DType::F32,
+    )?;
+    let expected_residual =
+        (x.to_dtype(DType::F32)? + residual.to_dtype(DType::F32)?)?.to_dtype(DType::BF16)?;
+    let expected = diffusion_rs_common::nn::ops::layer_norm(
+        &expected_residual,
+        &alpha.to_dtype(DType::BF16)?,
+        &beta.to_dtype(DType::BF16)?,
+        1e-5,
+    )?
+    .to_dtype(DType::F32)?;
+    assert_eq!(new_residual.dtype(), DType::BF16);
+    assert_eq!(normed.dtype(), DType::F32);
+    let diff = (normed - expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 5e-2, "{diff}");
+    Ok(())
+}
+
+fn add_rms_norm_cast(device: &Device) -> Result<()> {
+    let x = Tensor::randn(0f32, 1., (2, 3, 64), device)?.to_dtype(DType::BF16)?;
+    let alpha = Tensor::randn(1f32, 0.1, 64, device)?;
+
+    for (residual_dtype, out_dtype) in [
+        (DType::F32, DType::BF16),
+        (DType::BF16, DType::F16),
+        (DType::F16, DType::F32),
+    ] {
+        let residual = Tensor::randn(0f32, 1., (2, 3, 64), device)?.to_dtype(residual_dtype)?;
+        let (normed, new_residual) = diffusion_rs_common::nn::ops::add_rms_norm_cast(
+            &x,
+            &residual,
+            &alpha.to_dtype(residual_dtype)?,
+            1e-5,
+            out_dtype,
+        )?;
+        let expected_residual = (x.to_dtype(DType::F32)? + residual.to_dtype(DType::F32)?)?;
+        let expected = diffusion_rs_common::nn::ops::rms_norm(
+            &expected_residual,
+            &alpha.to_dtype(DType::F32)?,
+            1e-5,
+        )?
+        .to_dtype(out_dtype)?;
+        assert_eq!(new_residual.dtype(), DType::F32);
+        assert_eq!(normed.dtype(), out_dtype);
+        let diff = (new_residual - expected_residual)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert_eq!(diff, 0.);
+        let diff = (normed.to_dtype(DType::F32)? - expected.to_dtype(DType::F32)?)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 5e-2, "{diff}");
+    }
+
+    let residual = Tensor::zeros((2, 3, 32), DType::F32, device)?;
+    assert!(diffusion_rs_common::nn::ops::add_rms_norm_cast(
+        &x,
+        &residual,
+        &alpha,
+        1e-5,
+        DType::F32
+    )
+    .is_err());
+    Ok(())
+}
+
+#[test]
+fn prelu_from_init() -> Result<()> {
+    use diffusion_rs_common::nn::{Module, PReLU};
+    let dev = &Device::Cpu;
+    let xs = Tensor::new(
+        &[
+            [[1f32, -2.], [-4., 3.], [0.5, -1.]],
+            [[-1., -1.], [2., -6.], [-3., 0.]],
+        ],
+        dev,
+    )?;
+
+    // One slope per channel of dim 1, gradients sum `min(0, x)` over the batch and positions.
+    let prelu = PReLU::from_init(3, 0.25, dev)?;
+    let ys = prelu.forward(&xs)?;
+    assert_eq!(
+        ys.to_vec3::<f32>()?,
+        &[
+            [[1., -0.5], [-1., 3.], [0.5, -0.25]],
+            [[-0.25, -0.25], [2., -1.5], [-0.75, 0.]]
+        ]
+    );
+    let grads = ys.sum_all()?.backward()?;
+    let grad = grads.get(prelu.weight()).unwrap();
+    assert_eq!(grad.to_vec1::<f32>()?, &[-4., -10., -4.]);
+
+    // A single shared slope broadcasts over every channel.
+    let prelu = PReLU::from_init(1, 0.1, dev)?;
+    let ys = prelu.forward(&xs)?;
+    let expected = (xs.relu()? + (xs.minimum(0f32)? * 0.1)?)?;
+    assert_eq!(to_vec3_round(&ys, 5)?, to_vec3_round(&expected, 5)?);
+    let grads = ys.sum_all()?.backward()?;
+    assert_eq!(
+        grads.get(prelu.weight()).unwrap().to_vec1::<f32>()?,
+        &[-18.]
+    );
+
+    assert!(PReLU::from_init(2, 0.25, dev)?.forward(&xs).is_err());
+    assert!(PReLU::from_init(0, 0.25, dev).is_err());
+    Ok(())
+}
+
+#[test]
+fn activation_identity() -> anyhow::Result<()> {
+    use diffusion_rs_common::nn::{Activation, Module};
+
+    let xs = Tensor::randn(0f32, 1., (2, 8), &Device::Cpu)?;
+    let ys = Activation::Identity.forward(&xs)?;
+    assert_eq!(ys.to_vec2::<f32>()?, xs.to_vec2::<f32>()?);
+    for name in ["\"identity\"", "\"linear\"", "\"none\""] {
+        let act: Activation = serde_json::from_str(name)?;
+        assert_eq!(act, Activation::Identity);
+    }
+    Ok(())
+}
+
+fn softmax_last_dim_checked(device: &Device) -> Result<()> {
+    let xs = Tensor::new(&[[1f32, 2., 3.], [0.5, -1., 4.]], device)?;
+    let (ys, non_finite) = diffusion_rs_common::nn::ops::softmax_last_dim_checked(&xs)?;
+    let expected = diffusion_rs_common::nn::ops::softmax_last_dim(&xs)?;
+    assert!(!non_finite);
+    assert_eq!(to_vec2_round(&ys, 4)?, to_vec2_round(&expected, 4)?);
+
+    for bad in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+        let xs = Tensor::new(&[[1f32, 2., 3.], [0.5, bad, 4.]], device)?;
+        let (ys, non_finite) = diffusion_rs_common::nn::ops::softmax_last_dim_checked(&xs)?;
+        assert!(non_finite, "{bad} was not detected");
+        // Rows without a bad value are unaffected.
+        assert_eq!(
+            to_vec2_round(&ys.narrow(0, 0, 1)?, 4)?,
+            to_vec2_round(&expected.narrow(0, 0, 1)?, 4)?
+        );
+    }
+    Ok(())
+}
+
+fn cosine_similarity(device: &Device) -> Result<()> {
+    let a = Tensor::new(&[[1f32, 0., 0.], [1., 2., 3.], [0., 0., 0.]], device)?;
+    let b = Tensor::new(&[[0f32, 1., 0.], [2., 4., 6.], [1., 1., 1.]], device)?;
+    let sim = diffusion_rs_common::nn::ops::cosine_similarity(&a, &b, 1, 1e-8)?;
+    // Orthogonal, parallel, and a zero vector kept finite by eps.
+    assert_eq!(to_vec1_round(&sim, 4)?, &[0., 1., 0.]);
+
+    let a = Tensor::new(&[[3f32, 4.]], device)?;
+    let b = Tensor::new(&[[4f32, 3.]], device)?;
+    let sim = diffusion_rs_common::nn::ops::cosine_similarity(&a, &b, 1, 0.)?;
+    // (3 * 4 + 4 * 3) / (5 * 5)
+    assert_eq!(to_vec1_round(&sim, 4)?, &[0.96]);
+
+    // Along dim 0, with `b` broadcast against `a`.
+    let a = Tensor::new(&[[1f32, -1.], [1., 1.]], device)?;
+    let b = Tensor::new(&[[1f32], [1.]], device)?;
+    let sim = diffusion_rs_common::nn::ops::cosine_similarity(&a, &b, 0, 0.)?;
+    assert_eq!(to_vec1_round(&sim, 4)?, &[1., 0.]);
+
+    // eps clamps the product of the norms instead of being added to it.
+    let a = Tensor::new(&[[1f32, 0.], [0.1, 0.]], device)?;
+    let sim = diffusion_rs_common::nn::ops::cosine_similarity(&a, &a, 1, 0.5)?;
+    // 1 / max(1, 0.5) and 0.01 / max(0.01, 0.5)
+    assert_eq!(to_vec1_round(&sim, 4)?, &[1., 0.02]);
+    Ok(())
+}
+
+fn repeat_interleave(device: &Device) -> Result<()> {
+    let xs = Tensor::new(&[1f32, 2.], device)?;
+    let ys = diffusion_rs_common::nn::ops::repeat_interleave(&xs, 2, 0)?;
+    assert_eq!(ys.to_vec1::<f32>()?, &[1., 1., 2., 2.]);
+
+    let xs = Tensor::new(&[[1f32, 2.], [3., 4.]], device)?;
+    let ys = diffusion_rs_common::nn::ops::repeat_interleave(&xs, 3, 1)?;
+    assert_eq!(
+        ys.to_vec2::<f32>()?,
+        &[[1., 1., 1., 2., 2., 2.], [3., 3., 3., 4., 4., 4.]]
+    );
+    let ys = diffusion_rs_common::nn::ops::repeat_interleave(&xs, 2, 0)?;
+    assert_eq!(
+        ys.to_vec2::<f32>()?,
+        &[[1., 2.], [1., 2.], [3., 4.], [3., 4.]]
+    );
+
+    assert!(diffusion_rs_common::nn::ops::repeat_interleave(&xs, 2, 2).is_err());
+    assert!(diffusion_rs_common::nn::ops::repeat_interleave(&xs, 0, 0).is_err());
+    Ok(())
+}
+
+fn final_logit_softmax(device: &Device) -> Result<()> {
+    let logits = (Tensor::randn(0f32, 1., (3, 1000), device)? * 40.)?;
+    for (scale, softcapping) in [(0.5f32, 30f32), (1., 0.), (2., 1.)] {
+        let ys = diffusion_rs_common::nn::ops::final_logit_softmax(&logits, scale, softcapping)?;
+        let mut capped = (&logits * scale as f64)?;
+        if softcapping != 0. && softcapping != 1. {
+            capped = ((capped / softcapping as f64)?.tanh()? * softcapping as f64)?;
+        }
+        let expected = diffusion_rs_common::nn::ops::softmax_last_dim(&capped)?;
+        let diff = (ys - expected)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-5, "{scale} {softcapping}: {diff}");
+    }
+
+    let logits = logits.to_dtype(DType::BF16)?;
+    let ys = diffusion_rs_common::nn::ops::final_logit_softmax(&logits, 1., 30.)?;
+    assert_eq!(ys.dtype(), DType::BF16);
+    let capped = ((logits.to_dtype(DType::F32)? / 30.)?.tanh()? * 30.)?;
+    let expected = diffusion_rs_common::nn::ops::softmax_last_dim(&capped)?;
+    let diff = (ys.to_dtype(DType::F32)? - expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-2, "{diff}");
+
+    // Without the cap, fully masked rows output zeros as with `softmax_last_dim`.
+    let logits = Tensor::new(
+        &[
+            [f32::NEG_INFINITY, f32::NEG_INFINITY],
+            [0., f32::NEG_INFINITY],
+        ],
+        device,
+    )?;
+    for softcapping in [0f32, 1.] {
+        let ys = diffusion_rs_common::nn::ops::final_logit_softmax(&logits, 1., softcapping)?;
+        assert_eq!(ys.to_vec2::<f32>()?, [[0f32, 0.], [1., 0.]]);
+    }
+    Ok(())
+}
+
+fn cumsum_exp_scan(device: &Device) -> Result<()> {
+    fn reference(a: &[f32], b: &[f32], len: usize, inner: usize) -> Vec<f32> {
+        let mut h = vec![0f64; inner];
+        let mut out = vec![0f32; a.len()];
+        for block in 0..a.len() / (len * inner) {
+            h.iter_mut().for_each(|h| *h = 0.);
+            for t in 0..len {
+                for (i, h) in h.iter_mut().enumerate() {
+                    let k = (block * len + t) * inner + i;
+                    *h = a[k] as f64 * *h + b[k] as f64;
+                    out[k] = *h as f32;
+                }
+            }
+        }
+        out
+    }
+
+    let a = Tensor::new(&[[0.5f32, 1., 0.], [2., 0.5, 1.]], device)?;
+    let b = Tensor::new(&[[1f32, 2., 3.], [1., 1., -1.]], device)?;
+    let hs = diffusion_rs_common::nn::ops::cumsum_exp_scan(&a, &b, 1)?;
+    // Row 0: 1, 1 * 1 + 2, 0 * 3 + 3. Row 1: 1, 0.5 * 1 + 1, 1 * 1.5 - 1.
+    assert_eq!(hs.to_vec2::<f32>()?, &[[1., 3., 3.], [1., 1.5, 0.5]]);
+    let hs = diffusion_rs_common::nn::ops::cumsum_exp_scan(&a, &b, 0)?;
+    assert_eq!(hs.to_vec2::<f32>()?, &[[1., 2., 3.], [3., 2., 2.]]);
+
+    // (batch, len, channels) along len: a short sequence, and one long enough for the
+    // chunked parallel scan.
+    for len in [7, 20_000] {
+        let a = Tensor::rand(0.9f32, 1., (2, len, 3), device)?;
+        let b = Tensor::randn(0f32, 1., (2, len, 3), device)?;
+        let hs = diffusion_rs_common::nn::ops::cumsum_exp_scan(&a, &b, 1)?;
+        let expected = reference(
+            &a.flatten_all()?.to_vec1::<f32>()?,
+            &b.flatten_all()?.to_vec1::<f32>()?,
+            len,
+            3,
+        );
+        let hs = hs.flatten_all()?.to_vec1::<f32>()?;
+        for (h, e) in hs.iter().zip(expected.iter()) {
+            assert!((h - e).abs() <= 1e-4 * e.abs().max(1.), "{len}: {h} {e}");
+        }
+    }
+
+    assert!(diffusion_rs_common::nn::ops::cumsum_exp_scan(&a, &b.narrow(1, 0, 2)?, 1).is_err());
+    assert!(diffusion_rs_common::nn::ops::cumsum_exp_scan(&a, &b, 2).is_err());
+    Ok(())
+}
+
+fn silu_mul(device: &Device) -> Result<()> {
+    let gate = Tensor::randn(0f32, 3f32, (2, 5, 16), device)?;
+    let up = Tensor::randn(0f32, 1f32, (2, 5, 16), device)?;
+    let fused = diffusion_rs_common::nn::ops::silu_mul(&gate, &up)?;
+    let expected = (gate.silu()? * &up)?;
+    assert_eq!(to_vec3_round(&fused, 4)?, to_vec3_round(&expected, 4)?);
+
+    // Broadcasted and strided inputs.
+    let up_row = Tensor::randn(0f32, 1f32, 16, device)?;
+    let fused = diffusion_rs_common::nn::ops::silu_mul(&gate, &up_row)?;
+    let expected = gate.silu()?.broadcast_mul(&up_row)?;
+    assert_eq!(to_vec3_round(&fused, 4)?, to_vec3_round(&expected, 4)?);
+    let gate_t = gate.transpose(1, 2)?;
+    let up_t = up.transpose(1, 2)?;
+    let fused = diffusion_rs_common::nn::ops::silu_mul(&gate_t, &up_t)?;
+    let expected = (gate_t.silu()? * &up_t)?;
+    assert_eq!(to_vec3_round(&fused, 4)?, to_vec3_round(&expected, 4)?);
+
+    let gate = gate.to_dtype(DType::BF16)?;
+    let up = up.to_dtype(DType::BF16)?;
+    let fused = diffusion_rs_common::nn::ops::silu_mul(&gate, &up)?.to_dtype(DType::F32)?;
+    let expected = (gate.to_dtype(DType::F32)?.silu()? * up.to_dtype(DType::F32)?)?;
+    let diff = (fused - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 0.1);
+
+    assert!(diffusion_rs_common::nn::ops::silu_mul(&gate, &up.to_dtype(DType::F32)?).is_err());
+    assert!(diffusion_rs_common::nn::ops::silu_mul(&gate, &gate.narrow(2, 0, 3)?).is_err());
+    Ok(())
+}
+
+#[test]
+fn silu_mul_backward() -> Result<()> {
+    let dev = &Device::Cpu;
+    let gate = diffusion_rs_common::core::Var::new(&[-2f32, -0.5, 0., 1., 3.], dev)?;
+    let up = diffusion_rs_common::core::Var::new(&[0.5f32, -1., 2., 1.5, -0.25], dev)?;
+    let fused = diffusion_rs_common::nn::ops::silu_mul(&gate, &up)?.sum_all()?;
+    let grads = fused.backward()?;
+
+    let gate_c = diffusion_rs_common::core::Var::new(&[-2f32, -0.5, 0., 1., 3.], dev)?;
+    let up_c = diffusion_rs_common::core::Var::new(&[0.5f32, -1., 2., 1.5, -0.25], dev)?;
+    let composed = (gate_c.silu()? * up_c.as_tensor())?.sum_all()?;
+    let grads_c = composed.backward()?;
+
+    assert_eq!(
+        to_vec1_round(grads.get(&gate).unwrap(), 4)?,
+        to_vec1_round(grads_c.get(&gate_c).unwrap(), 4)?
+    );
+    assert_eq!(
+        to_vec1_round(grads.get(&up).unwrap(), 4)?,
+        to_vec1_round(grads_c.get(&up_c).unwrap(), 4)?
+    );
+    Ok(())
+}
+
+#[test]
+fn sdpa_cpu_f16() -> Result<()> {
+    let dev = &Device::Cpu;
+    let scale = 1. / 16.;
+    let q = Tensor::randn(0f32, 1f32, (2, 4, 5, 256), dev)?;
+    let k = Tensor::randn(0f32, 1f32, (2, 4, 7, 256), dev)?;
+    let v = Tensor::randn(0f32, 1f32, (2, 4, 7, 256), dev)?;
+    let reference =
+        diffusion_rs_common::nn::ops::softmax_last_dim(&(q.matmul(&k.t()?)? * scale as f64)?)?
+            .matmul(&v)?;
+    let out = diffusion_rs_common::nn::ops::sdpa(
+        &q.to_dtype(DType::F16)?,
+        &k.to_dtype(DType::F16)?,
+        &v.to_dtype(DType::F16)?,
+        scale,
+        1.,
+    )?;
+    assert_eq!(out.dtype(), DType::F16);
+    let diff = (out.to_dtype(DType::F32)? - &reference)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-2);
+
+    // GQA, each kv head is shared by two query heads.
+    let k2 = k.narrow(1, 0, 2)?;
+    let v2 = v.narrow(1, 0, 2)?;
+    let out = diffusion_rs_common::nn::ops::sdpa(&q, &k2, &v2, scale, 1.)?;
+    let expected = diffusion_rs_common::nn::ops::sdpa(
+        &q,
+        &diffusion_rs_common::nn::ops::repeat_interleave(&k2, 2, 1)?,
+        &diffusion_rs_common::nn::ops::repeat_interleave(&v2, 2, 1)?,
+        scale,
+        1.,
+    )?;
+    assert_eq!(
+        to_vec3_round(&out.flatten_to(1)?, 4)?,
+        to_vec3_round(&expected.flatten_to(1)?, 4)?
+    );
+
+    // The unscaled scores are 256 * 20 * 20 = 102400, above the F16 max of 65504.
+    let q = (Tensor::ones((1, 1, 2, 256), DType::F16, dev)? * 20.)?;
+    let v = Tensor::new(&[[[[1f32; 256], [3f32; 256]]]], dev)?.to_dtype(DType::F16)?;
+    let out = diffusion_rs_common::nn::ops::sdpa(&q, &q, &v, scale, 1.)?;
+    let out = out.to_dtype(DType::F32)?.flatten_all()?.to_vec1::<f32>()?;
+    assert!(out.iter().all(|&x| x == 2.), "{out:?}");
+    Ok(())
+}
+
+#[test]
+fn sdpa_f32_out_cpu() -> Result<()> {
+    let dev = &Device::Cpu;
+    let scale = 1. / 8.;
+    let q = Tensor::randn(0f32, 1f32, (2, 4, 5, 64), dev)?.to_dtype(DType::F16)?;
+    let k = Tensor::randn(0f32, 1f32, (2, 2, 7, 64), dev)?.to_dtype(DType::F16)?;
+    let v = Tensor::randn(0f32, 1f32, (2, 2, 7, 64), dev)?.to_dtype(DType::F16)?;
+    let reference = diffusion_rs_common::nn::ops::sdpa(
+        &q.to_dtype(DType::F32)?,
+        &k.to_dtype(DType::F32)?,
+        &v.to_dtype(DType::F32)?,
+        scale,
+        1.,
+    )?;
+    let out = diffusion_rs_common::nn::ops::sdpa_f32_out(&q, &k, &v, scale, 1.)?;
+    assert_eq!(out.dtype(), DType::F32);
+    // The unfused computation already runs in F32, only the final rounding is skipped.
+    let diff = (out - &reference)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-6);
+
+    let q = q.to_dtype(DType::F32)?;
+    assert!(diffusion_rs_common::nn::ops::sdpa_f32_out(&q, &k, &v, scale, 1.).is_err());
+    Ok(())
+}
+
+#[test]
+fn min_snr_weight() -> Result<()> {
+    let dev = &Device::Cpu;
+    let alpha_bar = [0f32, 0.1, 0.5, 0.9, 0.999, 1.];
+    let weights = diffusion_rs_common::nn::ops::min_snr_weight(&Tensor::new(&alpha_bar, dev)?, 5.)?;
+    let expected: Vec<f32> = alpha_bar
+        .iter()
+        .map(|&a| {
+            let snr = a / (1. - a);
+            if a == 0. {
+                1.
+            } else {
+                snr.min(5.) / snr
+            }
+        })
+        .collect();
+    assert_eq!(expected[5], 0.);
+    assert_eq!(
+        to_vec1_round(&weights, 5)?,
+        to_vec1_round(&Tensor::new(expected, dev)?, 5)?
+    );
+    assert!(
+        diffusion_rs_common::nn::ops::min_snr_weight(&Tensor::new(&alpha_bar, dev)?, 0.).is_err()
+    );
+    Ok(())
+}
+
+fn v_eps_conversion(device: &Device) -> Result<()> {
+    let alpha_bar = Tensor::new(&[0.01f32, 0.3, 0.7, 0.999], device)?.reshape((4, 1, 1))?;
+    let v = Tensor::randn(0f32, 1f32, (4, 3, 5), device)?;
+    let x_t = Tensor::randn(0f32, 1f32, (4, 3, 5), device)?;
+    let eps = diffusion_rs_common::nn::ops::v_to_eps(&v, &x_t, &alpha_bar)?;
+    let expected = (v.broadcast_mul(&alpha_bar.sqrt()?)?
+        + x_t.broadcast_mul(&alpha_bar.affine(-1., 1.)?.sqrt()?)?)?;
+    assert_eq!(to_vec3_round(&eps, 4)?, to_vec3_round(&expected, 4)?);
+
+    let round_trip = diffusion_rs_common::nn::ops::eps_to_v(&eps, &x_t, &alpha_bar)?;
+    assert_eq!(to_vec3_round(&round_trip, 3)?, to_vec3_round(&v, 3)?);
+    Ok(())
+}
+
+#[test]
+fn sdpa_block_sparse() -> Result<()> {
+    let dev = &Device::Cpu;
+    let scale = 0.25;
+    let q = Tensor::randn(0f32, 1f32, (2, 3, 10, 16), dev)?;
+    let k = Tensor::randn(0f32, 1f32, (2, 3, 10, 16), dev)?;
+    let v = Tensor::randn(0f32, 1f32, (2, 3, 10, 8), dev)?;
+
+    // Blocks of 4 positions, the last one only has 2.
+    let all = Tensor::ones((3, 3), DType::U8, dev)?;
+    let sparse = diffusion_rs_common::nn::ops::sdpa_block_sparse(&q, &k, &v, &all, 4, scale)?;
+    let dense = diffusion_rs_common::nn::ops::sdpa(&q, &k, &v, scale, 1.)?;
+    assert_eq!(
+        to_vec3_round(&sparse.flatten_to(1)?, 4)?,
+        to_vec3_round(&dense.flatten_to(1)?, 4)?
+    );
+
+    // Each query block attends to its own key block and the previous one.
+    let band = Tensor::new(&[[1u8, 0, 0], [1, 1, 0], [0, 1, 1]], dev)?;
+    let sparse = diffusion_rs_common::nn::ops::sdpa_block_sparse(&q, &k, &v, &band, 4, scale)?;
+    let mask: Vec<f32> = (0..10)
+        .flat_map(|i| {
+            (0..10).map(move |j| {
+                if j / 4 <= i / 4 && i / 4 - j / 4 <= 1 {
+                    0.
+                } else {
+                    f32::NEG_INFINITY
+                }
+            })
+        })
+        .collect();
+    let params = diffusion_rs_common::nn::ops::SdpaParams {
+        mask: Some(Tensor::from_vec(mask, (10, 10), dev)?),
+        causal: false,
+    };
+    let expected = diffusion_rs_common::nn::ops::sdpa_with_params(&q, &k, &v, scale, 1., &params)?;
+    assert_eq!(
+        to_vec3_round(&sparse.flatten_to(1)?, 4)?,
+        to_vec3_round(&expected.flatten_to(1)?, 4)?
+    );
+
+    let bad = Tensor::ones((3, 2), DType::U8, dev)?;
+    assert!(diffusion_rs_common::nn::ops::sdpa_block_sparse(&q, &k, &v, &bad, 4, scale).is_err());
+    Ok(())
+}
+
+#[test]
+fn pack_sequences() -> Result<()> {
+    let dev = &Device::Cpu;
+    let seqs = [3, 1, 5]
+        .iter()
+        .map(|&len| Tensor::randn(0f32, 1f32, (2, len, 4), dev))
+        .collect::<diffusion_rs_common::core::Result<Vec<_>>>()?;
+    let (packed, offsets) = diffusion_rs_common::nn::ops::pack_sequences(&seqs, 1)?;
+    assert_eq!(packed.dims(), &[2, 9, 4]);
+    assert_eq!(offsets, &[0, 3, 4, 9]);
+
+    let unpacked = diffusion_rs_common::nn::ops::unpack_sequences(&packed, &offsets, 1)?;
+    assert_eq!(unpacked.len(), 3);
+    for (xs, ys) in seqs.iter().zip(unpacked.iter()) {
+        assert_eq!(xs.to_vec3::<f32>()?, ys.to_vec3::<f32>()?);
+    }
+
+    assert!(diffusion_rs_common::nn::ops::unpack_sequences(&packed, &[0, 3, 8], 1).is_err());
+    assert!(diffusion_rs_common::nn::ops::unpack_sequences(&packed, &[0, 5, 4, 9], 1).is_err());
+    assert!(diffusion_rs_common::nn::ops::pack_sequences(&[], 1).is_err());
+    Ok(())
+}
+
+fn ema_update(device: &Device) -> Result<()> {
+    let model = Tensor::randn(0f32, 1f32, (3, 4), device)?;
+    let init = Tensor::zeros((3, 4), DType::F32, device)?;
+    let mut ema = init.copy()?;
+    diffusion_rs_common::nn::ops::ema_update(&mut ema, &model, 0.9)?;
+    let expected = ((&init * 0.9)? + (&model * 0.1)?)?;
+    assert_eq!(to_vec2_round(&ema, 5)?, to_vec2_round(&expected, 5)?);
+
+    // Strided model weights.
+    let model_t = Tensor::randn(0f32, 1f32, (4, 3), device)?.t()?;
+    let before = ema.copy()?;
+    diffusion_rs_common::nn::ops::ema_update(&mut ema, &model_t, 0.5)?;
+    let expected = ((&before * 0.5)? + (&model_t * 0.5)?)?;
+    assert_eq!(to_vec2_round(&ema, 5)?, to_vec2_round(&expected, 5)?);
+
+    let mut ema = init.copy()?;
+    for _ in 0..200 {
+        diffusion_rs_common::nn::ops::ema_update(&mut ema, &model, 0.95)?;
+    }
+    let diff = (&ema - &model)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-3);
+
+    // An ema created as a clone of the model does not write into the model.
+    let model_values = model.to_vec2::<f32>()?;
+    let mut ema = model.clone();
+    diffusion_rs_common::nn::ops::ema_update(&mut ema, &init, 0.5)?;
+    assert_eq!(model.to_vec2::<f32>()?, model_values);
+
+    let mut ema = init.copy()?;
+    let bad_shape = Tensor::zeros((4, 3), DType::F32, device)?;
+    assert!(diffusion_rs_common::nn::ops::ema_update(&mut ema, &bad_shape, 0.9).is_err());
+    let bad_dtype = Tensor::zeros((3, 4), DType::F16, device)?;
+    assert!(diffusion_rs_common::nn::ops::ema_update(&mut ema, &bad_dtype, 0.9).is_err());
+    Ok(())
+}
+
+fn inplace_add_scaled(device: &Device) -> Result<()> {
+    let y = Tensor::randn(0f32, 1f32, (2, 3, 4), device)?;
+    let init = Tensor::randn(0f32, 1f32, (2, 3, 4), device)?;
+    let mut x = init.copy()?;
+    diffusion_rs_common::nn::ops::inplace_add_scaled(&mut x, &y, 0.5)?;
+    let expected = (&init + (&y * 0.5)?)?;
+    assert_eq!(to_vec3_round(&x, 5)?, to_vec3_round(&expected, 5)?);
+
+    // Broadcast y over x.
+    let bias = Tensor::randn(0f32, 1f32, 4, device)?;
+    let before = x.copy()?;
+    diffusion_rs_common::nn::ops::inplace_add_scaled(&mut x, &bias, -2.)?;
+    let expected = before.broadcast_add(&(&bias * -2.)?)?;
+    assert_eq!(to_vec3_round(&x, 5)?, to_vec3_round(&expected, 5)?);
+
+    // Strided y and a non-contiguous x.
+    let y_t = Tensor::randn(0f32, 1f32, (2, 4, 3), device)?.transpose(1, 2)?;
+    let mut x_t = init.transpose(1, 2)?.copy()?.transpose(1, 2)?;
+    diffusion_rs_common::nn::ops::inplace_add_scaled(&mut x_t, &y_t, 3.)?;
+    let expected = (&init + (&y_t * 3.)?)?;
+    assert_eq!(to_vec3_round(&x_t, 5)?, to_vec3_round(&expected, 5)?);
+
+    // Adding a tensor to its own clone does not write into the original.
+    let values = y.to_vec3::<f32>()?;
+    let mut x = y.clone();
+    diffusion_rs_common::nn::ops::inplace_add_scaled(&mut x, &y, 1.)?;
+    assert_eq!(y.to_vec3::<f32>()?, values);
+    assert_eq!(to_vec3_round(&x, 5)?, to_vec3_round(&(&y * 2.)?, 5)?);
+
+    let bad_shape = Tensor::zeros((3, 2), DType::F32, device)?;
+    assert!(diffusion_rs_common::nn::ops::inplace_add_scaled(&mut x, &bad_shape, 1.).is_err());
+    let bad_dtype = Tensor::zeros((2, 3, 4), DType::F16, device)?;
+    assert!(diffusion_rs_common::nn::ops::inplace_add_scaled(&mut x, &bad_dtype, 1.).is_err());
+    Ok(())
+}
+
+fn pixel_shuffle_1d(device: &Device) -> Result<()> {
+    use diffusion_rs_common::nn::ops::{
+        pixel_shuffle, pixel_shuffle_1d, pixel_unshuffle, pixel_unshuffle_1d,
+    };
+    // Output channel `c` interleaves the input channels `c * r..(c + 1) * r` over time.
+    let xs = Tensor::arange(0f32, 12., device)?.reshape((1, 4, 3))?;
+    let ys = pixel_shuffle_1d(&xs, 2)?;
+    assert_eq!(
+        ys.to_vec3::<f32>()?,
+        [[[0f32, 3., 1., 4., 2., 5.], [6., 9., 7., 10., 8., 11.]]]
+    );
+
+    let xs = Tensor::randn(0f32, 1., (2, 12, 5), device)?;
+    for r in [1, 2, 3, 4, 6, 12] {
+        let ys = pixel_shuffle_1d(&xs, r)?;
+        assert_eq!(ys.dims(), [2, 12 / r, 5 * r]);
+        let zs = pixel_unshuffle_1d(&ys, r)?;
+        assert_eq!(zs.to_vec3::<f32>()?, xs.to_vec3::<f32>()?, "{r}");
+    }
+    assert!(pixel_shuffle_1d(&xs, 5).is_err());
+    assert!(pixel_shuffle_1d(&xs, 0).is_err());
+    assert!(pixel_unshuffle_1d(&xs, 2).is_err());
+
+    let xs = xs.reshape((2, 12, 5, 1))?;
+    assert!(pixel_shuffle(&xs, 3).is_err());
+    assert!(pixel_unshuffle(&xs, 5).is_err());
+    let xs = Tensor::randn(0f32, 1., (1, 8, 3, 2), device)?;
+    let zs = pixel_unshuffle(&pixel_shuffle(&xs, 2)?, 2)?;
+    assert_eq!(
+        zs.flatten_all()?.to_vec1::<f32>()?,
+        xs.flatten_all()?.to_vec1::<f32>()?
+    );
+    Ok(())
+}
+
+fn nchw_nhwc(device: &Device) -> Result<()> {
+    let xs = Tensor::arange(0f32, 120., device)?.reshape((2, 3, 4, 5))?;
+    let nhwc = diffusion_rs_common::nn::ops::nchw_to_nhwc(&xs)?;
+    assert_eq!(nhwc.dims(), [2, 4, 5, 3]);
+    assert!(nhwc.is_contiguous());
+    // Channels are now the fastest moving dim.
+    assert_eq!(
+        nhwc.get(0)?.get(0)?.get(1)?.to_vec1::<f32>()?,
+        xs.get(0)?
+            .narrow(1, 0, 1)?
+            .narrow(2, 1, 1)?
+            .flatten_all()?
+            .to_vec1::<f32>()?
+    );
+    let nchw = diffusion_rs_common::nn::ops::nhwc_to_nchw(&nhwc)?;
+    assert!(nchw.is_contiguous());
+    assert_eq!(
+        nchw.flatten_all()?.to_vec1::<f32>()?,
+        xs.flatten_all()?.to_vec1::<f32>()?
+    );
+
+    // Non contiguous inputs.
+    let xs_t = xs.transpose(2, 3)?;
+    let nhwc = diffusion_rs_common::nn::ops::nchw_to_nhwc(&xs_t)?;
+    assert!(nhwc.is_contiguous());
+    let nchw = diffusion_rs_common::nn::ops::nhwc_to_nchw(&nhwc)?;
+    assert_eq!(
+        nchw.flatten_all()?.to_vec1::<f32>()?,
+        xs_t.flatten_all()?.to_vec1::<f32>()?
+    );
+
+    assert!(diffusion_rs_common::nn::ops::nchw_to_nhwc(&xs.get(0)?).is_err());
+    assert!(diffusion_rs_common::nn::ops::nhwc_to_nchw(&xs.unsqueeze(0)?).is_err());
+    Ok(())
+}
+
+fn replication_pad2d(device: &Device) -> Result<()> {
+    use diffusion_rs_common::nn::ops::{replication_pad2d, replication_pad2d_uniform};
+    // np.pad([[1, 2], [3, 4]], 2, mode="edge")
+    let xs = Tensor::new(&[[1f32, 2.], [3., 4.]], device)?.reshape((1, 1, 2, 2))?;
+    let ys = replication_pad2d_uniform(&xs, 2)?;
+    assert_eq!(
+        ys.squeeze(0)?.squeeze(0)?.to_vec2::<f32>()?,
+        [
+            [1f32, 1., 1., 2., 2., 2.],
+            [1., 1., 1., 2., 2., 2.],
+            [1., 1., 1., 2., 2., 2.],
+            [3., 3., 3., 4., 4., 4.],
+            [3., 3., 3., 4., 4., 4.],
+            [3., 3., 3., 4., 4., 4.]
+        ]
+    );
+
+    // Every output pixel replicates the closest input pixel, as in np.pad(mode="edge").
+    let (h, w) = (3, 4);
+    let xs = Tensor::arange(0f32, (2 * 3 * h * w) as f32, device)?.reshape((2, 3, h, w))?;
+    let values = xs.flatten_all()?.to_vec1::<f32>()?;
+    for pad in [
+        [2, 2, 2, 2],
+        [3, 3, 3, 3],
+        [7, 7, 7, 7],
+        [1, 0, 3, 2],
+        [0, 5, 0, 0],
+    ] {
+        let [left, right, top, bottom] = pad;
+        let ys = replication_pad2d(&xs, pad)?;
+        let (out_h, out_w) = (h + top + bottom, w + left + right);
+        assert_eq!(ys.dims(), [2, 3, out_h, out_w]);
+        let mut expected = vec![];
+        for bc in 0..6 {
+            for i in 0..out_h {
+                for j in 0..out_w {
+                    let i = i.saturating_sub(top).min(h - 1);
+                    let j = j.saturating_sub(left).min(w - 1);
+                    expected.push(values[bc * h * w + i * w + j]);
+                }
+            }
+        }
+        assert_eq!(ys.flatten_all()?.to_vec1::<f32>()?, expected, "{pad:?}");
+    }
+    assert_eq!(
+        replication_pad2d_uniform(&xs, 0)?
+            .flatten_all()?
+            .to_vec1::<f32>()?,
+        values
+    );
+    assert!(replication_pad2d_uniform(&xs.get(0)?, 1).is_err());
+    Ok(())
+}
+
+fn reflection_pad2d(device: &Device) -> Result<()> {
+    use diffusion_rs_common::nn::ops::reflection_pad2d;
+    // np.pad(np.arange(1, 10).reshape(3, 3), 1, mode="reflect")
+    let xs = Tensor::arange(1f32, 10., device)?.reshape((1, 1, 3, 3))?;
+    let ys = reflection_pad2d(&xs, [1; 4])?;
+    assert_eq!(
+        ys.squeeze(0)?.squeeze(0)?.to_vec2::<f32>()?,
+        [
+            [5f32, 4., 5., 6., 5.],
+            [2., 1., 2., 3., 2.],
+            [5., 4., 5., 6., 5.],
+            [8., 7., 8., 9., 8.],
+            [5., 4., 5., 6., 5.]
+        ]
+    );
+
+    // np.pad(np.arange(1, 10).reshape(3, 3), ((0, 2), (2, 1)), mode="reflect")
+    let ys = reflection_pad2d(&xs, [2, 1, 0, 2])?;
+    assert_eq!(
+        ys.squeeze(0)?.squeeze(0)?.to_vec2::<f32>()?,
+        [
+            [3f32, 2., 1., 2., 3., 2.],
+            [6., 5., 4., 5., 6., 5.],
+            [9., 8., 7., 8., 9., 8.],
+            [6., 5., 4., 5., 6., 5.],
+            [3., 2., 1., 2., 3., 2.]
+        ]
+    );
+
+    // Channels and batches are padded independently.
+    let xs = Tensor::randn(0f32, 1f32, (2, 3, 4, 5), device)?;
+    let ys = reflection_pad2d(&xs, [2, 3, 1, 2])?;
+    assert_eq!(ys.dims(), [2, 3, 7, 10]);
+    let inner = ys.narrow(2, 1, 4)?.narrow(3, 2, 5)?;
+    assert_eq!(
+        inner.flatten_all()?.to_vec1::<f32>()?,
+        xs.flatten_all()?.to_vec1::<f32>()?
+    );
+
+    assert!(reflection_pad2d(&xs, [5, 0, 0, 0]).is_err());
+    assert!(reflection_pad2d(&xs, [0, 0, 0, 4]).is_err());
+    assert!(reflection_pad2d(&xs.get(0)?, [1; 4]).is_err());
+    Ok(())
+}
+
+fn constant_pad(device: &Device) -> Result<()> {
+    use diffusion_rs_common::nn::ops::{constant_pad2d, constant_pad_nd};
+    let xs = Tensor::new(&[[1f32, 2., 3.], [4., 5., 6.]], device)?;
+    let ys = constant_pad_nd(&xs, &[1, 2], 0.)?;
+    assert_eq!(
+        ys.to_vec2::<f32>()?,
+        [[0f32, 1., 2., 3., 0., 0.], [0., 4., 5., 6., 0., 0.]]
+    );
+    let ys = constant_pad_nd(&xs, &[0, 1, 1, 0], -1.5)?;
+    assert_eq!(
+        ys.to_vec2::<f32>()?,
+        [
+            [-1.5f32, -1.5, -1.5, -1.5],
+            [1., 2., 3., -1.5],
+            [4., 5., 6., -1.5]
+        ]
+    );
+
+    // The fill is built in the dtype of `xs`, including -inf as used by max pooling.
+    let ys = constant_pad_nd(&xs.to_dtype(DType::F16)?, &[1, 0], f64::NEG_INFINITY)?;
+    assert_eq!(ys.dtype(), DType::F16);
+    assert_eq!(
+        ys.to_dtype(DType::F32)?.to_vec2::<f32>()?,
+        [
+            [f32::NEG_INFINITY, 1., 2., 3.],
+            [f32::NEG_INFINITY, 4., 5., 6.]
+        ]
+    );
+
+    // torch.nn.functional.pad(x, (1, 1, 1, 1))
+    let xs = xs.reshape((1, 1, 2, 3))?;
+    let ys = constant_pad2d(&xs, [1, 1, 1, 1], 0.)?;
+    assert_eq!(
+        ys.squeeze(0)?.squeeze(0)?.to_vec2::<f32>()?,
+        [
+            [0f32, 0., 0., 0., 0.],
+            [0., 1., 2., 3., 0.],
+            [0., 4., 5., 6., 0.],
+            [0., 0., 0., 0., 0.]
+        ]
+    );
+
+    // Padding the outer dims keeps the dtype.
+    let xs = Tensor::new(&[[1u32, 2]], device)?;
+    let ys = constant_pad_nd(&xs, &[0, 0, 1, 1], 7.)?;
+    assert_eq!(ys.dtype(), DType::U32);
+    assert_eq!(ys.to_vec2::<u32>()?, [[7, 7], [1, 2], [7, 7]]);
+
+    assert!(constant_pad_nd(&xs, &[1], 0.).is_err());
+    assert!(constant_pad_nd(&xs, &[1, 1, 1, 1, 1, 1], 0.).is_err());
+    assert!(constant_pad2d(&xs, [1; 4], 0.).is_err());
+    Ok(())
+}
+
+fn pool2d(device: &Device) -> Result<()> {
+    use diffusion_rs_common::nn::ops::{avg_pool2d, max_pool2d};
+    // Reference values from torch.nn.functional.{avg,max}_pool2d(x, 2, 2, padding=1).
+    let xs = Tensor::arange(1f32, 13., device)?.reshape((1, 1, 3, 4))?;
+    let ys = avg_pool2d(&xs, [2, 2], [2, 2], [1, 1], true)?;
+    assert_eq!(
+        ys.get(0)?.get(0)?.to_vec2::<f32>()?,
+        [[0.25f32, 1.25, 1.], [3.5, 8.5, 5.]]
+    );
+    let ys = avg_pool2d(&xs, [2, 2], [2, 2], [1, 1], false)?;
+    assert_eq!(
+        ys.get(0)?.get(0)?.to_vec2::<f32>()?,
+        [[1f32, 2.5, 4.], [7., 8.5, 10.]]
+    );
+    let ys = max_pool2d(&xs, [2, 2], [2, 2], [1, 1])?;
+    assert_eq!(
+        ys.get(0)?.get(0)?.to_vec2::<f32>()?,
+        [[1f32, 3., 4.], [9., 11., 12.]]
+    );
+
+    // A window covering the whole input is a global average pooling.
+    let xs = Tensor::randn(0f32, 1., (2, 3, 4, 5), device)?;
+    let ys = avg_pool2d(&xs, [4, 5], [4, 5], [0, 0], true)?;
+    assert_eq!(ys.dims(), [2, 3, 1, 1]);
+    let expected = xs.mean_keepdim(3)?.mean_keepdim(2)?;
+    let diff = (ys - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5);
+
+    // Without padding this matches the tensor pooling ops, also on a non-contiguous input.
+    let xs = xs.transpose(2, 3)?;
+    let ys = max_pool2d(&xs, [3, 2], [1, 2], [0, 0])?;
+    let expected = xs.max_pool2d_with_stride((3, 2), (1, 2))?;
+    assert_eq!(ys.dims(), expected.dims());
+    let diff = (ys - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert_eq!(diff.to_scalar::<f32>()?, 0.);
+
+    assert!(max_pool2d(&xs, [2, 2], [2, 2], [2, 0]).is_err());
+    assert!(avg_pool2d(&xs, [2, 2], [0, 2], [0, 0], true).is_err());
+    assert!(avg_pool2d(&xs, [6, 2], [1, 1], [0, 0], true).is_err());
+    assert!(max_pool2d(&xs.flatten_to(1)?, [1, 1], [1, 1], [0, 0]).is_err());
+    let xs = Tensor::zeros((1, 1, 2, 2), DType::U32, device)?;
+    assert!(max_pool2d(&xs, [2, 2], [2, 2], [0, 0]).is_err());
+    Ok(())
+}
+
+fn adaptive_avg_pool2d(device: &Device) -> Result<()> {
+    use diffusion_rs_common::nn::ops::adaptive_avg_pool2d;
+    let xs = Tensor::randn(0f32, 1., (2, 3, 5, 7), device)?;
+    let ys = adaptive_avg_pool2d(&xs, [1, 1])?;
+    assert_eq!(ys.dims(), [2, 3, 1, 1]);
+    let expected = xs.mean_keepdim((2, 3))?;
+    let diff = (ys - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5);
+
+    // torch.nn.functional.adaptive_avg_pool2d(x, (2, 3)), the bins overlap along both dims.
+    let xs = Tensor::arange(1f32, 16., device)?.reshape((1, 1, 3, 5))?;
+    let ys = adaptive_avg_pool2d(&xs, [2, 3])?;
+    assert_eq!(
+        ys.get(0)?.get(0)?.to_vec2::<f32>()?,
+        [[4f32, 5.5, 7.], [9., 10.5, 12.]]
+    );
+    // Output sizes larger than the input repeat and average neighbouring values.
+    let ys = adaptive_avg_pool2d(&xs, [3, 7])?;
+    assert_eq!(
+        ys.get(0)?.get(0)?.get(0)?.to_vec1::<f32>()?,
+        [1f32, 1.5, 2.5, 3., 3.5, 4.5, 5.]
+    );
+    let ys = adaptive_avg_pool2d(&xs.to_dtype(DType::BF16)?, [3, 5])?;
+    assert_eq!(ys.dtype(), DType::BF16);
+    assert_eq!(
+        ys.get(0)?.to_dtype(DType::F32)?.to_vec3::<f32>()?,
+        xs.get(0)?.to_vec3::<f32>()?
+    );
+
+    assert!(adaptive_avg_pool2d(&xs, [0, 1]).is_err());
+    assert!(adaptive_avg_pool2d(&xs.flatten_to(1)?, [1, 1]).is_err());
+    Ok(())
+}
+
+#[test]
+fn sdpa_chunk_combine() -> Result<()> {
+    let dev = &Device::Cpu;
+    let scale = 0.125;
+    let q = Tensor::randn(0f32, 1f32, (2, 4, 3, 16), dev)?;
+    let k = Tensor::randn(0f32, 2f32, (2, 2, 11, 16), dev)?;
+    let v = Tensor::randn(0f32, 1f32, (2, 2, 11, 8), dev)?;
+    let expected = diffusion_rs_common::nn::ops::sdpa(&q, &k, &v, scale, 1.)?;
+
+    let states = [(0, 4), (4, 7)]
+        .iter()
+        .map(|&(start, len)| {
+            diffusion_rs_common::nn::ops::sdpa_chunk(
+                &q,
+                &k.narrow(2, start, len)?,
+                &v.narrow(2, start, len)?,
+                scale,
+            )
+        })
+        .collect::<diffusion_rs_common::core::Result<Vec<_>>>()?;
+    let combined = diffusion_rs_common::nn::ops::sdpa_combine(&states)?;
+    assert_eq!(
+        to_vec3_round(&combined.flatten_to(1)?, 4)?,
+        to_vec3_round(&expected.flatten_to(1)?, 4)?
+    );
+
+    // A single chunk is plain attention.
+    let single = diffusion_rs_common::nn::ops::sdpa_combine(&states[..1])?;
+    let expected = diffusion_rs_common::nn::ops::sdpa(
+        &q,
+        &k.narrow(2, 0, 4)?,
+        &v.narrow(2, 0, 4)?,
+        scale,
+        1.,
+    )?;
+    assert_eq!(
+        to_vec3_round(&single.flatten_to(1)?, 4)?,
+        to_vec3_round(&expected.flatten_to(1)?, 4)?
+    );
+
+    assert!(diffusion_rs_common::nn::ops::sdpa_combine(&[]).is_err());
+    Ok(())
+}
+
+fn fused_linear_act(dev: &Device) -> Result<()> {
+    use diffusion_rs_common::nn::{Activation, Linear, Module};
+
+    let x = Tensor::randn(0f32, 2f32, (2, 3, 8), dev)?;
+    let weight = Tensor::randn(0f32, 1f32, (6, 8), dev)?;
+    let bias = Tensor::randn(0f32, 1f32, 6, dev)?;
+    let linear = Linear::new(weight.clone(), Some(bias.clone()));
+    for act in [
+        Activation::Gelu,
+        Activation::NewGelu,
+        Activation::GeluPytorchTanh,
+        Activation::Relu,
+        Activation::Relu2,
+        Activation::Relu6,
+        Activation::Silu,
+        Activation::Swish,
+        Activation::Sigmoid,
+        Activation::HardSigmoid,
+        Activation::HardSwish,
+        Activation::Elu(0.5),
+        Activation::LeakyRelu(0.1),
+        Activation::Identity,
+        Activation::Swiglu,
+    ] {
+        let fused = diffusion_rs_common::nn::fused_linear_act(&x, &weight, Some(&bias), act)?;
+        let expected = act.forward(&linear.forward(&x)?)?;
+        assert_eq!(
+            to_vec3_round(&fused, 4)?,
+            to_vec3_round(&expected, 4)?,
+            "{act:?}"
+        );
+    }
+
+    let fused = diffusion_rs_common::nn::fused_linear_act(&x, &weight, None, Activation::Silu)?;
+    let expected = Linear::new(weight.clone(), None).forward(&x)?.silu()?;
+    assert_eq!(to_vec3_round(&fused, 4)?, to_vec3_round(&expected, 4)?);
+
+    let bad_bias = Tensor::zeros(5, DType::F32, dev)?;
+    assert!(diffusion_rs_common::nn::fused_linear_act(
+        &x,
+        &weight,
+        Some(&bad_bias),
+        Activation::Relu
+    )
+    .is_err());
+    Ok(())
+}
+
+fn grouped_rms_norm(device: &Device) -> Result<()> {
+    let (num_groups, group_size) = (4, 8);
+    let xs = Tensor::randn(0f32, 1f32, (2, 3, num_groups * group_size), device)?;
+    let eps = 1e-5;
+
+    let manual = |alpha: &Tensor| -> Result<Tensor> {
+        let groups = (0..num_groups)
+            .map(|g| {
+                let xs = xs.narrow(2, g * group_size, group_size)?;
+                let rms = (xs.sqr()?.mean_keepdim(2)? + eps as f64)?.sqrt()?;
+                let alpha = alpha.narrow(0, g * group_size, group_size)?;
+                Ok(xs.broadcast_div(&rms)?.broadcast_mul(&alpha)?)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Tensor::cat(&groups, 2)?)
+    };
+
+    let alpha = Tensor::randn(0f32, 1f32, num_groups * group_size, device)?;
+    let ys = diffusion_rs_common::nn::ops::grouped_rms_norm(&xs, num_groups, &alpha, eps)?;
+    assert_eq!(to_vec3_round(&ys, 4)?, to_vec3_round(&manual(&alpha)?, 4)?);
+
+    let shared = Tensor::randn(0f32, 1f32, group_size, device)?;
+    let ys = diffusion_rs_common::nn::ops::grouped_rms_norm(&xs, num_groups, &shared, eps)?;
+    let expected = manual(&shared.repeat(num_groups)?)?;
+    assert_eq!(to_vec3_round(&ys, 4)?, to_vec3_round(&expected, 4)?);
+
+    // A single group is a plain rms norm.
+    let ys = diffusion_rs_common::nn::ops::grouped_rms_norm(&xs, 1, &alpha, eps)?;
+    let expected = diffusion_rs_common::nn::ops::rms_norm_slow(&xs, &alpha, eps)?;
+    assert_eq!(to_vec3_round(&ys, 4)?, to_vec3_round(&expected, 4)?);
+
+    if !device.is_metal() {
+        let (xs, alpha) = (xs.to_dtype(DType::F64)?, alpha.to_dtype(DType::F64)?);
+        let ys = diffusion_rs_common::nn::ops::grouped_rms_norm(&xs, 1, &alpha, eps)?;
+        assert_eq!(ys.dtype(), DType::F64);
+        let expected = diffusion_rs_common::nn::ops::rms_norm_slow(&xs, &alpha, eps)?;
+        let diff = (ys - expected)?.abs()?.flatten_all()?.max(0)?;
+        assert!(diff.to_scalar::<f64>()? < 1e-10);
+    }
+
+    assert!(diffusion_rs_common::nn::ops::grouped_rms_norm(&xs, 5, &alpha, eps).is_err());
+    assert!(diffusion_rs_common::nn::ops::grouped_rms_norm(&xs, num_groups, &xs, eps).is_err());
+    Ok(())
+}
+
+fn inplace_scale(device: &Device) -> Result<()> {
+    let xs = Tensor::randn(0f32, 1f32, (3, 4, 5), device)?;
+    let expected = (&xs * 0.7)?;
+    let mut ys = xs.copy()?;
+    diffusion_rs_common::nn::ops::inplace_scale(&mut ys, 0.7)?;
+    assert_eq!(to_vec3_round(&ys, 5)?, to_vec3_round(&expected, 5)?);
+
+    // A contiguous view with a start offset only scales its own elements.
+    let base = xs.copy()?;
+    let mut view = base.narrow(0, 1, 1)?;
+    diffusion_rs_common::nn::ops::inplace_scale(&mut view, -2.)?;
+    let expected = Tensor::cat(
         &[
-            [[1.019, 0.6794, 4.0762], [0.1674, 1.6744, 4.521]],
-            [[0.4714, 0.4714, 4.9497], [1.206, 0.603, 3.6181]]
-        ]
+            xs.narrow(0, 0, 1)?,
+            (xs.narrow(0, 1, 1)? * -2.)?,
+            xs.narrow(0, 2, 1)?,
+        ],
+        0,
+    )?;
+    assert_eq!(to_vec3_round(&base, 5)?, to_vec3_round(&expected, 5)?);
+
+    let mut ints = Tensor::new(&[1u32, 2, 3], device)?;
+    assert!(diffusion_rs_common::nn::ops::inplace_scale(&mut ints, 2.).is_err());
+    let mut strided = xs.t()?;
+    assert!(diffusion_rs_common::nn::ops::inplace_scale(&mut strided, 2.).is_err());
+    Ok(())
+}
+
+#[test]
+fn sdpa_rope() -> Result<()> {
+    use diffusion_rs_common::nn::rotary_emb::rope;
+    let dev = &Device::Cpu;
+    let q = Tensor::randn(0f32, 1f32, (2, 4, 3, 16), dev)?;
+    let k = Tensor::randn(0f32, 1f32, (2, 2, 5, 16), dev)?;
+    let v = Tensor::randn(0f32, 1f32, (2, 2, 5, 16), dev)?;
+    let cos = Tensor::randn(0f32, 1f32, (6, 8), dev)?;
+    let sin = Tensor::randn(0f32, 1f32, (6, 8), dev)?;
+    let scale = 0.25;
+
+    let ys = diffusion_rs_common::nn::ops::sdpa_rope(&q, &k, &v, &cos, &sin, scale, 1.)?;
+    let expected = diffusion_rs_common::nn::ops::sdpa(
+        &rope(&q, &cos, &sin)?,
+        &rope(&k, &cos, &sin)?,
+        &v,
+        scale,
+        1.,
+    )?;
+    assert_eq!(ys.dims(), &[2, 4, 3, 16]);
+    let diff = (ys - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-6);
+
+    // The tables have to cover the longest sequence and half the head dim.
+    let short = cos.narrow(0, 0, 4)?;
+    assert!(
+        diffusion_rs_common::nn::ops::sdpa_rope(&q, &k, &v, &short, &short, scale, 1.).is_err()
     );
-    let t2 = diffusion_rs_common::nn::ops::rms_norm_slow(&tensor, &alpha, 1e-5)?;
-    assert_eq!(
-        to_vec3_round(&t2, 4)?,
-        &[
-            [[1.019, 0.6794, 4.0762], [0.1674, 1.6744, 4.521]],
-            [[0.4714, 0.4714, 4.9497], [1.206, 0.603, 3.6181]]
-        ]
+    let narrow = cos.narrow(1, 0, 4)?;
+    assert!(
+        diffusion_rs_common::nn::ops::sdpa_rope(&q, &k, &v, &narrow, &narrow, scale, 1.).is_err()
     );
-    let diff = (t - t2)?.abs()?.sum_all()?.to_vec0::<f32>()?;
-    assert!(diff < 1e-5);
     Ok(())
 }
 
-fn rms_norml(device: &Device) -> Result<()> {
-    use rand::{rngs::StdRng, Rng, SeedableRng};
+#[test]
+fn attention_scores() -> Result<()> {
+    let dev = &Device::Cpu;
+    let q = Tensor::randn(0f32, 1f32, (2, 4, 3, 8), dev)?;
+    let k = Tensor::randn(0f32, 1f32, (2, 4, 5, 8), dev)?;
+    let scores = diffusion_rs_common::nn::ops::attention_scores(&q, &k, 0.5, 1.)?;
+    assert_eq!(scores.dims(), &[2, 4, 3, 5]);
+    let expected = (q.matmul(&k.t()?)? * 0.5)?;
+    assert_eq!(
+        to_vec3_round(&scores.flatten_to(1)?, 4)?,
+        to_vec3_round(&expected.flatten_to(1)?, 4)?
+    );
 
-    let (b_size, seq_len, head_dim) = (24, 70, 64);
-    let el_count = b_size * seq_len * head_dim;
-    let mut rng = StdRng::seed_from_u64(299792458);
-    let src: Vec<f32> = (0..el_count).map(|_| rng.gen::<f32>()).collect();
-    let tensor = Tensor::new(src, device)?.reshape((b_size, seq_len, head_dim))?;
-    let alpha = Tensor::ones(head_dim, crate::core::DType::F32, device)?;
-    let t = diffusion_rs_common::nn::ops::rms_norm(&tensor, &alpha, 1e-5)?;
-    let t2 = diffusion_rs_common::nn::ops::rms_norm_slow(&tensor, &alpha, 1e-5)?;
-    let diff = (t - t2)?
-        .abs()?
-        .flatten_all()?
-        .max(0)?
-        .reshape(())?
-        .to_vec0::<f32>()?;
-    assert!(diff < 1e-5);
+    let capped = diffusion_rs_common::nn::ops::attention_scores(&q, &k, 0.5, 2.)?;
+    let expected = ((&expected / 2.)?.tanh()? * 2.)?;
+    assert_eq!(
+        to_vec3_round(&capped.flatten_to(1)?, 4)?,
+        to_vec3_round(&expected.flatten_to(1)?, 4)?
+    );
+
+    // GQA, each kv head is shared by two query heads.
+    let k2 = k.narrow(1, 0, 2)?;
+    let scores = diffusion_rs_common::nn::ops::attention_scores(&q, &k2, 0.5, 1.)?;
+    let expected =
+        (q.matmul(&diffusion_rs_common::nn::ops::repeat_interleave(&k2, 2, 1)?.t()?)? * 0.5)?;
+    assert_eq!(
+        to_vec3_round(&scores.flatten_to(1)?, 4)?,
+        to_vec3_round(&expected.flatten_to(1)?, 4)?
+    );
+
+    let bad_hidden = Tensor::randn(0f32, 1f32, (2, 4, 5, 6), dev)?;
+    assert!(diffusion_rs_common::nn::ops::attention_scores(&q, &bad_hidden, 0.5, 1.).is_err());
+    let bad_heads = Tensor::randn(0f32, 1f32, (2, 3, 5, 8), dev)?;
+    assert!(diffusion_rs_common::nn::ops::attention_scores(&q, &bad_heads, 0.5, 1.).is_err());
     Ok(())
 }
 
-fn layer_norm(device: &Device) -> Result<()> {
-    let data = &[[[3f32, 1., 4.], [1., 5., 9.]], [[2., 1., 7.], [8., 2., 8.]]];
-    let tensor = Tensor::new(data, device)?;
-    let alpha = Tensor::new(&[1f32, 2f32, 3f32], device)?;
-    let beta = Tensor::new(&[0.5f32, 0f32, -0.2f32], device)?;
-    let t = diffusion_rs_common::nn::ops::layer_norm(&tensor, &alpha, &beta, 1e-5)?;
+#[test]
+fn attn_softmax_last_dim_cpu() -> Result<()> {
+    use diffusion_rs_common::nn::ops::{attn_softmax_last_dim, inplace_attn_softmax_last_dim};
+    let dev = &Device::Cpu;
+    let xs = Tensor::randn(0f32, 1f32, (2, 3, 4, 7), dev)?;
+    let mask = Tensor::randn(0f32, 1f32, (4, 7), dev)?;
+    let reference = |xs: &Tensor, mask: &Tensor, scale: f64| -> Result<Tensor> {
+        diffusion_rs_common::nn::ops::softmax_last_dim(&(xs.broadcast_add(mask)? * scale)?)
+    };
+
+    let out = attn_softmax_last_dim(&xs, &mask, 0.3)?;
+    let expected = reference(&xs, &mask, 0.3)?;
     assert_eq!(
-        to_vec3_round(&t, 4)?,
-        &[
-            [[0.7673, -2.6726, 3.0071], [-0.7247, 0.0, 3.4742]],
-            [[-0.008, -1.778, 3.991], [1.2071, -2.8284, 1.9213]]
-        ]
+        out.flatten_all()?.to_vec1::<f32>()?,
+        expected.flatten_all()?.to_vec1::<f32>()?
     );
-    let t2 = diffusion_rs_common::nn::ops::layer_norm_slow(&tensor, &alpha, &beta, 1e-5)?;
+    let mut inplace = xs.copy()?;
+    inplace_attn_softmax_last_dim(&mut inplace, &mask, 0.3)?;
     assert_eq!(
-        to_vec3_round(&t2, 4)?,
+        inplace.flatten_all()?.to_vec1::<f32>()?,
+        out.flatten_all()?.to_vec1::<f32>()?
+    );
+
+    // Rank 3 inputs and a mask with -inf entries.
+    let xs3 = xs.flatten_to(1)?;
+    let causal = Tensor::new(
         &[
-            [[0.7673, -2.6726, 3.0071], [-0.7247, 0.0, 3.4742]],
-            [[-0.008, -1.778, 3.991], [1.2071, -2.8284, 1.9213]]
-        ]
+            [
+                0f32,
+                f32::NEG_INFINITY,
+                f32::NEG_INFINITY,
+                f32::NEG_INFINITY,
+            ],
+            [0., 0., f32::NEG_INFINITY, f32::NEG_INFINITY],
+            [0., 0., 0., f32::NEG_INFINITY],
+            [0., 0., 0., 0.],
+        ],
+        dev,
+    )?;
+    let xs3 = xs3.narrow(2, 0, 4)?.contiguous()?;
+    let out = attn_softmax_last_dim(&xs3, &causal, 1.)?;
+    let expected = reference(&xs3, &causal, 1.)?;
+    assert_eq!(to_vec3_round(&out, 6)?, to_vec3_round(&expected, 6)?);
+
+    for dtype in [DType::F16, DType::BF16] {
+        let xs = xs.to_dtype(dtype)?;
+        let mask = mask.to_dtype(dtype)?;
+        let out = attn_softmax_last_dim(&xs, &mask, 0.3)?.to_dtype(DType::F32)?;
+        let expected = reference(&xs, &mask, 0.3)?.to_dtype(DType::F32)?;
+        let diff = (out - expected)?.abs()?.flatten_all()?.max(0)?;
+        assert!(diff.to_scalar::<f32>()? < 1e-2);
+    }
+
+    // Masks broadcasting per head and non-contiguous inputs use the composed ops.
+    let head_mask = Tensor::randn(0f32, 1f32, (2, 3, 4, 7), dev)?;
+    let out = attn_softmax_last_dim(&xs, &head_mask, 0.3)?;
+    let expected = reference(&xs, &head_mask, 0.3)?;
+    assert_eq!(
+        out.flatten_all()?.to_vec1::<f32>()?,
+        expected.flatten_all()?.to_vec1::<f32>()?
     );
-    let diff = (t - t2)?.abs()?.sum_all()?.to_vec0::<f32>()?;
-    assert!(diff < 1e-5);
+    let mut inplace = xs.copy()?;
+    inplace_attn_softmax_last_dim(&mut inplace, &head_mask, 0.3)?;
+    assert_eq!(
+        inplace.flatten_all()?.to_vec1::<f32>()?,
+        expected.flatten_all()?.to_vec1::<f32>()?
+    );
+    let xs_t = xs.transpose(2, 3)?;
+    let out = attn_softmax_last_dim(&xs_t, &mask.t()?, 0.3)?;
+    let expected = reference(&xs_t, &mask.t()?, 0.3)?;
+    assert_eq!(
+        out.flatten_all()?.to_vec1::<f32>()?,
+        expected.flatten_all()?.to_vec1::<f32>()?
+    );
+
+    assert!(attn_softmax_last_dim(&xs, &mask.to_dtype(DType::F16)?, 1.).is_err());
+    assert!(attn_softmax_last_dim(&xs, &mask.t()?, 1.).is_err());
     Ok(())
 }
 
-fn layer_norml(device: &Device) -> Result<()> {
-    use rand::{rngs::StdRng, Rng, SeedableRng};
+#[test]
+fn quant_error() -> Result<()> {
+    use diffusion_rs_common::nn::ops::quant_error;
+    let dev = &Device::Cpu;
+    let xs = Tensor::randn(0f32, 1f32, (4, 16), dev)?;
+    let mut last = f64::INFINITY;
+    for bits in [2, 4, 8, 12] {
+        let (reconstructed, err) = quant_error(&xs, bits, None)?;
+        assert_eq!(reconstructed.dims(), xs.dims());
+        assert!(err < last, "{bits} bits: {err} >= {last}");
+        last = err;
+    }
 
-    let (b_size, seq_len, head_dim) = (24, 70, 64);
-    let el_count = b_size * seq_len * head_dim;
-    let mut rng = StdRng::seed_from_u64(299792458);
-    let src: Vec<f32> = (0..el_count).map(|_| rng.gen::<f32>()).collect();
-    let tensor = Tensor::new(src, device)?.reshape((b_size, seq_len, head_dim))?;
-    let alpha = Tensor::ones(head_dim, crate::core::DType::F32, device)?;
-    let beta = Tensor::zeros(head_dim, crate::core::DType::F32, device)?;
-    let t = diffusion_rs_common::nn::ops::layer_norm(&tensor, &alpha, &beta, 1e-5)?;
-    let t2 = diffusion_rs_common::nn::ops::layer_norm_slow(&tensor, &alpha, &beta, 1e-5)?;
-    let diff = (t - t2)?
-        .abs()?
-        .flatten_all()?
-        .max(0)?
-        .reshape(())?
-        .to_vec0::<f32>()?;
-    assert!(diff < 1e-5);
+    // Channels with very different ranges benefit from per-channel scales.
+    let scales = Tensor::new(&[1e-3f32, 1., 10., 1e3], dev)?.unsqueeze(1)?;
+    let xs = xs.broadcast_mul(&scales)?;
+    let (_, per_tensor) = quant_error(&xs, 8, None)?;
+    let (_, per_channel) = quant_error(&xs, 8, Some(0))?;
+    assert!(per_channel < per_tensor);
+
+    let same = Tensor::full(0.7f32, (3, 5), dev)?;
+    for dim in [None, Some(0), Some(1)] {
+        let (reconstructed, err) = quant_error(&same, 4, dim)?;
+        assert_eq!(err, 0.);
+        assert_eq!(reconstructed.to_vec2::<f32>()?, same.to_vec2::<f32>()?);
+    }
+    let (reconstructed, err) = quant_error(&Tensor::zeros((2, 3), DType::F32, dev)?, 8, Some(1))?;
+    assert_eq!(err, 0.);
+    assert_eq!(reconstructed.to_vec2::<f32>()?, [[0f32; 3]; 2]);
+
+    assert!(quant_error(&same, 1, None).is_err());
+    assert!(quant_error(&same, 8, Some(2)).is_err());
     Ok(())
 }
 
 #[test]
-fn softmax_numerical_stability() -> Result<()> {
+fn tensor_stats() -> Result<()> {
+    use diffusion_rs_common::nn::ops::{tensor_stats, TensorStats};
     let dev = &Device::Cpu;
-    let xs = Tensor::new(&[1234f32, 0.], dev)?;
-    let softmax = diffusion_rs_common::nn::ops::softmax(&xs, 0)?;
-    assert_eq!(softmax.to_vec1::<f32>()?, &[1f32, 0.]);
+    let xs = Tensor::new(&[[1.5f32, -7., 0.25], [3., 2., -0.75]], dev)?;
+    let stats = tensor_stats(&xs)?;
+    let flat = xs.flatten_all()?;
+    assert_eq!(stats.min, flat.min(0)?.to_scalar::<f32>()? as f64);
+    assert_eq!(stats.max, flat.max(0)?.to_scalar::<f32>()? as f64);
+    assert!((stats.mean - flat.mean(0)?.to_scalar::<f32>()? as f64).abs() < 1e-6);
+    assert_eq!(stats.absmax, 7.);
+    // Strided inputs and large tensors spanning several chunks.
+    assert_eq!(tensor_stats(&xs.t()?)?, stats);
+    let xs = Tensor::arange(0u32, 10_000, dev)?;
+    assert_eq!(
+        tensor_stats(&xs)?,
+        TensorStats {
+            min: 0.,
+            max: 9999.,
+            mean: 4999.5,
+            absmax: 9999.,
+        }
+    );
+    assert!(tensor_stats(&Tensor::zeros(0, DType::F32, dev)?).is_err());
+    Ok(())
+}
+
+fn attn_softmax_fused(device: &Device) -> Result<()> {
+    use diffusion_rs_common::nn::ops::{attn_softmax_last_dim, inplace_attn_softmax_last_dim};
+    // Long rows use several warps per row in the CUDA kernel.
+    for (seq, kv_seq) in [(3, 5), (4, 64), (2, 1500)] {
+        let xs = Tensor::randn(0f32, 1f32, (2, 3, seq, kv_seq), device)?;
+        let mask = Tensor::randn(0f32, 1f32, (seq, kv_seq), device)?;
+        let expected =
+            diffusion_rs_common::nn::ops::softmax_last_dim(&(xs.broadcast_add(&mask)? * 0.5)?)?;
+        let out = attn_softmax_last_dim(&xs, &mask, 0.5)?;
+        let diff = (&out - &expected)?.abs()?.flatten_all()?.max(0)?;
+        assert!(diff.to_scalar::<f32>()? < 1e-6);
+
+        let mut inplace = xs.copy()?;
+        inplace_attn_softmax_last_dim(&mut inplace, &mask, 0.5)?;
+        let diff = (&inplace - &out)?.abs()?.flatten_all()?.max(0)?;
+        assert_eq!(diff.to_scalar::<f32>()?, 0.);
+    }
+
+    // The cuda kernel has no f64 variant, f64 goes through the composed ops there.
+    if !device.is_metal() {
+        let xs = Tensor::randn(0f64, 1f64, (2, 3, 4, 5), device)?;
+        let mask = Tensor::randn(0f64, 1f64, (4, 5), device)?;
+        let expected =
+            diffusion_rs_common::nn::ops::softmax_last_dim(&(xs.broadcast_add(&mask)? * 0.5)?)?;
+        let out = attn_softmax_last_dim(&xs, &mask, 0.5)?;
+        let diff = (&out - &expected)?.abs()?.flatten_all()?.max(0)?;
+        assert!(diff.to_scalar::<f64>()? < 1e-12);
+
+        let mut inplace = xs.copy()?;
+        inplace_attn_softmax_last_dim(&mut inplace, &mask, 0.5)?;
+        let diff = (&inplace - &out)?.abs()?.flatten_all()?.max(0)?;
+        assert_eq!(diff.to_scalar::<f64>()?, 0.);
+    }
+
+    // Per-head masks and transposed inputs use the composed ops on the cpu and cuda, the metal
+    // kernel rejects them.
+    if !device.is_metal() {
+        let xs = Tensor::randn(0f32, 1f32, (2, 3, 4, 5), device)?;
+        let head_mask = Tensor::randn(0f32, 1f32, (2, 3, 4, 5), device)?;
+        let expected = diffusion_rs_common::nn::ops::softmax_last_dim(
+            &(xs.broadcast_add(&head_mask)? * 0.5)?,
+        )?;
+        let out = attn_softmax_last_dim(&xs, &head_mask, 0.5)?;
+        let diff = (&out - &expected)?.abs()?.flatten_all()?.max(0)?;
+        assert_eq!(diff.to_scalar::<f32>()?, 0.);
+        let mut inplace = xs.copy()?;
+        inplace_attn_softmax_last_dim(&mut inplace, &head_mask, 0.5)?;
+        let diff = (&inplace - &out)?.abs()?.flatten_all()?.max(0)?;
+        assert_eq!(diff.to_scalar::<f32>()?, 0.);
+
+        let xs_t = xs.transpose(2, 3)?;
+        let mask = Tensor::randn(0f32, 1f32, (5, 4), device)?;
+        let expected =
+            diffusion_rs_common::nn::ops::softmax_last_dim(&(xs_t.broadcast_add(&mask)? * 0.5)?)?;
+        let out = attn_softmax_last_dim(&xs_t, &mask, 0.5)?;
+        let diff = (&out - &expected)?.abs()?.flatten_all()?.max(0)?;
+        assert_eq!(diff.to_scalar::<f32>()?, 0.);
+    }
+    Ok(())
+}
+
+#[test]
+fn timestep_embedding() -> Result<()> {
+    let dev = &Device::Cpu;
+    let ts = [0f32, 1., 250., 999.];
+    let timesteps = Tensor::new(&ts, dev)?;
+    for dim in [4, 8, 7, 1] {
+        let emb = diffusion_rs_common::nn::ops::timestep_embedding(&timesteps, dim, 10000.)?;
+        assert_eq!(emb.dims(), &[ts.len(), dim]);
+        let half = dim / 2;
+        let expected = ts
+            .iter()
+            .map(|&t| {
+                let args = (0..half)
+                    .map(|i| t as f64 * (-(10000f64.ln()) * i as f64 / half as f64).exp())
+                    .collect::<Vec<_>>();
+                let mut row = args.iter().map(|a| a.sin()).collect::<Vec<_>>();
+                row.extend(args.iter().map(|a| a.cos()));
+                if dim % 2 == 1 {
+                    row.push(0.);
+                }
+                row
+            })
+            .collect::<Vec<_>>();
+        for (row, expected) in emb.to_vec2::<f32>()?.iter().zip(expected) {
+            for (v, e) in row.iter().zip(expected) {
+                assert!((*v as f64 - e).abs() < 1e-3, "dim {dim}: {v} vs {e}");
+            }
+        }
+    }
+
+    // Integer timesteps give F32 embeddings, float ones keep their dtype.
+    let emb = diffusion_rs_common::nn::ops::timestep_embedding(
+        &Tensor::new(&[3u32, 7], dev)?,
+        6,
+        10000.,
+    )?;
+    assert_eq!(emb.dtype(), DType::F32);
+    let emb = diffusion_rs_common::nn::ops::timestep_embedding(
+        &timesteps.to_dtype(DType::F16)?,
+        6,
+        100.,
+    )?;
+    assert_eq!(emb.dtype(), DType::F16);
+    assert!(diffusion_rs_common::nn::ops::timestep_embedding(&timesteps, 0, 10000.).is_err());
     Ok(())
 }
 
@@ -225,6 +2963,46 @@ fn rope(device: &Device) -> Result<()> {
     Ok(())
 }
 
+fn rope_partial(device: &Device) -> Result<()> {
+    use diffusion_rs_common::nn::rotary_emb::{rope, rope_partial};
+
+    let (b_size, num_head, seq_len, head_dim) = (2, 3, 10, 16);
+    let src = Tensor::randn(0f32, 1f32, (b_size, num_head, seq_len, head_dim), device)?;
+
+    let cos = Tensor::randn(0f32, 1f32, (seq_len, head_dim / 2), device)?;
+    let sin = Tensor::randn(0f32, 1f32, (seq_len, head_dim / 2), device)?;
+    let full = rope_partial(&src, &cos, &sin, head_dim)?;
+    let expected = rope(&src, &cos, &sin)?;
+    let sum_diff = (full - expected)?.abs()?.sum_all()?.to_vec0::<f32>()?;
+    assert_eq!(sum_diff, 0.);
+
+    let identity = rope_partial(&src, &cos, &sin, 0)?;
+    let sum_diff = (identity - &src)?.abs()?.sum_all()?.to_vec0::<f32>()?;
+    assert_eq!(sum_diff, 0.);
+
+    // 25% partial rotary.
+    let rotary_dim = head_dim / 4;
+    let cos = cos.narrow(1, 0, rotary_dim / 2)?.contiguous()?;
+    let sin = sin.narrow(1, 0, rotary_dim / 2)?.contiguous()?;
+    let partial = rope_partial(&src, &cos, &sin, rotary_dim)?;
+    let rotated = rope(&src.narrow(3, 0, rotary_dim)?.contiguous()?, &cos, &sin)?;
+    let sum_diff = (partial.narrow(3, 0, rotary_dim)? - rotated)?
+        .abs()?
+        .sum_all()?
+        .to_vec0::<f32>()?;
+    assert_eq!(sum_diff, 0.);
+    let sum_diff = (partial.narrow(3, rotary_dim, head_dim - rotary_dim)?
+        - src.narrow(3, rotary_dim, head_dim - rotary_dim)?)?
+    .abs()?
+    .sum_all()?
+    .to_vec0::<f32>()?;
+    assert_eq!(sum_diff, 0.);
+
+    assert!(rope_partial(&src, &cos, &sin, 3).is_err());
+    assert!(rope_partial(&src, &cos, &sin, head_dim + 2).is_err());
+    Ok(())
+}
+
 fn rope_thd(device: &Device) -> Result<()> {
     use rand::{rngs::StdRng, Rng, SeedableRng};
 
@@ -260,23 +3038,296 @@ fn sigmoid(device: &Device) -> Result<()> {
     let tensor = Tensor::new(data, device)?;
     let s1 = diffusion_rs_common::nn::ops::sigmoid(&tensor)?;
     let s2 = (1. / (1. + tensor.neg()?.exp()?)?)?;
-    let diff = (s1 - s2)?.abs()?.sum_all()?.to_vec0::<f32>()?;
+    let diff = (&s1 - s2)?.abs()?.sum_all()?.to_vec0::<f32>()?;
+    assert_eq!(diff, 0.);
+
+    // An odd element count skips the tiled kernel, the results have to match exactly.
+    let odd = Tensor::cat(&[tensor.flatten_all()?, Tensor::new(&[0.5f32], device)?], 0)?;
+    let s3 = diffusion_rs_common::nn::ops::sigmoid(&odd)?.narrow(0, 0, 12)?;
+    let diff = (s1.flatten_all()? - s3)?
+        .abs()?
+        .sum_all()?
+        .to_vec0::<f32>()?;
     assert_eq!(diff, 0.);
     Ok(())
 }
 
 test_device!(ropei, ropei_cpu, ropei_gpu, ropei_metal);
 test_device!(rope, rope_cpu, rope_gpu, rope_metal);
+test_device!(
+    rope_partial,
+    rope_partial_cpu,
+    rope_partial_gpu,
+    rope_partial_metal
+);
 test_device!(rope_thd, rope_thd_cpu, rope_thd_gpu, rope_thd_metal);
 test_device!(softmax, softmax_cpu, softmax_gpu, softmax_metal);
+test_device!(
+    softmax_dim,
+    softmax_dim_cpu,
+    softmax_dim_gpu,
+    softmax_dim_metal
+);
+test_device!(
+    log_softmax_last_dim,
+    log_softmax_last_dim_cpu,
+    log_softmax_last_dim_gpu,
+    log_softmax_last_dim_metal
+);
+test_device!(
+    softmax_fully_masked,
+    softmax_fully_masked_cpu,
+    softmax_fully_masked_gpu,
+    softmax_fully_masked_metal
+);
 test_device!(
     inplace_softmax,
     inplace_softmax_cpu,
     inplace_softmax_gpu,
     inplace_softmax_metal
 );
+test_device!(
+    attention_entropy,
+    attention_entropy_cpu,
+    attention_entropy_gpu,
+    attention_entropy_metal
+);
+test_device!(
+    nucleus_distribution_narrowed,
+    nucleus_distribution_narrowed_cpu,
+    nucleus_distribution_narrowed_gpu,
+    nucleus_distribution_narrowed_metal
+);
+test_device!(
+    softmax_temp_vec,
+    softmax_temp_vec_cpu,
+    softmax_temp_vec_gpu,
+    softmax_temp_vec_metal
+);
+test_device!(fma, fma_cpu, fma_gpu, fma_metal);
+test_device!(
+    seeded_dropout,
+    seeded_dropout_cpu,
+    seeded_dropout_gpu,
+    seeded_dropout_metal
+);
+test_device!(dropout2d, dropout2d_cpu, dropout2d_gpu, dropout2d_metal);
+test_device!(
+    rsqrt_reciprocal,
+    rsqrt_reciprocal_cpu,
+    rsqrt_reciprocal_gpu,
+    rsqrt_reciprocal_metal
+);
+test_device!(
+    segment_softmax,
+    segment_softmax_cpu,
+    segment_softmax_gpu,
+    segment_softmax_metal
+);
+test_device!(
+    bias_add_nchw,
+    bias_add_nchw_cpu,
+    bias_add_nchw_gpu,
+    bias_add_nchw_metal
+);
+test_device!(
+    masked_mean,
+    masked_mean_cpu,
+    masked_mean_gpu,
+    masked_mean_metal
+);
+test_device!(kvconcat, kvconcat_cpu, kvconcat_gpu, kvconcat_metal);
+test_device!(
+    softmax_matmul,
+    softmax_matmul_cpu,
+    softmax_matmul_gpu,
+    softmax_matmul_metal
+);
+test_device!(nan_to_num, nan_to_num_cpu, nan_to_num_gpu, nan_to_num_metal);
+test_device!(
+    add_layer_norm_cast,
+    add_layer_norm_cast_cpu,
+    add_layer_norm_cast_gpu,
+    add_layer_norm_cast_metal
+);
+test_device!(
+    add_rms_norm_cast,
+    add_rms_norm_cast_cpu,
+    add_rms_norm_cast_gpu,
+    add_rms_norm_cast_metal
+);
+test_device!(
+    softmax_last_dim_checked,
+    softmax_last_dim_checked_cpu,
+    softmax_last_dim_checked_gpu,
+    softmax_last_dim_checked_metal
+);
+test_device!(
+    cosine_similarity,
+    cosine_similarity_cpu,
+    cosine_similarity_gpu,
+    cosine_similarity_metal
+);
+test_device!(
+    repeat_interleave,
+    repeat_interleave_cpu,
+    repeat_interleave_gpu,
+    repeat_interleave_metal
+);
+test_device!(
+    final_logit_softmax,
+    final_logit_softmax_cpu,
+    final_logit_softmax_gpu,
+    final_logit_softmax_metal
+);
+test_device!(
+    cumsum_exp_scan,
+    cumsum_exp_scan_cpu,
+    cumsum_exp_scan_gpu,
+    cumsum_exp_scan_metal
+);
+test_device!(silu_mul, silu_mul_cpu, silu_mul_gpu, silu_mul_metal);
+test_device!(
+    v_eps_conversion,
+    v_eps_conversion_cpu,
+    v_eps_conversion_gpu,
+    v_eps_conversion_metal
+);
+test_device!(
+    channel_gate,
+    channel_gate_cpu,
+    channel_gate_gpu,
+    channel_gate_metal
+);
+test_device!(
+    spatial_seq_roundtrip,
+    spatial_seq_roundtrip_cpu,
+    spatial_seq_roundtrip_gpu,
+    spatial_seq_roundtrip_metal
+);
+test_device!(ema_update, ema_update_cpu, ema_update_gpu, ema_update_metal);
+test_device!(
+    pixel_shuffle_1d,
+    pixel_shuffle_1d_cpu,
+    pixel_shuffle_1d_gpu,
+    pixel_shuffle_1d_metal
+);
+test_device!(nchw_nhwc, nchw_nhwc_cpu, nchw_nhwc_gpu, nchw_nhwc_metal);
+test_device!(
+    replication_pad2d,
+    replication_pad2d_cpu,
+    replication_pad2d_gpu,
+    replication_pad2d_metal
+);
+test_device!(
+    reflection_pad2d,
+    reflection_pad2d_cpu,
+    reflection_pad2d_gpu,
+    reflection_pad2d_metal
+);
+test_device!(
+    constant_pad,
+    constant_pad_cpu,
+    constant_pad_gpu,
+    constant_pad_metal
+);
+test_device!(pool2d, pool2d_cpu, pool2d_gpu, pool2d_metal);
+test_device!(
+    adaptive_avg_pool2d,
+    adaptive_avg_pool2d_cpu,
+    adaptive_avg_pool2d_gpu,
+    adaptive_avg_pool2d_metal
+);
+test_device!(
+    inplace_add_scaled,
+    inplace_add_scaled_cpu,
+    inplace_add_scaled_gpu,
+    inplace_add_scaled_metal
+);
+test_device!(
+    grouped_rms_norm,
+    grouped_rms_norm_cpu,
+    grouped_rms_norm_gpu,
+    grouped_rms_norm_metal
+);
+test_device!(
+    inplace_scale,
+    inplace_scale_cpu,
+    inplace_scale_gpu,
+    inplace_scale_metal
+);
+test_device!(
+    attn_softmax_fused,
+    attn_softmax_fused_cpu,
+    attn_softmax_fused_gpu,
+    attn_softmax_fused_metal
+);
+test_device!(
+    glu_modules,
+    glu_modules_cpu,
+    glu_modules_gpu,
+    glu_modules_metal
+);
+test_device!(
+    swiglu_fused,
+    swiglu_fused_cpu,
+    swiglu_fused_gpu,
+    swiglu_fused_metal
+);
+test_device!(geglu_mlp, geglu_mlp_cpu, geglu_mlp_gpu, geglu_mlp_metal);
+test_device!(tanh, tanh_cpu, tanh_gpu, tanh_metal);
+test_device!(mish, mish_cpu, mish_gpu, mish_metal);
+test_device!(powf, powf_cpu, powf_gpu, powf_metal);
+test_device!(
+    gelu_variants,
+    gelu_variants_cpu,
+    gelu_variants_gpu,
+    gelu_variants_metal
+);
 test_device!(rms_norm, rms_norm_cpu, rms_norm_gpu, rms_norm_metal);
+test_device!(
+    inplace_norms,
+    inplace_norms_cpu,
+    inplace_norms_gpu,
+    inplace_norms_metal
+);
+test_device!(
+    rms_norm_bf16_accum,
+    rms_norm_bf16_accum_cpu,
+    rms_norm_bf16_accum_gpu,
+    rms_norm_bf16_accum_metal
+);
+test_device!(
+    rms_norm_cast,
+    rms_norm_cast_cpu,
+    rms_norm_cast_gpu,
+    rms_norm_cast_metal
+);
 test_device!(rms_norml, rms_norml_cpu, rms_norml_gpu, rms_norml_metal);
+test_device!(
+    norm_widths,
+    norm_widths_cpu,
+    norm_widths_gpu,
+    norm_widths_metal
+);
 test_device!(layer_norm, ln_cpu, ln_gpu, ln_metal);
 test_device!(layer_norml, lnl_cpu, lnl_gpu, lnl_metal);
+test_device!(
+    group_norm_fused,
+    group_norm_fused_cpu,
+    group_norm_fused_gpu,
+    group_norm_fused_metal
+);
+test_device!(
+    layer_norm2d,
+    layer_norm2d_cpu,
+    layer_norm2d_gpu,
+    layer_norm2d_metal
+);
 test_device!(sigmoid, sigmoid_cpu, sigmoid_gpu, sigmoid_metal);
+test_device!(
+    fused_linear_act,
+    fused_linear_act_cpu,
+    fused_linear_act_gpu,
+    fused_linear_act_metal
+);
@@ -249,6 +249,148 @@ mod metal_sdpa_tests {
         Ok(())
     }
 
+    #[test]
+    fn sdpa_vector_2pass_softcapping_threshold() -> crate::core::Result<()> {
+        use crate::core::{Device, Tensor};
+
+        // kv_seq = 1023 runs the single pass kernel, kv_seq = 1024 the 2 pass one. Masking out
+        // the last key of the longer sequence makes both compute the same capped attention.
+        const BS: usize = 2;
+        const R: usize = 1;
+        const L: usize = 1024;
+        const DK: usize = 64;
+        const H: usize = 3;
+        const SOFTCAP: f32 = 20.;
+
+        let device = Device::new_metal(0)?;
+
+        let q = (Tensor::randn(0f32, 1f32, (BS, H, R, DK), &device)? * 4.)?;
+        let k = (Tensor::randn(0f32, 1f32, (BS, H, L, DK), &device)? * 4.)?;
+        let v = Tensor::randn(0f32, 1f32, (BS, H, L, DK), &device)?;
+        let mask: Vec<f32> = (0..L)
+            .map(|j| if j == L - 1 { f32::NEG_INFINITY } else { 0. })
+            .collect();
+        let mask = Tensor::from_vec(mask, (1, 1, 1, L), &device)?;
+
+        let one_pass = diffusion_rs_common::nn::ops::sdpa(
+            &q,
+            &k.narrow(2, 0, L - 1)?.contiguous()?,
+            &v.narrow(2, 0, L - 1)?.contiguous()?,
+            1.,
+            SOFTCAP,
+        )?;
+        let params = diffusion_rs_common::nn::ops::SdpaParams {
+            mask: Some(mask),
+            causal: false,
+        };
+        let two_pass =
+            diffusion_rs_common::nn::ops::sdpa_with_params(&q, &k, &v, 1., SOFTCAP, &params)?;
+
+        assert_eq!(one_pass.shape(), two_pass.shape());
+
+        let error: f32 = (&one_pass - &two_pass)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar()?;
+
+        assert!(error <= 1e-4, "{}", error);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sdpa_f32_out() -> crate::core::Result<()> {
+        use crate::core::{DType, Device, Tensor};
+
+        const BS: usize = 4;
+        const R: usize = 1;
+        const L: usize = 256;
+        const DK: usize = 64;
+        const H: usize = 3;
+        let scale: f64 = f64::from(DK as u32).sqrt().recip();
+
+        let device = Device::new_metal(0)?;
+
+        let q = Tensor::randn(0f32, 1f32, (BS, H, R, DK), &device)?;
+        let k = Tensor::randn(0f32, 1f32, (BS, H, L, DK), &device)?;
+        let v = Tensor::randn(0f32, 1f32, (BS, H, L, DK), &device)?;
+        let (q16, k16, v16) = (
+            q.to_dtype(DType::F16)?,
+            k.to_dtype(DType::F16)?,
+            v.to_dtype(DType::F16)?,
+        );
+
+        // All F32 reference on the half precision inputs.
+        let ground_truth = {
+            let (q, k, v) = (
+                q16.to_dtype(DType::F32)?,
+                k16.to_dtype(DType::F32)?,
+                v16.to_dtype(DType::F32)?,
+            );
+            let att = (q * scale)?.matmul(&k.t()?)?;
+            diffusion_rs_common::nn::ops::softmax_last_dim(&att)?.matmul(&v)?
+        };
+
+        let f32_out =
+            diffusion_rs_common::nn::ops::sdpa_f32_out(&q16, &k16, &v16, scale as f32, 1.)?;
+        assert_eq!(f32_out.dtype(), DType::F32);
+        let f16_out = diffusion_rs_common::nn::ops::sdpa(&q16, &k16, &v16, scale as f32, 1.)?
+            .to_dtype(DType::F32)?;
+
+        // The fused half precision kernel runs and only its output is cast.
+        let diff: f32 = (&f16_out - &f32_out)?.abs()?.sum_all()?.to_scalar()?;
+        assert_eq!(diff, 0.);
+        let error: f32 = (&ground_truth - &f32_out)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar()?;
+        assert!(error < 1e-2, "{error}");
+
+        assert!(diffusion_rs_common::nn::ops::sdpa_f32_out(&q, &k, &v, scale as f32, 1.).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn sdpa_into() -> crate::core::Result<()> {
+        use crate::core::{DType, Device, Tensor};
+
+        const BS: usize = 4;
+        const R: usize = 1;
+        const L: usize = 64;
+        const DK: usize = 64;
+        const H: usize = 3;
+        let scale: f64 = f64::from(DK as u32).sqrt().recip();
+
+        let device = Device::new_metal(0)?;
+
+        let out = Tensor::zeros((BS, H, R, DK), DType::F32, &device)?;
+        // Reuse the same output across steps.
+        for _ in 0..3 {
+            let q = Tensor::randn(0f32, 1f32, (BS, H, R, DK), &device)?;
+            let k = Tensor::randn(0f32, 1f32, (BS, H, L, DK), &device)?;
+            let v = Tensor::randn(0f32, 1f32, (BS, H, L, DK), &device)?;
+
+            let expected = diffusion_rs_common::nn::ops::sdpa(&q, &k, &v, scale as f32, 1.)?;
+            diffusion_rs_common::nn::ops::sdpa_into(&q, &k, &v, scale as f32, 1., &out)?;
+
+            let error: f32 = (&expected - &out)?.abs()?.sum_all()?.to_scalar()?;
+            assert_eq!(error, 0.);
+        }
+
+        let q = Tensor::randn(0f32, 1f32, (BS, H, R, DK), &device)?;
+        let k = Tensor::randn(0f32, 1f32, (BS, H, L, DK), &device)?;
+        let bad_out = Tensor::zeros((BS, H, 2, DK), DType::F32, &device)?;
+        assert!(
+            diffusion_rs_common::nn::ops::sdpa_into(&q, &k, &k, scale as f32, 1., &bad_out)
+                .is_err()
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn sdpa_vector_cross() -> crate::core::Result<()> {
         use crate::core::{DType, Device, Tensor};
@@ -287,6 +429,176 @@ mod metal_sdpa_tests {
         Ok(())
     }
 
+    #[test]
+    fn sdpa_full_mask() -> crate::core::Result<()> {
+        use crate::core::{DType, Device, Tensor};
+
+        // Force seqlen = 100, causal with an additional padding mask
+        const BS: usize = 4;
+        const R: usize = 100;
+        const L: usize = 100;
+        const DK: usize = 64;
+        const H: usize = 3;
+        let scale: f64 = f64::from(DK as u32).sqrt().recip();
+
+        let device = Device::new_metal(0)?;
+
+        let q = Tensor::randn(0f32, 1f32, (BS, H, R, DK), &device)?;
+        let k = Tensor::randn(0f32, 1f32, (BS, H, L, DK), &device)?;
+        let v = Tensor::randn(0f32, 1f32, (BS, H, L, DK), &device)?;
+        let padding: Vec<f32> = (0..L)
+            .map(|j| if j % 7 == 3 { f32::NEG_INFINITY } else { 0. })
+            .collect();
+        let padding = Tensor::from_vec(padding, (1, 1, 1, L), &device)?;
+        let causal: Vec<f32> = (0..R)
+            .flat_map(|i| (0..L).map(move |j| if j > i { f32::NEG_INFINITY } else { 0. }))
+            .collect();
+        let causal = Tensor::from_vec(causal, (R, L), &device)?;
+
+        let ground_truth = {
+            let att = (q.clone() * scale)?.matmul(&k.clone().t()?)?;
+            let att = att.broadcast_add(&padding)?.broadcast_add(&causal)?;
+            let att = diffusion_rs_common::nn::ops::softmax_last_dim(&att.to_dtype(DType::F32)?)?
+                .to_dtype(q.dtype())?;
+            att.matmul(&v.clone())?
+        };
+
+        let params = diffusion_rs_common::nn::ops::SdpaParams {
+            mask: Some(padding),
+            causal: true,
+        };
+        let sdpa_output =
+            diffusion_rs_common::nn::ops::sdpa_with_params(&q, &k, &v, scale as f32, 1., &params)?;
+
+        assert_eq!(ground_truth.shape(), sdpa_output.shape());
+
+        let error: f32 = ((&ground_truth - &sdpa_output)?.abs()? / &ground_truth.abs()?)?
+            .sum_all()?
+            .to_scalar()?;
+
+        assert!(error <= 0.0005, "{}", error);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sdpa_vector_2pass_mask() -> crate::core::Result<()> {
+        use crate::core::{DType, Device, Tensor};
+
+        // Allow vectorized, seqlen = 1 but kseqlen is >1024
+        const BS: usize = 4;
+        const R: usize = 1;
+        const L: usize = 2048;
+        const DK: usize = 64;
+        const H: usize = 3;
+        let scale: f64 = f64::from(DK as u32).sqrt().recip();
+
+        let device = Device::new_metal(0)?;
+
+        let q = Tensor::randn(0f32, 1f32, (BS, H, R, DK), &device)?;
+        let k = Tensor::randn(0f32, 1f32, (BS, H, L, DK), &device)?;
+        let v = Tensor::randn(0f32, 1f32, (BS, H, L, DK), &device)?;
+        let mask = Tensor::randn(0f32, 1f32, (BS, H, R, L), &device)?;
+
+        let ground_truth = {
+            let att = (q.clone() * scale)?.matmul(&k.clone().t()?)?;
+            let att = (att + &mask)?;
+            let att = diffusion_rs_common::nn::ops::softmax_last_dim(&att.to_dtype(DType::F32)?)?
+                .to_dtype(q.dtype())?;
+            att.matmul(&v.clone())?
+        };
+
+        let params = diffusion_rs_common::nn::ops::SdpaParams {
+            mask: Some(mask),
+            causal: false,
+        };
+        let sdpa_output =
+            diffusion_rs_common::nn::ops::sdpa_with_params(&q, &k, &v, scale as f32, 1., &params)?;
+
+        assert_eq!(ground_truth.shape(), sdpa_output.shape());
+
+        let error: f32 = ((&ground_truth - &sdpa_output)?.abs()? / &ground_truth.abs()?)?
+            .sum_all()?
+            .to_scalar()?;
+
+        assert!(error <= 0.002, "{}", error);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sdpa_fully_masked_rows() -> crate::core::Result<()> {
+        use crate::core::{DType, Device, Tensor};
+
+        // q_seq = 37 leaves a partial block in the full kernel, q_seq = 1 with kv_seq 256 and
+        // 2048 uses the single and two pass vector kernels.
+        const BS: usize = 2;
+        const DK: usize = 64;
+        const H: usize = 3;
+        let scale: f64 = f64::from(DK as u32).sqrt().recip();
+
+        let device = Device::new_metal(0)?;
+
+        for (r, l) in [(37, 64), (1, 256), (1, 2048)] {
+            let q = Tensor::randn(0f32, 1f32, (BS, H, r, DK), &device)?;
+            let k = Tensor::randn(0f32, 1f32, (BS, H, l, DK), &device)?;
+            let v = Tensor::randn(0f32, 1f32, (BS, H, l, DK), &device)?;
+            // The last query row of the last batch and head is fully masked out.
+            let mask: Vec<f32> = (0..BS * H * r * l)
+                .map(|i| {
+                    if i >= (BS * H * r - 1) * l || i % 5 == 2 {
+                        f32::NEG_INFINITY
+                    } else {
+                        0.
+                    }
+                })
+                .collect();
+            let mask = Tensor::from_vec(mask, (BS, H, r, l), &device)?;
+
+            let ground_truth = {
+                let att = (q.clone() * scale)?.matmul(&k.clone().t()?)?;
+                let att = (att + &mask)?;
+                let att =
+                    diffusion_rs_common::nn::ops::softmax_last_dim(&att.to_dtype(DType::F32)?)?
+                        .to_dtype(q.dtype())?;
+                att.matmul(&v.clone())?
+            };
+
+            let params = diffusion_rs_common::nn::ops::SdpaParams {
+                mask: Some(mask),
+                causal: false,
+            };
+            let sdpa_output = diffusion_rs_common::nn::ops::sdpa_with_params(
+                &q,
+                &k,
+                &v,
+                scale as f32,
+                1.,
+                &params,
+            )?;
+
+            assert_eq!(ground_truth.shape(), sdpa_output.shape());
+            let masked_row = sdpa_output
+                .get(BS - 1)?
+                .get(H - 1)?
+                .get(r - 1)?
+                .to_vec1::<f32>()?;
+            assert!(
+                masked_row.iter().all(|&v| v == 0.),
+                "{r} {l} {masked_row:?}"
+            );
+
+            let error: f32 = (&ground_truth - &sdpa_output)?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar()?;
+            assert!(error <= 1e-3, "{r} {l} {error}");
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn attn_softmax_mask() -> crate::core::Result<()> {
         use crate::core::{Device, Tensor};
@@ -390,4 +702,160 @@ mod metal_sdpa_tests {
 
         Ok(())
     }
+
+    #[test]
+    fn attn_softmax_mask_rank3() -> crate::core::Result<()> {
+        use crate::core::{Device, Tensor};
+
+        let device = Device::new_metal(0)?;
+
+        for kv_seq in [64, 63] {
+            let tensor = Tensor::randn(0f32, 1f32, (4, 32, kv_seq), &device)?;
+            let mask = Tensor::randn(0f32, 1f32, (32, kv_seq), &device)?;
+
+            let ground_truth =
+                diffusion_rs_common::nn::ops::softmax_last_dim(&tensor.broadcast_add(&mask)?)?;
+            let softmax_out =
+                diffusion_rs_common::nn::ops::attn_softmax_last_dim(&tensor, &mask, 1.)?;
+
+            let error: f32 = (&ground_truth - &softmax_out)?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar()?;
+            assert!(error < 1e-5, "{kv_seq}: {error}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn attn_softmax_mask_rank4() -> crate::core::Result<()> {
+        use crate::core::{Device, Tensor};
+
+        let device = Device::new_metal(0)?;
+
+        let tensor = Tensor::randn(0f32, 1f32, (2, 3, 16, 24), &device)?;
+        let mask = Tensor::randn(0f32, 1f32, (16, 24), &device)?;
+
+        let ground_truth =
+            diffusion_rs_common::nn::ops::softmax_last_dim(&tensor.broadcast_add(&mask)?)?;
+        let softmax_out = diffusion_rs_common::nn::ops::attn_softmax_last_dim(&tensor, &mask, 1.)?;
+
+        let error: f32 = (&ground_truth - &softmax_out)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar()?;
+        assert!(error < 1e-5, "{error}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn attn_softmax_mask_bad_rank() -> crate::core::Result<()> {
+        use crate::core::{Device, Tensor};
+
+        let device = Device::new_metal(0)?;
+
+        let mask = Tensor::zeros((16, 24), crate::core::DType::F32, &device)?;
+        for shape in [vec![16, 24], vec![1, 2, 3, 16, 24]] {
+            let tensor = Tensor::zeros(shape, crate::core::DType::F32, &device)?;
+            let err = diffusion_rs_common::nn::ops::attn_softmax_last_dim(&tensor, &mask, 1.)
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains("expects xs of rank 3 or 4"), "{err}");
+        }
+
+        let tensor = Tensor::zeros((2, 16, 24), crate::core::DType::F32, &device)?;
+        let err = diffusion_rs_common::nn::ops::attn_softmax_last_dim(&tensor, &tensor, 1.)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("expects mask of rank 2, got rank 3"), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn sdpa_with_sinks() -> crate::core::Result<()> {
+        use crate::core::{DType, Device, Tensor};
+
+        // Vectorized path, seqlen = 1, attending over the sinks followed by L keys.
+        const BS: usize = 2;
+        const R: usize = 1;
+        const L: usize = 24;
+        const S: usize = 4;
+        const DK: usize = 64;
+        const H: usize = 3;
+        let scale: f64 = f64::from(DK as u32).sqrt().recip();
+
+        let device = Device::new_metal(0)?;
+
+        let q = Tensor::randn(0f32, 1f32, (BS, H, R, DK), &device)?;
+        let k = Tensor::randn(0f32, 1f32, (BS, H, L, DK), &device)?;
+        let v = Tensor::randn(0f32, 1f32, (BS, H, L, DK), &device)?;
+
+        let no_sinks = Tensor::zeros((BS, H, 0, DK), DType::F32, &device)?;
+        let plain = diffusion_rs_common::nn::ops::sdpa(&q, &k, &v, scale as f32, 1.)?;
+        let with_sinks = diffusion_rs_common::nn::ops::sdpa_with_sinks(
+            &q,
+            &k,
+            &v,
+            &no_sinks,
+            &no_sinks,
+            scale as f32,
+            1.,
+        )?;
+        let error: f32 = (&plain - &with_sinks)?.abs()?.sum_all()?.to_scalar()?;
+        assert_eq!(error, 0.);
+
+        let sink_k = Tensor::randn(0f32, 1f32, (BS, H, S, DK), &device)?;
+        let sink_v = Tensor::randn(0f32, 1f32, (BS, H, S, DK), &device)?;
+        let ground_truth = {
+            let k = Tensor::cat(&[&sink_k, &k], 2)?;
+            let v = Tensor::cat(&[&sink_v, &v], 2)?;
+            let att = (q.clone() * scale)?.matmul(&k.t()?)?;
+            let att = diffusion_rs_common::nn::ops::softmax_last_dim(&att)?;
+            att.matmul(&v)?
+        };
+        let sdpa_output = diffusion_rs_common::nn::ops::sdpa_with_sinks(
+            &q,
+            &k,
+            &v,
+            &sink_k,
+            &sink_v,
+            scale as f32,
+            1.,
+        )?;
+        assert_eq!(ground_truth.shape(), sdpa_output.shape());
+        let error: f32 = ((&ground_truth - &sdpa_output)?.abs()? / &ground_truth.abs()?)?
+            .sum_all()?
+            .to_scalar()?;
+        assert!(error <= 0.0017, "{}", error);
+
+        let bad_sinks = Tensor::randn(0f32, 1f32, (BS, H + 1, S, DK), &device)?;
+        assert!(diffusion_rs_common::nn::ops::sdpa_with_sinks(
+            &q,
+            &k,
+            &v,
+            &bad_sinks,
+            &sink_v,
+            scale as f32,
+            1.,
+        )
+        .is_err());
+        let short_sinks = sink_v.narrow(2, 0, S - 1)?;
+        assert!(diffusion_rs_common::nn::ops::sdpa_with_sinks(
+            &q,
+            &k,
+            &v,
+            &sink_k,
+            &short_sinks,
+            scale as f32,
+            1.,
+        )
+        .is_err());
+
+        Ok(())
+    }
 }